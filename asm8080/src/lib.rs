@@ -0,0 +1,245 @@
+//! `asm8080!`: Intel 8080 assembly in Rust, assembled at compile time.
+//!
+//! The statements are separated by `;` and use the syntax of the
+//! [assembler](../rust_8080/assembler/index.html), labels, directives and
+//! macros included. The macro gives a `Vec<u8>` holding the memory from
+//! address 0 to the last byte assembled, ready for `Cpu::from_raw`:
+//!
+//! ```
+//! use asm8080::asm8080;
+//!
+//! let program = asm8080! {
+//!     MVI B, 3;
+//! LOOP: DCR B;
+//!     JNZ LOOP;
+//!     HLT
+//! };
+//! assert_eq!(program, [0x06, 3, 0x05, 0xc2, 0x02, 0x00, 0x76]);
+//! ```
+//!
+//! The Rust literals are accepted as well as the ones of the assembler:
+//! `0x2A`, `0b101`, `"text"`... A number starting with a digit and ending
+//! with a letter, like `0FFH`, must not look like a Rust float: write `0x1E`
+//! rather than `1EH`.
+//!
+//! The errors of the assembler are compile errors, pointing at the token
+//! they are about, or at the statement:
+//!
+//! ```compile_fail
+//! # use asm8080::asm8080;
+//! let program = asm8080! { JMP NOWHERE };
+//! ```
+
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+use rust_8080::assembler;
+
+/// A statement: its source, and the text and the span of its tokens
+#[derive(Default)]
+struct Statement {
+    text: String,
+    tokens: Vec<(String, Span)>,
+}
+
+/// the decimal value of a Rust integer literal like `0x2A` or `1_000u16`
+fn integer(literal: &str) -> Option<String> {
+    let literal = literal.replace('_', "");
+    let literal = ["u8", "u16", "u32", "usize", "i8", "i16", "i32", "isize"]
+        .iter()
+        .find_map(|suffix| literal.strip_suffix(suffix))
+        .unwrap_or(&literal);
+    let (radix, digits) = match literal.get(..2) {
+        Some("0x") => (16, &literal[2..]),
+        Some("0o") => (8, &literal[2..]),
+        Some("0b") => (2, &literal[2..]),
+        _ => (10, literal),
+    };
+    u32::from_str_radix(digits, radix)
+        .ok()
+        .map(|n| n.to_string())
+}
+
+/// the bytes of a Rust string or character literal, without the quotes
+fn unescape(literal: &str) -> Option<Vec<u8>> {
+    let inner = literal.get(1..literal.len() - 1)?;
+    let mut bytes = Vec::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next()? {
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                '0' => '\0',
+                'x' => {
+                    let hex: String = chars.by_ref().take(2).collect();
+                    u8::from_str_radix(&hex, 16).ok()? as char
+                }
+                c => c,
+            },
+            c => c,
+        };
+        if !c.is_ascii() {
+            return None;
+        }
+        bytes.push(c as u8);
+    }
+    Some(bytes)
+}
+
+/// a string of the assembler: the printable characters quoted, the others
+/// as numbers
+fn string(bytes: &[u8]) -> String {
+    if bytes.is_empty() {
+        return "''".to_string();
+    }
+    let mut parts = Vec::new();
+    let mut quoted = String::new();
+    for &b in bytes {
+        if (0x20..0x7f).contains(&b) {
+            if b == b'\'' {
+                quoted.push('\'');
+            }
+            quoted.push(b as char);
+        } else {
+            if !quoted.is_empty() {
+                parts.push(format!("'{}'", quoted));
+                quoted.clear();
+            }
+            parts.push(b.to_string());
+        }
+    }
+    if !quoted.is_empty() {
+        parts.push(format!("'{}'", quoted));
+    }
+    parts.join(",")
+}
+
+/// the text of a literal in the syntax of the assembler
+fn literal(literal: &Literal) -> String {
+    let text = literal.to_string();
+    let unescaped = match text.chars().next() {
+        Some('"') | Some('\'') => unescape(&text),
+        _ => None,
+    };
+    match unescaped {
+        Some(bytes) => string(&bytes),
+        None => integer(&text).unwrap_or(text),
+    }
+}
+
+/// split the tokens in statements at the `;`
+fn statements(input: TokenStream, statements: &mut Vec<Statement>) -> Result<(), (String, Span)> {
+    for token in input {
+        let (text, span) = match &token {
+            TokenTree::Punct(p) if p.as_char() == ';' => {
+                statements.push(Statement::default());
+                continue;
+            }
+            TokenTree::Group(group) if group.delimiter() == Delimiter::None => {
+                self::statements(group.stream(), statements)?;
+                continue;
+            }
+            TokenTree::Group(group) if group.delimiter() == Delimiter::Parenthesis => {
+                let mut inner = vec![Statement::default()];
+                self::statements(group.stream(), &mut inner)?;
+                if inner.len() > 1 {
+                    return Err(("`;` inside parentheses".to_string(), group.span()));
+                }
+                let inner = inner.pop().unwrap_or_default();
+                let last = statements.last_mut().expect("a statement");
+                last.tokens.extend(inner.tokens);
+                (format!("({})", inner.text), group.span())
+            }
+            TokenTree::Group(group) => {
+                return Err(("Unexpected brackets".to_string(), group.span()));
+            }
+            TokenTree::Ident(ident) => (ident.to_string(), ident.span()),
+            TokenTree::Punct(p) => (p.as_char().to_string(), p.span()),
+            TokenTree::Literal(l) => (literal(l), l.span()),
+        };
+        let statement = statements.last_mut().expect("a statement");
+        // `LOOP:` and `A,B` are written without spaces
+        if !statement.text.is_empty() && text != ":" && text != "," {
+            statement.text.push(' ');
+        }
+        statement.text.push_str(&text);
+        if !matches!(token, TokenTree::Group(_)) {
+            statement.tokens.push((text, span));
+        }
+    }
+    Ok(())
+}
+
+/// `compile_error!(message)` at `span`
+fn compile_error(message: &str, span: Span) -> TokenStream {
+    let mut bang = Punct::new('!', Spacing::Alone);
+    bang.set_span(span);
+    let mut message = Literal::string(message);
+    message.set_span(span);
+    let mut group = Group::new(Delimiter::Parenthesis, TokenTree::Literal(message).into());
+    group.set_span(span);
+    [
+        TokenTree::Ident(Ident::new("compile_error", span)),
+        TokenTree::Punct(bang),
+        TokenTree::Group(group),
+    ]
+    .iter()
+    .cloned()
+    .collect()
+}
+
+/// the token of `statement` an error is about, its first token otherwise
+fn culprit(statement: &Statement, message: &str) -> Option<Span> {
+    // the values like `012CH` are compared to the decimal numbers
+    let words: Vec<String> = message
+        .split_whitespace()
+        .map(|word| word.trim_end_matches(':'))
+        .map(|word| match word.strip_suffix('H') {
+            Some(hex) => u16::from_str_radix(hex, 16).map_or(word.to_string(), |n| n.to_string()),
+            None => word.to_string(),
+        })
+        .collect();
+    let about = statement
+        .tokens
+        .iter()
+        .rev()
+        .find(|(text, _)| words.iter().any(|word| word.eq_ignore_ascii_case(text)));
+    about
+        .or_else(|| statement.tokens.first())
+        .map(|(_, span)| *span)
+}
+
+/// Assemble 8080 code at compile time into a `Vec<u8>` loaded at address 0
+#[proc_macro]
+pub fn asm8080(input: TokenStream) -> TokenStream {
+    let mut lines = vec![Statement::default()];
+    if let Err((message, span)) = statements(input, &mut lines) {
+        return compile_error(&message, span);
+    }
+    let source: String = lines.iter().map(|s| format!("\t{}\n", s.text)).collect();
+    let assembly = match assembler::assemble(&source) {
+        Ok(assembly) => assembly,
+        Err(e) => {
+            let (message, span) = match e.downcast_ref::<assembler::Error>() {
+                // the line is given by the span, but not the macros
+                Some(error) if error.macros.is_empty() => {
+                    let statement = error
+                        .location
+                        .line
+                        .checked_sub(1)
+                        .and_then(|i| lines.get(i));
+                    let span = statement.and_then(|s| culprit(s, &error.message));
+                    (error.message.clone(), span)
+                }
+                _ => (e.to_string(), None),
+            };
+            return compile_error(&message, span.unwrap_or_else(Span::call_site));
+        }
+    };
+    let mut memory = vec![0; assembly.origin as usize];
+    memory.extend_from_slice(&assembly.image);
+    let bytes: Vec<String> = memory.iter().map(|b| format!("{}u8", b)).collect();
+    format!("::std::vec::Vec::<u8>::from([{}])", bytes.join(", "))
+        .parse()
+        .expect("a valid vector")
+}
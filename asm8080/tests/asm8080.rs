@@ -0,0 +1,40 @@
+use asm8080::asm8080;
+use rust_8080::Cpu;
+
+#[test]
+fn test_program() {
+    let program = asm8080! {
+        MVI B, 3;
+        MVI A, 0;
+    LOOP: INR A;
+        DCR B;
+        JNZ LOOP;
+        HLT
+    };
+    let mut cpu = Cpu::from_raw(program);
+    cpu.trace = false;
+    while cpu.ram[cpu.pc] != 0x76 {
+        cpu.cycle();
+    }
+    assert_eq!(cpu.reg.a, 3);
+}
+
+#[test]
+fn test_literals() {
+    assert_eq!(
+        asm8080! { DB 0FFH, 0b101, 1_000 AND 0xff, 'A', "a'b\r\n", "" },
+        [0xff, 5, 0xe8, b'A', b'a', b'\'', b'b', 13, 10]
+    );
+    assert_eq!(asm8080! { LXI H, (1 + 2) * 3 }, [0x21, 9, 0]);
+    assert_eq!(asm8080! {}, Vec::<u8>::new());
+}
+
+#[test]
+fn test_origin() {
+    let program = asm8080! {
+        ORG 4;
+    START: JMP START;
+        END START
+    };
+    assert_eq!(program, [0, 0, 0, 0, 0xc3, 4, 0]);
+}
@@ -0,0 +1,1331 @@
+//! Two-pass assembler for the Intel 8080 syntax.
+//!
+//! ```text
+//! BDOS    EQU     5
+//!         ORG     100H
+//! START:  MVI     C,9             ; print the message
+//!         LXI     D,MSG
+//!         CALL    BDOS
+//!         RET
+//! MSG:    DB      'Hello',0DH,0AH,'$'
+//! ```
+//! A line holds an optional label, followed by a `:` or starting in the first
+//! column, an instruction or a directive with its operands, and a `;`
+//! comment. A line starting with `*` is a comment. The operands are
+//! [expressions](expr/index.html), where the registers are the numbers of
+//! their encoding (`B` is 0, `A` is 7, `SP` and `PSW` are 6) as in the
+//! Digital Research ASM.
+//!
+//! The directives are:
+//! - `ORG ADDR` to continue at `ADDR`
+//! - `NAME EQU VALUE` to define a constant, and `NAME SET VALUE` for a name
+//!   which can be redefined
+//! - `DB` for bytes and strings, `DW` for little endian words, and `DS N` to
+//!   reserve `N` bytes
+//! - `END [START]` to stop the assembly
+//!
+//! The macros and the conditions of the Digital Research MAC are supported:
+//! - `NAME MACRO PARAMS` up to `ENDM` defines a macro, whose body can declare
+//!   `LOCAL` labels, given a unique `??NNNN` name by every expansion, and
+//!   stop early with `EXITM` (see [macros] for the substitutions)
+//! - `REPT N` repeats the lines up to `ENDM`, `IRP NAME,<A,B>` repeats them
+//!   with `NAME` replaced by every item of the list and `IRPC NAME,TEXT` by
+//!   every character of `TEXT`
+//! - `IF EXPR`, `ELSE` and `ENDIF` assemble the lines when the expression is
+//!   not 0, `NUL` being true when nothing follows it, like in `IF NUL ARG`
+//! - `INCLUDE FILE` assembles another file, and `MACLIB NAME` the file
+//!   `NAME.LIB`, found next to the source
+//!
+//! The first pass finds the address of every label, the second one writes
+//! the bytes and the [listing]. The names used by `ORG`, `DS`, `IF` and
+//! `REPT` must be defined before them, as the addresses of the following
+//! labels depend on them.
+
+pub mod expr;
+pub mod listing;
+pub mod macros;
+
+use crate::instruction::{AluOp, Cond, Instruction, Pair, PairPsw, Reg};
+use crate::symbols::SymbolTable;
+use crate::Memory;
+use anyhow::{anyhow, bail, Result};
+use expr::{Env, Parser, Token, Value};
+use listing::Entry;
+use macros::Macro;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+/// A line of the source or of an included file, starting at 1
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Location {
+    /// the included file, `None` for the source given to the assembler
+    pub file: Option<String>,
+    pub line: usize,
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{} line {}", file, self.line),
+            None => write!(f, "line {}", self.line),
+        }
+    }
+}
+
+/// An error and where it happened
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Error {
+    /// the line of a file, the call of the macro for an error in a macro
+    pub location: Location,
+    pub message: String,
+    /// the macros being expanded, from the outermost, with the line of their
+    /// definition being assembled
+    pub macros: Vec<(String, Location)>,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: ", self.location)?;
+        for (name, location) in &self.macros {
+            write!(f, "in {}, {}: ", name, location)?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// The result of an assembly
+#[derive(Clone, Debug, Default)]
+pub struct Assembly {
+    /// address of the first byte of `image`
+    pub origin: u16,
+    /// the bytes from the lowest to the highest address written, the gaps
+    /// left by `DS` and `ORG` being zeros
+    pub image: Vec<u8>,
+    /// the labels and the constants
+    pub symbols: SymbolTable,
+    /// the address given to `END`
+    pub entry: Option<u16>,
+    /// the lines assembled, with their address and their bytes
+    pub listing: Vec<Entry>,
+}
+
+impl Assembly {
+    /// the image loaded at its origin
+    pub fn memory(&self) -> Memory {
+        let mut memory = Memory::default();
+        memory.load_at(self.origin as usize, &self.image);
+        memory
+    }
+
+    /// the `.PRN` listing
+    pub fn prn(&self) -> String {
+        listing::prn(&self.listing)
+    }
+
+    /// the `.SYM` file in the Digital Research format
+    pub fn sym(&self) -> String {
+        self.symbols.to_dri()
+    }
+}
+
+/// the instructions, in the order of their opcodes
+const MNEMONICS: [&str; 78] = [
+    "NOP", "LXI", "STAX", "INX", "INR", "DCR", "MVI", "RLC", "DAD", "LDAX", "DCX", "RRC", "RAL",
+    "RAR", "SHLD", "DAA", "LHLD", "CMA", "STA", "STC", "LDA", "CMC", "MOV", "HLT", "ADD", "ADC",
+    "SUB", "SBB", "ANA", "XRA", "ORA", "CMP", "RNZ", "POP", "JNZ", "JMP", "CNZ", "PUSH", "ADI",
+    "RST", "RZ", "RET", "JZ", "CZ", "CALL", "ACI", "RNC", "JNC", "OUT", "CNC", "SUI", "RC", "JC",
+    "IN", "CC", "SBI", "RPO", "JPO", "XTHL", "CPO", "ANI", "RPE", "PCHL", "JPE", "XCHG", "CPE",
+    "XRI", "RP", "JP", "DI", "CP", "ORI", "RM", "SPHL", "JM", "EI", "CM", "CPI",
+];
+
+const DIRECTIVES: [&str; 19] = [
+    "ORG", "EQU", "SET", "DB", "DW", "DS", "END", "MACRO", "ENDM", "LOCAL", "EXITM", "REPT", "IRP",
+    "IRPC", "IF", "ELSE", "ENDIF", "INCLUDE", "MACLIB",
+];
+
+/// the directives repeating the lines up to an `ENDM`
+const BLOCKS: [&str; 4] = ["MACRO", "REPT", "IRP", "IRPC"];
+
+/// the registers, worth the number of their encoding
+const REGISTERS: [(&str, u16); 10] = [
+    ("B", 0),
+    ("C", 1),
+    ("D", 2),
+    ("E", 3),
+    ("H", 4),
+    ("L", 5),
+    ("M", 6),
+    ("A", 7),
+    ("SP", 6),
+    ("PSW", 6),
+];
+
+/// true for the names which can not be defined
+fn is_reserved(name: &str) -> bool {
+    MNEMONICS.contains(&name)
+        || DIRECTIVES.contains(&name)
+        || expr::OPERATORS.contains(&name)
+        || REGISTERS.iter().any(|(reg, _)| *reg == name)
+}
+
+/// A line of a file or of a macro
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Line {
+    pub location: Location,
+    pub text: String,
+}
+
+/// the lines of a file, up to the CP/M end of file marker
+fn lines(file: Option<&str>, text: &str) -> Vec<Line> {
+    let text = text.split('\x1a').next().unwrap_or_default();
+    text.lines()
+        .enumerate()
+        .map(|(i, text)| Line {
+            location: Location {
+                file: file.map(str::to_string),
+                line: i + 1,
+            },
+            text: text.to_string(),
+        })
+        .collect()
+}
+
+/// the name at the start of `text`, after the blanks, and the text after it
+fn word(text: &str) -> (Option<String>, &str) {
+    let text = text.trim_start();
+    match text
+        .find(|c: char| !expr::is_ident(c))
+        .unwrap_or(text.len())
+    {
+        0 => (None, text),
+        end => (Some(text[..end].to_ascii_uppercase()), &text[end..]),
+    }
+}
+
+/// `text` without its `;` comment
+fn strip_comment(text: &str) -> &str {
+    let mut string = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '\'' => string = !string,
+            ';' if !string => return &text[..i],
+            _ => (),
+        }
+    }
+    text
+}
+
+/// A line split in its fields, the operands are kept as text to be given
+/// to the macros
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+struct Statement {
+    label: Option<String>,
+    op: Option<String>,
+    operands: String,
+}
+
+impl Statement {
+    /// split `text`, where a name in the first column is a label unless it
+    /// is an instruction, a directive or a macro
+    fn parse(text: &str, is_macro: &dyn Fn(&str) -> bool) -> Result<Self> {
+        if text.starts_with('*') {
+            return Ok(Statement::default());
+        }
+        let text = strip_comment(text);
+        let first_column = text.starts_with(expr::is_ident);
+        let mut statement = Statement::default();
+        let rest = match (word(text), word(word(text).1).0) {
+            ((Some(label), after), _) if after.trim_start().starts_with(':') => {
+                statement.label = Some(label);
+                &after.trim_start()[1..]
+            }
+            ((Some(label), after), Some(op)) if ["EQU", "SET", "MACRO"].contains(&&op[..]) => {
+                statement.label = Some(label);
+                after
+            }
+            ((Some(label), after), _)
+                if first_column && !is_reserved(&label) && !is_macro(&label) =>
+            {
+                statement.label = Some(label);
+                after
+            }
+            _ => text,
+        };
+        if let Some(label) = &statement.label {
+            if is_reserved(label) {
+                bail!("{} is a reserved word", label);
+            }
+            if label.starts_with(|c: char| c.is_ascii_digit()) {
+                bail!("Invalid label {}", label);
+            }
+        }
+        match word(rest) {
+            (Some(op), operands) => {
+                statement.op = Some(op);
+                statement.operands = operands.trim().to_string();
+            }
+            (None, rest) if rest.trim().is_empty() => (),
+            (None, rest) => bail!("Unexpected {}, expected an instruction", rest.trim()),
+        }
+        Ok(statement)
+    }
+
+    /// the directive or the instruction of a line, if it can be parsed
+    fn op(text: &str) -> Option<String> {
+        Statement::parse(text, &|_| false).ok()?.op
+    }
+}
+
+/// split the operands at the commas
+fn operands(tokens: &[Token]) -> Vec<&[Token]> {
+    match tokens {
+        [] => Vec::new(),
+        tokens => tokens.split(|t| *t == Token::Comma).collect(),
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Kind {
+    Label,
+    Equ,
+    Set,
+}
+
+/// The lines being assembled: a file, or the expansion of a macro
+struct Frame {
+    lines: Rc<Vec<Line>>,
+    next: usize,
+    /// the macro, `REPT`, `IRP` or `IRPC` expanded, `None` for a file
+    expansion: Option<String>,
+    /// the number of `IF` opened before the frame
+    conditions: usize,
+}
+
+impl Frame {
+    /// the line being assembled
+    fn location(&self) -> Location {
+        match self.lines.get(self.next.saturating_sub(1)) {
+            Some(line) => line.location.clone(),
+            None => Location::default(),
+        }
+    }
+}
+
+/// An `IF` being assembled
+struct Condition {
+    /// the lines are assembled
+    active: bool,
+    /// the lines around the `IF` are assembled
+    parent: bool,
+    seen_else: bool,
+}
+
+#[derive(Default)]
+struct Assembler {
+    /// the names and their value
+    symbols: HashMap<String, (u16, Kind)>,
+    /// the names in the order of their definition
+    order: Vec<String>,
+    /// 1 or 2
+    pass: u8,
+    pc: u16,
+    /// the address of the current statement, `$`
+    here: u16,
+    memory: Vec<u8>,
+    /// the lowest and the highest address written
+    written: Option<(u16, u16)>,
+    entry: Option<u16>,
+    source: Rc<Vec<Line>>,
+    frames: Vec<Frame>,
+    conditions: Vec<Condition>,
+    macros: HashMap<String, Rc<Macro>>,
+    /// the number of local labels generated
+    locals: usize,
+    /// where the included files are searched
+    dir: Option<PathBuf>,
+    /// the included files already read, by name
+    files: HashMap<String, Rc<Vec<Line>>>,
+    listing: Vec<Entry>,
+}
+
+impl Env for Assembler {
+    fn symbol(&self, name: &str) -> Result<Option<u16>> {
+        if let Some((_, value)) = REGISTERS.iter().find(|(reg, _)| *reg == name) {
+            return Ok(Some(*value));
+        }
+        match self.symbols.get(name) {
+            Some((value, _)) => Ok(Some(*value)),
+            None if self.pass == 1 => Ok(None),
+            None => bail!("Undefined symbol {}", name),
+        }
+    }
+
+    fn here(&self) -> u16 {
+        self.here
+    }
+}
+
+impl Assembler {
+    /// evaluate an operand
+    fn eval(&self, tokens: &[Token]) -> Result<Value> {
+        let mut parser = Parser::new(tokens, self);
+        let value = parser.expr()?;
+        match parser.peek() {
+            None => Ok(value),
+            Some(token) => bail!("Unexpected {}", token),
+        }
+    }
+
+    /// evaluate an operand which must be known in the first pass
+    fn now(&self, tokens: &[Token]) -> Result<u16> {
+        let value = self.eval(tokens)?;
+        match value.defined {
+            true => Ok(value.value),
+            false => bail!("The operand must only use names defined before"),
+        }
+    }
+
+    fn word(&self, tokens: &[Token]) -> Result<u16> {
+        Ok(self.eval(tokens)?.value)
+    }
+
+    /// a byte, or a negative number down to -256
+    fn byte(&self, tokens: &[Token]) -> Result<u8> {
+        let value = self.eval(tokens)?.value;
+        match value {
+            0..=0xff | 0xff00..=0xffff => Ok(value as u8),
+            _ => bail!("Value {:04X}H does not fit in a byte", value),
+        }
+    }
+
+    fn reg(&self, tokens: &[Token]) -> Result<Reg> {
+        use Reg::*;
+
+        let regs = [B, C, D, E, H, L, M, A];
+        match regs.get(self.eval(tokens)?.value as usize) {
+            Some(&reg) => Ok(reg),
+            None => bail!("Invalid register"),
+        }
+    }
+
+    fn pair(&self, tokens: &[Token]) -> Result<Pair> {
+        match self.eval(tokens)?.value {
+            0 => Ok(Pair::BC),
+            2 => Ok(Pair::DE),
+            4 => Ok(Pair::HL),
+            6 => Ok(Pair::SP),
+            _ => bail!("Invalid register pair"),
+        }
+    }
+
+    fn pair_psw(&self, tokens: &[Token]) -> Result<PairPsw> {
+        Ok(match self.pair(tokens)? {
+            Pair::BC => PairPsw::BC,
+            Pair::DE => PairPsw::DE,
+            Pair::HL => PairPsw::HL,
+            Pair::SP => PairPsw::PSW,
+        })
+    }
+
+    /// define a name, which can only change when it is set
+    fn define(&mut self, name: &str, value: u16, kind: Kind) -> Result<()> {
+        match self.symbols.get(name) {
+            None => self.order.push(name.to_string()),
+            Some(&(_, Kind::Set)) if kind == Kind::Set => (),
+            Some(&(old, old_kind)) if self.pass == 2 && old_kind == kind => {
+                if old != value {
+                    bail!("Phase error: {} was {:04X}H, now {:04X}H", name, old, value);
+                }
+            }
+            Some(_) => bail!("{} is already defined", name),
+        }
+        self.symbols.insert(name.to_string(), (value, kind));
+        Ok(())
+    }
+
+    /// the listing of the current line, in the second pass
+    fn entry(&mut self) -> Option<&mut Entry> {
+        match self.pass {
+            2 => self.listing.last_mut(),
+            _ => None,
+        }
+    }
+
+    /// list a line of the current file or macro
+    fn list(&mut self, line: &Line) {
+        if self.pass == 2 {
+            let expanded = self.frames.last().is_some_and(|f| f.expansion.is_some());
+            self.listing.push(Entry {
+                text: line.text.clone(),
+                expanded,
+                ..Default::default()
+            });
+        }
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            let pc = self.pc;
+            if let Some(entry) = self.entry() {
+                entry.addr.get_or_insert(pc);
+                entry.bytes.push(byte);
+            }
+            if self.pass == 2 {
+                self.memory[self.pc as usize] = byte;
+                self.written = Some(match self.written {
+                    Some((low, high)) => (low.min(self.pc), high.max(self.pc)),
+                    None => (self.pc, self.pc),
+                });
+            }
+            self.pc = self.pc.wrapping_add(1);
+        }
+    }
+
+    /// assemble a statement without macros nor conditions, false after
+    /// `END`
+    fn statement(&mut self, statement: &Statement) -> Result<bool> {
+        let op = statement.op.as_deref().unwrap_or_default();
+        let tokens = expr::tokenize(&statement.operands)?;
+        let args = operands(&tokens);
+        let count = |n: usize| match args.len() == n {
+            true => Ok(()),
+            false => Err(anyhow!("{} expects {} operand(s)", op, n)),
+        };
+        match op {
+            "" => (),
+            "ORG" => {
+                count(1)?;
+                self.pc = self.now(args[0])?;
+                let pc = self.pc;
+                if let Some(entry) = self.entry() {
+                    entry.addr = Some(pc);
+                }
+            }
+            "EQU" | "SET" => {
+                count(1)?;
+                let label = match &statement.label {
+                    Some(label) => label,
+                    None => bail!("{} needs a name", op),
+                };
+                let value = self.eval(args[0])?;
+                let kind = if op == "EQU" { Kind::Equ } else { Kind::Set };
+                // defined in the second pass when it uses a later label
+                if value.defined {
+                    self.define(label, value.value, kind)?;
+                }
+                if let Some(entry) = self.entry() {
+                    entry.value = Some(value.value);
+                }
+            }
+            "DB" => {
+                if args.is_empty() {
+                    bail!("DB expects operands");
+                }
+                for arg in args {
+                    match arg {
+                        [Token::Str(s)] if s.len() != 1 => self.emit(s),
+                        arg => {
+                            let byte = self.byte(arg)?;
+                            self.emit(&[byte]);
+                        }
+                    }
+                }
+            }
+            "DW" => {
+                if args.is_empty() {
+                    bail!("DW expects operands");
+                }
+                for arg in args {
+                    let word = self.word(arg)?;
+                    self.emit(&word.to_le_bytes());
+                }
+            }
+            "DS" => {
+                count(1)?;
+                self.pc = self.pc.wrapping_add(self.now(args[0])?);
+                let here = self.here;
+                if let Some(entry) = self.entry() {
+                    entry.addr = Some(here);
+                }
+            }
+            "END" => {
+                if !args.is_empty() {
+                    count(1)?;
+                    self.entry = Some(self.word(args[0])?);
+                }
+                return Ok(false);
+            }
+            op => {
+                let instruction = self.instruction(op, &args)?;
+                self.emit(&instruction.encode());
+            }
+        }
+        Ok(true)
+    }
+
+    fn instruction(&self, op: &str, args: &[&[Token]]) -> Result<Instruction> {
+        use Instruction::*;
+
+        let count = |n: usize| match args.len() == n {
+            true => Ok(()),
+            false => Err(anyhow!("{} expects {} operand(s)", op, n)),
+        };
+        let alu = |name: &str| {
+            let ops = ["ADD", "ADC", "SUB", "SBB", "ANA", "XRA", "ORA", "CMP"];
+            let imms = ["ADI", "ACI", "SUI", "SBI", "ANI", "XRI", "ORI", "CPI"];
+            let all = [
+                AluOp::Add,
+                AluOp::Adc,
+                AluOp::Sub,
+                AluOp::Sbb,
+                AluOp::Ana,
+                AluOp::Xra,
+                AluOp::Ora,
+                AluOp::Cmp,
+            ];
+            let find = |names: [&str; 8]| names.iter().position(|n| *n == name).map(|i| all[i]);
+            (find(ops), find(imms))
+        };
+        let cond = |name: &str| {
+            let conds = [
+                ("NZ", Cond::NZ),
+                ("Z", Cond::Z),
+                ("NC", Cond::NC),
+                ("C", Cond::C),
+                ("PO", Cond::PO),
+                ("PE", Cond::PE),
+                ("P", Cond::P),
+                ("M", Cond::M),
+            ];
+            conds.iter().find(|(n, _)| *n == name).map(|&(_, c)| c)
+        };
+
+        let no_operand = match op {
+            "NOP" => Some(Nop),
+            "RLC" => Some(Rlc),
+            "RRC" => Some(Rrc),
+            "RAL" => Some(Ral),
+            "RAR" => Some(Rar),
+            "DAA" => Some(Daa),
+            "CMA" => Some(Cma),
+            "STC" => Some(Stc),
+            "CMC" => Some(Cmc),
+            "HLT" => Some(Hlt),
+            "RET" => Some(Ret),
+            "XTHL" => Some(Xthl),
+            "PCHL" => Some(Pchl),
+            "XCHG" => Some(Xchg),
+            "DI" => Some(Di),
+            "EI" => Some(Ei),
+            "SPHL" => Some(Sphl),
+            _ => None,
+        };
+        if let Some(instruction) = no_operand {
+            count(0)?;
+            return Ok(instruction);
+        }
+        Ok(match op {
+            "MOV" => {
+                count(2)?;
+                let (dst, src) = (self.reg(args[0])?, self.reg(args[1])?);
+                if (dst, src) == (Reg::M, Reg::M) {
+                    bail!("MOV M,M is HLT");
+                }
+                Mov(dst, src)
+            }
+            "MVI" => {
+                count(2)?;
+                Mvi(self.reg(args[0])?, self.byte(args[1])?)
+            }
+            "INR" | "DCR" => {
+                count(1)?;
+                let reg = self.reg(args[0])?;
+                if op == "INR" {
+                    Inr(reg)
+                } else {
+                    Dcr(reg)
+                }
+            }
+            "LXI" => {
+                count(2)?;
+                Lxi(self.pair(args[0])?, self.word(args[1])?)
+            }
+            "INX" | "DCX" | "DAD" | "LDAX" | "STAX" => {
+                count(1)?;
+                let pair = self.pair(args[0])?;
+                match op {
+                    "INX" => Inx(pair),
+                    "DCX" => Dcx(pair),
+                    "DAD" => Dad(pair),
+                    _ if pair == Pair::HL || pair == Pair::SP => {
+                        bail!("{} only takes B or D", op)
+                    }
+                    "LDAX" => Ldax(pair),
+                    _ => Stax(pair),
+                }
+            }
+            "PUSH" | "POP" => {
+                count(1)?;
+                let pair = self.pair_psw(args[0])?;
+                if op == "PUSH" {
+                    Push(pair)
+                } else {
+                    Pop(pair)
+                }
+            }
+            "SHLD" | "LHLD" | "STA" | "LDA" | "JMP" | "CALL" => {
+                count(1)?;
+                let addr = self.word(args[0])?;
+                match op {
+                    "SHLD" => Shld(addr),
+                    "LHLD" => Lhld(addr),
+                    "STA" => Sta(addr),
+                    "LDA" => Lda(addr),
+                    "JMP" => Jmp(addr),
+                    _ => Call(addr),
+                }
+            }
+            "IN" | "OUT" => {
+                count(1)?;
+                let port = self.byte(args[0])?;
+                if op == "IN" {
+                    In(port)
+                } else {
+                    Out(port)
+                }
+            }
+            "RST" => {
+                count(1)?;
+                match self.eval(args[0])?.value {
+                    n @ 0..=7 => Rst(n as u8),
+                    _ => bail!("RST expects a number from 0 to 7"),
+                }
+            }
+            op => match (alu(op), op.split_at(1)) {
+                ((Some(alu), _), _) => {
+                    count(1)?;
+                    Alu(alu, self.reg(args[0])?)
+                }
+                ((_, Some(alu)), _) => {
+                    count(1)?;
+                    AluImm(alu, self.byte(args[0])?)
+                }
+                (_, ("R", c)) if cond(c).is_some() => {
+                    count(0)?;
+                    Rcc(cond(c).unwrap())
+                }
+                (_, ("J", c)) if cond(c).is_some() => {
+                    count(1)?;
+                    Jcc(cond(c).unwrap(), self.word(args[0])?)
+                }
+                (_, ("C", c)) if cond(c).is_some() => {
+                    count(1)?;
+                    Ccc(cond(c).unwrap(), self.word(args[0])?)
+                }
+                _ => bail!("Unknown instruction {}", op),
+            },
+        })
+    }
+
+    /// true when the current lines are assembled
+    fn active(&self) -> bool {
+        self.conditions.last().is_none_or(|c| c.active)
+    }
+
+    /// assemble `lines` before the rest of the current ones
+    fn push(&mut self, lines: Vec<Line>, expansion: Option<String>) -> Result<()> {
+        if self.frames.len() >= 64 {
+            bail!("Too many nested macros or files");
+        }
+        self.frames.push(Frame {
+            lines: Rc::new(lines),
+            next: 0,
+            expansion,
+            conditions: self.conditions.len(),
+        });
+        Ok(())
+    }
+
+    /// the next line to assemble, finishing the files and the macros
+    fn next_line(&mut self) -> Result<Option<Line>> {
+        while let Some(frame) = self.frames.last_mut() {
+            if let Some(line) = frame.lines.get(frame.next) {
+                frame.next += 1;
+                return Ok(Some(line.clone()));
+            }
+            if self.conditions.len() > frame.conditions {
+                bail!("IF without ENDIF");
+            }
+            self.frames.pop();
+        }
+        Ok(None)
+    }
+
+    /// the lines up to the `ENDM` closing a `MACRO`, `REPT`, `IRP` or `IRPC`
+    fn body(&mut self) -> Result<Vec<Line>> {
+        let mut body = Vec::new();
+        let mut depth = 0;
+        loop {
+            let frame = self.frames.last_mut().expect("no lines");
+            let line = match frame.lines.get(frame.next) {
+                Some(line) => line.clone(),
+                None => bail!("Missing ENDM"),
+            };
+            frame.next += 1;
+            self.list(&line);
+            match Statement::op(&line.text).as_deref() {
+                Some(op) if BLOCKS.contains(&op) => depth += 1,
+                Some("ENDM") if depth == 0 => return Ok(body),
+                Some("ENDM") => depth -= 1,
+                _ => (),
+            }
+            body.push(line);
+        }
+    }
+
+    /// the lines of `body` with the parameters and the local labels replaced
+    fn expand(&mut self, body: &[Line], params: &[(String, String)]) -> Vec<Line> {
+        let mut params = params.to_vec();
+        let mut lines = Vec::new();
+        let mut depth = 0;
+        for line in body {
+            match Statement::op(&line.text).as_deref() {
+                Some("LOCAL") if depth == 0 => {
+                    let operands = Statement::parse(&line.text, &|_| false)
+                        .map(|s| s.operands)
+                        .unwrap_or_default();
+                    for name in macros::arguments(&operands) {
+                        self.locals += 1;
+                        params.push((name, format!("??{:04}", self.locals)));
+                    }
+                    continue;
+                }
+                Some(op) if BLOCKS.contains(&op) => depth += 1,
+                Some("ENDM") => depth -= 1,
+                _ => (),
+            }
+            lines.push(line);
+        }
+        lines
+            .into_iter()
+            .map(|line| Line {
+                location: line.location.clone(),
+                text: macros::substitute(&line.text, &params),
+            })
+            .collect()
+    }
+
+    /// `IF`, `ELSE` and `ENDIF`, which are followed even in the lines which
+    /// are not assembled
+    fn condition(&mut self, op: &str, operands: &str) -> Result<()> {
+        // the conditions opened in the current file or macro
+        let open = self.conditions.len() > self.frames.last().map_or(0, |f| f.conditions);
+        match op {
+            "IF" => {
+                let parent = self.active();
+                let active = parent && self.now(&expr::tokenize(operands)?)? != 0;
+                self.conditions.push(Condition {
+                    active,
+                    parent,
+                    seen_else: false,
+                });
+            }
+            "ELSE" => match self.conditions.last_mut() {
+                Some(condition) if open => {
+                    if condition.seen_else {
+                        bail!("ELSE after ELSE");
+                    }
+                    condition.seen_else = true;
+                    condition.active = condition.parent && !condition.active;
+                }
+                _ => bail!("ELSE without IF"),
+            },
+            "ENDIF" => match open {
+                true => drop(self.conditions.pop()),
+                false => bail!("ENDIF without IF"),
+            },
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// find an included file
+    fn load(&mut self, name: &str) -> Result<Rc<Vec<Line>>> {
+        if let Some(lines) = self.files.get(name) {
+            return Ok(lines.clone());
+        }
+        let dir = self.dir.clone().unwrap_or_default();
+        let path = [name.to_string(), name.to_ascii_lowercase()]
+            .iter()
+            .map(|name| dir.join(name))
+            .find(|path| path.exists())
+            .ok_or_else(|| anyhow!("Cannot find {}", name))?;
+        let text = std::fs::read(path)?;
+        let lines = Rc::new(lines(Some(name), &String::from_utf8_lossy(&text)));
+        self.files.insert(name.to_string(), lines.clone());
+        Ok(lines)
+    }
+
+    /// assemble a line, false after `END`
+    fn line(&mut self, line: &Line) -> Result<bool> {
+        self.list(line);
+        if !self.active() {
+            if let Some(op) = Statement::op(&line.text) {
+                self.condition(&op, "")?;
+            }
+            return Ok(true);
+        }
+        let statement = Statement::parse(&line.text, &|name| self.macros.contains_key(name))?;
+        self.here = self.pc;
+        let op = statement.op.as_deref().unwrap_or_default();
+        match (&statement.label, op) {
+            (_, "EQU") | (_, "SET") | (_, "MACRO") => (),
+            (Some(label), _) => {
+                let pc = self.pc;
+                self.define(label, pc, Kind::Label)?;
+                if let Some(entry) = self.entry() {
+                    entry.addr = Some(pc);
+                }
+            }
+            (None, _) => (),
+        }
+        match op {
+            "IF" | "ELSE" | "ENDIF" => self.condition(op, &statement.operands)?,
+            "MACRO" => {
+                let name = match &statement.label {
+                    Some(name) => name.clone(),
+                    None => bail!("MACRO needs a name"),
+                };
+                let mut params = Vec::new();
+                for param in macros::arguments(&statement.operands) {
+                    match word(&param) {
+                        (Some(name), "") => params.push(name),
+                        _ => bail!("Invalid parameter {}", param),
+                    }
+                }
+                let body = self.body()?;
+                self.macros.insert(name, Rc::new(Macro { params, body }));
+            }
+            "REPT" => {
+                let count = self.now(&expr::tokenize(&statement.operands)?)?;
+                let body = self.body()?;
+                let mut lines = Vec::new();
+                for _ in 0..count {
+                    lines.extend(self.expand(&body, &[]));
+                }
+                self.push(lines, Some(op.to_string()))?;
+            }
+            "IRP" | "IRPC" => {
+                let args = macros::arguments(&statement.operands);
+                let (param, list) = match (args.first().map(|p| word(p)), args.get(1)) {
+                    (Some((Some(param), "")), Some(list)) if args.len() == 2 => (param, list),
+                    _ => bail!("{} expects a name and a list", op),
+                };
+                let items: Vec<String> = match op {
+                    "IRP" => macros::arguments(list),
+                    _ => list.chars().map(String::from).collect(),
+                };
+                let body = self.body()?;
+                let mut lines = Vec::new();
+                for item in items {
+                    lines.extend(self.expand(&body, &[(param.clone(), item)]));
+                }
+                self.push(lines, Some(op.to_string()))?;
+            }
+            "ENDM" => bail!("ENDM without MACRO"),
+            "LOCAL" => bail!("LOCAL outside of a macro"),
+            "EXITM" => {
+                let frame = match self.frames.iter().rposition(|f| f.expansion.is_some()) {
+                    Some(frame) => frame,
+                    None => bail!("EXITM outside of a macro"),
+                };
+                self.conditions.truncate(self.frames[frame].conditions);
+                self.frames.truncate(frame);
+            }
+            "INCLUDE" | "MACLIB" => {
+                let mut name = statement.operands.trim_matches('\'').to_string();
+                if op == "MACLIB" && !name.contains('.') {
+                    name.push_str(".LIB");
+                }
+                let lines = self.load(&name)?;
+                self.push(lines.to_vec(), None)?;
+            }
+            name if self.macros.contains_key(name) => {
+                let mac = self.macros[name].clone();
+                let args = macros::arguments(&statement.operands);
+                if args.len() > mac.params.len() {
+                    bail!("{} expects {} parameter(s)", name, mac.params.len());
+                }
+                let params: Vec<_> = mac
+                    .params
+                    .iter()
+                    .cloned()
+                    .zip(args.into_iter().chain(std::iter::repeat(String::new())))
+                    .collect();
+                let lines = self.expand(&mac.body, &params);
+                self.push(lines, Some(name.to_string()))?;
+            }
+            _ => return self.statement(&statement),
+        }
+        Ok(true)
+    }
+
+    /// the error `e` at the line being assembled
+    fn error(&self, e: anyhow::Error) -> Error {
+        let file = self.frames.iter().rposition(|f| f.expansion.is_none());
+        let (location, expansions) = match file {
+            Some(file) => (self.frames[file].location(), &self.frames[file + 1..]),
+            None => (Location::default(), &[][..]),
+        };
+        Error {
+            location,
+            message: e.to_string(),
+            macros: expansions
+                .iter()
+                .map(|f| (f.expansion.clone().unwrap_or_default(), f.location()))
+                .collect(),
+        }
+    }
+
+    /// run a pass over the source
+    fn pass(&mut self, pass: u8) -> Result<(), Error> {
+        self.pass = pass;
+        self.pc = 0;
+        self.entry = None;
+        self.listing.clear();
+        self.locals = 0;
+        self.macros.clear();
+        self.conditions.clear();
+        self.frames.clear();
+        self.push(self.source.to_vec(), None)
+            .map_err(|e| self.error(e))?;
+        loop {
+            let line = match self.next_line() {
+                Ok(Some(line)) => line,
+                Ok(None) => break,
+                Err(e) => return Err(self.error(e)),
+            };
+            match self.line(&line) {
+                Ok(true) => (),
+                Ok(false) => break,
+                Err(e) => return Err(self.error(e)),
+            }
+        }
+        Ok(())
+    }
+
+    /// assemble `source`
+    fn run(mut self, source: &str) -> Result<Assembly> {
+        self.source = Rc::new(lines(None, source));
+        self.memory = vec![0; 0x10000];
+        self.pass(1)?;
+        self.pass(2)?;
+
+        let mut symbols = SymbolTable::new();
+        for name in &self.order {
+            symbols.insert(name, self.symbols[name].0);
+        }
+        let (origin, image) = match self.written {
+            Some((low, high)) => (low, self.memory[low as usize..=high as usize].to_vec()),
+            None => (0, Vec::new()),
+        };
+        Ok(Assembly {
+            origin,
+            image,
+            symbols,
+            entry: self.entry,
+            listing: self.listing,
+        })
+    }
+}
+
+/// assemble `source`, the included files are searched in the current
+/// directory
+pub fn assemble(source: &str) -> Result<Assembly> {
+    Assembler::default().run(source)
+}
+
+/// assemble the file `file`, the included files are searched next to it
+pub fn assemble_file(file: &str) -> Result<Assembly> {
+    let source = std::fs::read(file)?;
+    let assembler = Assembler {
+        dir: std::path::Path::new(file).parent().map(PathBuf::from),
+        ..Default::default()
+    };
+    assembler.run(&String::from_utf8_lossy(&source))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decompiler::traversal::{self, Hints};
+
+    fn error(source: &str) -> Error {
+        assemble(source).unwrap_err().downcast().unwrap()
+    }
+
+    #[test]
+    fn test_assemble() {
+        let source = "
+BDOS    EQU     5
+        ORG     100H
+START:  MVI     C,9             ; print the message
+        LXI     D,MSG
+        CALL    BDOS
+LOOP    JNZ     LOOP
+        RET
+MSG:    DB      'Hello',0DH,0AH,'$'
+        DW      START, HIGH MSG, -2
+        DS      2
+        DB      'A'+1, 'it''s'
+COUNT   SET     1
+COUNT   SET     COUNT+1
+        MVI     A,COUNT
+        END     START
+        NOP
+";
+        let assembly = assemble(source).unwrap();
+        assert_eq!(assembly.origin, 0x100);
+        assert_eq!(assembly.entry, Some(0x100));
+        assert_eq!(
+            assembly.image,
+            [
+                0x0e, 0x09, 0x11, 0x0c, 0x01, 0xcd, 0x05, 0x00, 0xc2, 0x08, 0x01, 0xc9, b'H', b'e',
+                b'l', b'l', b'o', 0x0d, 0x0a, b'$', 0x00, 0x01, 0x01, 0x00, 0xfe, 0xff, 0x00, 0x00,
+                b'B', b'i', b't', b'\'', b's', 0x3e, 0x02
+            ]
+        );
+        assert_eq!(assembly.symbols.addr("msg"), Some(0x10c));
+        assert_eq!(assembly.symbols.addr("LOOP"), Some(0x108));
+        assert_eq!(assembly.symbols.addr("BDOS"), Some(5));
+        assert_eq!(assembly.symbols.addr("COUNT"), Some(2));
+        assert_eq!(assembly.memory()[0x100], 0x0e);
+    }
+
+    #[test]
+    fn test_instructions() {
+        // every documented opcode, assembled and decoded back
+        for opcode in 0..=255u8 {
+            let bytes = [opcode, 0x34, 0x12];
+            let instruction = Instruction::decode(&bytes).unwrap();
+            if instruction.is_undocumented() {
+                continue;
+            }
+            let text = crate::decompiler::instr_with_format(
+                &bytes,
+                &SymbolTable::new(),
+                &crate::decompiler::Format::plain(),
+            )
+            .0;
+            let assembly = assemble(&format!("\t{}", text)).unwrap();
+            assert_eq!(assembly.image, instruction.encode(), "{}", text);
+        }
+        let image = |source| assemble(source).unwrap().image;
+        assert_eq!(
+            image(" PUSH PSW\n POP B\n LXI SP,0"),
+            [0xf5, 0xc1, 0x31, 0, 0]
+        );
+        assert_eq!(
+            image(" MOV A,M\n MVI M,-1\n RST 7"),
+            [0x7e, 0x36, 0xff, 0xff]
+        );
+        assert_eq!(
+            image(" LXI H,'AB'\n CPI 'a'"),
+            [0x21, 0x42, 0x41, 0xfe, 0x61]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            error("\tNOP\n\tMVI A,300"),
+            Error {
+                location: Location {
+                    file: None,
+                    line: 2
+                },
+                message: "Value 012CH does not fit in a byte".into(),
+                macros: Vec::new(),
+            }
+        );
+        assert_eq!(error("\tJMP NOWHERE").message, "Undefined symbol NOWHERE");
+        assert_eq!(error("X:\tNOP\nX:\tNOP").location.line, 2);
+        assert_eq!(error("\tFOO A").message, "Unknown instruction FOO");
+        assert_eq!(error("\tMOV A").message, "MOV expects 2 operand(s)");
+        assert_eq!(error("\tLDAX H").message, "LDAX only takes B or D");
+        assert_eq!(error("\tMVI X,1").message, "Undefined symbol X");
+        assert_eq!(error("\tMVI 8,1").message, "Invalid register");
+        assert_eq!(error("\tDS LATER\nLATER EQU 1").location.line, 1);
+        assert_eq!(error("A:\tNOP").message, "A is a reserved word");
+        assert_eq!(error("\tDB 'abc").message, "Unterminated string");
+        assert_eq!(error("\tMVI A,1 2").message, "Unexpected 2");
+        assert_eq!(
+            error("X\tEQU\t1\nX\tEQU\t2").message,
+            "X is already defined"
+        );
+        assert!(error("\tMVI A,1/0").to_string().starts_with("line 1: "));
+    }
+
+    #[test]
+    fn test_macros() {
+        let source = "
+PRINT   MACRO   MSG, CHAR
+        LOCAL   TEXT, SKIP
+        JMP     SKIP
+TEXT:   DB      '&MSG$'
+SKIP:   LXI     D,TEXT
+        IF      NOT NUL CHAR
+        MVI     E,CHAR
+        ENDIF
+        ENDM
+        ORG     100H
+        PRINT   <Hi, you>
+        PRINT   Yo,'!'
+        REPT    2
+        NOP
+        ENDM
+        IRP     REG,<B,C>
+        INR     REG
+        ENDM
+        IRPC    X,AB
+        DB      '&X'
+        ENDM
+";
+        let assembly = assemble(source).unwrap();
+        assert_eq!(
+            assembly.image,
+            [
+                0xc3, 0x0b, 0x01, b'H', b'i', b',', b' ', b'y', b'o', b'u', b'$', 0x11, 0x03, 0x01,
+                0xc3, 0x14, 0x01, b'Y', b'o', b'$', 0x11, 0x11, 0x01, 0x1e, b'!', 0x00, 0x00, 0x04,
+                0x0c, b'A', b'B'
+            ]
+        );
+        assert_eq!(assembly.symbols.addr("??0001"), Some(0x103));
+        assert_eq!(assembly.symbols.addr("??0004"), Some(0x114));
+
+        // EXITM, and a macro calling another one
+        let source = "
+TWICE   MACRO   N
+        ONCE    N
+        ONCE    N
+        ENDM
+ONCE    MACRO   N
+        IF      N EQ 0
+        EXITM
+        ENDIF
+        DB      N
+        ENDM
+        TWICE   1
+        TWICE   0
+        ONCE    2
+";
+        assert_eq!(assemble(source).unwrap().image, [1, 1, 2]);
+    }
+
+    #[test]
+    fn test_conditions() {
+        let source = "
+DEBUG   EQU     0
+        IF      DEBUG
+        DB      1
+        IF      1
+        DB      2
+        ELSE
+        DB      3
+        ENDIF
+        ELSE
+        DB      4
+        IF      DEBUG EQ 0
+        DB      5
+        ENDIF
+        ENDIF
+        IF      0
+        this line is not assembled
+        ENDIF
+";
+        assert_eq!(assemble(source).unwrap().image, [4, 5]);
+        assert_eq!(error("\tIF 1\n\tNOP").message, "IF without ENDIF");
+        assert_eq!(error("\tENDIF").message, "ENDIF without IF");
+        assert_eq!(
+            error("\tIF 1\n\tELSE\n\tELSE\n\tENDIF").message,
+            "ELSE after ELSE"
+        );
+        assert_eq!(error("\tIF LATER\n\tENDIF\nLATER:").location.line, 1);
+    }
+
+    #[test]
+    fn test_include() {
+        let mut assembler = Assembler::default();
+        let lib = "SAVE    MACRO\n        PUSH    B\n        FOO\n        ENDM\n";
+        assembler
+            .files
+            .insert("REGS.LIB".into(), Rc::new(lines(Some("REGS.LIB"), lib)));
+        assembler.files.insert(
+            "consts.asm".into(),
+            Rc::new(lines(Some("consts.asm"), "ONE\tEQU\t1\n")),
+        );
+        let source = "\tMACLIB\tREGS\n\tINCLUDE\tconsts.asm\n\tMVI\tA,ONE\n\tSAVE\n";
+        let e: Error = assembler.run(source).unwrap_err().downcast().unwrap();
+        // the error is reported at the call and in the macro
+        assert_eq!(
+            e.to_string(),
+            "line 4: in SAVE, REGS.LIB line 3: Unknown instruction FOO"
+        );
+        assert_eq!(
+            e.macros,
+            [(
+                "SAVE".to_string(),
+                Location {
+                    file: Some("REGS.LIB".into()),
+                    line: 3
+                }
+            )]
+        );
+        assert_eq!(
+            error("\tINCLUDE\tNOWHERE.ASM").message,
+            "Cannot find NOWHERE.ASM"
+        );
+    }
+
+    #[test]
+    fn test_macro_errors() {
+        let e = error("LOAD\tMACRO\n\tMVI\tA,X\n\tENDM\n\tNOP\n\tLOAD\n");
+        assert_eq!(e.location.line, 5);
+        assert_eq!(e.to_string(), "line 5: in LOAD, line 2: Undefined symbol X");
+        assert_eq!(error("LOAD\tMACRO\n\tNOP\n").message, "Missing ENDM");
+        assert_eq!(error("\tENDM").message, "ENDM without MACRO");
+        assert_eq!(error("\tEXITM").message, "EXITM outside of a macro");
+        assert_eq!(
+            error("LOAD\tMACRO\tN\n\tENDM\n\tLOAD\t1,2").message,
+            "LOAD expects 1 parameter(s)"
+        );
+        assert_eq!(
+            error("LOAD\tMACRO\n\tLOAD\n\tENDM\n\tLOAD").message,
+            "Too many nested macros or files"
+        );
+    }
+
+    #[test]
+    fn test_listing_round_trip() {
+        // the listings of the disassembler assemble back to the same bytes
+        let programs: [&[u8]; 4] = [
+            include_bytes!("../tests/bin/8080PRE.COM"),
+            include_bytes!("../tests/bin/TST8080.COM"),
+            include_bytes!("../tests/bin/8080EXER.COM"),
+            include_bytes!("../tests/bin/8080EXM.COM"),
+        ];
+        for bytes in programs {
+            let hints = Hints {
+                cpm: true,
+                ..Default::default()
+            };
+            let disassembly = traversal::disassemble(bytes, 0x100, &hints, &SymbolTable::new());
+            let assembly = assemble(&disassembly.listing()).unwrap();
+            assert_eq!(assembly.origin, 0x100);
+            assert_eq!(assembly.image, bytes);
+        }
+    }
+}
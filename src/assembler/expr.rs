@@ -0,0 +1,457 @@
+//! Tokens and expressions of the assembler.
+//!
+//! The expressions follow the Digital Research assemblers:
+//! - numbers are decimal, or end with `H` (hexadecimal), `O` or `Q` (octal),
+//!   `B` (binary) or `D` (decimal), and a `$` inside a number is ignored, as
+//!   in `1111$0000B`
+//! - a string of one or two characters is worth their ASCII codes, `'AB'`
+//!   being `4142H`, and a quote is doubled inside a string
+//! - `$` is the address of the current instruction
+//! - the operators are, from the highest to the lowest precedence,
+//!   `HIGH LOW` and the unary `+ -`, then `* / MOD SHL SHR`, `+ -`,
+//!   `EQ NE LT LE GT GE`, `NOT`, `AND`, and `OR XOR`
+//! - `NUL` is true when nothing follows it, to test the empty arguments of
+//!   the macros
+//!
+//! Everything is computed on 16 bits, the comparisons give `0FFFFH` when
+//! true and 0 when false.
+
+use anyhow::{anyhow, bail, Result};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Token {
+    /// a name or a word operator, upper cased
+    Ident(String),
+    Number(u16),
+    Str(Vec<u8>),
+    /// `$`
+    Here,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Comma,
+    Colon,
+    LParen,
+    RParen,
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Token::Ident(name) => f.write_str(name),
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Str(s) => write!(f, "'{}'", String::from_utf8_lossy(s)),
+            Token::Here => f.write_str("$"),
+            Token::Plus => f.write_str("+"),
+            Token::Minus => f.write_str("-"),
+            Token::Star => f.write_str("*"),
+            Token::Slash => f.write_str("/"),
+            Token::Comma => f.write_str(","),
+            Token::Colon => f.write_str(":"),
+            Token::LParen => f.write_str("("),
+            Token::RParen => f.write_str(")"),
+        }
+    }
+}
+
+/// true for the characters allowed in a name
+pub fn is_ident(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '?' | '@' | '_')
+}
+
+/// parse a number with its radix suffix
+fn number(word: &str) -> Result<u16> {
+    let digits = word.replace('$', "").to_ascii_uppercase();
+    let (digits, radix) = match digits.chars().last() {
+        Some('H') => (&digits[..digits.len() - 1], 16),
+        Some('O' | 'Q') => (&digits[..digits.len() - 1], 8),
+        Some('B') => (&digits[..digits.len() - 1], 2),
+        Some('D') => (&digits[..digits.len() - 1], 10),
+        _ => (&digits[..], 10),
+    };
+    match u32::from_str_radix(digits, radix) {
+        Ok(n) if n <= 0xffff => Ok(n as u16),
+        Ok(_) => bail!("Number too large: {}", word),
+        Err(_) => bail!("Invalid number: {}", word),
+    }
+}
+
+/// split `text` in tokens, stopping at a `;` comment
+pub fn tokenize(text: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let token = match c {
+            ';' => break,
+            c if c.is_whitespace() => continue,
+            '\'' => {
+                let mut s = Vec::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '\'')) if chars.peek().map(|&(_, c)| c) == Some('\'') => {
+                            chars.next();
+                            s.push(b'\'');
+                        }
+                        Some((_, '\'')) => break,
+                        Some((_, c)) if c.is_ascii() => s.push(c as u8),
+                        Some((_, c)) => bail!("Invalid character in string: {}", c),
+                        None => bail!("Unterminated string"),
+                    }
+                }
+                Token::Str(s)
+            }
+            c if c.is_ascii_digit() || is_ident(c) => {
+                let mut end = start + 1;
+                while let Some(&(i, c)) = chars.peek() {
+                    // a `$` only separates the digits of a number
+                    let separator = c == '$' && text.as_bytes()[start].is_ascii_digit();
+                    if !is_ident(c) && !separator {
+                        break;
+                    }
+                    end = i + 1;
+                    chars.next();
+                }
+                let word = &text[start..end];
+                if c.is_ascii_digit() {
+                    Token::Number(number(word)?)
+                } else {
+                    Token::Ident(word.to_ascii_uppercase())
+                }
+            }
+            '$' => Token::Here,
+            '+' => Token::Plus,
+            '-' => Token::Minus,
+            '*' => Token::Star,
+            '/' => Token::Slash,
+            ',' => Token::Comma,
+            ':' => Token::Colon,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            c => bail!("Unexpected character: {}", c),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// the value of the names, and of `$`
+pub trait Env {
+    /// the value of `name`, `None` when it is not defined (yet)
+    fn symbol(&self, name: &str) -> Result<Option<u16>>;
+    fn here(&self) -> u16;
+}
+
+/// a value and whether it depends on an undefined name, which is computed
+/// as 0
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Value {
+    pub value: u16,
+    pub defined: bool,
+}
+
+impl Value {
+    fn new(value: u16) -> Self {
+        Value {
+            value,
+            defined: true,
+        }
+    }
+
+    fn map(self, f: impl FnOnce(u16) -> u16) -> Self {
+        Value {
+            value: f(self.value),
+            ..self
+        }
+    }
+
+    fn zip(self, other: Self, f: impl FnOnce(u16, u16) -> Result<u16>) -> Result<Self> {
+        Ok(Value {
+            value: f(self.value, other.value)?,
+            defined: self.defined && other.defined,
+        })
+    }
+}
+
+/// the word operators, which can not be used as names
+pub const OPERATORS: [&str; 16] = [
+    "MOD", "SHL", "SHR", "NOT", "AND", "OR", "XOR", "EQ", "NE", "LT", "LE", "GT", "GE", "HIGH",
+    "LOW", "NUL",
+];
+
+/// A parser of the expressions in a list of tokens
+pub struct Parser<'a, E: Env> {
+    tokens: &'a [Token],
+    pos: usize,
+    env: &'a E,
+}
+
+impl<'a, E: Env> Parser<'a, E> {
+    pub fn new(tokens: &'a [Token], env: &'a E) -> Self {
+        Parser {
+            tokens,
+            pos: 0,
+            env,
+        }
+    }
+
+    /// the next token
+    pub fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<&'a Token> {
+        let token = self.tokens.get(self.pos);
+        self.pos += 1;
+        token
+    }
+
+    /// consume the next token if it is the operator `op`
+    fn eat(&mut self, op: &str) -> bool {
+        match self.peek() {
+            Some(Token::Ident(name)) if name == op => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// parse an expression
+    pub fn expr(&mut self) -> Result<Value> {
+        let mut value = self.and()?;
+        loop {
+            if self.eat("OR") {
+                value = value.zip(self.and()?, |a, b| Ok(a | b))?;
+            } else if self.eat("XOR") {
+                value = value.zip(self.and()?, |a, b| Ok(a ^ b))?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn and(&mut self) -> Result<Value> {
+        let mut value = self.not()?;
+        while self.eat("AND") {
+            value = value.zip(self.not()?, |a, b| Ok(a & b))?;
+        }
+        Ok(value)
+    }
+
+    fn not(&mut self) -> Result<Value> {
+        if self.eat("NOT") {
+            Ok(self.not()?.map(|v| !v))
+        } else {
+            self.comparison()
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Value> {
+        let value = self.sum()?;
+        let compare: fn(u16, u16) -> bool = match self.peek() {
+            Some(Token::Ident(op)) => match op.as_str() {
+                "EQ" => |a, b| a == b,
+                "NE" => |a, b| a != b,
+                "LT" => |a, b| a < b,
+                "LE" => |a, b| a <= b,
+                "GT" => |a, b| a > b,
+                "GE" => |a, b| a >= b,
+                _ => return Ok(value),
+            },
+            _ => return Ok(value),
+        };
+        self.pos += 1;
+        value.zip(self.sum()?, |a, b| {
+            Ok(if compare(a, b) { 0xffff } else { 0 })
+        })
+    }
+
+    fn sum(&mut self) -> Result<Value> {
+        let mut value = self.product()?;
+        loop {
+            match self.peek() {
+                Some(Token::Plus) => {
+                    self.pos += 1;
+                    value = value.zip(self.product()?, |a, b| Ok(a.wrapping_add(b)))?;
+                }
+                Some(Token::Minus) => {
+                    self.pos += 1;
+                    value = value.zip(self.product()?, |a, b| Ok(a.wrapping_sub(b)))?;
+                }
+                _ => return Ok(value),
+            }
+        }
+    }
+
+    fn product(&mut self) -> Result<Value> {
+        let mut value = self.unary()?;
+        loop {
+            let op: fn(u16, u16) -> Result<u16> = match self.peek() {
+                Some(Token::Star) => |a, b| Ok(a.wrapping_mul(b)),
+                Some(Token::Slash) => {
+                    |a, b| a.checked_div(b).ok_or_else(|| anyhow!("Division by zero"))
+                }
+                Some(Token::Ident(op)) if op == "MOD" => {
+                    |a, b| a.checked_rem(b).ok_or_else(|| anyhow!("Division by zero"))
+                }
+                Some(Token::Ident(op)) if op == "SHL" => {
+                    |a, b| Ok(a.checked_shl(b as u32).unwrap_or(0))
+                }
+                Some(Token::Ident(op)) if op == "SHR" => {
+                    |a, b| Ok(a.checked_shr(b as u32).unwrap_or(0))
+                }
+                _ => return Ok(value),
+            };
+            self.pos += 1;
+            let rhs = self.unary()?;
+            // an undefined operand is 0 and must not fail the division
+            value = match rhs.defined {
+                true => value.zip(rhs, op)?,
+                false => value.zip(rhs, |_, _| Ok(0))?,
+            };
+        }
+    }
+
+    fn unary(&mut self) -> Result<Value> {
+        if self.eat("NUL") {
+            let empty = self.peek().is_none();
+            self.pos = self.tokens.len();
+            return Ok(Value::new(if empty { 0xffff } else { 0 }));
+        }
+        if self.eat("HIGH") {
+            return Ok(self.unary()?.map(|v| v >> 8));
+        }
+        if self.eat("LOW") {
+            return Ok(self.unary()?.map(|v| v & 0xff));
+        }
+        match self.peek() {
+            Some(Token::Minus) => {
+                self.pos += 1;
+                Ok(self.unary()?.map(|v| v.wrapping_neg()))
+            }
+            Some(Token::Plus) => {
+                self.pos += 1;
+                self.unary()
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Value> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Value::new(*n)),
+            Some(Token::Here) => Ok(Value::new(self.env.here())),
+            Some(Token::Str(s)) => match s.as_slice() {
+                [c] => Ok(Value::new(*c as u16)),
+                [h, l] => Ok(Value::new(u16::from_be_bytes([*h, *l]))),
+                _ => bail!("A string in an expression must have 1 or 2 characters"),
+            },
+            Some(Token::LParen) => {
+                let value = self.expr()?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(value),
+                    _ => bail!("Missing )"),
+                }
+            }
+            Some(Token::Ident(name)) if !OPERATORS.contains(&name.as_str()) => {
+                match self.env.symbol(name)? {
+                    Some(value) => Ok(Value::new(value)),
+                    None => Ok(Value {
+                        value: 0,
+                        defined: false,
+                    }),
+                }
+            }
+            Some(token) => bail!("Unexpected {} in expression", token),
+            None => bail!("Missing expression"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    struct Symbols(HashMap<&'static str, u16>);
+
+    impl Env for Symbols {
+        fn symbol(&self, name: &str) -> Result<Option<u16>> {
+            Ok(self.0.get(name).copied())
+        }
+
+        fn here(&self) -> u16 {
+            0x100
+        }
+    }
+
+    fn eval(text: &str) -> Result<Value> {
+        let env = Symbols([("START", 0x1234)].iter().copied().collect());
+        let tokens = tokenize(text)?;
+        let mut parser = Parser::new(&tokens, &env);
+        let value = parser.expr()?;
+        match parser.peek() {
+            None => Ok(value),
+            Some(token) => bail!("Unexpected {}", token),
+        }
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            tokenize("loop: mvi a,'''' ; comment").unwrap(),
+            [
+                Token::Ident("LOOP".into()),
+                Token::Colon,
+                Token::Ident("MVI".into()),
+                Token::Ident("A".into()),
+                Token::Comma,
+                Token::Str(b"'".to_vec()),
+            ]
+        );
+        assert_eq!(
+            tokenize("DB 'a;b', 0FFH").unwrap(),
+            [
+                Token::Ident("DB".into()),
+                Token::Str(b"a;b".to_vec()),
+                Token::Comma,
+                Token::Number(0xff),
+            ]
+        );
+        assert!(tokenize("DB 'abc").is_err());
+        assert!(tokenize("10000H").is_err());
+        assert!(tokenize("12AB").is_err());
+    }
+
+    #[test]
+    fn test_eval() {
+        let value = |text| eval(text).unwrap().value;
+        assert_eq!(
+            value("0FFH + 10 + 17O + 101B + 7Q + 10D"),
+            0xff + 10 + 15 + 5 + 7 + 10
+        );
+        assert_eq!(value("1111$0000B"), 0xf0);
+        assert_eq!(value("2 + 3 * 4"), 14);
+        assert_eq!(value("(2 + 3) * 4"), 20);
+        assert_eq!(value("HIGH START + LOW START"), 0x12 + 0x34);
+        assert_eq!(value("$ + 3"), 0x103);
+        assert_eq!(value("-1"), 0xffff);
+        assert_eq!(value("'A' + 'BC'"), 0x41 + 0x4243);
+        assert_eq!(value("17 MOD 5 SHL 1"), 4);
+        assert_eq!(value("1 SHL 4 OR 1"), 0x11);
+        assert_eq!(value("NOT 0 AND 5"), 5);
+        assert_eq!(value("START EQ 1234H"), 0xffff);
+        assert_eq!(value("3 GT 4 XOR 1"), 1);
+        assert_eq!(value("NUL"), 0xffff);
+        assert_eq!(value("NOT NUL 'A', 'B'"), 0xffff);
+        assert!(eval("1 / 0").is_err());
+        assert!(eval("(1 + 2").is_err());
+        assert!(eval("1 +").is_err());
+        assert!(eval("'ABC'").is_err());
+
+        let undefined = eval("LATER + 1").unwrap();
+        assert!(!undefined.defined);
+        assert!(eval("1 / LATER").is_ok());
+    }
+}
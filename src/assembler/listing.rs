@@ -0,0 +1,107 @@
+//! The `.PRN` listing of the assembler.
+//!
+//! As in the listings of the Digital Research ASM and MAC, every line of the
+//! source follows 16 columns giving:
+//! - the address of the line and up to 5 of the bytes it generates, the
+//!   other bytes being listed 5 by 5 on the next lines
+//! - or the value of the names defined by `EQU` and `SET`, followed by `=`
+//! - and a `+` before the lines expanded from a macro
+//!
+//! ```text
+//! 0005 =          BDOS    EQU     5
+//! 0100                    ORG     100H
+//! 0100 0E09       START:  MVI     C,9
+//! 0102 110C01             LXI     D,MSG
+//! ```
+
+use std::fmt::Write;
+
+/// A line of the listing
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Entry {
+    /// the line of the source, or of a macro
+    pub text: String,
+    /// the address of the line
+    pub addr: Option<u16>,
+    /// the value given by `EQU` or `SET`
+    pub value: Option<u16>,
+    pub bytes: Vec<u8>,
+    /// the line comes from a macro, a `REPT`, an `IRP` or an `IRPC`
+    pub expanded: bool,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+/// write the listing of `entries`
+pub fn prn(entries: &[Entry]) -> String {
+    let mut out = String::new();
+    for entry in entries {
+        let mut chunks = entry.bytes.chunks(5);
+        let fields = match (entry.value, entry.addr) {
+            (Some(value), _) => format!("{:04X} =", value),
+            (None, Some(addr)) => format!("{:04X} {}", addr, hex(chunks.next().unwrap_or(&[]))),
+            (None, None) => String::new(),
+        };
+        let marker = if entry.expanded { '+' } else { ' ' };
+        let line = format!("{:<15}{}{}", fields, marker, entry.text);
+        let _ = writeln!(out, "{}", line.trim_end());
+        let mut addr = entry.addr.unwrap_or_default();
+        for chunk in chunks {
+            addr = addr.wrapping_add(5);
+            let _ = writeln!(out, "{:04X} {}", addr, hex(chunk));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble;
+
+    #[test]
+    fn test_prn() {
+        let source = "\
+BDOS\tEQU\t5
+\tORG\t100H
+PRINT\tMACRO\tMSG
+\tLXI\tD,MSG
+\tMVI\tC,9
+\tENDM
+START:\tPRINT\tTEXT\t; hello
+\tJMP\tBDOS
+\tIF\t0
+\tNOP
+\tENDIF
+TEXT:\tDB\t'Hello, world$'
+\tDS\t2
+\tEND
+";
+        let assembly = assemble(source).unwrap();
+        assert_eq!(
+            assembly.prn(),
+            "\
+0005 =          BDOS\tEQU\t5
+0100            \tORG\t100H
+                PRINT\tMACRO\tMSG
+                \tLXI\tD,MSG
+                \tMVI\tC,9
+                \tENDM
+0100            START:\tPRINT\tTEXT\t; hello
+0100 110801    +\tLXI\tD,TEXT
+0103 0E09      +\tMVI\tC,9
+0105 C30500     \tJMP\tBDOS
+                \tIF\t0
+                \tNOP
+                \tENDIF
+0108 48656C6C6F TEXT:\tDB\t'Hello, world$'
+010D 2C20776F72
+0112 6C6424
+0115            \tDS\t2
+                \tEND
+"
+        );
+        assert_eq!(assembly.sym(), "0005 BDOS\t0100 START\t0108 TEXT\r\n\x1a");
+    }
+}
@@ -0,0 +1,157 @@
+//! Text substitutions of the macros.
+//!
+//! As in the Digital Research MAC, the parameters are replaced by the text
+//! given to the macro:
+//! - a parameter is replaced where it appears as a name, and in a string
+//!   only after a `&`, like `'&NAME'`
+//! - `&` concatenates a parameter with the text around it, as in `L&N:`
+//! - an argument between `<` and `>` can contain commas, the brackets are
+//!   removed
+//! - a comment starting with `;;` is not copied in the expansion
+
+use super::expr::is_ident;
+use super::Line;
+
+/// A macro: its parameters, upper cased, and its body with the location of
+/// every line of its definition
+#[derive(Clone, Debug, Default)]
+pub struct Macro {
+    pub params: Vec<String>,
+    pub body: Vec<Line>,
+}
+
+/// split the arguments of a macro call, or of `IRP`, at the commas outside
+/// of the strings and of the `<>` brackets
+pub fn arguments(text: &str) -> Vec<String> {
+    if text.trim().is_empty() {
+        return Vec::new();
+    }
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut depth = 0;
+    let mut string = false;
+    for c in text.chars() {
+        match c {
+            _ if string => {
+                arg.push(c);
+                string = c != '\'';
+            }
+            '\'' => {
+                arg.push(c);
+                string = true;
+            }
+            '<' => {
+                if depth > 0 {
+                    arg.push(c);
+                }
+                depth += 1;
+            }
+            '>' if depth > 0 => {
+                depth -= 1;
+                if depth > 0 {
+                    arg.push(c);
+                }
+            }
+            ',' if depth == 0 => {
+                args.push(arg.trim().to_string());
+                arg.clear();
+            }
+            c => arg.push(c),
+        }
+    }
+    args.push(arg.trim().to_string());
+    args
+}
+
+/// the value of the parameter `name`
+fn lookup<'a>(params: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    params
+        .iter()
+        .rev()
+        .find(|(param, _)| param.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/// replace the parameters in a line of a macro
+pub fn substitute(text: &str, params: &[(String, String)]) -> String {
+    let mut out = String::new();
+    let mut string = false;
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let name_len = rest.find(|c: char| !is_ident(c)).unwrap_or(rest.len());
+        match c {
+            '\'' => {
+                string = !string;
+                out.push(c);
+                rest = &rest[1..];
+            }
+            '&' => {
+                let after = &rest[1..];
+                let len = after.find(|c: char| !is_ident(c)).unwrap_or(after.len());
+                match lookup(params, &after[..len]) {
+                    Some(value) if len > 0 => {
+                        out.push_str(value);
+                        rest = after[len..].strip_prefix('&').unwrap_or(&after[len..]);
+                    }
+                    // a lone `&` outside the strings only separates names
+                    _ => {
+                        if string {
+                            out.push('&');
+                        }
+                        rest = after;
+                    }
+                }
+            }
+            ';' if !string => {
+                if !rest.starts_with(";;") {
+                    out.push_str(rest);
+                }
+                break;
+            }
+            _ if !string && name_len > 0 => {
+                let name = &rest[..name_len];
+                // a number is never a parameter
+                match lookup(params, name) {
+                    Some(value) if !c.is_ascii_digit() => out.push_str(value),
+                    _ => out.push_str(name),
+                }
+                rest = &rest[name_len..];
+            }
+            c => {
+                out.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    out.trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arguments() {
+        assert_eq!(arguments("  "), Vec::<String>::new());
+        assert_eq!(arguments("A, 'x,y' ,<1,2>"), ["A", "'x,y'", "1,2"]);
+        assert_eq!(arguments("<<a>,b>,"), ["<a>,b", ""]);
+        assert_eq!(arguments("'it''s',B"), ["'it''s'", "B"]);
+    }
+
+    #[test]
+    fn test_substitute() {
+        let params = [
+            ("REG".to_string(), "B".to_string()),
+            ("N".to_string(), "12".to_string()),
+        ];
+        let substitute = |text| substitute(text, &params);
+        assert_eq!(substitute("\tMOV\tA,REG\t; REG"), "\tMOV\tA,B\t; REG");
+        assert_eq!(
+            substitute("L&N:\tDB\t'REG &REG',N+1 ;; hidden"),
+            "L12:\tDB\t'REG B',12+1"
+        );
+        assert_eq!(substitute("\tDB\t'a&b',REGS,10N"), "\tDB\t'a&b',REGS,10N");
+        assert_eq!(substitute("X&N&Y"), "X12Y");
+        assert_eq!(substitute("\tLXI\tH,'''&N'"), "\tLXI\tH,'''12'");
+    }
+}
@@ -0,0 +1,275 @@
+//! Shadow call stack.
+//!
+//! The 8080 keeps the return addresses on the stack with everything else, so
+//! nothing tells where a subroutine was called from. When
+//! [Cpu::call_stack](../struct.Cpu.html#structfield.call_stack) is set, the
+//! cpu records every CALL, RST and interrupt in a separate stack, popped by
+//! the RET instructions, which gives a backtrace at any time.
+//!
+//! Code playing with SP directly (SPHL, XTHL, POP of a return address, RET
+//! used as a jump...) breaks this bookkeeping, every frame affected is
+//! reported as a [Mismatch].
+
+use crate::symbols::SymbolTable;
+use crate::Memory;
+
+/// What entered a frame
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FrameKind {
+    /// a CALL or a conditional call
+    Call,
+    /// a RST instruction executed by the program
+    Rst(u8),
+    /// a RST instruction sent by an interrupting device
+    Interrupt(u8),
+}
+
+/// A subroutine call which did not return yet
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    /// address of the calling instruction, the interrupted instruction for
+    /// interrupts
+    pub call_site: u16,
+    /// address of the subroutine
+    pub target: u16,
+    pub return_addr: u16,
+    /// value of SP once the return address was pushed
+    pub sp: u16,
+}
+
+/// A frame which was not left through a RET to its return address
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Mismatch {
+    /// SP moved past the return address without a RET, the frame is dropped
+    Discarded { pc: u16, frame: Frame },
+    /// the return address was modified on the stack, by XTHL for example
+    Overwritten { pc: u16, frame: Frame, addr: u16 },
+    /// a RET jumped to something which is not the return address of the
+    /// current frame, the frame is kept
+    Return { pc: u16, frame: Frame, addr: u16 },
+}
+
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Mismatch::Discarded { pc, frame } => write!(
+                f,
+                "{:#06x}: the return address {:#06x} of the call at {:#06x} was popped without a RET",
+                pc, frame.return_addr, frame.call_site
+            ),
+            Mismatch::Overwritten { pc, frame, addr } => write!(
+                f,
+                "{:#06x}: the return address {:#06x} of the call at {:#06x} was replaced by {:#06x}",
+                pc, frame.return_addr, frame.call_site, addr
+            ),
+            Mismatch::Return { pc, frame, addr } => write!(
+                f,
+                "{:#06x}: RET to {:#06x} instead of {:#06x}, the return address of the call at {:#06x}",
+                pc, addr, frame.return_addr, frame.call_site
+            ),
+        }
+    }
+}
+
+/// true if the stack was popped past `frame`, the stack growing down
+fn popped(frame: &Frame, sp: u16) -> bool {
+    sp > frame.sp
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+    mismatches: Vec<Mismatch>,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// the frames, the innermost last
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// the mismatches detected since the last call to `take_mismatches`
+    pub fn mismatches(&self) -> &[Mismatch] {
+        &self.mismatches
+    }
+
+    pub fn take_mismatches(&mut self) -> Vec<Mismatch> {
+        std::mem::take(&mut self.mismatches)
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.mismatches.clear();
+    }
+
+    pub(crate) fn call(&mut self, frame: Frame) {
+        self.frames.push(frame);
+    }
+
+    /// a RET at `pc` read `addr` on the stack at `sp`
+    pub(crate) fn ret(&mut self, pc: u16, sp: u16, addr: u16) {
+        match self.frames.last() {
+            // the caller is unknown, the program loader for example
+            None => (),
+            Some(frame) if frame.sp == sp && frame.return_addr == addr => {
+                self.frames.pop();
+            }
+            Some(&frame) => self.mismatches.push(Mismatch::Return { pc, frame, addr }),
+        }
+    }
+
+    /// look for the frames affected by the instruction at `pc`
+    pub(crate) fn check(&mut self, pc: u16, sp: u16, ram: &Memory) {
+        while let Some(&frame) = self.frames.last() {
+            if !popped(&frame, sp) {
+                break;
+            }
+            self.frames.pop();
+            self.mismatches.push(Mismatch::Discarded { pc, frame });
+        }
+        for frame in self.frames.iter_mut() {
+            if frame.sp as usize + 1 >= ram.len() {
+                continue;
+            }
+            let addr = ram.dword(frame.sp);
+            if addr != frame.return_addr {
+                self.mismatches.push(Mismatch::Overwritten {
+                    pc,
+                    frame: *frame,
+                    addr,
+                });
+                // a RET to the new address is now the expected one
+                frame.return_addr = addr;
+            }
+        }
+    }
+
+    /// the backtrace of the cpu stopped at `pc`
+    pub fn backtrace(&self, pc: u16, symbols: &SymbolTable) -> Backtrace {
+        let mut frames = Vec::new();
+        let mut addr = pc;
+        for frame in self.frames.iter().rev() {
+            frames.push(BacktraceFrame::new(addr, Some(frame.kind), symbols));
+            addr = frame.call_site;
+        }
+        frames.push(BacktraceFrame::new(addr, None, symbols));
+        Backtrace { frames }
+    }
+}
+
+/// One line of a backtrace
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BacktraceFrame {
+    /// the current instruction, or the instruction which called the
+    /// previous frame
+    pub addr: u16,
+    /// the closest symbol, with the offset of `addr` from it
+    pub name: Option<(String, u16)>,
+    /// how the function containing `addr` was entered, None for the
+    /// outermost frame
+    pub kind: Option<FrameKind>,
+}
+
+impl BacktraceFrame {
+    fn new(addr: u16, kind: Option<FrameKind>, symbols: &SymbolTable) -> Self {
+        Self {
+            addr,
+            name: symbols
+                .lookup(addr)
+                .map(|(name, offset)| (name.to_string(), offset)),
+            kind,
+        }
+    }
+
+    /// the symbol and offset, or the address when there is no symbol
+    pub fn function(&self) -> String {
+        match &self.name {
+            Some((name, 0)) => name.clone(),
+            Some((name, offset)) => format!("{}+{}", name, offset),
+            None => format!("{:04x}", self.addr),
+        }
+    }
+}
+
+/// The frames of the shadow call stack, the innermost first
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Backtrace {
+    pub frames: Vec<BacktraceFrame>,
+}
+
+impl std::fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (i, frame) in self.frames.iter().enumerate() {
+            write!(f, "#{:<3} {:#06x}", i, frame.addr)?;
+            if frame.name.is_some() {
+                write!(f, " in {}", frame.function())?;
+            }
+            match frame.kind {
+                Some(FrameKind::Rst(n)) => write!(f, " (RST {})", n)?,
+                Some(FrameKind::Interrupt(n)) => write!(f, " (interrupt RST {})", n)?,
+                _ => (),
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cpu;
+
+    #[test]
+    fn test_interrupt_and_xthl() {
+        let mut cpu = Cpu::from_raw(vec![0; 0x40]);
+        cpu.trace = false;
+        cpu.call_stack = Some(CallStack::new());
+        cpu.ram[0x10] = 0xe3; // XTHL
+        cpu.ram[0x11] = 0xc9; // RET
+        cpu.pc = 0x05;
+        cpu.sp = 0x30;
+        cpu.reg.h = 0x00;
+        cpu.reg.l = 0x20;
+
+        cpu.interrupt(2);
+        assert_eq!(cpu.pc, 0x10);
+        let stack = cpu.call_stack.as_ref().unwrap();
+        assert_eq!(stack.depth(), 1);
+        assert_eq!(stack.frames()[0].kind, FrameKind::Interrupt(2));
+        assert_eq!(stack.frames()[0].return_addr, 0x05);
+
+        // the interrupt handler replaces its return address with HL
+        cpu.cycle();
+        let stack = cpu.call_stack.as_mut().unwrap();
+        let frame = stack.frames()[0];
+        assert_eq!(
+            stack.take_mismatches(),
+            [Mismatch::Overwritten {
+                pc: 0x10,
+                frame: Frame {
+                    return_addr: 0x05,
+                    ..frame
+                },
+                addr: 0x20
+            }]
+        );
+        assert_eq!(cpu.reg.hl(), 0x05);
+
+        cpu.cycle();
+        assert_eq!(cpu.pc, 0x20);
+        let stack = cpu.call_stack.as_ref().unwrap();
+        assert_eq!(stack.depth(), 0);
+        assert!(stack.mismatches().is_empty());
+        assert_eq!(cpu.backtrace().frames.len(), 1);
+    }
+}
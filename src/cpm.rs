@@ -0,0 +1,111 @@
+//! CP/M 2.2 emulation.
+//!
+//! - [bdos] implements the BDOS functions in Rust, the drives being host
+//!   directories
+//! - [runner] runs a `.COM` program with its arguments, as the CCP would
+//! - [bios] implements the BIOS in Rust, under the CCP and the BDOS loaded
+//!   from a [disk] image
+//! - [disk] reads and writes the disk images
+//! - [fs] lists, reads and writes the files of the disk images
+//!
+//! The console is a [Console]: the [Terminal] of the emulator, or a
+//! [Buffer] for the tests and the batch runs.
+
+pub mod bdos;
+pub mod bios;
+pub mod disk;
+pub mod fs;
+pub mod runner;
+
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::sync::mpsc::{self, Receiver};
+
+/// The console of CP/M
+pub trait Console {
+    /// a character is waiting
+    fn ready(&mut self) -> bool;
+    /// wait for the next character, `None` at the end of the input
+    fn read(&mut self) -> Option<u8>;
+    fn write(&mut self, c: u8);
+}
+
+/// A console reading and writing bytes in memory
+#[derive(Clone, Debug, Default)]
+pub struct Buffer {
+    pub input: VecDeque<u8>,
+    pub output: Vec<u8>,
+}
+
+impl Buffer {
+    /// a console typing `input`
+    pub fn new(input: &[u8]) -> Self {
+        Buffer {
+            input: input.iter().copied().collect(),
+            output: Vec::new(),
+        }
+    }
+}
+
+impl Console for Buffer {
+    fn ready(&mut self) -> bool {
+        !self.input.is_empty()
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        self.input.pop_front()
+    }
+
+    fn write(&mut self, c: u8) {
+        self.output.push(c);
+    }
+}
+
+/// The console of the emulator: stdin, read by a thread so the status can
+/// be polled, and stdout
+pub struct Terminal {
+    input: Receiver<u8>,
+    pending: Option<u8>,
+}
+
+impl Default for Terminal {
+    fn default() -> Self {
+        let (sender, input) = mpsc::channel();
+        std::thread::spawn(move || {
+            for byte in std::io::stdin().lock().bytes() {
+                // the return key of CP/M
+                let byte = match byte {
+                    Ok(b'\n') => b'\r',
+                    Ok(byte) => byte,
+                    Err(_) => break,
+                };
+                if sender.send(byte).is_err() {
+                    break;
+                }
+            }
+        });
+        Terminal {
+            input,
+            pending: None,
+        }
+    }
+}
+
+impl Console for Terminal {
+    fn ready(&mut self) -> bool {
+        if self.pending.is_none() {
+            self.pending = self.input.try_recv().ok();
+        }
+        self.pending.is_some()
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        self.pending.take().or_else(|| self.input.recv().ok())
+    }
+
+    fn write(&mut self, c: u8) {
+        let mut stdout = std::io::stdout();
+        let _ = stdout.write_all(&[c]);
+        let _ = stdout.flush();
+    }
+}
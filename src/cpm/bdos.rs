@@ -0,0 +1,817 @@
+//! A CP/M 2.2 BDOS written in Rust.
+//!
+//! [Bdos::install] puts a `RET` at the BDOS entry point and a jump to it at
+//! 0005H. [Bdos::step] runs the functions called there in Rust before
+//! letting the CPU return to the program, so the `.COM` programs run
+//! unmodified.
+//!
+//! The drives A: to P: are host directories, the user 0 being the
+//! directory itself and the users 1 to 15 its subdirectories `1` to `15`.
+//! The names of the host files are compared without case, the files created
+//! are upper case. The data of the files is read and written directly, an
+//! FCB only holds the position in the file: its allocation map is left
+//! empty.
+
+use super::disk::Dpb;
+use super::Console;
+use crate::decompiler::cpm::{function, BDOS};
+use crate::Cpu;
+use anyhow::{bail, Result};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+/// the start of the BDOS, the top of the memory given to the programs
+pub const BASE: u16 = 0xfe00;
+/// the entry point of the BDOS, after its serial number
+pub const ENTRY: u16 = BASE + 6;
+/// the disk parameter block of every drive
+const DPB: u16 = BASE + 0x10;
+/// the allocation vector of every drive
+const ALV: u16 = BASE + 0x20;
+
+/// the size of a record
+const RECORD: usize = 128;
+/// the records of an extent
+const EXTENT: usize = 128;
+
+/// A name and a type, 8 and 3 characters padded with spaces, `?` matching
+/// any character
+pub(super) type Name = [u8; 11];
+
+/// the name of a host file, if CP/M can use it
+pub(super) fn host_name(file: &str) -> Option<Name> {
+    let (name, ext) = match file.rfind('.') {
+        Some(dot) => (&file[..dot], &file[dot + 1..]),
+        None => (file, ""),
+    };
+    let valid =
+        |part: &str, len| !part.is_empty() && part.len() <= len || part.is_empty() && len == 3;
+    let allowed = |c: char| c.is_ascii_graphic() && !"<>.,;:=?*[]".contains(c);
+    if !valid(name, 8) || !valid(ext, 3) || !name.chars().chain(ext.chars()).all(allowed) {
+        return None;
+    }
+    let mut padded = [b' '; 11];
+    padded[..name.len()].copy_from_slice(name.to_ascii_uppercase().as_bytes());
+    padded[8..8 + ext.len()].copy_from_slice(ext.to_ascii_uppercase().as_bytes());
+    Some(padded)
+}
+
+/// the host file name of `name`
+pub(super) fn file_name(name: &Name) -> String {
+    let part = |bytes: &[u8]| String::from_utf8_lossy(bytes).trim_end().to_string();
+    match part(&name[8..]) {
+        ext if ext.is_empty() => part(&name[..8]),
+        ext => format!("{}.{}", part(&name[..8]), ext),
+    }
+}
+
+fn matches(pattern: &Name, name: &Name) -> bool {
+    pattern.iter().zip(name).all(|(p, c)| *p == b'?' || p == c)
+}
+
+/// the records of a file
+fn records(path: &PathBuf) -> usize {
+    let len = std::fs::metadata(path).map_or(0, |m| m.len() as usize);
+    len.div_ceil(RECORD)
+}
+
+/// A file control block in the memory of the CPU
+struct Fcb {
+    addr: usize,
+}
+
+impl Fcb {
+    /// the drive, 0 for the current one
+    fn drive(&self, cpu: &Cpu) -> u8 {
+        cpu.ram[self.addr]
+    }
+
+    /// the name at `offset`, 0 for the name of the file, 16 for the new name
+    /// of a rename
+    fn name(&self, cpu: &Cpu, offset: usize) -> Name {
+        let mut name = [0; 11];
+        for (i, c) in name.iter_mut().enumerate() {
+            *c = (cpu.ram[self.addr + offset + 1 + i] & 0x7f).to_ascii_uppercase();
+        }
+        name
+    }
+
+    /// the record at the sequential position: the extent and the current
+    /// record
+    fn position(&self, cpu: &Cpu) -> usize {
+        let extent = (cpu.ram[self.addr + 14] & 0x3f) as usize * 32
+            + (cpu.ram[self.addr + 12] & 0x1f) as usize;
+        extent * EXTENT + cpu.ram[self.addr + 32] as usize
+    }
+
+    /// set the sequential position, and the records of the extent of a file
+    /// of `records` records
+    fn seek(&self, cpu: &mut Cpu, record: usize, records: usize) {
+        let extent = record / EXTENT;
+        cpu.ram[self.addr + 12] = (extent % 32) as u8;
+        cpu.ram[self.addr + 14] = (extent / 32) as u8;
+        cpu.ram[self.addr + 15] = records.saturating_sub(extent * EXTENT).min(EXTENT) as u8;
+        cpu.ram[self.addr + 32] = (record % EXTENT) as u8;
+    }
+
+    /// the random record, `None` when it overflows
+    fn random(&self, cpu: &Cpu) -> Option<usize> {
+        let addr = self.addr + 33;
+        match cpu.ram[addr + 2] {
+            0 => Some(cpu.ram[addr] as usize | (cpu.ram[addr + 1] as usize) << 8),
+            _ => None,
+        }
+    }
+
+    fn set_random(&self, cpu: &mut Cpu, record: usize) {
+        let addr = self.addr + 33;
+        cpu.ram[addr] = record as u8;
+        cpu.ram[addr + 1] = (record >> 8) as u8;
+        cpu.ram[addr + 2] = (record >> 16) as u8;
+    }
+}
+
+/// The BDOS and its state
+pub struct Bdos<C> {
+    pub console: C,
+    /// the host directories of the drives A: to P:
+    pub drives: [Option<PathBuf>; 16],
+    /// the current drive, 0 for A:
+    pub drive: u8,
+    pub user: u8,
+    /// the address of the record read or written
+    pub dma: u16,
+    /// the program return code of CP/M 3, 0FF00H to 0FFFEH for a failure
+    pub code: u16,
+    /// the directory entries left to F_SNEXT
+    search: VecDeque<[u8; 32]>,
+}
+
+impl<C: Console> Bdos<C> {
+    pub fn new(console: C) -> Self {
+        Bdos {
+            console,
+            drives: Default::default(),
+            drive: 0,
+            user: 0,
+            dma: 0x80,
+            code: 0,
+            search: VecDeque::new(),
+        }
+    }
+
+    /// use the host directory `dir` as the drive `drive`, 0 for A:
+    pub fn mount(&mut self, drive: u8, dir: impl Into<PathBuf>) {
+        self.drives[drive as usize] = Some(dir.into());
+    }
+
+    /// write the entry point, the disk parameters and the jump at 0005H
+    pub fn install(&self, cpu: &mut Cpu) {
+        cpu.ram.load_at(BASE as usize, &[0; 0x40]);
+        cpu.ram.load_at(ENTRY as usize, &[0xc9]);
+        cpu.ram.load_at(DPB as usize, &Dpb::SSSD.bytes());
+        let [low, high] = ENTRY.to_le_bytes();
+        cpu.ram.load_at(BDOS as usize, &[0xc3, low, high]);
+    }
+
+    /// execute an instruction, or the function called at the entry point
+    /// before returning
+    pub fn step(&mut self, cpu: &mut Cpu) -> Result<()> {
+        if self.trap(cpu)? {
+            cpu.cycle();
+        }
+        Ok(())
+    }
+
+    /// execute the function called when the CPU is at the entry point, the
+    /// `RET` there being left to the CPU. False when the program warm boots,
+    /// the CPU being sent to 0000H.
+    pub fn trap(&mut self, cpu: &mut Cpu) -> Result<bool> {
+        if cpu.pc == ENTRY as usize {
+            // P_TERMCPM warm boots
+            if cpu.reg.c == 0 {
+                cpu.pc = 0;
+                return Ok(false);
+            }
+            self.call(cpu)?;
+        }
+        Ok(true)
+    }
+
+    /// the directory of `drive` for `user`
+    fn dir(&self, drive: u8, user: u8) -> Option<PathBuf> {
+        let dir = self.drives.get(drive as usize)?.clone()?;
+        match user {
+            0 => Some(dir),
+            user => Some(dir.join(user.to_string())),
+        }
+    }
+
+    /// the drive of an FCB
+    fn drive(&self, cpu: &Cpu, fcb: &Fcb) -> u8 {
+        match fcb.drive(cpu) {
+            0 | b'?' => self.drive,
+            drive => drive - 1,
+        }
+    }
+
+    /// the files of `drive` for `user`, with their name
+    fn files(&self, drive: u8, user: u8) -> Vec<(Name, PathBuf)> {
+        let dir = match self.dir(drive, user) {
+            Some(dir) => dir,
+            None => return Vec::new(),
+        };
+        let mut files: Vec<_> = std::fs::read_dir(&dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter(|entry| entry.path().is_file())
+            .filter_map(|entry| {
+                Some((
+                    host_name(&entry.file_name().to_string_lossy())?,
+                    entry.path(),
+                ))
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    /// the files of the current user matching the name of an FCB
+    fn find(&self, cpu: &Cpu, fcb: &Fcb) -> Vec<(Name, PathBuf)> {
+        let pattern = fcb.name(cpu, 0);
+        let mut files = self.files(self.drive(cpu, fcb), self.user);
+        files.retain(|(name, _)| matches(&pattern, name));
+        files
+    }
+
+    /// the path of a new file named by an FCB
+    fn create_path(&self, cpu: &Cpu, fcb: &Fcb, offset: usize) -> Option<PathBuf> {
+        let name = fcb.name(cpu, offset);
+        if name.contains(&b'?') || name[0] == b' ' {
+            return None;
+        }
+        Some(
+            self.dir(self.drive(cpu, fcb), self.user)?
+                .join(file_name(&name)),
+        )
+    }
+
+    /// read the record `record` of `path` at the DMA address, false at the end
+    /// of the file
+    fn read(&self, cpu: &mut Cpu, path: &PathBuf, record: usize) -> bool {
+        let mut buffer = [0x1a; RECORD];
+        let read = File::open(path).and_then(|mut file| {
+            file.seek(SeekFrom::Start((record * RECORD) as u64))?;
+            let mut len = 0;
+            while len < RECORD {
+                match file.read(&mut buffer[len..])? {
+                    0 => break,
+                    n => len += n,
+                }
+            }
+            Ok(len)
+        });
+        match read {
+            Ok(len) if len > 0 => {
+                let dma = self.dma as usize;
+                cpu.ram.load_at(dma, &buffer);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// write the record at the DMA address to the record `record` of `path`
+    fn write(&self, cpu: &Cpu, path: &PathBuf, record: usize) -> bool {
+        let dma = self.dma as usize;
+        let data = &cpu.ram[dma..dma + RECORD];
+        OpenOptions::new()
+            .write(true)
+            .open(path)
+            .and_then(|mut file| {
+                file.seek(SeekFrom::Start((record * RECORD) as u64))?;
+                file.write_all(data)
+            })
+            .is_ok()
+    }
+
+    /// the directory entry of a file, for its last extent
+    fn entry(user: u8, name: &Name, path: &PathBuf) -> [u8; 32] {
+        let records = records(path);
+        let extent = records.saturating_sub(1) / EXTENT;
+        let mut entry = [0; 32];
+        entry[0] = user;
+        entry[1..12].copy_from_slice(name);
+        entry[12] = (extent % 32) as u8;
+        entry[14] = (extent / 32) as u8;
+        entry[15] = (records - extent * EXTENT) as u8;
+        entry
+    }
+
+    /// give the next directory entry found to the program
+    fn next_entry(&mut self, cpu: &mut Cpu) -> u8 {
+        match self.search.pop_front() {
+            Some(entry) => {
+                cpu.ram.load_at(self.dma as usize, &entry);
+                0
+            }
+            None => 0xff,
+        }
+    }
+
+    /// execute the function in C with the parameter in E or DE
+    pub fn call(&mut self, cpu: &mut Cpu) -> Result<()> {
+        let number = cpu.reg.c;
+        let e = cpu.reg.e;
+        let fcb = Fcb {
+            addr: cpu.reg.de() as usize,
+        };
+        // the FCB and the DMA buffer used must be in memory
+        let (fcb_len, dma_len) = match number {
+            15 | 16 | 19 | 22 | 23 => (33, 0),
+            17 => (12, 32),
+            18 => (0, 32),
+            20 | 21 => (33, RECORD),
+            33 | 34 | 40 => (36, RECORD),
+            35 | 36 => (36, 0),
+            _ => (0, 0),
+        };
+        if fcb.addr + fcb_len > cpu.ram.len() || self.dma as usize + dma_len > cpu.ram.len() {
+            Self::result(cpu, 0xff);
+            return Ok(());
+        }
+        let result: u16 = match number {
+            // C_READ
+            1 => {
+                let c = self.console.read().unwrap_or(0x1a);
+                self.console.write(c);
+                c as u16
+            }
+            // C_WRITE
+            2 => {
+                self.console.write(e);
+                0
+            }
+            // C_RAWIO
+            6 => match e {
+                0xff if self.console.ready() => self.console.read().unwrap_or(0x1a) as u16,
+                0xff => 0,
+                0xfe => self.console.ready() as u16 * 0xff,
+                0xfd => self.console.read().unwrap_or(0x1a) as u16,
+                c => {
+                    self.console.write(c);
+                    0
+                }
+            },
+            // C_WRITESTR
+            9 => {
+                let len = cpu.ram.len();
+                let text = (0..len).map(|i| cpu.ram[(fcb.addr + i) % len]);
+                for c in text.take_while(|&c| c != b'$') {
+                    self.console.write(c);
+                }
+                0
+            }
+            // C_READSTR
+            10 => {
+                let room = cpu.ram.len().saturating_sub(fcb.addr + 2);
+                let max = match room {
+                    0 => 0,
+                    room => (cpu.ram[fcb.addr] as usize).min(room),
+                };
+                let mut line = Vec::new();
+                loop {
+                    match self.console.read() {
+                        None | Some(b'\r') | Some(b'\n') => break,
+                        Some(0x08) | Some(0x7f) => {
+                            if line.pop().is_some() {
+                                for c in b"\x08 \x08" {
+                                    self.console.write(*c);
+                                }
+                            }
+                        }
+                        Some(c) if line.len() < max => {
+                            self.console.write(c);
+                            line.push(c);
+                        }
+                        Some(_) => (),
+                    }
+                }
+                self.console.write(b'\r');
+                if room > 0 {
+                    cpu.ram[fcb.addr + 1] = line.len() as u8;
+                    cpu.ram.load_at(fcb.addr + 2, &line);
+                }
+                0
+            }
+            // C_STAT
+            11 => self.console.ready() as u16 * 0xff,
+            // S_BDOSVER
+            12 => 0x22,
+            // DRV_ALLRESET
+            13 => {
+                self.drive = 0;
+                self.dma = 0x80;
+                0
+            }
+            // DRV_SET
+            14 => match self.drives.get(e as usize) {
+                Some(Some(_)) => {
+                    self.drive = e;
+                    0
+                }
+                _ => 0xff,
+            },
+            // F_OPEN
+            15 => match self.find(cpu, &fcb).first() {
+                Some((_, path)) => {
+                    let records = records(path);
+                    let extent = fcb.position(cpu) / EXTENT;
+                    if extent > 0 && records <= extent * EXTENT {
+                        0xff
+                    } else {
+                        let cr = cpu.ram[fcb.addr + 32] as usize;
+                        fcb.seek(cpu, extent * EXTENT, records);
+                        cpu.ram[fcb.addr + 32] = cr as u8;
+                        0
+                    }
+                }
+                None => 0xff,
+            },
+            // F_CLOSE, the data is already written
+            16 => match self.find(cpu, &fcb).is_empty() {
+                true => 0xff,
+                false => 0,
+            },
+            // F_SFIRST, `?` as the drive finds the files of all the users
+            17 => {
+                let users = match fcb.drive(cpu) {
+                    b'?' => 0..16,
+                    _ => self.user..self.user + 1,
+                };
+                let pattern = fcb.name(cpu, 0);
+                let drive = self.drive(cpu, &fcb);
+                self.search = users
+                    .flat_map(|user| {
+                        let files = self.files(drive, user);
+                        files
+                            .into_iter()
+                            .filter(|(name, _)| matches(&pattern, name))
+                            .map(move |(name, path)| Self::entry(user, &name, &path))
+                    })
+                    .collect();
+                self.next_entry(cpu) as u16
+            }
+            // F_SNEXT
+            18 => self.next_entry(cpu) as u16,
+            // F_DELETE
+            19 => {
+                let files = self.find(cpu, &fcb);
+                let deleted = files
+                    .iter()
+                    .filter(|(_, path)| std::fs::remove_file(path).is_ok());
+                match deleted.count() {
+                    0 => 0xff,
+                    _ => 0,
+                }
+            }
+            // F_READ
+            20 => match self.find(cpu, &fcb).first() {
+                Some((_, path)) => {
+                    let record = fcb.position(cpu);
+                    match self.read(cpu, path, record) {
+                        true => {
+                            fcb.seek(cpu, record + 1, records(path));
+                            0
+                        }
+                        false => 1,
+                    }
+                }
+                None => 9,
+            },
+            // F_WRITE
+            21 => match self.find(cpu, &fcb).first() {
+                Some((_, path)) => {
+                    let record = fcb.position(cpu);
+                    match self.write(cpu, path, record) {
+                        true => {
+                            fcb.seek(cpu, record + 1, records(path));
+                            0
+                        }
+                        false => 2,
+                    }
+                }
+                None => 9,
+            },
+            // F_MAKE
+            22 => match self.create_path(cpu, &fcb, 0) {
+                Some(path) if File::create(&path).is_ok() => {
+                    fcb.seek(cpu, 0, 0);
+                    0
+                }
+                _ => 0xff,
+            },
+            // F_RENAME
+            23 => {
+                let files = self.find(cpu, &fcb);
+                let renamed = match (files.first(), self.create_path(cpu, &fcb, 16)) {
+                    (Some((_, from)), Some(to)) => std::fs::rename(from, to).is_ok(),
+                    _ => false,
+                };
+                match renamed {
+                    true => 0,
+                    false => 0xff,
+                }
+            }
+            // DRV_LOGINVEC
+            24 => self
+                .drives
+                .iter()
+                .enumerate()
+                .filter(|(_, dir)| dir.is_some())
+                .fold(0, |vector, (drive, _)| vector | 1 << drive),
+            // DRV_GET
+            25 => self.drive as u16,
+            // F_DMAOFF
+            26 => {
+                self.dma = fcb.addr as u16;
+                0
+            }
+            // DRV_ALLOCVEC
+            27 => ALV,
+            // DRV_SETRO, DRV_ROVEC and F_ATTRIB: the drives are never read only
+            28..=30 => 0,
+            // DRV_DPB
+            31 => DPB,
+            // F_USERNUM
+            32 => match e {
+                0xff => self.user as u16,
+                user => {
+                    self.user = user & 0x0f;
+                    0
+                }
+            },
+            // F_READRAND
+            33 => match (self.find(cpu, &fcb).first(), fcb.random(cpu)) {
+                (_, None) => 6,
+                (Some((_, path)), Some(record)) => {
+                    fcb.seek(cpu, record, records(path));
+                    match self.read(cpu, path, record) {
+                        true => 0,
+                        false => 1,
+                    }
+                }
+                (None, _) => 9,
+            },
+            // F_WRITERAND and F_WRITEZF, the host fills the gaps with zeros
+            34 | 40 => match (self.find(cpu, &fcb).first(), fcb.random(cpu)) {
+                (_, None) => 6,
+                (Some((_, path)), Some(record)) => match self.write(cpu, path, record) {
+                    true => {
+                        fcb.seek(cpu, record, records(path));
+                        0
+                    }
+                    false => 2,
+                },
+                (None, _) => 9,
+            },
+            // F_SIZE
+            35 => match self.find(cpu, &fcb).first() {
+                Some((_, path)) => {
+                    fcb.set_random(cpu, records(path));
+                    0
+                }
+                None => 0xff,
+            },
+            // F_RANDREC
+            36 => {
+                let record = fcb.position(cpu);
+                fcb.set_random(cpu, record);
+                0
+            }
+            // DRV_RESET
+            37 => 0,
+            // P_CODE of CP/M 3, to give an exit status to the batch runs
+            108 => match cpu.reg.de() {
+                0xffff => self.code,
+                code => {
+                    self.code = code;
+                    0
+                }
+            },
+            _ => match function(number) {
+                Some((name, _)) => bail!("BDOS function {} {} is not implemented", number, name),
+                None => bail!("Unknown BDOS function {}", number),
+            },
+        };
+        Self::result(cpu, result);
+        Ok(())
+    }
+
+    /// return `result` to the program: the bytes in A and L, the words in HL
+    /// and BA
+    fn result(cpu: &mut Cpu, result: u16) {
+        cpu.reg.hl_set(result);
+        cpu.reg.a = result as u8;
+        cpu.reg.b = (result >> 8) as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::cpm::Buffer;
+
+    /// a host directory for the drive A:
+    fn drive(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("rust-8080-bdos-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// run `source` at 0100H until it warm boots
+    fn run(source: &str, bdos: &mut Bdos<Buffer>) -> Cpu {
+        let program = assemble(source).unwrap();
+        let mut cpu = Cpu::from_raw(vec![0; 0x10000]);
+        cpu.trace = false;
+        cpu.ram.load_at(0x100, &program.image);
+        cpu.symbols = program.symbols;
+        cpu.pc = 0x100;
+        bdos.install(&mut cpu);
+        while cpu.pc != 0 {
+            bdos.step(&mut cpu).unwrap();
+        }
+        cpu
+    }
+
+    /// call the function `number` with `de`, giving A
+    fn call(bdos: &mut Bdos<Buffer>, cpu: &mut Cpu, number: u8, de: u16) -> u8 {
+        cpu.reg.c = number;
+        cpu.reg.de_set(de);
+        bdos.call(cpu).unwrap();
+        cpu.reg.a
+    }
+
+    /// an FCB at 005CH for `name`
+    fn fcb(cpu: &mut Cpu, name: &[u8; 11]) {
+        cpu.ram.load_at(0x5c, &[0; 36]);
+        cpu.ram.load_at(0x5d, name);
+    }
+
+    #[test]
+    fn test_console() {
+        let mut bdos = Bdos::new(Buffer::new(b"xhello\x7fp\rz"));
+        let cpu = run(
+            "\
+\tORG\t100H
+\tMVI\tC,1
+\tCALL\t5
+\tSTA\tCHARS
+\tLXI\tD,LINE
+\tMVI\tC,10
+\tCALL\t5
+\tMVI\tC,9
+\tLXI\tD,TEXT
+\tCALL\t5
+\tMVI\tC,6
+\tMVI\tE,0FFH
+\tCALL\t5
+\tSTA\tCHARS+1
+\tMVI\tC,12
+\tCALL\t5
+\tSHLD\tVERSION
+\tMVI\tC,0
+\tCALL\t5
+TEXT:\tDB\t'ok$'
+CHARS:\tDS\t2
+VERSION:\tDS\t2
+LINE:\tDB\t4
+\tDS\t5
+",
+            &mut bdos,
+        );
+        let output = String::from_utf8_lossy(&bdos.console.output).into_owned();
+        assert_eq!(output, "xhell\x08 \x08p\rok");
+        let addr = |name| cpu.symbols.addr(name).unwrap() as usize;
+        assert_eq!(&cpu.ram[addr("CHARS")..addr("CHARS") + 2], b"xz");
+        assert_eq!(cpu.ram[addr("VERSION")], 0x22);
+        assert_eq!(&cpu.ram[addr("LINE")..addr("LINE") + 6], b"\x04\x04help");
+    }
+
+    #[test]
+    fn test_files() {
+        let dir = drive("files");
+        let mut bdos = Bdos::new(Buffer::default());
+        bdos.mount(0, &dir);
+        let mut cpu = Cpu::from_raw(vec![0; 0x10000]);
+        bdos.install(&mut cpu);
+
+        // make and write 130 records
+        fcb(&mut cpu, b"DATA    TXT");
+        assert_eq!(call(&mut bdos, &mut cpu, 15, 0x5c), 0xff);
+        assert_eq!(call(&mut bdos, &mut cpu, 22, 0x5c), 0);
+        for record in 0..130 {
+            cpu.ram.load_at(0x80, &[record as u8; 128]);
+            assert_eq!(call(&mut bdos, &mut cpu, 21, 0x5c), 0);
+        }
+        assert_eq!((cpu.ram[0x5c + 12], cpu.ram[0x5c + 32]), (1, 2));
+        assert_eq!(call(&mut bdos, &mut cpu, 16, 0x5c), 0);
+        assert_eq!(
+            std::fs::metadata(dir.join("DATA.TXT")).unwrap().len(),
+            130 * 128
+        );
+
+        // read them back, in any case
+        std::fs::rename(dir.join("DATA.TXT"), dir.join("data.txt")).unwrap();
+        fcb(&mut cpu, b"DATA    TXT");
+        assert_eq!(call(&mut bdos, &mut cpu, 15, 0x5c), 0);
+        assert_eq!(cpu.ram[0x5c + 15], 128);
+        for record in 0..130 {
+            assert_eq!(call(&mut bdos, &mut cpu, 20, 0x5c), 0);
+            assert_eq!(cpu.ram[0x80], record as u8);
+        }
+        assert_eq!(call(&mut bdos, &mut cpu, 20, 0x5c), 1);
+
+        // random access
+        cpu.ram.load_at(0x5c + 33, &[129, 0, 0]);
+        assert_eq!(call(&mut bdos, &mut cpu, 33, 0x5c), 0);
+        assert_eq!(cpu.ram[0x80], 129);
+        assert_eq!(call(&mut bdos, &mut cpu, 36, 0x5c), 0);
+        assert_eq!(cpu.ram[0x5c + 33], 129);
+        cpu.ram.load_at(0x5c + 33, &[0, 1, 0]);
+        assert_eq!(call(&mut bdos, &mut cpu, 33, 0x5c), 1);
+        assert_eq!(call(&mut bdos, &mut cpu, 34, 0x5c), 0);
+        assert_eq!(call(&mut bdos, &mut cpu, 35, 0x5c), 0);
+        assert_eq!(&cpu.ram[0x5c + 33..0x5c + 36], [1, 1, 0]);
+        cpu.ram.load_at(0x5c + 33, &[0, 0, 1]);
+        assert_eq!(call(&mut bdos, &mut cpu, 33, 0x5c), 6);
+
+        // search, rename and delete
+        std::fs::write(dir.join("OTHER.COM"), b"").unwrap();
+        std::fs::write(dir.join("too-long-name.txt"), b"").unwrap();
+        fcb(&mut cpu, b"???????????");
+        assert_eq!(call(&mut bdos, &mut cpu, 17, 0x5c), 0);
+        assert_eq!(&cpu.ram[0x80..0x90], b"\0DATA    TXT\x02\0\0\x01");
+        assert_eq!(call(&mut bdos, &mut cpu, 18, 0x5c), 0);
+        assert_eq!(&cpu.ram[0x81..0x8c], b"OTHER   COM");
+        assert_eq!(call(&mut bdos, &mut cpu, 18, 0x5c), 0xff);
+
+        fcb(&mut cpu, b"OTHER   COM");
+        cpu.ram.load_at(0x5c + 17, b"NEW     COM");
+        assert_eq!(call(&mut bdos, &mut cpu, 23, 0x5c), 0);
+        assert!(dir.join("NEW.COM").exists());
+        fcb(&mut cpu, b"????????COM");
+        assert_eq!(call(&mut bdos, &mut cpu, 19, 0x5c), 0);
+        assert!(!dir.join("NEW.COM").exists());
+        assert_eq!(call(&mut bdos, &mut cpu, 19, 0x5c), 0xff);
+
+        // drives and users
+        assert_eq!(call(&mut bdos, &mut cpu, 14, 1), 0xff);
+        assert_eq!(call(&mut bdos, &mut cpu, 24, 0), 1);
+        assert_eq!(call(&mut bdos, &mut cpu, 32, 3), 0);
+        assert_eq!(call(&mut bdos, &mut cpu, 32, 0xff), 3);
+        fcb(&mut cpu, b"DATA    TXT");
+        assert_eq!(call(&mut bdos, &mut cpu, 15, 0x5c), 0xff);
+
+        cpu.reg.c = 3;
+        assert!(bdos.call(&mut cpu).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_out_of_memory() {
+        let dir = drive("out-of-memory");
+        let mut bdos = Bdos::new(Buffer::new(b"abc\r"));
+        bdos.mount(0, &dir);
+        let mut cpu = Cpu::from_raw(vec![0; 0x10000]);
+        bdos.install(&mut cpu);
+
+        // the FCB or the DMA buffer past the end of the memory
+        for number in [15, 17, 20, 21, 33, 34, 35, 36] {
+            assert_eq!(call(&mut bdos, &mut cpu, number, 0xfff0), 0xff);
+        }
+        fcb(&mut cpu, b"DATA    TXT");
+        assert_eq!(call(&mut bdos, &mut cpu, 22, 0x5c), 0);
+        assert_eq!(call(&mut bdos, &mut cpu, 26, 0xffc0), 0);
+        assert_eq!(call(&mut bdos, &mut cpu, 21, 0x5c), 0xff);
+        assert_eq!(call(&mut bdos, &mut cpu, 18, 0), 0xff);
+        assert_eq!(cpu.ram.len(), 0x10000);
+
+        // the strings wrap or are cut at the end of the memory
+        cpu.ram.load_at(0xfffe, b"ok");
+        cpu.ram.load_at(0, b"!$");
+        assert_eq!(call(&mut bdos, &mut cpu, 9, 0xfffe), 0);
+        assert_eq!(bdos.console.output, b"ok!");
+        cpu.ram[0xfffe] = 10;
+        assert_eq!(call(&mut bdos, &mut cpu, 10, 0xfffe), 0);
+        assert_eq!(call(&mut bdos, &mut cpu, 10, 0xffff), 0);
+        assert_eq!(cpu.ram.len(), 0x10000);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,422 @@
+//! A CP/M 2.2 BIOS written in Rust, under the CCP and the BDOS of Digital
+//! Research.
+//!
+//! [Bios::boot] loads the CCP and the BDOS from the system tracks of the
+//! disk A:, at the address they were built for, and puts the jump table of
+//! the BIOS right after them. Every entry of the table jumps to a `RET`:
+//! [Bios::step] runs the function in Rust when the CPU gets there, before
+//! letting it return. The disks are [Disk] images, with their disk
+//! parameter headers and tables above the jump table.
+//!
+//! [Cpu] executes the whole 8080 instruction set the CCP and the BDOS use,
+//! as the exercisers of `tests/bin` check. The system of Digital Research is
+//! not distributed here: booting it to the `A>` prompt is an ignored test,
+//! run with the disk image given by `CPM22_IMAGE`.
+
+use super::disk::{Disk, SECTOR};
+use super::Console;
+use crate::Cpu;
+use anyhow::{bail, Result};
+use std::collections::VecDeque;
+
+/// the functions of the jump table
+pub const FUNCTIONS: [&str; 17] = [
+    "BOOT", "WBOOT", "CONST", "CONIN", "CONOUT", "LIST", "PUNCH", "READER", "HOME", "SELDSK",
+    "SETTRK", "SETSEC", "SETDMA", "READ", "WRITE", "LISTST", "SECTRAN",
+];
+
+/// the size of the CCP and the BDOS, the BIOS following them
+pub const SYSTEM: u16 = 0x1600;
+/// the offset of the BDOS from the CCP
+const BDOS: u16 = 0x800;
+/// the `RET` of every function, from the jump table
+const TRAPS: u16 = 0x40;
+/// the directory buffer of the drives, from the jump table
+const DIRBUF: u16 = 0x80;
+/// the disk parameter headers and their tables, from the jump table
+const TABLES: u16 = 0x100;
+
+/// The BIOS, its devices and its disks
+pub struct Bios<C> {
+    pub console: C,
+    /// the disks in the drives A: to P:, the system being on A:
+    pub disks: [Option<Disk>; 16],
+    /// what was written to the printer and to the punch
+    pub list: Vec<u8>,
+    pub punch: Vec<u8>,
+    /// what the reader gives, 1AH after its end
+    pub reader: VecDeque<u8>,
+    /// the address of the CCP, found on the system tracks by the cold boot
+    pub ccp: u16,
+    /// the disk parameter header of every drive, 0 without a disk
+    dph: [u16; 16],
+    disk: u8,
+    track: u16,
+    sector: u16,
+    dma: u16,
+    /// CONIN was called after the end of the console input
+    ended: bool,
+}
+
+impl<C: Console> Bios<C> {
+    pub fn new(console: C) -> Self {
+        Bios {
+            console,
+            disks: Default::default(),
+            list: Vec::new(),
+            punch: Vec::new(),
+            reader: VecDeque::new(),
+            ccp: 0,
+            dph: [0; 16],
+            disk: 0,
+            track: 0,
+            sector: 1,
+            dma: 0x80,
+            ended: false,
+        }
+    }
+
+    /// put `disk` in the drive `drive`, 0 for A:, before the cold boot
+    pub fn mount(&mut self, drive: u8, disk: Disk) {
+        self.disks[drive as usize] = Some(disk);
+    }
+
+    /// the address of the jump table
+    pub fn base(&self) -> u16 {
+        self.ccp + SYSTEM
+    }
+
+    /// the CCP and the BDOS on the system tracks of A:, after the cold boot
+    /// loader, and the address of the CCP
+    fn system(&self) -> Result<(u16, Vec<u8>)> {
+        let disk = match &self.disks[0] {
+            Some(disk) => disk,
+            None => bail!("No disk in A:"),
+        };
+        let spt = disk.format.dpb.spt;
+        let mut system = Vec::with_capacity(SYSTEM as usize);
+        for n in 1..=SYSTEM / SECTOR as u16 {
+            match disk.read(n / spt, n % spt + 1) {
+                Some(sector) => system.extend_from_slice(sector),
+                None => bail!("The system tracks of A: are too short"),
+            }
+        }
+        // the BDOS starts with its serial number and a jump into its first
+        // page
+        let bdos = match system[BDOS as usize + 6..BDOS as usize + 9] {
+            [0xc3, _, high] => (high as u16) << 8,
+            _ => bail!("No CP/M system on the tracks of A:"),
+        };
+        match bdos.checked_sub(BDOS) {
+            Some(ccp) if (0x100..=0xffff - SYSTEM - TABLES).contains(&ccp) => Ok((ccp, system)),
+            _ => bail!("No CP/M system on the tracks of A:"),
+        }
+    }
+
+    /// write the jump table, its traps and the disk parameter headers
+    fn install(&mut self, cpu: &mut Cpu) -> Result<()> {
+        let base = self.base();
+        cpu.ram
+            .load_at(base as usize, &vec![0; 0x10000 - base as usize]);
+        for i in 0..FUNCTIONS.len() as u16 {
+            let [low, high] = (base + TRAPS + i).to_le_bytes();
+            cpu.ram.load_at((base + 3 * i) as usize, &[0xc3, low, high]);
+            cpu.ram[(base + TRAPS + i) as usize] = 0xc9;
+        }
+
+        let word = |addr: usize| (addr as u16).to_le_bytes();
+        let mut addr = (base + TABLES) as usize;
+        for (drive, disk) in self.disks.iter().enumerate() {
+            self.dph[drive] = 0;
+            let disk = match disk {
+                Some(disk) => disk,
+                None => continue,
+            };
+            let dpb = disk.format.dpb;
+            let table = disk.format.table();
+            let (xlt, csv) = (addr + 31, addr + 31 + table.len());
+            let alv = csv + dpb.cks as usize;
+            if alv + dpb.alv() > 0x10000 {
+                bail!(
+                    "The tables of the drive {}: do not fit in memory",
+                    (b'A' + drive as u8) as char
+                );
+            }
+            let xlt = if table.is_empty() { 0 } else { xlt };
+            let headers = [xlt, 0, 0, 0, (base + DIRBUF) as usize, addr + 16, csv, alv];
+            let header: Vec<u8> = headers.iter().flat_map(|addr| word(*addr)).collect();
+            cpu.ram.load_at(addr, &header);
+            cpu.ram.load_at(addr + 16, &dpb.bytes());
+            cpu.ram.load_at(addr + 31, &table);
+            self.dph[drive] = addr as u16;
+            addr = alv + dpb.alv();
+        }
+        Ok(())
+    }
+
+    /// the cold boot: install the BIOS, clear the IOBYTE and the current
+    /// drive, then warm boot
+    pub fn boot(&mut self, cpu: &mut Cpu) -> Result<()> {
+        self.ccp = self.system()?.0;
+        self.install(cpu)?;
+        cpu.ram.load_at(3, &[0, 0]);
+        self.warm_boot(cpu)
+    }
+
+    /// reload the CCP and the BDOS, write the page zero and jump to the CCP
+    /// with the current drive in C
+    fn warm_boot(&mut self, cpu: &mut Cpu) -> Result<()> {
+        let (_, system) = self.system()?;
+        cpu.ram.load_at(self.ccp as usize, &system);
+        let [low, high] = (self.base() + 3).to_le_bytes();
+        cpu.ram.load_at(0, &[0xc3, low, high]);
+        let [low, high] = (self.ccp + BDOS + 6).to_le_bytes();
+        cpu.ram.load_at(5, &[0xc3, low, high]);
+        self.dma = 0x80;
+        cpu.reg.c = cpu.ram[4];
+        cpu.sp = 0x80;
+        cpu.pc = self.ccp as usize;
+        Ok(())
+    }
+
+    /// execute an instruction, or the function of the jump table reached
+    /// before returning
+    pub fn step(&mut self, cpu: &mut Cpu) -> Result<()> {
+        let traps = (self.base() + TRAPS) as usize;
+        if self.ccp != 0 && (traps..traps + FUNCTIONS.len()).contains(&cpu.pc) {
+            let function = cpu.pc - traps;
+            self.call(cpu, function)?;
+            // the boots jump to the CCP
+            if function <= 1 {
+                return Ok(());
+            }
+        }
+        cpu.cycle();
+        Ok(())
+    }
+
+    /// run the system booted until it halts or reads after the end of the
+    /// console input
+    pub fn run(&mut self, cpu: &mut Cpu) -> Result<()> {
+        while !self.ended && cpu.ram[cpu.pc] != 0x76 {
+            self.step(cpu)?;
+        }
+        Ok(())
+    }
+
+    /// read the current sector at the DMA address, 1 when it does not exist
+    fn read(&self, cpu: &mut Cpu) -> u16 {
+        let disk = self.disks[self.disk as usize].as_ref();
+        match disk.and_then(|disk| disk.read(self.track, self.sector)) {
+            Some(sector) => {
+                cpu.ram.load_at(self.dma as usize, sector);
+                0
+            }
+            None => 1,
+        }
+    }
+
+    /// write the current sector from the DMA address, 1 when it fails
+    fn write(&mut self, cpu: &Cpu) -> u16 {
+        let dma = self.dma as usize;
+        let (track, sector) = (self.track, self.sector);
+        match self.disks[self.disk as usize].as_mut() {
+            Some(disk) if dma + SECTOR <= 0x10000 => {
+                match disk.write(track, sector, &cpu.ram[dma..dma + SECTOR]) {
+                    Ok(()) => 0,
+                    Err(_) => 1,
+                }
+            }
+            _ => 1,
+        }
+    }
+
+    /// execute the function `function` of the jump table, with its
+    /// parameter in C or BC and DE
+    pub fn call(&mut self, cpu: &mut Cpu, function: usize) -> Result<()> {
+        let (c, bc) = (cpu.reg.c, cpu.reg.bc());
+        let result = match function {
+            0 => return self.boot(cpu),
+            1 => return self.warm_boot(cpu),
+            // CONST
+            2 => Some(self.console.ready() as u16 * 0xff),
+            // CONIN
+            3 => match self.console.read() {
+                Some(c) => Some(c as u16),
+                None => {
+                    self.ended = true;
+                    Some(0x1a)
+                }
+            },
+            // CONOUT
+            4 => {
+                self.console.write(c);
+                None
+            }
+            // LIST
+            5 => {
+                self.list.push(c);
+                None
+            }
+            // PUNCH
+            6 => {
+                self.punch.push(c);
+                None
+            }
+            // READER
+            7 => Some(self.reader.pop_front().unwrap_or(0x1a) as u16),
+            // HOME
+            8 => {
+                self.track = 0;
+                None
+            }
+            // SELDSK, the disk parameter header or 0
+            9 => match self.dph.get(c as usize) {
+                Some(&dph) if dph != 0 => {
+                    self.disk = c;
+                    Some(dph)
+                }
+                _ => Some(0),
+            },
+            // SETTRK
+            10 => {
+                self.track = bc;
+                None
+            }
+            // SETSEC
+            11 => {
+                self.sector = bc;
+                None
+            }
+            // SETDMA
+            12 => {
+                self.dma = bc;
+                None
+            }
+            13 => Some(self.read(cpu)),
+            14 => Some(self.write(cpu)),
+            // LISTST, the printer is always ready
+            15 => Some(0xff),
+            // SECTRAN, through the table at DE, the sectors being numbered
+            // from 1
+            16 => match cpu.reg.de() {
+                0 => Some(bc + 1),
+                table => Some(cpu.ram[table.wrapping_add(bc) as usize] as u16),
+            },
+            _ => bail!("Unknown BIOS function {}", function),
+        };
+        // the bytes are returned in A, the addresses in HL
+        if let Some(result) = result {
+            cpu.reg.hl_set(result);
+            cpu.reg.a = result as u8;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::cpm::disk::{Dpb, Format};
+    use crate::cpm::Buffer;
+
+    /// call the function `function` with BC and DE, giving HL
+    fn call(bios: &mut Bios<Buffer>, cpu: &mut Cpu, function: usize, bc: u16, de: u16) -> u16 {
+        cpu.reg.bc_set(bc);
+        cpu.reg.de_set(de);
+        bios.call(cpu, function).unwrap();
+        cpu.reg.hl()
+    }
+
+    #[test]
+    fn test_boot() {
+        // a CCP echoing what is typed, changing its prompt, and the start
+        // of a BDOS
+        let system = assemble(
+            "\
+BIOS\tEQU\t0FA00H
+\tORG\t0E400H
+CCP:\tMVI\tC,'>'
+PROMPT\tEQU\tCCP+1
+\tCALL\tBIOS+12
+\tCALL\tBIOS+9
+\tMOV\tC,A
+\tCALL\tBIOS+12
+\tCPI\t'w'
+\tJZ\tBIOS+3
+\tMVI\tA,'$'
+\tSTA\tPROMPT
+\tJMP\tCCP
+\tORG\t0EC00H
+\tDB\t0,22,0,0,0,0
+\tJMP\tFBASE
+\tDW\t0,0,0,0
+FBASE:\tRET
+",
+        )
+        .unwrap();
+        let mut disk = Disk::new(Format::SSSD);
+        disk.image[SECTOR..SECTOR + system.image.len()].copy_from_slice(&system.image);
+        let mut bios = Bios::new(Buffer::new(b"xwy"));
+        bios.mount(0, disk);
+
+        let mut cpu = Cpu::from_raw(vec![0; 0x10000]);
+        cpu.trace = false;
+        bios.boot(&mut cpu).unwrap();
+        assert_eq!((bios.ccp, bios.base(), cpu.pc), (0xe400, 0xfa00, 0xe400));
+        assert_eq!(cpu.ram[0..8], [0xc3, 0x03, 0xfa, 0, 0, 0xc3, 0x06, 0xec]);
+        assert_eq!(cpu.ram[0xfa0c..0xfa0f], [0xc3, 0x44, 0xfa]);
+        bios.run(&mut cpu).unwrap();
+        assert_eq!(bios.console.output, b">x$w>y$");
+    }
+
+    #[test]
+    fn test_disks() {
+        let mut bios = Bios::new(Buffer::default());
+        bios.mount(0, Disk::new(Format::SSSD));
+        bios.ccp = 0xe400;
+        let mut cpu = Cpu::from_raw(vec![0; 0x10000]);
+        bios.install(&mut cpu).unwrap();
+
+        let dph = call(&mut bios, &mut cpu, 9, 0, 0) as usize;
+        let word = |cpu: &Cpu, addr: usize| u16::from_le_bytes([cpu.ram[addr], cpu.ram[addr + 1]]);
+        let dpb = word(&cpu, dph + 10) as usize;
+        assert_eq!(cpu.ram[dpb..dpb + 15], Dpb::SSSD.bytes());
+        assert_eq!(word(&cpu, dph + 8), 0xfa80);
+        let xlt = word(&cpu, dph);
+        assert_eq!(call(&mut bios, &mut cpu, 16, 1, xlt), 7);
+        assert_eq!(call(&mut bios, &mut cpu, 16, 1, 0), 2);
+        assert_eq!(call(&mut bios, &mut cpu, 9, 1, 0), 0);
+
+        // write and read back a sector
+        call(&mut bios, &mut cpu, 10, 2, 0);
+        call(&mut bios, &mut cpu, 11, 7, 0);
+        call(&mut bios, &mut cpu, 12, 0x200, 0);
+        cpu.ram.load_at(0x200, &[0x55; SECTOR]);
+        assert_eq!(call(&mut bios, &mut cpu, 14, 0, 0), 0);
+        let disk = bios.disks[0].as_ref().unwrap();
+        assert_eq!(disk.image[(2 * 26 + 6) * SECTOR], 0x55);
+        call(&mut bios, &mut cpu, 12, 0x300, 0);
+        assert_eq!(call(&mut bios, &mut cpu, 13, 0, 0), 0);
+        assert_eq!(cpu.ram[0x300..0x380], [0x55; SECTOR]);
+        call(&mut bios, &mut cpu, 10, 77, 0);
+        assert_eq!(call(&mut bios, &mut cpu, 13, 0, 0), 1);
+    }
+
+    #[test]
+    #[ignore = "needs a CP/M 2.2 disk image in CPM22_IMAGE"]
+    fn test_cpm22() {
+        // the system of Digital Research is not distributed with the sources
+        let image = std::env::var("CPM22_IMAGE").expect("CPM22_IMAGE is not set");
+        let mut bios = Bios::new(Buffer::new(b"DIR\r"));
+        bios.mount(0, Disk::open(&image, Format::SSSD).unwrap());
+        let mut cpu = Cpu::from_raw(vec![0; 0x10000]);
+        cpu.trace = false;
+        bios.boot(&mut cpu).unwrap();
+        bios.run(&mut cpu).unwrap();
+        let output = String::from_utf8_lossy(&bios.console.output);
+        assert!(output.contains("A>DIR"), "{}", output);
+        assert!(output.trim_end().ends_with("A>"), "{}", output);
+    }
+}
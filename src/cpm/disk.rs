@@ -0,0 +1,321 @@
+//! CP/M disk images.
+//!
+//! An image holds the sectors of every track one after the other, in their
+//! physical order: the skew of the sectors is applied by the BIOS, with the
+//! translation table of the [Format].
+
+use anyhow::{anyhow, bail, Result};
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// the size of a sector
+pub const SECTOR: usize = 128;
+
+/// A disk parameter block
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Dpb {
+    /// the sectors of a track
+    pub spt: u16,
+    /// the block shift and mask, a block holding `128 << bsh` bytes
+    pub bsh: u8,
+    pub blm: u8,
+    /// the extent mask
+    pub exm: u8,
+    /// the last block
+    pub dsm: u16,
+    /// the last directory entry
+    pub drm: u16,
+    /// the blocks of the directory, from the bit 15 for the block 0
+    pub al: u16,
+    /// the size of the directory check vector
+    pub cks: u16,
+    /// the reserved tracks
+    pub off: u16,
+}
+
+impl Dpb {
+    /// the 8" single sided single density disks of the IBM 3740
+    pub const SSSD: Dpb = Dpb {
+        spt: 26,
+        bsh: 3,
+        blm: 7,
+        exm: 0,
+        dsm: 242,
+        drm: 63,
+        al: 0xc000,
+        cks: 16,
+        off: 2,
+    };
+
+    /// the parameters of `tracks` tracks of `spt` sectors, `off` of them
+    /// reserved, used as blocks of `block` bytes with a directory of
+    /// `entries` entries
+    pub fn new(spt: u16, tracks: u16, off: u16, block: usize, entries: usize) -> Result<Dpb> {
+        if !block.is_power_of_two() || !(1024..=16384).contains(&block) {
+            bail!("Invalid block size {}", block);
+        }
+        let records = tracks.saturating_sub(off) as usize * spt as usize;
+        let blocks = records * SECTOR / block;
+        if blocks == 0 || blocks > 0x10000 {
+            bail!("Invalid number of blocks {}", blocks);
+        }
+        let dsm = blocks - 1;
+        if dsm > 255 && block == 1024 {
+            bail!("The blocks of 1024 bytes can not address {} blocks", blocks);
+        }
+        let directory = (entries * 32).div_ceil(block);
+        if entries == 0 || !entries.is_multiple_of(4) || directory > 16 || directory >= blocks {
+            bail!("Invalid number of directory entries {}", entries);
+        }
+        Ok(Dpb {
+            spt,
+            bsh: block.trailing_zeros() as u8 - 7,
+            blm: (block / SECTOR - 1) as u8,
+            exm: match dsm {
+                0..=255 => block / 1024 - 1,
+                _ => block / 2048 - 1,
+            } as u8,
+            dsm: dsm as u16,
+            drm: (entries - 1) as u16,
+            al: (0xffff_0000u32 >> directory) as u16,
+            cks: (entries / 4) as u16,
+            off,
+        })
+    }
+
+    /// the 15 bytes of the block in memory
+    pub fn bytes(&self) -> [u8; 15] {
+        let [spt0, spt1] = self.spt.to_le_bytes();
+        let [dsm0, dsm1] = self.dsm.to_le_bytes();
+        let [drm0, drm1] = self.drm.to_le_bytes();
+        let [al0, al1] = self.al.to_be_bytes();
+        let [cks0, cks1] = self.cks.to_le_bytes();
+        let [off0, off1] = self.off.to_le_bytes();
+        [
+            spt0, spt1, self.bsh, self.blm, self.exm, dsm0, dsm1, drm0, drm1, al0, al1, cks0, cks1,
+            off0, off1,
+        ]
+    }
+
+    /// the size of a block
+    pub fn block(&self) -> usize {
+        SECTOR << self.bsh
+    }
+
+    /// the size of the allocation vector
+    pub fn alv(&self) -> usize {
+        self.dsm as usize / 8 + 1
+    }
+}
+
+/// The layout of a disk: its parameters, tracks and skew
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Format {
+    pub dpb: Dpb,
+    pub tracks: u16,
+    /// the physical sectors between two logical ones, 0 for none
+    pub skew: u8,
+}
+
+impl Format {
+    /// the IBM 3740 format: 77 tracks of 26 sectors, skewed by 6
+    pub const SSSD: Format = Format {
+        dpb: Dpb::SSSD,
+        tracks: 77,
+        skew: 6,
+    };
+
+    /// the size of an image
+    pub fn size(&self) -> usize {
+        self.tracks as usize * self.dpb.spt as usize * SECTOR
+    }
+
+    /// the translation table of the logical sectors, giving the physical
+    /// sectors from 1; empty without a skew
+    pub fn table(&self) -> Vec<u8> {
+        let spt = self.dpb.spt as usize;
+        if self.skew == 0 {
+            return Vec::new();
+        }
+        let mut used = vec![false; spt];
+        let mut table = Vec::with_capacity(spt);
+        let mut sector = 0;
+        for _ in 0..spt {
+            while used[sector] {
+                sector = (sector + 1) % spt;
+            }
+            used[sector] = true;
+            table.push(sector as u8 + 1);
+            sector = (sector + self.skew as usize) % spt;
+        }
+        table
+    }
+}
+
+impl std::str::FromStr for Format {
+    type Err = anyhow::Error;
+
+    /// `ibm-3740`, or `SPT,TRACKS,OFF,BLOCK,ENTRIES[,SKEW]`
+    fn from_str(s: &str) -> Result<Self> {
+        if s.eq_ignore_ascii_case("ibm-3740") || s.eq_ignore_ascii_case("sssd") {
+            return Ok(Format::SSSD);
+        }
+        let numbers = s
+            .split(',')
+            .map(|n| n.trim().parse::<u16>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| anyhow!("Invalid format {}", s))?;
+        let (numbers, skew) = match numbers[..] {
+            [_, _, _, _, _] => (&numbers[..], 0),
+            [.., skew] if numbers.len() == 6 && skew < 0x100 => (&numbers[..5], skew as u8),
+            _ => bail!(
+                "Invalid format {}, expected SPT,TRACKS,OFF,BLOCK,ENTRIES[,SKEW]",
+                s
+            ),
+        };
+        let (spt, tracks, off) = (numbers[0], numbers[1], numbers[2]);
+        let dpb = Dpb::new(spt, tracks, off, numbers[3] as usize, numbers[4] as usize)?;
+        Ok(Format { dpb, tracks, skew })
+    }
+}
+
+/// A disk image, written back to its file when opened from one
+#[derive(Clone, Debug)]
+pub struct Disk {
+    pub format: Format,
+    pub image: Vec<u8>,
+    path: Option<PathBuf>,
+}
+
+impl Disk {
+    /// a formatted disk, its directory being empty
+    pub fn new(format: Format) -> Self {
+        Disk {
+            format,
+            image: vec![0xe5; format.size()],
+            path: None,
+        }
+    }
+
+    /// the image in `path`, the sectors missing at its end being formatted
+    pub fn open(path: impl AsRef<Path>, format: Format) -> Result<Self> {
+        let path = path.as_ref();
+        let mut image = std::fs::read(path)?;
+        if image.len() > format.size() {
+            bail!(
+                "{} has {} bytes, more than the {} of its format",
+                path.display(),
+                image.len(),
+                format.size()
+            );
+        }
+        image.resize(format.size(), 0xe5);
+        Ok(Disk {
+            format,
+            image,
+            path: Some(path.to_path_buf()),
+        })
+    }
+
+    /// write the image to `path`, where it is written from now on
+    pub fn save(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        std::fs::write(&path, &self.image)?;
+        self.path = Some(path.as_ref().to_path_buf());
+        Ok(())
+    }
+
+    /// the offset in the image of a physical sector, numbered from 1
+    fn offset(&self, track: u16, sector: u16) -> Option<usize> {
+        let spt = self.format.dpb.spt;
+        if track >= self.format.tracks || sector == 0 || sector > spt {
+            return None;
+        }
+        Some((track as usize * spt as usize + sector as usize - 1) * SECTOR)
+    }
+
+    /// a physical sector, numbered from 1
+    pub fn read(&self, track: u16, sector: u16) -> Option<&[u8]> {
+        let offset = self.offset(track, sector)?;
+        Some(&self.image[offset..offset + SECTOR])
+    }
+
+    /// write a physical sector, numbered from 1, to the image and its file
+    pub fn write(&mut self, track: u16, sector: u16, data: &[u8]) -> Result<()> {
+        let offset = match self.offset(track, sector) {
+            Some(offset) => offset,
+            None => bail!("No sector {} on the track {}", sector, track),
+        };
+        self.image[offset..offset + SECTOR].copy_from_slice(&data[..SECTOR]);
+        if let Some(path) = &self.path {
+            // a short file gets the formatted sectors before this one
+            let mut file = OpenOptions::new().write(true).open(path)?;
+            let start = (file.metadata()?.len() as usize).min(offset);
+            file.seek(SeekFrom::Start(start as u64))?;
+            file.write_all(&self.image[start..offset + SECTOR])?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sssd() {
+        assert_eq!(
+            Dpb::SSSD.bytes(),
+            [26, 0, 3, 7, 0, 242, 0, 63, 0, 0xc0, 0, 16, 0, 2, 0]
+        );
+        assert_eq!(
+            Format::SSSD.table(),
+            [
+                1, 7, 13, 19, 25, 5, 11, 17, 23, 3, 9, 15, 21, 2, 8, 14, 20, 26, 6, 12, 18, 24, 4,
+                10, 16, 22
+            ]
+        );
+        assert_eq!(Format::SSSD.size(), 256_256);
+        assert_eq!(Dpb::new(26, 77, 2, 1024, 64).unwrap(), Dpb::SSSD);
+        assert_eq!("ibm-3740".parse::<Format>().unwrap(), Format::SSSD);
+        assert_eq!("26,77,2,1024,64,6".parse::<Format>().unwrap(), Format::SSSD);
+    }
+
+    #[test]
+    fn test_dpb() {
+        // 40 tracks of 40 records, without skew
+        let format: Format = "40,40,1,1024,64".parse().unwrap();
+        assert_eq!(
+            format.dpb.bytes(),
+            [40, 0, 3, 7, 0, 194, 0, 63, 0, 0xc0, 0, 16, 0, 1, 0]
+        );
+        assert!(format.table().is_empty());
+        // a hard disk with 16 bit block numbers
+        let dpb = Dpb::new(128, 512, 1, 4096, 1024).unwrap();
+        assert_eq!((dpb.bsh, dpb.blm, dpb.exm), (5, 31, 1));
+        assert_eq!(
+            (dpb.dsm, dpb.drm, dpb.al, dpb.cks),
+            (2043, 1023, 0xff00, 256)
+        );
+        assert!(Dpb::new(26, 300, 2, 1024, 64).is_err());
+        assert!("26,77,2,1000,64".parse::<Format>().is_err());
+        assert!("26,77".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn test_image() {
+        let path = std::env::temp_dir().join(format!("rust-8080-disk-{}.img", std::process::id()));
+        std::fs::write(&path, [0; 300]).unwrap();
+        let mut disk = Disk::open(&path, Format::SSSD).unwrap();
+        assert_eq!(disk.read(0, 3).unwrap()[43..45], [0, 0xe5]);
+        disk.write(2, 26, &[0x42; SECTOR]).unwrap();
+        assert!(disk.write(77, 1, &[0; SECTOR]).is_err());
+        assert!(disk.read(0, 0).is_none());
+
+        let written = std::fs::read(&path).unwrap();
+        assert_eq!(written.len(), 3 * 26 * SECTOR);
+        assert_eq!(written[..301], disk.image[..301]);
+        assert_eq!(written[(2 * 26 + 25) * SECTOR], 0x42);
+        std::fs::remove_file(path).unwrap();
+    }
+}
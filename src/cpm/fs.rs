@@ -0,0 +1,396 @@
+//! The CP/M 2.2 file system of a disk image, like cpmtools.
+//!
+//! The directory and the files are in the blocks following the reserved
+//! tracks, the directory taking the first blocks. Each entry of 32 bytes
+//! gives the user, the name, the extent and the blocks of a part of a
+//! file: 16 blocks of 8 bits, or 8 of 16 bits when the disk has more than
+//! 256 blocks. The records are read and written through the skew of the
+//! [Format](super::disk::Format).
+
+use super::bdos::{file_name, host_name, Name};
+use super::disk::{Disk, Dpb, SECTOR};
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+
+/// a deleted or unused entry
+const UNUSED: u8 = 0xe5;
+/// the end of a text file
+const EOF: u8 = 0x1a;
+
+/// A directory entry
+type Entry = [u8; 32];
+
+/// A file of the directory
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct File {
+    pub user: u8,
+    /// the name, like `NAME.TYP`
+    pub name: String,
+    /// the size in records of 128 bytes
+    pub records: usize,
+    /// the attributes T1 and T2
+    pub read_only: bool,
+    pub system: bool,
+}
+
+/// the name of a file, like `NAME.TYP`, in a directory entry
+fn name(file: &str) -> Result<Name> {
+    match host_name(file) {
+        Some(name) => Ok(name),
+        None => bail!("Invalid CP/M file name {}", file),
+    }
+}
+
+/// the name of an entry, without the attributes
+fn entry_name(entry: &Entry) -> Name {
+    let mut name = [0; 11];
+    for (c, e) in name.iter_mut().zip(&entry[1..12]) {
+        *c = e & 0x7f;
+    }
+    name
+}
+
+/// the logical extent of an entry, counting the extents of 16K
+fn extent(entry: &Entry) -> usize {
+    (entry[12] & 0x1f) as usize | ((entry[14] & 0x3f) as usize) << 5
+}
+
+/// The file system of a disk image
+pub struct FileSystem {
+    pub disk: Disk,
+    /// the translation table of the sectors
+    table: Vec<u8>,
+}
+
+impl FileSystem {
+    pub fn new(disk: Disk) -> Self {
+        let table = disk.format.table();
+        FileSystem { disk, table }
+    }
+
+    fn dpb(&self) -> Dpb {
+        self.disk.format.dpb
+    }
+
+    /// the records of a block
+    fn block_records(&self) -> usize {
+        self.dpb().block() / SECTOR
+    }
+
+    /// the track and the physical sector of a record after the reserved
+    /// tracks
+    fn locate(&self, record: usize) -> (u16, u16) {
+        let spt = self.dpb().spt as usize;
+        let track = self.dpb().off as usize + record / spt;
+        let sector = record % spt;
+        let sector = self.table.get(sector).map_or(sector + 1, |s| *s as usize);
+        (track as u16, sector as u16)
+    }
+
+    fn read_record(&self, record: usize) -> Result<&[u8]> {
+        let (track, sector) = self.locate(record);
+        match self.disk.read(track, sector) {
+            Some(data) => Ok(data),
+            None => bail!("No sector {} on the track {}", sector, track),
+        }
+    }
+
+    fn write_record(&mut self, record: usize, data: &[u8]) -> Result<()> {
+        let (track, sector) = self.locate(record);
+        self.disk.write(track, sector, data)
+    }
+
+    /// the entries of the directory
+    fn directory(&self) -> Result<Vec<Entry>> {
+        let entries = self.dpb().drm as usize + 1;
+        let mut directory = Vec::with_capacity(entries);
+        for record in 0..entries.div_ceil(4) {
+            let data = self.read_record(record)?;
+            for entry in data.chunks(32) {
+                directory.push(entry.try_into()?);
+            }
+        }
+        directory.truncate(entries);
+        Ok(directory)
+    }
+
+    fn write_entry(&mut self, index: usize, entry: &Entry) -> Result<()> {
+        let mut data = self.read_record(index / 4)?.to_vec();
+        data[index % 4 * 32..][..32].copy_from_slice(entry);
+        self.write_record(index / 4, &data)
+    }
+
+    /// the blocks of an entry, 0 for none
+    fn blocks(&self, entry: &Entry) -> Vec<usize> {
+        match self.dpb().dsm {
+            0..=255 => entry[16..].iter().map(|b| *b as usize).collect(),
+            _ => entry[16..]
+                .chunks(2)
+                .map(|b| u16::from_le_bytes([b[0], b[1]]) as usize)
+                .collect(),
+        }
+    }
+
+    /// the blocks in use, by the directory and by the files
+    fn used(&self, directory: &[Entry]) -> Result<Vec<bool>> {
+        let dpb = self.dpb();
+        let mut used = vec![false; dpb.dsm as usize + 1];
+        let reserved = (dpb.al.leading_ones() as usize).min(used.len());
+        used[..reserved].fill(true);
+        for entry in directory.iter().filter(|entry| entry[0] <= 15) {
+            for block in self.blocks(entry).into_iter().filter(|b| *b != 0) {
+                match used.get_mut(block) {
+                    Some(used) => *used = true,
+                    None => bail!("Bad block {} in the directory", block),
+                }
+            }
+        }
+        Ok(used)
+    }
+
+    /// the indexes of the entries of a file, in the order of their extents
+    fn entries(directory: &[Entry], user: u8, name: &Name) -> Vec<usize> {
+        let mut entries: Vec<usize> = (0..directory.len())
+            .filter(|i| directory[*i][0] == user && entry_name(&directory[*i]) == *name)
+            .collect();
+        entries.sort_by_key(|i| extent(&directory[*i]));
+        entries
+    }
+
+    /// the files of every user
+    pub fn list(&self) -> Result<Vec<File>> {
+        let mut files = BTreeMap::new();
+        for entry in self.directory()?.iter().filter(|entry| entry[0] <= 15) {
+            let name = entry_name(entry);
+            let file = files.entry((entry[0], name)).or_insert_with(|| File {
+                user: entry[0],
+                name: file_name(&name),
+                records: 0,
+                read_only: entry[9] & 0x80 != 0,
+                system: entry[10] & 0x80 != 0,
+            });
+            let records = extent(entry) * 128 + entry[15].min(128) as usize;
+            file.records = file.records.max(records);
+        }
+        Ok(files.into_values().collect())
+    }
+
+    /// the content of the file `name` of `user`, as whole records
+    pub fn read(&self, user: u8, name: &str) -> Result<Vec<u8>> {
+        let directory = self.directory()?;
+        let entries = Self::entries(&directory, user, &self::name(name)?);
+        if entries.is_empty() {
+            bail!("No file {}:{}", user, name);
+        }
+        let (records, per_block) = (self.dpb().exm as usize * 128 + 128, self.block_records());
+        let mut data = Vec::new();
+        for entry in entries.iter().map(|i| &directory[*i]) {
+            // the first record of the entry, and the end of its last extent
+            let first = extent(entry) / (records / 128) * records;
+            let end = extent(entry) * 128 + entry[15].min(128) as usize;
+            // the blocks never written are holes
+            data.resize(end * SECTOR, 0);
+            let blocks = self.blocks(entry);
+            for record in first..end {
+                let block = blocks[(record - first) / per_block];
+                if block != 0 {
+                    let data_record = block * per_block + (record - first) % per_block;
+                    let sector = self.read_record(data_record)?;
+                    data[record * SECTOR..][..SECTOR].copy_from_slice(sector);
+                }
+            }
+        }
+        Ok(data)
+    }
+
+    /// write `data` to the file `name` of `user`, replacing it if it exists;
+    /// the last record is padded with 1AH
+    pub fn write(&mut self, user: u8, name: &str, data: &[u8]) -> Result<()> {
+        let file = self::name(name)?;
+        if user > 15 {
+            bail!("Invalid user {}", user);
+        }
+        let mut directory = self.directory()?;
+        let replaced = Self::entries(&directory, user, &file);
+        for i in &replaced {
+            directory[*i][0] = UNUSED;
+        }
+
+        let dpb = self.dpb();
+        let per_block = self.block_records();
+        let pointers = if dpb.dsm > 255 { 8 } else { 16 };
+        let entry_records = (dpb.exm as usize * 128 + 128).min(pointers * per_block);
+        let records = data.len().div_ceil(SECTOR);
+        let blocks = records.div_ceil(per_block);
+        let free_entries: Vec<usize> = (0..directory.len())
+            .filter(|i| directory[*i][0] == UNUSED)
+            .collect();
+        if free_entries.len() < records.div_ceil(entry_records).max(1) {
+            bail!("The directory is full");
+        }
+        let used = self.used(&directory)?;
+        let free_blocks: Vec<usize> = (0..used.len()).filter(|b| !used[*b]).collect();
+        if free_blocks.len() < blocks {
+            bail!("The disk is full");
+        }
+
+        // the old entries are only freed once the new ones fit
+        for i in replaced {
+            self.write_entry(i, &directory[i])?;
+        }
+        for record in 0..records {
+            let block = free_blocks[record / per_block];
+            let mut sector = [EOF; SECTOR];
+            let chunk = &data[record * SECTOR..data.len().min(record * SECTOR + SECTOR)];
+            sector[..chunk.len()].copy_from_slice(chunk);
+            self.write_record(block * per_block + record % per_block, &sector)?;
+        }
+        let mut entries = free_entries.into_iter();
+        let mut first = 0;
+        loop {
+            let count = (records - first).min(entry_records);
+            let extent = match count {
+                0 => first / 128,
+                _ => (first + count - 1) / 128,
+            };
+            let mut entry = [0; 32];
+            entry[0] = user;
+            entry[1..12].copy_from_slice(&file);
+            entry[12] = (extent & 0x1f) as u8;
+            entry[14] = (extent >> 5) as u8;
+            entry[15] = (first + count - extent * 128) as u8;
+            let blocks = &free_blocks[first / per_block..(first + count).div_ceil(per_block)];
+            for (i, block) in blocks.iter().enumerate() {
+                match pointers {
+                    16 => entry[16 + i] = *block as u8,
+                    _ => entry[16 + 2 * i..][..2].copy_from_slice(&(*block as u16).to_le_bytes()),
+                }
+            }
+            self.write_entry(entries.next().expect("a free entry"), &entry)?;
+            first += count;
+            if first >= records {
+                return Ok(());
+            }
+        }
+    }
+
+    /// delete the file `name` of `user`
+    pub fn delete(&mut self, user: u8, name: &str) -> Result<()> {
+        let directory = self.directory()?;
+        let entries = Self::entries(&directory, user, &self::name(name)?);
+        if entries.is_empty() {
+            bail!("No file {}:{}", user, name);
+        }
+        for i in entries {
+            let mut entry = directory[i];
+            entry[0] = UNUSED;
+            self.write_entry(i, &entry)?;
+        }
+        Ok(())
+    }
+
+    /// rename the file `from` of `user` to `to`, keeping its attributes
+    pub fn rename(&mut self, user: u8, from: &str, to: &str) -> Result<()> {
+        let directory = self.directory()?;
+        let to_name = name(to)?;
+        let entries = Self::entries(&directory, user, &name(from)?);
+        if entries.is_empty() {
+            bail!("No file {}:{}", user, from);
+        }
+        if !Self::entries(&directory, user, &to_name).is_empty() {
+            bail!("{}:{} already exists", user, to);
+        }
+        for i in entries {
+            let mut entry = directory[i];
+            for (c, n) in entry[1..12].iter_mut().zip(&to_name) {
+                *c = *c & 0x80 | n;
+            }
+            self.write_entry(i, &entry)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpm::disk::Format;
+
+    /// bytes that do not repeat every record
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut fs = FileSystem::new(Disk::new(Format::SSSD));
+        assert_eq!(fs.list().unwrap(), []);
+        // 3 extents
+        let data = content(40 * 1024);
+        fs.write(0, "big.dat", &data).unwrap();
+        fs.write(3, "HELLO.TXT", b"hello\r\n").unwrap();
+        fs.write(0, "EMPTY", b"").unwrap();
+        let listing = fs.list().unwrap();
+        let names: Vec<_> = listing
+            .iter()
+            .map(|file| (file.user, file.name.as_str(), file.records))
+            .collect();
+        assert_eq!(
+            names,
+            [(0, "BIG.DAT", 320), (0, "EMPTY", 0), (3, "HELLO.TXT", 1)]
+        );
+        assert_eq!(fs.read(0, "BIG.DAT").unwrap(), data);
+        assert_eq!(&fs.read(3, "hello.txt").unwrap()[..8], b"hello\r\n\x1a");
+        assert!(fs.read(0, "HELLO.TXT").is_err());
+        assert_eq!(fs.read(0, "EMPTY").unwrap(), b"");
+
+        // the first directory record, then the blocks 2 to 41 of the file
+        let entry = fs.read_record(0).unwrap();
+        assert_eq!(&entry[..16], b"\0BIG     DAT\0\0\0\x80");
+        assert_eq!(entry[16..32], (2..18).collect::<Vec<u8>>()[..]);
+        assert_eq!(fs.locate(2 * 8), (2, 20));
+
+        // the blocks freed are used again
+        fs.delete(0, "BIG.DAT").unwrap();
+        fs.write(0, "AGAIN", &data[..1024]).unwrap();
+        assert_eq!(fs.blocks(&fs.directory().unwrap()[0])[..2], [2, 0]);
+
+        fs.rename(3, "HELLO.TXT", "BYE.TXT").unwrap();
+        assert!(fs.rename(0, "EMPTY", "AGAIN").is_err());
+        assert!(fs.delete(0, "BIG.DAT").is_err());
+        let names: Vec<_> = fs.list().unwrap().into_iter().map(|f| f.name).collect();
+        assert_eq!(names, ["AGAIN", "EMPTY", "BYE.TXT"]);
+    }
+
+    #[test]
+    fn test_full() {
+        let format: Format = "26,10,2,1024,64".parse().unwrap();
+        let mut fs = FileSystem::new(Disk::new(format));
+        // 26 blocks, 2 for the directory
+        assert!(fs.write(0, "HUGE", &content(25 * 1024)).is_err());
+        fs.write(0, "LARGE", &content(24 * 1024)).unwrap();
+        assert!(fs.write(0, "MORE", b"x").is_err());
+        // the file replaced frees its blocks
+        fs.write(0, "LARGE", &content(20 * 1024)).unwrap();
+        fs.write(0, "MORE", b"x").unwrap();
+        for n in 0..61 {
+            fs.write(1, &format!("F{}", n), b"").unwrap();
+        }
+        assert!(fs.write(1, "F61", b"").is_err());
+    }
+
+    #[test]
+    fn test_large_disk() {
+        let data = content(100 * 1024);
+        // 4 extents in an entry, then 16 bit block numbers
+        for (format, dsm, exm) in [("128,40,1,4096,256", 155, 3), ("128,80,1,2048,256", 631, 0)] {
+            let format: Format = format.parse().unwrap();
+            assert_eq!((format.dpb.dsm, format.dpb.exm), (dsm, exm));
+            let mut fs = FileSystem::new(Disk::new(format));
+            fs.write(2, "DATA", &data).unwrap();
+            assert_eq!(fs.read(2, "DATA").unwrap(), data);
+            assert_eq!(fs.list().unwrap()[0].records, 800);
+        }
+    }
+}
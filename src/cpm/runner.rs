@@ -0,0 +1,241 @@
+//! Running a `.COM` program as the CCP would.
+//!
+//! The page zero gets the warm boot jump at 0000H, the BDOS jump at 0005H
+//! giving the top of the memory, the FCBs of the first two arguments at
+//! 005CH and 006CH and the command tail at 0080H. The program is loaded at
+//! 0100H, with a stack returning to 0000H, and runs until it warm boots,
+//! halts, faults, or exceeds the limits given for the batch runs.
+
+use super::bdos::{Bdos, BASE};
+use super::Console;
+use crate::Cpu;
+use anyhow::{bail, Result};
+use std::time::{Duration, Instant};
+
+/// the warm boot entry of the BIOS, after the cold boot jump
+pub const WBOOT: u16 = BASE + 0x103;
+/// the stack given to the program, a `RET` going back to 0000H
+pub const STACK: u16 = BASE - 0x40;
+
+/// How a program stopped
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Exit {
+    /// a warm boot, with the program return code of CP/M 3
+    Boot(u16),
+    Halt,
+    /// the limit of instructions was reached
+    Limit,
+    Timeout,
+    /// the cpu could not execute an instruction
+    Fault(String),
+}
+
+impl Exit {
+    /// the exit status: 0 for a warm boot, 1 for a failure return code or a
+    /// halt, 2 for the limits, 3 for a fault
+    pub fn status(&self) -> i32 {
+        match self {
+            Exit::Boot(code) if *code >= 0xff00 && *code != 0xffff => 1,
+            Exit::Boot(_) => 0,
+            Exit::Halt => 1,
+            Exit::Limit | Exit::Timeout => 2,
+            Exit::Fault(_) => 3,
+        }
+    }
+}
+
+/// the FCB of an argument like `B:NAME.TYP`, `*` filling the rest of the
+/// name or the type with `?`
+pub fn fcb(arg: &str) -> [u8; 16] {
+    let mut fcb = [0; 16];
+    fcb[1..12].copy_from_slice(&[b' '; 11]);
+    let arg = arg.to_ascii_uppercase();
+    let bytes = arg.as_bytes();
+    let rest = match bytes {
+        [drive @ b'A'..=b'P', b':', rest @ ..] => {
+            fcb[0] = drive - b'A' + 1;
+            rest
+        }
+        _ => bytes,
+    };
+    let mut parts = rest.splitn(2, |c| *c == b'.');
+    let name = parts.next().unwrap_or_default();
+    let ext = parts.next().unwrap_or_default();
+    let (name_field, ext_field) = fcb[1..12].split_at_mut(8);
+    for (field, part) in [(name_field, name), (ext_field, ext)] {
+        for (i, c) in part.iter().take(field.len()).enumerate() {
+            if *c == b'*' {
+                field[i..].fill(b'?');
+                break;
+            }
+            field[i] = *c;
+        }
+    }
+    fcb
+}
+
+/// A program and the BDOS running it
+pub struct Runner<C> {
+    pub cpu: Cpu,
+    pub bdos: Bdos<C>,
+    /// the instructions executed before giving up
+    pub limit: Option<u64>,
+    pub timeout: Option<Duration>,
+}
+
+impl<C: Console> Runner<C> {
+    /// load `program` at 0100H with the arguments `args`, and build the
+    /// page zero
+    pub fn new(program: &[u8], args: &[String], bdos: Bdos<C>) -> Result<Self> {
+        if 0x100 + program.len() > STACK as usize {
+            bail!(
+                "The program of {} bytes does not fit in memory",
+                program.len()
+            );
+        }
+        let mut cpu = Cpu::from_raw(vec![0; 0x10000]);
+        cpu.trace = false;
+        cpu.ram.load_at(0x100, program);
+        bdos.install(&mut cpu);
+
+        let [low, high] = WBOOT.to_le_bytes();
+        cpu.ram
+            .load_at(0, &[0xc3, low, high, 0, bdos.drive | bdos.user << 4]);
+        let arg = |i: usize| args.get(i).map_or("", String::as_str);
+        cpu.ram.load_at(0x5c, &fcb(arg(0)));
+        cpu.ram.load_at(0x6c, &fcb(arg(1)));
+        // the length, up to 126 characters and a 0 fit in the 128 bytes at 0080H
+        let mut tail: Vec<u8> = args
+            .iter()
+            .flat_map(|arg| std::iter::once(b' ').chain(arg.to_ascii_uppercase().into_bytes()))
+            .take(126)
+            .collect();
+        tail.insert(0, tail.len() as u8);
+        tail.push(0);
+        cpu.ram.load_at(0x80, &tail);
+
+        cpu.sp = STACK;
+        cpu.pc = 0x100;
+        Ok(Runner {
+            cpu,
+            bdos,
+            limit: None,
+            timeout: None,
+        })
+    }
+
+    /// run the program until it stops
+    pub fn run(&mut self) -> Result<Exit> {
+        let start = Instant::now();
+        let mut steps: u64 = 0;
+        loop {
+            let pc = self.cpu.pc;
+            if pc == 0 || pc == WBOOT as usize {
+                return Ok(Exit::Boot(self.bdos.code));
+            }
+            if self.cpu.ram[pc] == 0x76 {
+                return Ok(Exit::Halt);
+            }
+            if self.limit.is_some_and(|limit| steps >= limit) {
+                return Ok(Exit::Limit);
+            }
+            // the clock is only read from time to time
+            if steps.is_multiple_of(4096) && self.timeout.is_some_and(|t| start.elapsed() >= t) {
+                return Ok(Exit::Timeout);
+            }
+            if self.bdos.trap(&mut self.cpu)? {
+                if let Err(message) = self.cpu.try_cycle() {
+                    return Ok(Exit::Fault(message));
+                }
+            }
+            steps += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::cpm::Buffer;
+
+    fn runner(source: &str, args: &[&str]) -> Runner<Buffer> {
+        let program = assemble(source).unwrap();
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        Runner::new(&program.image, &args, Bdos::new(Buffer::default())).unwrap()
+    }
+
+    #[test]
+    fn test_fcb() {
+        assert_eq!(&fcb("b:hello.com"), b"\x02HELLO   COM\0\0\0\0");
+        assert_eq!(&fcb("*.c*"), b"\0????????C??\0\0\0\0");
+        assert_eq!(&fcb("LONGNAME123.TEXT"), b"\0LONGNAMETEX\0\0\0\0");
+        assert_eq!(&fcb(""), b"\0           \0\0\0\0");
+    }
+
+    #[test]
+    fn test_page_zero() {
+        let mut runner = runner(
+            "\
+\tORG\t100H
+\tLXI\tD,81H
+\tMVI\tC,9
+\tCALL\t5
+\tRET
+",
+            &["a:in.txt", "out", "$"],
+        );
+        let ram = &runner.cpu.ram;
+        assert_eq!(ram[0..3], [0xc3, 0x03, 0xff]);
+        assert_eq!(ram[5..8], [0xc3, 0x06, 0xfe]);
+        assert_eq!(&ram[0x5c..0x68], b"\x01IN      TXT");
+        assert_eq!(&ram[0x6c..0x78], b"\0OUT        ");
+        assert_eq!(&ram[0x80..0x90], b"\x0f A:IN.TXT OUT $");
+        assert_eq!(runner.run().unwrap(), Exit::Boot(0));
+        assert_eq!(runner.bdos.console.output, b" A:IN.TXT OUT ");
+
+        // a long tail is cut to stay in the page zero
+        let long = vec!["argument".to_string(); 20];
+        let runner = Runner::new(&[0xc9], &long, Bdos::new(Buffer::default())).unwrap();
+        let ram = &runner.cpu.ram;
+        assert_eq!(ram[0x80], 126);
+        assert_eq!(ram[0xff], 0);
+        assert_eq!(ram[0x100], 0xc9);
+    }
+
+    #[test]
+    fn test_exits() {
+        let source = "\tORG\t100H\n\tMVI\tC,108\n\tLXI\tD,0FF00H\n\tCALL\t5\n\tJMP\t0\n";
+        let exit = runner(source, &[]).run().unwrap();
+        assert_eq!((exit.status(), exit), (1, Exit::Boot(0xff00)));
+
+        let exit = runner("\tORG\t100H\n\tNOP\n\tHLT\n", &[]).run().unwrap();
+        assert_eq!((exit.status(), exit), (1, Exit::Halt));
+
+        // an undocumented opcode
+        let exit = runner("\tORG\t100H\n\tNOP\n\tDB\t8\n", &[]).run().unwrap();
+        assert_eq!(exit.status(), 3);
+        assert!(matches!(exit, Exit::Fault(_)));
+
+        let mut looping = runner("\tORG\t100H\nLOOP:\tJMP\tLOOP\n", &[]);
+        looping.limit = Some(1000);
+        assert_eq!(looping.run().unwrap(), Exit::Limit);
+        looping.limit = None;
+        looping.timeout = Some(Duration::from_millis(10));
+        assert_eq!(looping.run().unwrap().status(), 2);
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        // the exercisers of tests/bin, checking every documented instruction
+        let run = |file: &str| {
+            let program = std::fs::read(file).unwrap();
+            let mut runner = Runner::new(&program, &[], Bdos::new(Buffer::default())).unwrap();
+            runner.limit = Some(100_000);
+            assert_eq!(runner.run().unwrap(), Exit::Boot(0));
+            String::from_utf8(runner.bdos.console.output).unwrap()
+        };
+        assert!(run("tests/bin/TST8080.COM").ends_with("CPU IS OPERATIONAL"));
+        assert!(run("tests/bin/8080PRE.COM").contains("Preliminary tests complete"));
+    }
+}
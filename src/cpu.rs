@@ -1,24 +1,57 @@
+mod alu;
+mod call;
 mod dcr;
 mod halt;
+mod jmp;
 mod lhld;
 mod mov;
 mod mvi;
 mod nop;
 mod pop;
 mod push;
+mod rotate;
 mod sphl;
 
+use crate::callstack::{Backtrace, CallStack, Frame, FrameKind};
+use crate::decompiler::Format;
+use crate::instruction::{AluOp, Instruction, Instruction::*, PairPsw};
+use crate::symbols::SymbolTable;
 use crate::*;
 use anyhow::Result;
+use std::cell::Cell;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Once;
 use Flags::*;
 
+thread_local! {
+    /// set while [Cpu::try_cycle] catches the panics of an instruction
+    static QUIET: Cell<bool> = const { Cell::new(false) };
+}
+
+/// User function to read from port
+pub type PortIn = Box<dyn Fn(&Cpu, u8) -> u8>;
+/// User function to write to port
+pub type PortOut = Box<dyn Fn(&Cpu, u8, u8)>;
+
 pub struct Cpu {
     /// User function to read from port.
     /// Called when the IN instruction appear
-    pub port_in: Option<Box<dyn Fn(&Cpu, u8) -> u8>>,
+    pub port_in: Option<PortIn>,
     /// User function to write to port.
     /// Called when the OUT instruction appear
-    pub port_out: Option<Box<dyn Fn(&Cpu, u8, u8)>>,
+    pub port_out: Option<PortOut>,
+    /// Print every executed instruction and the registers
+    pub trace: bool,
+    /// Names used in place of the addresses in the trace
+    pub symbols: SymbolTable,
+    /// How the instructions are written in the trace
+    pub format: Format,
+    /// Shadow stack of the subroutine calls, kept up to date when set
+    pub call_stack: Option<CallStack>,
+    /// Number of executed instructions
+    pub instructions: u64,
+    /// Interrupts enabled by EI, disabled by DI and by an interrupt
+    pub interrupts: bool,
 
     pub reg: Registers,
     /// stack pointer
@@ -28,35 +61,17 @@ pub struct Cpu {
     pub ram: Memory,
 }
 
-/// get the 8 bit port address
-fn p(opcode: &[u8]) -> u8 {
-    if opcode.len() < 2 {
-        panic!("Malformed opcode");
-    }
-    opcode[1]
-}
-
-/// merge the byte 2 and 3 of the opcode to create a 16 bits number
-fn d16(opcode: &[u8]) -> u16 {
-    if opcode.len() < 3 {
-        panic!("Malformed opcode");
-    }
-    ((opcode[2] as u16) << 8) | opcode[1] as u16
-}
-
-/// merge the byte 2 and 3 of the opcode to create a usize
-fn addr(opcode: &[u8]) -> usize {
-    if opcode.len() < 3 {
-        panic!("Malformed opcode");
-    }
-    ((opcode[2] as usize) << 8) | opcode[1] as usize
-}
-
 impl Cpu {
     pub fn from_filename_at(file: &str, starting_addr: usize) -> Result<Self> {
         Ok(Self {
             port_in: None,
             port_out: None,
+            trace: true,
+            symbols: SymbolTable::new(),
+            format: Format::default(),
+            call_stack: None,
+            instructions: 0,
+            interrupts: false,
             reg: Registers::new(),
 
             sp: 0,
@@ -70,6 +85,12 @@ impl Cpu {
         Self {
             port_in: None,
             port_out: None,
+            trace: true,
+            symbols: SymbolTable::new(),
+            format: Format::default(),
+            call_stack: None,
+            instructions: 0,
+            interrupts: false,
             reg: Registers::new(),
 
             sp: 0,
@@ -79,72 +100,162 @@ impl Cpu {
         }
     }
 
-    #[bitmatch]
+    /// the backtrace of the shadow call stack, with a single frame when it is
+    /// disabled
+    pub fn backtrace(&self) -> Backtrace {
+        self.call_stack
+            .as_ref()
+            .map_or_else(CallStack::new, Clone::clone)
+            .backtrace(self.pc as u16, &self.symbols)
+    }
+
     pub fn cycle(&mut self) {
+        let pc = self.pc;
+        self.ram.set_writer(Some((pc as u16, self.instructions)));
         let opcode = &self.ram[self.pc..];
-        println!("{:04x}\t{}", self.pc, decompiler::instr(opcode));
-
-        #[bitmatch]
-        match opcode[0] {
-            "0000_0000" => self.nop(),
-            "1100_0011" => self.jmp(addr(opcode)),
-            "11cc_c010" => self.cond_jmp(c, addr(opcode)),
-            "1100_1101" => self.call(addr(opcode)),
-            "1100_1001" => self.ret(),
+        if self.trace {
+            if let Some(label) = self.symbols.name(self.pc as u16) {
+                println!("{}:", label);
+            }
+            println!(
+                "{:04x}\t{}",
+                self.pc,
+                decompiler::instr_with_format(opcode, &self.symbols, &self.format).0
+            );
+        }
+
+        let byte = opcode[0];
+        let instruction = match Instruction::decode(opcode) {
+            Ok(instruction) => instruction,
+            Err(_) => panic!("Malformed opcode"),
+        };
+
+        match instruction {
+            Nop => self.nop(),
+            Jmp(addr) => self.jmp(addr as usize),
+            Jcc(c, addr) => self.cond_jmp(c as u8, addr as usize),
+            Call(addr) => self.call(addr as usize),
+            Ret => self.ret(),
+            Ccc(c, addr) => self.cond_call(c as u8, addr as usize),
+            Rcc(c) => self.cond_ret(c as u8),
+            Rst(n) => self.rst(n),
+            Pchl => self.pchl(),
+            Xthl => self.xthl(),
             // ports
-            "1101_1011 " => self.r#in(p(opcode)),
-            "1101_0011" => self.out(p(opcode)),
+            In(port) => self.r#in(port),
+            Out(port) => self.out(port),
             // register
-            "00rr_r101" => self.dcr(r.into()),
-            "00rr_r100" => self.inr(r.into()),
-            "1011_1sss" => self.cmp(s.into()),
-            "1111_1110" => self.cpi(opcode[1]),
-            "00aa_a110" => self.mvi(a.into(), opcode[1]),
+            Dcr(r) => self.dcr(r as usize),
+            Inr(r) => self.inr(r as usize),
+            Alu(op, r) => self.alu(op, r as usize),
+            AluImm(op, d8) => self.alu_imm(op, d8),
+            Mvi(r, d8) => self.mvi(r as usize, d8),
+            Rlc => self.rlc(),
+            Rrc => self.rrc(),
+            Ral => self.ral(),
+            Rar => self.rar(),
+            Daa => self.daa(),
+            Cma => self.cma(),
+            Stc => self.stc(),
+            Cmc => self.cmc(),
             // register pair
-            "1111_1001" => self.sphl(),
-            "00rr_0001" => self.lxi(r, d16(opcode)),
-            "0011_1010" => self.lda(d16(opcode)),
-            "0011_0010" => self.sta(d16(opcode)),
-            "0010_1010" => self.lhld(d16(opcode)),
-            "0010_0010" => self.shld(d16(opcode)),
-            "00rr_1010" => self.ldax(r),
-            "00rr_0010" => self.stax(r),
-            "00rr_1011" => self.dcx(r),
-            "00rr_0011" => self.inx(r),
-            "1111_0001" => self.pop_psw(),
-            "11rr_0001" => self.pop(r),
-            "1111_0101" => self.push_psw(),
-            "11rr_0101" => self.push(r),
+            Sphl => self.sphl(),
+            Lxi(rp, d16) => self.lxi(rp as u8, d16),
+            Lda(d16) => self.lda(d16),
+            Sta(d16) => self.sta(d16),
+            Lhld(d16) => self.lhld(d16),
+            Shld(d16) => self.shld(d16),
+            Ldax(rp) => self.ldax(rp as u8),
+            Stax(rp) => self.stax(rp as u8),
+            Dcx(rp) => self.dcx(rp as u8),
+            Inx(rp) => self.inx(rp as u8),
+            Dad(rp) => self.dad(rp as u8),
+            Xchg => self.xchg(),
+            Pop(PairPsw::PSW) => self.pop_psw(),
+            Pop(rp) => self.pop(rp as u8),
+            Push(PairPsw::PSW) => self.push_psw(),
+            Push(rp) => self.push(rp as u8),
             // other
-            "0111_0110" => self.halt(), // overlap with the mov instruction
-            "01aa_abbb" => self.mov(a.into(), b.into()),
-            "aaaa_aaaa" => panic!("Instruction {0:#010b} {0:#04x} is not implemented", a),
+            Ei => self.ei(true),
+            Di => self.ei(false),
+            Hlt => self.halt(),
+            Mov(dst, src) => self.mov(dst as usize, src as usize),
+            _ => panic!("Instruction {0:#010b} {0:#04x} is not implemented", byte),
+        }
+
+        self.ram.set_writer(None);
+        self.instructions += 1;
+
+        if let Some(stack) = &mut self.call_stack {
+            stack.check(pc as u16, self.sp, &self.ram);
+        }
+
+        if self.trace {
+            println!("sp: {0} {0:#x}", self.sp);
+            println!("registers: {:?}", self.reg);
         }
+    }
 
-        println!("sp: {0} {0:#x}", self.sp);
-        println!("registers: {:?}", self.reg);
+    /// execute one instruction like [Cpu::cycle], returning the message of
+    /// its panic instead of printing it
+    pub fn try_cycle(&mut self) -> Result<(), String> {
+        static HOOK: Once = Once::new();
+        HOOK.call_once(|| {
+            let previous = panic::take_hook();
+            panic::set_hook(Box::new(move |info| {
+                if !QUIET.with(Cell::get) {
+                    previous(info)
+                }
+            }));
+        });
+
+        QUIET.with(|quiet| quiet.set(true));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| self.cycle()));
+        QUIET.with(|quiet| quiet.set(false));
+        result.map_err(|err| {
+            // the next writes are not the faulty instruction's
+            self.ram.set_writer(None);
+            if let Some(s) = err.downcast_ref::<&str>() {
+                s.to_string()
+            } else if let Some(s) = err.downcast_ref::<String>() {
+                s.clone()
+            } else {
+                "unknown error".to_string()
+            }
+        })
     }
 
-    /// helper to push something on the stack
+    /// helper to push something on the stack, which grows down
     fn internal_push(&mut self, value: u16) {
+        self.sp = self.sp.wrapping_sub(2);
         self.ram.dword_set(self.sp, value);
     }
 
     /// helper to pop something from the stack
     fn internal_pop(&mut self) -> u16 {
-        self.ram.dword(self.sp as usize)
+        let value = self.ram.dword(self.sp);
+        self.sp = self.sp.wrapping_add(2);
+        value
     }
 
-    // ============= INSTRUCTIONS ==============
-
-    /// Unconditionnal jump
-    fn jmp(&mut self, addr: usize) {
-        self.pc = addr;
+    /// helper to record a call in the shadow stack, once the return address
+    /// is pushed and before jumping
+    fn enter(&mut self, kind: FrameKind, target: u16, return_addr: u16) {
+        let frame = Frame {
+            kind,
+            call_site: self.pc as u16,
+            target,
+            return_addr,
+            sp: self.sp,
+        };
+        if let Some(stack) = &mut self.call_stack {
+            stack.call(frame);
+        }
     }
 
-    /// Conditionnal jump
-    fn cond_jmp(&mut self, cond: u8, addr: usize) {
-        let cond = match cond {
+    /// helper to evaluate the condition of the conditional instructions
+    fn condition(&self, cond: u8) -> bool {
+        match cond {
             0b000 => !self.reg.zero(),
             0b001 => self.reg.zero(),
             0b010 => !self.reg.carry(),
@@ -153,15 +264,23 @@ impl Cpu {
             0b101 => self.reg.parity(),
             0b110 => !self.reg.sign(),
             0b111 => self.reg.sign(),
-            c => panic!("cond_jmp called with invalid value: {:b}", c),
-        };
-        if cond {
-            self.pc = addr;
-        } else {
-            self.pc += 2;
+            c => panic!("condition called with invalid value: {:b}", c),
         }
     }
 
+    /// Interrupt the cpu with the instruction RST `n`: the current PC is
+    /// pushed and the execution continues at `n * 8`, with the interrupts
+    /// disabled
+    pub fn interrupt(&mut self, n: u8) {
+        let ret_addr = self.pc as u16;
+        self.interrupts = false;
+        self.internal_push(ret_addr);
+        self.enter(FrameKind::Interrupt(n), n as u16 * 8, ret_addr);
+        self.pc = n as usize * 8;
+    }
+
+    // ============= INSTRUCTIONS ==============
+
     /// Read input port into A
     fn r#in(&mut self, pa: u8) {
         self.reg.a = self.port_in.as_ref().unwrap()(self, pa);
@@ -174,24 +293,37 @@ impl Cpu {
         self.pc += 2;
     }
 
-    /// Unconditionnal subroutine call
-    fn call(&mut self, addr: usize) {
-        let ret_addr = self.pc + 3;
-        self.internal_push(ret_addr as u16);
-        self.sp += 2;
-        self.pc = addr;
+    /// Restart, call the subroutine at `n * 8`
+    fn rst(&mut self, n: u8) {
+        let ret_addr = self.pc as u16 + 1;
+        self.internal_push(ret_addr);
+        self.enter(FrameKind::Rst(n), n as u16 * 8, ret_addr);
+        self.pc = n as usize * 8;
+    }
+
+    /// Jump to the address in H:L
+    fn pchl(&mut self) {
+        self.pc = self.reg.hl() as usize;
     }
 
-    /// Return from a subroutine call
-    fn ret(&mut self) {
-        self.pc = self.internal_pop() as usize;
-        self.sp -= 2;
+    /// Exchange the top of the stack with H:L
+    fn xthl(&mut self) {
+        let top = self.ram.dword(self.sp);
+        self.ram.dword_set(self.sp, self.reg.hl());
+        self.reg.hl_set(top);
+        self.pc += 1;
+    }
+
+    /// Enable or disable the interrupts
+    fn ei(&mut self, enabled: bool) {
+        self.interrupts = enabled;
+        self.pc += 1;
     }
 
     /// Load A from memory
     /// Write the content of mem[d16] to A
     fn lda(&mut self, d16: u16) {
-        self.ram[d16 as usize] = self.reg.a;
+        self.reg.a = self.ram[d16 as usize];
         self.pc += 3;
     }
 
@@ -267,20 +399,28 @@ impl Cpu {
         self.pc += 1;
     }
 
-    /// Compare register with A
-    fn cmp(&mut self, r: usize) {
-        let res = self.reg.a.overflowing_sub(self.reg[r]);
-        self.reg
-            .update_flags(res, &[Zero, Sign, Parity, Carry, AuxCarry]);
+    /// Add register pair to H:L
+    /// Only update the carry
+    fn dad(&mut self, rp: u8) {
+        let value = match rp {
+            0x00 => self.reg.bc(),
+            0x01 => self.reg.de(),
+            0x02 => self.reg.hl(),
+            0x03 => self.sp,
+            a => panic!("DAD called with invalid register pair: {:x}", a),
+        };
+        let (res, carry) = self.reg.hl().overflowing_add(value);
+        self.reg.hl_set(res);
+        self.reg.set_carry(carry);
         self.pc += 1;
     }
 
-    /// Compare immediate with A
-    fn cpi(&mut self, val: u8) {
-        let res = self.reg.a.overflowing_sub(val);
-        self.reg
-            .update_flags(res, &[Zero, Sign, Parity, Carry, AuxCarry]);
-        self.pc += 2;
+    /// Exchange H:L with D:E
+    fn xchg(&mut self) {
+        let de = self.reg.de();
+        self.reg.de_set(self.reg.hl());
+        self.reg.hl_set(de);
+        self.pc += 1;
     }
 
     /// Increment register
@@ -292,7 +432,8 @@ impl Cpu {
         };
         let res = r.overflowing_add(1);
         *r = res.0;
-        self.reg.update_flags(res, &[Zero, Sign, Parity, AuxCarry]);
+        self.reg.update_flags(res, &[Zero, Sign, Parity]);
+        self.reg.set_half_carry(res.0 & 0x0f == 0);
         self.pc += 1;
     }
 }
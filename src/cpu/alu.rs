@@ -0,0 +1,231 @@
+use super::*;
+
+impl Cpu {
+    /// Arithmetic or logical operation between A and a register or the
+    /// memory at H:L
+    pub fn alu(&mut self, op: AluOp, r: usize) {
+        let value = match r {
+            0x06 => self.ram[self.reg.hl() as usize],
+            r => self.reg[r],
+        };
+        self.accumulate(op, value);
+        self.pc += 1;
+    }
+
+    /// Arithmetic or logical operation between A and an immediate byte
+    pub fn alu_imm(&mut self, op: AluOp, value: u8) {
+        self.accumulate(op, value);
+        self.pc += 2;
+    }
+
+    /// helper to apply `op` to A and `value`, CMP leaving A unchanged
+    fn accumulate(&mut self, op: AluOp, value: u8) {
+        let (a, carry) = (self.reg.a, self.reg.carry());
+        let res = match op {
+            AluOp::Add => self.add(value, false),
+            AluOp::Adc => self.add(value, carry),
+            AluOp::Sub | AluOp::Cmp => self.sub(value, false),
+            AluOp::Sbb => self.sub(value, carry),
+            // the AND of the 8080 takes the auxiliary carry from the bits 3
+            AluOp::Ana => self.logic(a & value, (a | value) & 0x08 != 0),
+            AluOp::Xra => self.logic(a ^ value, false),
+            AluOp::Ora => self.logic(a | value, false),
+        };
+        if op != AluOp::Cmp {
+            self.reg.a = res;
+        }
+    }
+
+    /// helper to add `value` and the carry to A, updating all the flags
+    fn add(&mut self, value: u8, carry: bool) -> u8 {
+        let a = self.reg.a;
+        let sum = a as u16 + value as u16 + carry as u16;
+        self.reg
+            .set_half_carry((a & 0x0f) + (value & 0x0f) + carry as u8 > 0x0f);
+        self.reg
+            .update_flags((sum as u8, sum > 0xff), &[Zero, Sign, Parity, Carry]);
+        sum as u8
+    }
+
+    /// helper to subtract `value` and the borrow from A, updating all the
+    /// flags. The 8080 adds the complement, the carry becoming a borrow but
+    /// the auxiliary carry being kept.
+    fn sub(&mut self, value: u8, borrow: bool) -> u8 {
+        let res = self.add(!value, !borrow);
+        self.reg.set_carry(!self.reg.carry());
+        res
+    }
+
+    /// helper to set the flags of a logical operation, which clears the carry
+    fn logic(&mut self, res: u8, half_carry: bool) -> u8 {
+        self.reg.set_half_carry(half_carry);
+        self.reg
+            .update_flags((res, false), &[Zero, Sign, Parity, Carry]);
+        res
+    }
+
+    /// Decimal adjust A, after the addition of two BCD numbers
+    pub fn daa(&mut self) {
+        let a = self.reg.a;
+        let mut carry = self.reg.carry();
+        let mut correction = 0;
+        if self.reg.half_carry() || a & 0x0f > 9 {
+            correction |= 0x06;
+        }
+        if carry || a >> 4 > 9 || (a >> 4 >= 9 && a & 0x0f > 9) {
+            correction |= 0x60;
+            carry = true;
+        }
+        self.reg.a = self.add(correction, false);
+        self.reg.set_carry(carry);
+        self.pc += 1;
+    }
+
+    /// Complement A, without changing the flags
+    pub fn cma(&mut self) {
+        self.reg.a = !self.reg.a;
+        self.pc += 1;
+    }
+
+    /// Set the carry
+    pub fn stc(&mut self) {
+        self.reg.set_carry(true);
+        self.pc += 1;
+    }
+
+    /// Complement the carry
+    pub fn cmc(&mut self) {
+        self.reg.set_carry(!self.reg.carry());
+        self.pc += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm8080::asm8080;
+
+    /// run the instructions of `program` but the last, a HLT
+    fn run(mut program: Vec<u8>) -> Cpu {
+        program.resize(0x100, 0);
+        let mut cpu = Cpu::from_raw(program);
+        cpu.trace = false;
+        while cpu.ram[cpu.pc] != 0x76 {
+            cpu.cycle();
+        }
+        cpu
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let cpu = run(asm8080! {
+            MVI A, 0x2e;
+            MVI B, 0x74;
+            ADD B;
+            HLT
+        });
+        assert_eq!(cpu.reg.a, 0xa2);
+        assert!(cpu.reg.sign() && !cpu.reg.zero() && !cpu.reg.carry());
+        assert!(cpu.reg.half_carry() && !cpu.reg.parity());
+
+        let cpu = run(asm8080! {
+            STC;
+            MVI A, 0xff;
+            ACI 0;
+            HLT
+        });
+        assert_eq!(cpu.reg.a, 0);
+        assert!(cpu.reg.zero() && cpu.reg.carry() && cpu.reg.half_carry());
+
+        let cpu = run(asm8080! {
+            MVI A, 0x3e;
+            SUI 0x3e;
+            HLT
+        });
+        assert_eq!(cpu.reg.a, 0);
+        assert!(cpu.reg.zero() && !cpu.reg.carry() && cpu.reg.half_carry());
+
+        let cpu = run(asm8080! {
+            STC;
+            MVI A, 0x04;
+            MVI L, 0x02;
+            SBB L;
+            HLT
+        });
+        assert_eq!(cpu.reg.a, 0x01);
+        assert!(!cpu.reg.carry() && !cpu.reg.parity());
+
+        let cpu = run(asm8080! {
+            MVI A, 0x0a;
+            MVI E, 0x05;
+            CMP E;
+            HLT
+        });
+        assert_eq!(cpu.reg.a, 0x0a);
+        assert!(!cpu.reg.carry() && !cpu.reg.zero());
+        let cpu = run(asm8080! {
+            MVI A, 0x02;
+            CPI 0x05;
+            HLT
+        });
+        assert_eq!(cpu.reg.a, 0x02);
+        assert!(cpu.reg.carry() && cpu.reg.sign());
+    }
+
+    #[test]
+    fn test_logic() {
+        let cpu = run(asm8080! {
+            STC;
+            MVI A, 0xfc;
+            ANI 0x0f;
+            HLT
+        });
+        assert_eq!(cpu.reg.a, 0x0c);
+        assert!(!cpu.reg.carry() && cpu.reg.half_carry() && cpu.reg.parity());
+
+        let cpu = run(asm8080! {
+            MVI A, 0x5c;
+            MVI C, 0x78;
+            XRA C;
+            HLT
+        });
+        assert_eq!(cpu.reg.a, 0x24);
+        assert!(!cpu.reg.half_carry() && cpu.reg.parity());
+
+        let cpu = run(asm8080! {
+            LXI H, 0x0010;
+            MVI M, 0x0f;
+            MVI A, 0x33;
+            ORA M;
+            CMA;
+            HLT
+        });
+        assert_eq!(cpu.reg.a, 0xc0);
+        assert!(!cpu.reg.zero() && !cpu.reg.carry());
+    }
+
+    #[test]
+    fn test_daa() {
+        // 38 + 45 = 83 in BCD
+        let cpu = run(asm8080! {
+            MVI A, 0x38;
+            ADI 0x45;
+            DAA;
+            HLT
+        });
+        assert_eq!(cpu.reg.a, 0x83);
+        assert!(!cpu.reg.carry());
+
+        // 99 + 1 = 100
+        let cpu = run(asm8080! {
+            MVI A, 0x99;
+            ADI 0x01;
+            DAA;
+            CMC;
+            CMC;
+            HLT
+        });
+        assert_eq!(cpu.reg.a, 0x00);
+        assert!(cpu.reg.carry() && cpu.reg.zero());
+    }
+}
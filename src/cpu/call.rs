@@ -0,0 +1,72 @@
+use super::*;
+
+impl Cpu {
+    /// Unconditionnal subroutine call
+    pub fn call(&mut self, addr: usize) {
+        let ret_addr = self.pc + 3;
+        self.internal_push(ret_addr as u16);
+        self.enter(FrameKind::Call, addr as u16, ret_addr as u16);
+        self.pc = addr;
+    }
+
+    /// Conditional subroutine call
+    pub fn cond_call(&mut self, cond: u8, addr: usize) {
+        if self.condition(cond) {
+            self.call(addr);
+        } else {
+            self.pc += 3;
+        }
+    }
+
+    /// Return from a subroutine call
+    pub fn ret(&mut self) {
+        let (pc, sp) = (self.pc as u16, self.sp);
+        let addr = self.internal_pop();
+        self.pc = addr as usize;
+        if let Some(stack) = &mut self.call_stack {
+            stack.ret(pc, sp, addr);
+        }
+    }
+
+    /// Conditional return from a subroutine call
+    pub fn cond_ret(&mut self, cond: u8) {
+        if self.condition(cond) {
+            self.ret();
+        } else {
+            self.pc += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm8080::asm8080;
+
+    #[test]
+    fn test_call_ret() {
+        let mut program = asm8080! {
+            LXI SP, 0x80;
+            PUSH B;
+            CALL ROUTINE;
+            POP D;
+            HLT;
+        ROUTINE:
+            RET
+        };
+        program.resize(0x100, 0);
+        let mut cpu = Cpu::from_raw(program);
+        cpu.trace = false;
+        cpu.reg.bc_set(0x1234);
+        for _ in 0..3 {
+            cpu.cycle();
+        }
+        assert_eq!(cpu.pc, 0x09);
+        cpu.cycle(); // RET
+        assert_eq!(cpu.pc, 0x07);
+        cpu.cycle(); // POP D
+        assert_eq!(cpu.pc, 0x08);
+        assert_eq!(cpu.sp, 0x80);
+        assert_eq!(cpu.reg.de(), 0x1234);
+    }
+}
@@ -10,7 +10,9 @@ impl Cpu {
         };
         let res = r.overflowing_sub(1);
         *r = res.0;
-        self.reg.update_flags(res, &[Zero, Sign, Parity, AuxCarry]);
+        self.reg.update_flags(res, &[Zero, Sign, Parity]);
+        // the 8080 adds 0FFH, with a carry from the bit 3 unless it was 0
+        self.reg.set_half_carry(res.0 & 0x0f != 0x0f);
         self.pc += 1;
     }
 }
@@ -23,7 +25,12 @@ mod tests {
     fn test_dcr() {
         let mut cpu = Cpu::from_raw(vec![0]);
         cpu.dcr(0);
-        assert_eq!(cpu.reg.sign(), true);
-        assert_eq!(cpu.reg.carry(), false);
+        assert!(cpu.reg.sign());
+        assert!(!cpu.reg.carry());
+        // 0 - 1 borrows from the bit 4
+        assert!(!cpu.reg.half_carry());
+        cpu.reg.b = 0x12;
+        cpu.dcr(0);
+        assert!(cpu.reg.half_carry());
     }
 }
@@ -10,11 +10,20 @@ impl Cpu {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use asm8080::asm8080;
 
     #[test]
     #[should_panic]
     fn test_halt() {
-        let mut cpu = Cpu::from_raw(vec![0b01110110]);
+        let mut cpu = Cpu::from_raw(asm8080! { HLT });
         cpu.cycle();
     }
+
+    #[test]
+    fn test_try_cycle() {
+        let mut cpu = Cpu::from_raw(asm8080! { HLT });
+        cpu.trace = false;
+        assert_eq!(cpu.try_cycle(), Err("CPU HALTED".to_string()));
+        assert_eq!(cpu.pc, 0);
+    }
 }
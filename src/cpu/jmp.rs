@@ -0,0 +1,37 @@
+use super::*;
+
+impl Cpu {
+    /// Unconditionnal jump
+    pub fn jmp(&mut self, addr: usize) {
+        self.pc = addr;
+    }
+
+    /// Conditionnal jump
+    pub fn cond_jmp(&mut self, cond: u8, addr: usize) {
+        if self.condition(cond) {
+            self.pc = addr;
+        } else {
+            self.pc += 3;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm8080::asm8080;
+
+    #[test]
+    fn test_cond_jmp() {
+        let mut cpu = Cpu::from_raw(asm8080! {
+            JZ 0x1234;
+            JNZ 0x4321
+        });
+        cpu.trace = false;
+        cpu.reg.set_zero(false);
+        cpu.cycle(); // not taken, on to the next instruction
+        assert_eq!(cpu.pc, 3);
+        cpu.cycle(); // taken
+        assert_eq!(cpu.pc, 0x4321);
+    }
+}
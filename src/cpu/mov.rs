@@ -19,11 +19,14 @@ impl Cpu {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use asm8080::asm8080;
 
     #[test]
     fn test_mov() {
-        //                                 MOV  A <- D   MOV  M <- D
-        let mut cpu = Cpu::from_raw(vec![0b01_111_010, 0b01_110_010]);
+        let mut cpu = Cpu::from_raw(asm8080! {
+            MOV A, D;
+            MOV M, D
+        });
         cpu.reg.b = 12;
         cpu.reg.c = 2;
         cpu.reg.d = 42;
@@ -15,11 +15,11 @@ impl Cpu {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use asm8080::asm8080;
 
     #[test]
     fn test_mvi() {
-        //                                 MVI  A <- D8
-        let mut cpu = Cpu::from_raw(vec![0b00_111_110, 42]);
+        let mut cpu = Cpu::from_raw(asm8080! { MVI A, 42 });
         cpu.mvi(0, 1);
         assert_eq!(cpu.reg.b, 1);
         cpu.mvi(1, 2);
@@ -6,17 +6,19 @@ impl Cpu {
     /// see the [pop_psw](#method.pop_psw) method
     /// ```rust
     /// use rust_8080::*;
+    /// use rust_8080::assembler::assemble;
     ///
-    /// let mut cpu = Cpu::from_raw(vec![0b11010001, 0x00, 0xff, 0xaa]);
-    /// cpu.pc = 0; // pop the content of sp to 01 (de)
-    /// cpu.sp = 2; // make sp point to 0xff, 0xff
+    /// let program = assemble(" POP D\n NOP\n DB 0FFH,0AAH").unwrap();
+    /// let mut cpu = Cpu::from_raw(program.image);
+    /// cpu.pc = 0; // pop the content of sp to de
+    /// cpu.sp = 2; // make sp point to 0xff, 0xaa
     /// cpu.reg.de_set(0);
     /// cpu.cycle();
-    /// assert_eq!(cpu.sp, 0);
+    /// assert_eq!(cpu.sp, 4);
     /// assert_eq!(cpu.pc, 1);
-    /// assert_eq!(cpu.reg.d, 0xff);
-    /// assert_eq!(cpu.reg.e, 0xaa);
-    /// assert_eq!(cpu.reg.de(), 0xffaa);
+    /// assert_eq!(cpu.reg.d, 0xaa);
+    /// assert_eq!(cpu.reg.e, 0xff);
+    /// assert_eq!(cpu.reg.de(), 0xaaff);
     /// ```
     pub fn pop(&mut self, rp: u8) {
         let tmp = self.internal_pop();
@@ -27,7 +29,6 @@ impl Cpu {
             0x02 => self.reg.hl_set(tmp),
             a => panic!("POP called with invalid register pair: {:x}", a),
         }
-        self.sp -= 2;
         self.pc += 1;
     }
 
@@ -36,13 +37,15 @@ impl Cpu {
     /// see the function [pop](#method.pop) for other registers
     /// ```rust
     /// use rust_8080::*;
+    /// use rust_8080::assembler::assemble;
     ///
-    /// let mut cpu = Cpu::from_raw(vec![0b11110001, 0x00, 0xaa, 0xff]);
-    /// cpu.pc = 0; // pop the content of sp to 11 (a + flags)
-    /// cpu.sp = 2; // make sp point to 0xff, 0xff
+    /// let program = assemble(" POP PSW\n NOP\n DB 0D7H,0AAH").unwrap();
+    /// let mut cpu = Cpu::from_raw(program.image);
+    /// cpu.pc = 0; // pop the content of sp to the flags and a
+    /// cpu.sp = 2; // make sp point to 0xd7, 0xaa
     /// cpu.reg.a = 0;
     /// cpu.cycle();
-    /// assert_eq!(cpu.sp, 0);
+    /// assert_eq!(cpu.sp, 4);
     /// assert_eq!(cpu.pc, 1);
     /// assert_eq!(cpu.reg.a, 0xaa);
     /// assert_eq!(cpu.reg.sign(), true);
@@ -54,7 +57,6 @@ impl Cpu {
     pub fn pop_psw(&mut self) {
         let tmp = self.internal_pop();
 
-        self.sp -= 2;
         self.reg.set_psw(tmp);
         self.pc += 1;
     }
@@ -68,16 +70,17 @@ mod tests {
     fn test_push_pop() {
         let mut cpu = Cpu::from_raw(vec![0x00, 0x01, 0x02, 0x03]);
         cpu.pc = 0;
-        cpu.sp = 0; // make sp point to 0x00, 0x01
+        cpu.sp = 4; // push to 0x02, 0x03
         cpu.reg.bc_set(0x4235);
         cpu.push(0x00); // bc
         assert_eq!(cpu.sp, 2);
+        assert_eq!(cpu.ram[2..4], [0x35, 0x42]);
         assert_eq!(cpu.pc, 1);
         assert_eq!(cpu.reg.bc(), 0x4235); // this could be wrong on arm?
         assert_eq!(cpu.reg.de(), 0x0000);
 
         cpu.pop(0x01); // de
-        assert_eq!(cpu.sp, 0);
+        assert_eq!(cpu.sp, 4);
         assert_eq!(cpu.pc, 2);
         assert_eq!(cpu.reg.bc(), cpu.reg.de());
     }
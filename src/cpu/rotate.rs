@@ -0,0 +1,64 @@
+use super::*;
+
+impl Cpu {
+    /// Rotate A left, the bit 7 going to the bit 0 and the carry
+    pub fn rlc(&mut self) {
+        let a = self.reg.a;
+        self.reg.a = a.rotate_left(1);
+        self.reg.set_carry(a & 0x80 != 0);
+        self.pc += 1;
+    }
+
+    /// Rotate A right, the bit 0 going to the bit 7 and the carry
+    pub fn rrc(&mut self) {
+        let a = self.reg.a;
+        self.reg.a = a.rotate_right(1);
+        self.reg.set_carry(a & 0x01 != 0);
+        self.pc += 1;
+    }
+
+    /// Rotate A left through the carry
+    pub fn ral(&mut self) {
+        let a = self.reg.a;
+        self.reg.a = a << 1 | self.reg.carry() as u8;
+        self.reg.set_carry(a & 0x80 != 0);
+        self.pc += 1;
+    }
+
+    /// Rotate A right through the carry
+    pub fn rar(&mut self) {
+        let a = self.reg.a;
+        self.reg.a = a >> 1 | (self.reg.carry() as u8) << 7;
+        self.reg.set_carry(a & 0x01 != 0);
+        self.pc += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm8080::asm8080;
+
+    #[test]
+    fn test_rotate() {
+        let mut cpu = Cpu::from_raw(asm8080! {
+            RLC;
+            RRC;
+            RAL;
+            RAR
+        });
+        cpu.trace = false;
+        cpu.reg.a = 0xf2;
+        cpu.cycle();
+        assert_eq!((cpu.reg.a, cpu.reg.carry()), (0xe5, true));
+        cpu.reg.set_carry(false);
+        cpu.cycle();
+        assert_eq!((cpu.reg.a, cpu.reg.carry()), (0xf2, true));
+        cpu.reg.set_carry(false);
+        cpu.cycle();
+        assert_eq!((cpu.reg.a, cpu.reg.carry()), (0xe4, true));
+        cpu.cycle();
+        assert_eq!((cpu.reg.a, cpu.reg.carry()), (0xf2, false));
+        assert_eq!(cpu.pc, 4);
+    }
+}
@@ -0,0 +1,907 @@
+mod command;
+pub mod dap;
+pub mod expr;
+pub mod gdb;
+mod listing;
+mod value;
+
+pub use command::Command;
+pub use expr::{Expr, LogMessage};
+pub use listing::Listing;
+pub use value::{Register, Value};
+
+use crate::callstack::CallStack;
+use crate::instruction::Instruction;
+use crate::provenance::{Granularity, Provenance};
+use crate::symbols::SymbolTable;
+use crate::*;
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+
+const HLT: u8 = 0x76;
+const STATE_MAGIC: &[u8] = b"8080";
+
+/// Why the execution stopped
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Stop {
+    /// the requested instructions were executed
+    Step,
+    Breakpoint(usize),
+    Watchpoint {
+        addr: usize,
+        old: u8,
+        new: u8,
+    },
+    /// the next instruction is a HLT
+    Halt,
+    /// the cpu panicked while executing an instruction
+    Fault(String),
+}
+
+impl std::fmt::Display for Stop {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Stop::Step => Ok(()),
+            Stop::Breakpoint(addr) => write!(f, "Breakpoint at {:#06x}", addr),
+            Stop::Watchpoint { addr, old, new } => write!(
+                f,
+                "Watchpoint at {:#06x}: {:#04x} -> {:#04x}",
+                addr, old, new
+            ),
+            Stop::Halt => write!(f, "CPU halted"),
+            Stop::Fault(msg) => write!(f, "CPU fault: {}", msg),
+        }
+    }
+}
+
+/// Decide if a breakpoint or a watchpoint stops the execution when it is hit
+#[derive(Clone, Debug, Default)]
+pub struct Condition {
+    /// only stop when this expression, kept with its source, is not 0
+    pub expr: Option<(String, Expr)>,
+    /// only stop once the expression was true this many times
+    pub hit_count: usize,
+    /// number of times the expression was true
+    pub hits: usize,
+    /// log this message, kept with its source, instead of stopping
+    pub log: Option<(String, LogMessage)>,
+}
+
+impl Condition {
+    pub fn when(expr: &str, symbols: &SymbolTable) -> Result<Self> {
+        Ok(Self {
+            expr: Some((expr.to_string(), Expr::parse(expr, symbols)?)),
+            ..Default::default()
+        })
+    }
+
+    /// count the hit and tell if the execution should stop, the tracepoint
+    /// messages are pushed in `logs`
+    fn triggered(&mut self, cpu: &Cpu, logs: &mut Vec<String>) -> bool {
+        if let Some((_, expr)) = &self.expr {
+            if expr.eval(cpu) == 0 {
+                return false;
+            }
+        }
+        self.hits += 1;
+        if self.hits < self.hit_count {
+            return false;
+        }
+        match &self.log {
+            Some((_, log)) => {
+                logs.push(log.format(cpu));
+                false
+            }
+            None => true,
+        }
+    }
+}
+
+impl std::fmt::Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some((expr, _)) = &self.expr {
+            write!(f, " if {}", expr)?;
+        }
+        if self.hit_count > 1 {
+            write!(f, " after {} hits", self.hit_count)?;
+        }
+        if let Some((log, _)) = &self.log {
+            write!(f, " log \"{}\"", log)?;
+        }
+        write!(f, " (hit {} times)", self.hits)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Watchpoint {
+    /// last value seen at the watched address
+    pub value: u8,
+    pub condition: Condition,
+}
+
+/// Drive a `Cpu` instruction by instruction, stopping on breakpoints and
+/// watchpoints
+pub struct Debugger {
+    pub cpu: Cpu,
+    pub breakpoints: BTreeMap<usize, Condition>,
+    pub watchpoints: BTreeMap<usize, Watchpoint>,
+    /// messages of the tracepoints hit since the last call to `take_logs`
+    pub logs: Vec<String>,
+    last_command: Option<Command>,
+}
+
+impl Debugger {
+    pub fn new(mut cpu: Cpu) -> Self {
+        cpu.trace = false;
+        cpu.call_stack.get_or_insert_with(CallStack::new);
+        Self {
+            cpu,
+            breakpoints: BTreeMap::new(),
+            watchpoints: BTreeMap::new(),
+            logs: Vec::new(),
+            last_command: None,
+        }
+    }
+
+    fn byte(&self, addr: usize) -> Option<u8> {
+        if addr < self.cpu.ram.len() {
+            Some(self.cpu.ram[addr])
+        } else {
+            None
+        }
+    }
+
+    /// stop the execution when the byte at `addr` is modified
+    pub fn watch(&mut self, addr: usize, condition: Condition) -> Result<()> {
+        match self.byte(addr) {
+            Some(value) => self
+                .watchpoints
+                .insert(addr, Watchpoint { value, condition }),
+            None => bail!("{:#06x} is out of memory", addr),
+        };
+        Ok(())
+    }
+
+    /// messages logged by the tracepoints since the last call
+    pub fn take_logs(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.logs)
+    }
+
+    /// execute exactly one instruction
+    pub fn step(&mut self) -> Stop {
+        match self.byte(self.cpu.pc) {
+            None => return Stop::Fault(format!("PC {:#06x} is out of memory", self.cpu.pc)),
+            Some(HLT) => return Stop::Halt,
+            Some(_) => (),
+        }
+
+        if let Err(message) = self.cpu.try_cycle() {
+            return Stop::Fault(message);
+        }
+        if let Some(stack) = &mut self.cpu.call_stack {
+            let mismatches = stack.take_mismatches();
+            self.logs
+                .extend(mismatches.iter().map(|m| format!("warning: {}", m)));
+        }
+
+        // every value is updated before a watchpoint stops the cpu
+        let mut changes = Vec::new();
+        for (&addr, watch) in self.watchpoints.iter_mut() {
+            if let Some(&new) = self.cpu.ram[..].get(addr) {
+                if new != watch.value {
+                    changes.push((addr, std::mem::replace(&mut watch.value, new), new));
+                }
+            }
+        }
+        for (addr, old, new) in changes {
+            let watch = self.watchpoints.get_mut(&addr).unwrap();
+            if watch.condition.triggered(&self.cpu, &mut self.logs) {
+                return Stop::Watchpoint { addr, old, new };
+            }
+        }
+        Stop::Step
+    }
+
+    /// execute instructions until `done` returns true or something stops the cpu
+    pub fn run_until(&mut self, mut done: impl FnMut(&Cpu) -> bool) -> Stop {
+        loop {
+            match self.step() {
+                Stop::Step => (),
+                stop => return stop,
+            }
+            if let Some(bp) = self.breakpoints.get_mut(&self.cpu.pc) {
+                if bp.triggered(&self.cpu, &mut self.logs) {
+                    return Stop::Breakpoint(self.cpu.pc);
+                }
+            }
+            if done(&self.cpu) {
+                return Stop::Step;
+            }
+        }
+    }
+
+    /// the return address of the call at PC, None if it is not a call
+    pub fn call_return(&self) -> Option<usize> {
+        self.byte(self.cpu.pc)?;
+        match Instruction::decode(&self.cpu.ram[self.cpu.pc..]) {
+            Ok(instruction) if instruction.is_call() => Some(self.cpu.pc + instruction.len()),
+            _ => None,
+        }
+    }
+
+    /// execute one instruction, running subroutine calls to completion
+    pub fn step_over(&mut self) -> Stop {
+        let ret = match self.call_return() {
+            Some(ret) => ret,
+            None => return self.step(),
+        };
+        let sp = self.cpu.sp;
+        self.run_until(|cpu| cpu.pc == ret && cpu.sp == sp)
+    }
+
+    /// run until a breakpoint, a watchpoint or a halt
+    pub fn cont(&mut self) -> Stop {
+        self.run_until(|_| false)
+    }
+
+    /// disassemble the instruction at `addr`, returning its text and length
+    pub fn disassemble(&self, addr: usize) -> (String, usize) {
+        if self.byte(addr).is_none() {
+            return ("??".to_string(), 1);
+        }
+        decompiler::instr_with_format(&self.cpu.ram[addr..], &self.cpu.symbols, &self.cpu.format)
+    }
+
+    /// guess the address of the instruction ending right before `addr`
+    pub fn previous_instruction(&self, addr: usize) -> usize {
+        (1..=3)
+            .rev()
+            .filter_map(|back| addr.checked_sub(back))
+            .find(|&start| start + self.disassemble(start).1 == addr)
+            .unwrap_or_else(|| addr.saturating_sub(1))
+    }
+
+    /// find an address a few instructions before `addr` from which a linear
+    /// disassembly falls exactly on `addr`
+    fn list_start(&self, addr: usize) -> usize {
+        for back in (1..=9).rev() {
+            let mut start = match addr.checked_sub(back) {
+                Some(start) => start,
+                None => continue,
+            };
+            let from = start;
+            while start < addr {
+                start += self.disassemble(start).1;
+            }
+            if start == addr {
+                return from;
+            }
+        }
+        addr
+    }
+
+    /// write the registers and the memory in a file
+    pub fn save_state(&self, file: &str) -> Result<()> {
+        let reg = &self.cpu.reg;
+        let mut state = STATE_MAGIC.to_vec();
+        state.extend([reg.flags, reg.a, reg.b, reg.c, reg.d, reg.e, reg.h, reg.l]);
+        state.extend(self.cpu.sp.to_le_bytes());
+        state.extend((self.cpu.pc as u16).to_le_bytes());
+        state.extend(&self.cpu.ram[..]);
+        std::fs::write(file, state)?;
+        Ok(())
+    }
+
+    /// restore a state written by [save_state](#method.save_state)
+    pub fn restore_state(&mut self, file: &str) -> Result<()> {
+        let state = std::fs::read(file)?;
+        if state.len() < 16 || &state[..4] != STATE_MAGIC {
+            bail!("{} is not a saved state", file);
+        }
+        let reg = &mut self.cpu.reg;
+        reg.flags = state[4];
+        reg.a = state[5];
+        reg.b = state[6];
+        reg.c = state[7];
+        reg.d = state[8];
+        reg.e = state[9];
+        reg.h = state[10];
+        reg.l = state[11];
+        self.cpu.sp = u16::from_le_bytes([state[12], state[13]]);
+        self.cpu.pc = u16::from_le_bytes([state[14], state[15]]) as usize;
+        let provenance = self.cpu.ram.provenance.take();
+        self.cpu.ram = Memory::from_raw(state[16..].to_vec());
+        // the writes before the restore are meaningless
+        self.cpu.ram.provenance = provenance.map(|mut p| {
+            p.clear();
+            p
+        });
+        // the calls made before the save are unknown
+        if let Some(stack) = &mut self.cpu.call_stack {
+            stack.clear();
+        }
+        for (&addr, watch) in self.watchpoints.iter_mut() {
+            watch.value = self.cpu.ram[..].get(addr).copied().unwrap_or_default();
+        }
+        Ok(())
+    }
+
+    fn print_registers(&self, out: &mut impl Write) -> Result<()> {
+        let reg = &self.cpu.reg;
+        writeln!(
+            out,
+            "A={:02x} B={:02x} C={:02x} D={:02x} E={:02x} H={:02x} L={:02x}  BC={:04x} DE={:04x} HL={:04x} SP={:04x} PC={:04x}",
+            reg.a,
+            reg.b,
+            reg.c,
+            reg.d,
+            reg.e,
+            reg.h,
+            reg.l,
+            reg.bc(),
+            reg.de(),
+            reg.hl(),
+            self.cpu.sp,
+            self.cpu.pc
+        )?;
+        writeln!(
+            out,
+            "S={} Z={} AC={} P={} CY={}",
+            reg.sign() as u8,
+            reg.zero() as u8,
+            reg.half_carry() as u8,
+            reg.parity() as u8,
+            reg.carry() as u8
+        )?;
+        Ok(())
+    }
+
+    fn print_memory(&self, out: &mut impl Write, addr: usize, len: usize) -> Result<()> {
+        let end = (addr + len).min(self.cpu.ram.len());
+        if addr >= end {
+            bail!("{:#06x} is out of memory", addr);
+        }
+        for line in (addr..end).step_by(16) {
+            let bytes = &self.cpu.ram[line..(line + 16).min(end)];
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            let ascii: String = bytes
+                .iter()
+                .map(|&b| match b {
+                    0x20..=0x7e => b as char,
+                    _ => '.',
+                })
+                .collect();
+            writeln!(out, "{:04x}  {:<47}  {}", line, hex.join(" "), ascii)?;
+        }
+        Ok(())
+    }
+
+    fn print_listing(&self, out: &mut impl Write, addr: usize, count: usize) -> Result<()> {
+        let mut addr = self.list_start(addr);
+        for _ in 0..count {
+            if addr >= self.cpu.ram.len() {
+                break;
+            }
+            let (text, len) = self.disassemble(addr);
+            if let Some(label) = self.cpu.symbols.name(addr as u16) {
+                writeln!(out, "{}:", label)?;
+            }
+            let marker = if addr == self.cpu.pc { "=>" } else { "  " };
+            let bp = if self.breakpoints.contains_key(&addr) {
+                "*"
+            } else {
+                " "
+            };
+            writeln!(out, "{}{} {:04x}\t{}", marker, bp, addr, text)?;
+            addr += len;
+        }
+        Ok(())
+    }
+
+    fn provenance(&self) -> Result<&Provenance> {
+        match &self.cpu.ram.provenance {
+            Some(provenance) => Ok(provenance),
+            None => bail!("The memory writes are not recorded, enable it with `provenance`"),
+        }
+    }
+
+    /// the condition of the breakpoint or the watchpoint at `addr`
+    fn condition(&mut self, addr: usize) -> Result<&mut Condition> {
+        if let Some(cond) = self.breakpoints.get_mut(&addr) {
+            return Ok(cond);
+        }
+        match self.watchpoints.get_mut(&addr) {
+            Some(watch) => Ok(&mut watch.condition),
+            None => bail!("No breakpoint or watchpoint at {:#06x}", addr),
+        }
+    }
+
+    /// `addr` followed by the closest symbol, like `0x0105 <PRINT+2>`
+    pub fn location(&self, addr: usize) -> String {
+        match self.cpu.symbols.lookup(addr as u16) {
+            Some((name, 0)) => format!("{:#06x} <{}>", addr, name),
+            Some((name, offset)) => format!("{:#06x} <{}+{}>", addr, name, offset),
+            None => format!("{:#06x}", addr),
+        }
+    }
+
+    fn print_stop(&self, out: &mut impl Write, stop: &Stop) -> Result<()> {
+        if *stop != Stop::Step {
+            writeln!(out, "{}", stop)?;
+        }
+        let (text, _) = self.disassemble(self.cpu.pc);
+        writeln!(out, "=> {}\t{}", self.location(self.cpu.pc), text)?;
+        Ok(())
+    }
+
+    /// execute a single debugger command
+    pub fn execute(&mut self, cmd: &Command, out: &mut impl Write) -> Result<()> {
+        match cmd {
+            Command::Step(n) => {
+                let mut stop = Stop::Step;
+                for _ in 0..*n {
+                    stop = self.step();
+                    if stop != Stop::Step {
+                        break;
+                    }
+                }
+                self.print_stop(out, &stop)?;
+            }
+            Command::Next => {
+                let stop = self.step_over();
+                self.print_stop(out, &stop)?;
+            }
+            Command::Continue => {
+                let stop = self.cont();
+                self.print_stop(out, &stop)?;
+            }
+            Command::Break(addr, cond) => {
+                let addr = addr.resolve(&self.cpu)? as usize;
+                let cond = match cond {
+                    Some(expr) => Condition::when(expr, &self.cpu.symbols)?,
+                    None => Condition::default(),
+                };
+                self.breakpoints.insert(addr, cond);
+                writeln!(out, "Breakpoint set at {}", self.location(addr))?;
+            }
+            Command::Watch(addr, cond) => {
+                let addr = addr.resolve(&self.cpu)? as usize;
+                let cond = match cond {
+                    Some(expr) => Condition::when(expr, &self.cpu.symbols)?,
+                    None => Condition::default(),
+                };
+                self.watch(addr, cond)?;
+                writeln!(out, "Watchpoint set at {}", self.location(addr))?;
+            }
+            Command::Trace(addr, msg) => {
+                let addr = addr.resolve(&self.cpu)? as usize;
+                let log = LogMessage::parse(msg, &self.cpu.symbols)?;
+                let cond = self.breakpoints.entry(addr).or_default();
+                cond.log = Some((msg.clone(), log));
+                writeln!(out, "Tracepoint set at {}", self.location(addr))?;
+            }
+            Command::Condition(addr, expr) => {
+                let addr = addr.resolve(&self.cpu)? as usize;
+                let expr = match expr {
+                    Some(expr) => Some((expr.clone(), Expr::parse(expr, &self.cpu.symbols)?)),
+                    None => None,
+                };
+                self.condition(addr)?.expr = expr;
+            }
+            Command::Hits(addr, count) => {
+                let addr = addr.resolve(&self.cpu)? as usize;
+                let cond = self.condition(addr)?;
+                cond.hit_count = *count;
+                cond.hits = 0;
+            }
+            Command::Delete(addr) => {
+                let addr = addr.resolve(&self.cpu)? as usize;
+                let bp = self.breakpoints.remove(&addr).is_some();
+                let wp = self.watchpoints.remove(&addr).is_some();
+                if !bp && !wp {
+                    bail!("No breakpoint or watchpoint at {:#06x}", addr);
+                }
+            }
+            Command::Breakpoints => {
+                for (&addr, cond) in self.breakpoints.iter() {
+                    writeln!(out, "break {}{}", self.location(addr), cond)?;
+                }
+                for (&addr, watch) in self.watchpoints.iter() {
+                    writeln!(out, "watch {}{}", self.location(addr), watch.condition)?;
+                }
+            }
+            Command::Print(expr) => {
+                let value = Expr::parse(expr, &self.cpu.symbols)?.eval(&self.cpu);
+                writeln!(out, "{} = {:#x} ({})", expr, value, value)?;
+            }
+            Command::Registers => self.print_registers(out)?,
+            Command::Provenance(None) => {
+                self.cpu.ram.provenance = None;
+                writeln!(out, "Not recording the memory writes")?;
+            }
+            Command::Provenance(Some((depth, granularity))) => {
+                let (provenance, unit) = match granularity {
+                    Granularity::Address => (Provenance::new(*depth), "address"),
+                    Granularity::Page => (Provenance::per_page(*depth), "page"),
+                };
+                writeln!(
+                    out,
+                    "Recording the last {} writes of every {}",
+                    provenance.depth(),
+                    unit
+                )?;
+                self.cpu.ram.provenance = Some(provenance);
+            }
+            Command::Writes(addr, len) => {
+                let addr = addr.resolve(&self.cpu)?;
+                if *len == 0 {
+                    return Ok(());
+                }
+                // the last address is kept in the 64K of the 8080
+                let end = (addr as usize).saturating_add(len - 1).min(0xffff) as u16;
+                for write in self.provenance()?.writes(addr..=end) {
+                    let (text, _) = self.disassemble(write.pc as usize);
+                    writeln!(
+                        out,
+                        "{:04x} written at instruction {} by {}\t{}",
+                        write.addr,
+                        write.instruction,
+                        self.location(write.pc as usize),
+                        text
+                    )?;
+                }
+            }
+            Command::Writer(addr) => {
+                let addr = addr.resolve(&self.cpu)?;
+                match self.provenance()?.last_write(addr) {
+                    Some(write) => {
+                        writeln!(
+                            out,
+                            "{:#06x} was last written at instruction {} by {}",
+                            addr,
+                            write.instruction,
+                            self.location(write.pc as usize)
+                        )?;
+                        self.print_listing(out, write.pc as usize, 10)?;
+                    }
+                    None => writeln!(out, "No recorded write to {:#06x}", addr)?,
+                }
+            }
+            Command::Backtrace => write!(out, "{}", self.cpu.backtrace())?,
+            Command::Examine(addr, len) => {
+                self.print_memory(out, addr.resolve(&self.cpu)? as usize, *len)?
+            }
+            Command::Edit(addr, bytes) => {
+                let addr = addr.resolve(&self.cpu)? as usize;
+                if addr + bytes.len() > self.cpu.ram.len() {
+                    bail!("{:#06x} is out of memory", addr);
+                }
+                self.cpu.ram[addr..addr + bytes.len()].copy_from_slice(bytes);
+            }
+            Command::List(addr, count) => {
+                let addr = match addr {
+                    Some(addr) => addr.resolve(&self.cpu)? as usize,
+                    None => self.cpu.pc,
+                };
+                self.print_listing(out, addr, *count)?;
+            }
+            Command::Format(color, syntax, radix) => {
+                let format = &mut self.cpu.format;
+                format.color = color.unwrap_or(format.color);
+                format.syntax = syntax.unwrap_or(format.syntax);
+                format.radix = radix.unwrap_or(format.radix);
+                writeln!(
+                    out,
+                    "{} {:?} {:?}",
+                    if format.color { "color" } else { "nocolor" },
+                    format.syntax,
+                    format.radix
+                )?;
+            }
+            Command::Set(reg, value) => {
+                let value = value.resolve(&self.cpu)?;
+                if !reg.is_pair() && value > 0xff {
+                    bail!("{:#x} does not fit in register {}", value, reg);
+                }
+                reg.set(&mut self.cpu, value);
+            }
+            Command::Load(file, addr) => {
+                let bytes = std::fs::read(file)?;
+                let addr = addr.resolve(&self.cpu)? as usize;
+                self.cpu.ram.load_at(addr, &bytes);
+                writeln!(out, "Loaded {} bytes at {:#06x}", bytes.len(), addr)?;
+            }
+            Command::Symbols(file) => {
+                let symbols = SymbolTable::from_file(file)?;
+                self.cpu.symbols.extend(&symbols);
+                writeln!(out, "Loaded {} symbols", symbols.len())?;
+            }
+            Command::Save(file) => self.save_state(file)?,
+            Command::Restore(file) => self.restore_state(file)?,
+            Command::Help => writeln!(out, "{}", command::HELP)?,
+            Command::Quit => (),
+        }
+        Ok(())
+    }
+
+    /// Read commands from `input` until `quit` or the end of the input.
+    /// An empty line repeats the previous command.
+    pub fn repl(&mut self, input: impl BufRead, mut out: impl Write) -> Result<()> {
+        self.print_stop(&mut out, &Stop::Step)?;
+        write!(out, "(8080) ")?;
+        out.flush()?;
+
+        for line in input.lines() {
+            let line = line?;
+            let cmd = if line.trim().is_empty() {
+                self.last_command.clone()
+            } else {
+                match line.parse() {
+                    Ok(cmd) => Some(cmd),
+                    Err(e) => {
+                        writeln!(out, "error: {}", e)?;
+                        None
+                    }
+                }
+            };
+
+            if let Some(cmd) = cmd {
+                if cmd == Command::Quit {
+                    break;
+                }
+                let res = self.execute(&cmd, &mut out);
+                for log in self.take_logs() {
+                    writeln!(out, "{}", log)?;
+                }
+                if let Err(e) = res {
+                    writeln!(out, "error: {}", e)?;
+                }
+                self.last_command = Some(cmd);
+            }
+            write!(out, "(8080) ")?;
+            out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program() -> Cpu {
+        let mut ram = vec![
+            0x3e, 0x01, //       MVI  A, 1
+            0x3c, //             INR  A
+            0x32, 0x20, 0x00, // STA  0x0020
+            0xcd, 0x10, 0x00, // CALL 0x0010
+            0x76, //             HLT
+        ];
+        ram.resize(0x30, 0);
+        ram[0x10] = 0x3c; //     INR  A
+        ram[0x11] = 0xc9; //     RET
+        let mut cpu = Cpu::from_raw(ram);
+        cpu.sp = 0x28;
+        cpu
+    }
+
+    #[test]
+    fn test_breakpoint_and_watchpoint() {
+        let mut dbg = Debugger::new(program());
+        dbg.breakpoints.insert(3, Condition::default());
+        dbg.watch(0x20, Condition::default()).unwrap();
+
+        assert_eq!(dbg.cont(), Stop::Breakpoint(3));
+        assert_eq!(dbg.cpu.reg.a, 2);
+        assert_eq!(
+            dbg.cont(),
+            Stop::Watchpoint {
+                addr: 0x20,
+                old: 0,
+                new: 2
+            }
+        );
+        assert_eq!(dbg.cpu.pc, 6);
+        assert_eq!(dbg.cont(), Stop::Halt);
+        assert_eq!(dbg.cpu.reg.a, 3);
+    }
+
+    #[test]
+    fn test_watchpoints_written_together() {
+        let mut ram = vec![
+            0x21, 0x01, 0x01, // LXI  H, 0x0101
+            0x22, 0x20, 0x00, // SHLD 0x0020
+            0x00, //             NOP
+            0x76, //             HLT
+        ];
+        ram.resize(0x30, 0);
+        let mut dbg = Debugger::new(Cpu::from_raw(ram));
+        dbg.watch(0x20, Condition::default()).unwrap();
+        dbg.watch(0x21, Condition::default()).unwrap();
+
+        let stop = dbg.cont();
+        assert_eq!(
+            stop,
+            Stop::Watchpoint {
+                addr: 0x20,
+                old: 0,
+                new: 1
+            }
+        );
+        // the second write is not reported by the next instruction
+        assert_eq!(dbg.watchpoints[&0x21].value, 1);
+        assert_eq!(dbg.cont(), Stop::Halt);
+    }
+
+    #[test]
+    fn test_next_steps_over_call() {
+        let mut dbg = Debugger::new(program());
+        dbg.cpu.pc = 6;
+        assert_eq!(dbg.step_over(), Stop::Step);
+        assert_eq!(dbg.cpu.pc, 9);
+        assert_eq!(dbg.cpu.sp, 0x28);
+        assert_eq!(dbg.cpu.reg.a, 1);
+    }
+
+    #[test]
+    fn test_repl() {
+        let mut dbg = Debugger::new(program());
+        let input = b"set b 0x42\nb 3\nc\nr\nx 0 4\nl 0 2\ne 0x10 0x76\nnext\n\nq\n";
+        let mut out = Vec::new();
+        dbg.repl(&input[..], &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains("Breakpoint at 0x0003"));
+        assert!(out.contains("A=02 B=42"));
+        assert!(out.contains("0000  3e 01 3c 32"));
+        assert!(out.contains("0002\tINR"));
+        assert!(out.contains("CPU halted"));
+        assert_eq!(dbg.cpu.pc, 0x10);
+    }
+
+    #[test]
+    fn test_symbols() {
+        let mut dbg = Debugger::new(program());
+        dbg.cpu.symbols = SymbolTable::parse("0003 STORE\t0010 INCR").unwrap();
+        let input = b"b incr\nc\nl 3 5\nx store 3\n";
+        let mut out = Vec::new();
+        dbg.repl(&input[..], &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains("Breakpoint set at 0x0010"));
+        assert!(out.contains("=> 0x0010 <INCR>"));
+        assert!(out.contains("STORE:\n"));
+        assert!(out.contains("CALL\t\x1B[1;35mINCR"));
+        assert!(out.contains("0003  32 20 00"));
+        assert_eq!(dbg.location(0x11), "0x0011 <INCR+1>");
+    }
+
+    #[test]
+    fn test_conditions() {
+        let mut ram = vec![
+            0x3c, //             INR  A
+            0x32, 0x20, 0x00, // STA  0x0020
+            0xc3, 0x00, 0x00, // JMP  0x0000
+        ];
+        ram.resize(0x30, 0);
+        let mut dbg = Debugger::new(Cpu::from_raw(ram));
+        let symbols = &dbg.cpu.symbols;
+        dbg.breakpoints
+            .insert(1, Condition::when("A == 3", symbols).unwrap());
+        dbg.watch(0x20, Condition::when("[0x20] > 4", symbols).unwrap())
+            .unwrap();
+
+        assert_eq!(dbg.cont(), Stop::Breakpoint(1));
+        assert_eq!(dbg.cpu.reg.a, 3);
+        let stop = dbg.cont();
+        assert_eq!(
+            stop,
+            Stop::Watchpoint {
+                addr: 0x20,
+                old: 4,
+                new: 5
+            }
+        );
+
+        let input = b"trace 4 a={A} mem={[0x20]}\nhits 4 2\ncondition 1\nhits 1 3\ncondition 0x20 [0x20] > 100\nc\nbreakpoints\np A * 2 + [0x20]\n";
+        let mut out = Vec::new();
+        dbg.repl(&input[..], &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        // the tracepoint only logs from its second hit, the breakpoint stops
+        // at its third hit whatever the value of A
+        assert!(!out.contains("a=0x6"));
+        assert!(out.contains("a=0x7 mem=0x7\n"));
+        assert!(out.contains("Breakpoint at 0x0001"));
+        assert_eq!(dbg.cpu.reg.a, 8);
+        assert!(out.contains("break 0x0001 after 3 hits (hit 3 times)"));
+        assert!(out.contains("break 0x0004 after 2 hits log \"a={A} mem={[0x20]}\" (hit 2 times)"));
+        assert!(out.contains("watch 0x0020 if [0x20] > 100 (hit 1 times)"));
+        assert!(out.contains("A * 2 + [0x20] = 0x17 (23)"));
+    }
+
+    #[test]
+    fn test_backtrace() {
+        let mut ram = vec![0; 0x30];
+        ram[0x00..0x04].copy_from_slice(&[
+            0xcd, 0x10, 0x00, // MAIN:  CALL OUTER
+            0x76, //                    HLT
+        ]);
+        ram[0x08..0x0a].copy_from_slice(&[
+            0x00, //             RST1:  NOP
+            0xc9, //                    RET
+        ]);
+        ram[0x10..0x14].copy_from_slice(&[
+            0xcd, 0x20, 0x00, // OUTER: CALL INNER
+            0xc9, //                    RET
+        ]);
+        ram[0x20..0x24].copy_from_slice(&[
+            0xcf, //             INNER: RST  1
+            0xc1, //                    POP  B
+            0xc5, //                    PUSH B
+            0xc9, //                    RET
+        ]);
+        let mut cpu = Cpu::from_raw(ram);
+        cpu.sp = 0x30;
+        cpu.symbols = SymbolTable::parse("0000 MAIN\t0008 RST1\t0010 OUTER\t0020 INNER").unwrap();
+        let mut dbg = Debugger::new(cpu);
+        dbg.breakpoints.insert(0x09, Condition::default());
+        assert_eq!(dbg.cont(), Stop::Breakpoint(0x09));
+
+        let mut out = Vec::new();
+        dbg.execute(&Command::Backtrace, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "#0   0x0009 in RST1+1 (RST 1)\n#1   0x0020 in INNER\n#2   0x0010 in OUTER\n#3   0x0000 in MAIN\n"
+        );
+
+        // INNER pops its return address and pushes it back before returning
+        assert_eq!(dbg.cont(), Stop::Halt);
+        let logs = dbg.take_logs();
+        assert_eq!(logs.len(), 2);
+        assert!(
+            logs[0].contains("0x0021: the return address 0x0013 of the call at 0x0010 was popped")
+        );
+        assert!(logs[1].contains("0x0023: RET to 0x0013 instead of 0x0003"));
+        assert_eq!(dbg.cpu.call_stack.as_ref().unwrap().depth(), 0);
+    }
+
+    #[test]
+    fn test_provenance() {
+        let mut dbg = Debugger::new(program());
+        dbg.cpu.symbols = SymbolTable::parse("0003 STORE\t0010 INCR").unwrap();
+        let input = b"writer 0x20\nprovenance 4\nc\nwrites 0x20 12\nwriter 0x20\n";
+        let mut out = Vec::new();
+        dbg.repl(&input[..], &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains("error: The memory writes are not recorded"));
+        assert!(out.contains("Recording the last 4 writes of every address"));
+        // STA then the return address pushed by CALL
+        assert!(out.contains("0026 written at instruction 3 by 0x0006 <STORE+3>\tCALL"));
+        assert!(out.contains(
+            "0027 written at instruction 3 by 0x0006 <STORE+3>\tCALL\t\x1B[1;35mINCR\x1B[m\n0020 written at instruction 2 by 0x0003 <STORE>\tSTA"
+        ));
+        assert!(out.contains("0x0020 was last written at instruction 2 by 0x0003 <STORE>\n"));
+        assert!(out.contains("   0003\tSTA"));
+    }
+
+    #[test]
+    fn test_save_restore() {
+        let file = std::env::temp_dir().join("rust-8080-debugger-state");
+        let file = file.to_str().unwrap();
+        let mut dbg = Debugger::new(program());
+        dbg.cpu.reg.a = 0x12;
+        dbg.cpu.pc = 6;
+        dbg.save_state(file).unwrap();
+
+        let mut other = Debugger::new(Cpu::from_raw(vec![0]));
+        other.restore_state(file).unwrap();
+        assert_eq!(other.cpu.reg, dbg.cpu.reg);
+        assert_eq!(other.cpu.pc, 6);
+        assert_eq!(other.cpu.sp, 0x28);
+        assert_eq!(&other.cpu.ram[..], &dbg.cpu.ram[..]);
+        std::fs::remove_file(file).unwrap();
+    }
+}
@@ -0,0 +1,300 @@
+use super::value::{Register, Value};
+use crate::decompiler::{Radix, Syntax};
+use crate::number::parse_number;
+use crate::provenance::Granularity;
+use anyhow::{anyhow, bail, Result};
+
+/// A command typed in the debugger prompt
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Command {
+    /// execute N instructions
+    Step(usize),
+    /// execute one instruction, stepping over subroutine calls
+    Next,
+    /// run until a breakpoint, a watchpoint or a halt
+    Continue,
+    /// add a breakpoint, with an optional condition
+    Break(Value, Option<String>),
+    /// add a watchpoint, with an optional condition
+    Watch(Value, Option<String>),
+    /// add a tracepoint logging a message instead of stopping
+    Trace(Value, String),
+    /// set or remove the condition of a breakpoint or a watchpoint
+    Condition(Value, Option<String>),
+    /// only stop after a breakpoint or a watchpoint was hit N times
+    Hits(Value, usize),
+    /// remove the breakpoint and the watchpoint at an address
+    Delete(Value),
+    /// list the breakpoints and watchpoints
+    Breakpoints,
+    /// evaluate an expression
+    Print(String),
+    Registers,
+    /// print the shadow call stack
+    Backtrace,
+    /// record the last N writes of every address or page, or stop recording
+    Provenance(Option<(usize, Granularity)>),
+    /// print the recorded writes to N bytes of memory
+    Writes(Value, usize),
+    /// disassemble around the last instruction which wrote to an address
+    Writer(Value),
+    /// dump N bytes of memory
+    Examine(Value, usize),
+    /// write bytes in memory
+    Edit(Value, Vec<u8>),
+    /// disassemble N instructions around an address
+    List(Option<Value>, usize),
+    /// change the colours, the syntax or the radix of the disassembly
+    Format(Option<bool>, Option<Syntax>, Option<Radix>),
+    Set(Register, Value),
+    /// load a raw binary at an address
+    Load(String, Value),
+    /// load a symbol file
+    Symbols(String),
+    Save(String),
+    Restore(String),
+    Help,
+    Quit,
+}
+
+pub const HELP: &str = "\
+step [N]            s   execute N instructions
+next                n   execute one instruction, stepping over calls
+continue            c   run until a breakpoint, a watchpoint or a halt
+break ADDR [if EXPR]    b   add a breakpoint, stopping only if EXPR is true
+watch ADDR [if EXPR]    w   stop when the byte at ADDR is modified and EXPR is true
+trace ADDR MESSAGE          log MESSAGE when reaching ADDR, `{EXPR}` is replaced by its value
+condition ADDR [EXPR]       set or remove the condition of a breakpoint or watchpoint
+hits ADDR N                 only stop once a breakpoint or watchpoint was hit N times
+delete ADDR             d   remove the breakpoint and watchpoint at ADDR
+breakpoints             i   list the breakpoints and watchpoints
+print EXPR              p   evaluate an expression
+registers           r   print the registers
+backtrace           bt  print the subroutine calls which led to PC
+provenance [N] [page]   record the last N writes of every address or page (off to stop)
+writes ADDR [LEN]       print the recorded writes to LEN bytes at ADDR
+writer ADDR             disassemble around the last instruction which wrote to ADDR
+examine ADDR [LEN]  x   dump LEN bytes of memory
+edit ADDR BYTE...   e   write bytes in memory
+list [ADDR] [N]     l   disassemble N instructions around ADDR (default PC)
+format [color|nocolor] [att|intel|zilog] [hex|oct|dec]
+                        change the disassembly output, print it without argument
+set REG VALUE           set a register
+load FILE [ADDR]        load a binary file at ADDR (default 0x100)
+symbols FILE            load a symbol file (DRI or L80 .SYM, or NAME = ADDR lines)
+save FILE               save the registers and the memory in FILE
+restore FILE            restore a state saved with `save`
+help                h   print this message
+quit                q   exit the debugger
+
+Expressions use registers, flags (S Z AC P CY), memory bytes [ADDR] and words
+W[ADDR], symbols and the C operators, like `A == 0x1A && [HL] > 3 && !CY`.
+
+Numbers can be written 42, 0x2A, $2A, 2AH, 0o52, 52O, 52Q, 0b101010 or 101010B.
+Addresses and values can also be a register name: A B C D E H L F BC DE HL PSW SP PC,
+or the name of a symbol.";
+
+impl std::str::FromStr for Command {
+    type Err = anyhow::Error;
+
+    fn from_str(line: &str) -> Result<Self> {
+        let mut words = line.split_whitespace();
+        let cmd = words
+            .next()
+            .ok_or_else(|| anyhow!("Empty command"))?
+            .to_ascii_lowercase();
+        let args: Vec<&str> = words.collect();
+        // the end of the line after the first `n` arguments
+        let rest = |n: usize| -> Option<String> {
+            let mut rest = line.trim_start();
+            for _ in 0..=n {
+                rest = rest.trim_start();
+                rest = &rest[rest.find(char::is_whitespace).unwrap_or(rest.len())..];
+            }
+            Some(rest.trim().to_string()).filter(|r| !r.is_empty())
+        };
+        // the optional `if EXPR` after the first `n` arguments
+        let cond = |n: usize| -> Result<Option<String>> {
+            match args.get(n) {
+                None => Ok(None),
+                Some(kw) if kw.eq_ignore_ascii_case("if") => rest(n + 1)
+                    .map(Some)
+                    .ok_or_else(|| anyhow!("Missing condition after if")),
+                Some(kw) => bail!("Expected `if` and a condition, found {}", kw),
+            }
+        };
+
+        let value = |i: usize| -> Result<Value> {
+            args.get(i)
+                .ok_or_else(|| anyhow!("Missing argument to {}", cmd))?
+                .parse()
+        };
+        let count = |i: usize, default: usize| -> Result<usize> {
+            args.get(i)
+                .map_or(Ok(default as u16), |n| parse_number(n))
+                .map(usize::from)
+        };
+        let file = |i: usize| -> Result<String> {
+            args.get(i)
+                .map(|s| s.to_string())
+                .ok_or_else(|| anyhow!("Missing file name to {}", cmd))
+        };
+
+        Ok(match cmd.as_str() {
+            "s" | "step" => Command::Step(count(0, 1)?),
+            "n" | "next" => Command::Next,
+            "c" | "continue" => Command::Continue,
+            "b" | "break" => Command::Break(value(0)?, cond(1)?),
+            "w" | "watch" => Command::Watch(value(0)?, cond(1)?),
+            "trace" => Command::Trace(
+                value(0)?,
+                rest(1).ok_or_else(|| anyhow!("Missing message to trace"))?,
+            ),
+            "condition" => Command::Condition(value(0)?, rest(1)),
+            "hits" => Command::Hits(
+                value(0)?,
+                parse_number(args.get(1).ok_or_else(|| anyhow!("Missing hit count"))?)? as usize,
+            ),
+            "d" | "delete" => Command::Delete(value(0)?),
+            "i" | "breakpoints" => Command::Breakpoints,
+            "p" | "print" => Command::Print(rest(0).ok_or_else(|| anyhow!("Missing expression"))?),
+            "r" | "registers" => Command::Registers,
+            "bt" | "backtrace" | "where" => Command::Backtrace,
+            "provenance" => match args.first() {
+                Some(off) if off.eq_ignore_ascii_case("off") => Command::Provenance(None),
+                _ => {
+                    let granularity = match args.get(1) {
+                        None => Granularity::Address,
+                        Some(page) if page.eq_ignore_ascii_case("page") => Granularity::Page,
+                        Some(other) => bail!("Expected `page`, found {}", other),
+                    };
+                    Command::Provenance(Some((count(0, 8)?, granularity)))
+                }
+            },
+            "writes" => Command::Writes(value(0)?, count(1, 1)?),
+            "writer" => Command::Writer(value(0)?),
+            "x" | "examine" => Command::Examine(value(0)?, count(1, 16)?),
+            "e" | "edit" => {
+                let bytes = args
+                    .iter()
+                    .skip(1)
+                    .map(|b| {
+                        let b = parse_number(b)?;
+                        if b > 0xff {
+                            bail!("{:#x} does not fit in a byte", b);
+                        }
+                        Ok(b as u8)
+                    })
+                    .collect::<Result<Vec<u8>>>()?;
+                if bytes.is_empty() {
+                    bail!("Missing bytes to edit");
+                }
+                Command::Edit(value(0)?, bytes)
+            }
+            "l" | "list" => match args.len() {
+                0 => Command::List(None, 10),
+                _ => Command::List(Some(value(0)?), count(1, 10)?),
+            },
+            "format" => {
+                let (mut color, mut syntax, mut radix) = (None, None, None);
+                for arg in &args {
+                    match arg.to_ascii_lowercase().as_str() {
+                        "color" | "colour" => color = Some(true),
+                        "nocolor" | "nocolour" => color = Some(false),
+                        _ => match arg.parse::<Syntax>() {
+                            Ok(s) => syntax = Some(s),
+                            Err(_) => {
+                                radix = Some(arg.parse().map_err(|_| {
+                                    anyhow!("Unknown format {}, expected color, nocolor, att, intel, zilog, hex, oct or dec", arg)
+                                })?)
+                            }
+                        },
+                    }
+                }
+                Command::Format(color, syntax, radix)
+            }
+            "set" => Command::Set(
+                args.first()
+                    .ok_or_else(|| anyhow!("Missing register to set"))?
+                    .parse()?,
+                value(1)?,
+            ),
+            "load" => Command::Load(
+                file(0)?,
+                args.get(1)
+                    .map_or(Ok(Value::Number(0x100)), |v| v.parse())?,
+            ),
+            "symbols" => Command::Symbols(file(0)?),
+            "save" => Command::Save(file(0)?),
+            "restore" => Command::Restore(file(0)?),
+            "h" | "help" => Command::Help,
+            "q" | "quit" => Command::Quit,
+            _ => bail!(
+                "Unknown command {}, type `help` for a list of commands",
+                cmd
+            ),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!("s".parse::<Command>().unwrap(), Command::Step(1));
+        assert_eq!("step 0x10".parse::<Command>().unwrap(), Command::Step(16));
+        assert_eq!(
+            "b 105H".parse::<Command>().unwrap(),
+            Command::Break(Value::Number(0x105), None)
+        );
+        assert_eq!(
+            "break print if  A == 0x1A && [HL] > 3 "
+                .parse::<Command>()
+                .unwrap(),
+            Command::Break(
+                Value::Symbol("print".to_string()),
+                Some("A == 0x1A && [HL] > 3".to_string())
+            )
+        );
+        assert_eq!(
+            "trace 0x100 hl={HL}".parse::<Command>().unwrap(),
+            Command::Trace(Value::Number(0x100), "hl={HL}".to_string())
+        );
+        assert_eq!(
+            "condition 0x100".parse::<Command>().unwrap(),
+            Command::Condition(Value::Number(0x100), None)
+        );
+        assert!("b 0x100 when A".parse::<Command>().is_err());
+        assert!("b 0x100 if".parse::<Command>().is_err());
+        assert_eq!(
+            "x hl 4".parse::<Command>().unwrap(),
+            Command::Examine(Value::Register(Register::HL), 4)
+        );
+        assert_eq!(
+            "e 0x20 1 0xff 17o".parse::<Command>().unwrap(),
+            Command::Edit(Value::Number(0x20), vec![1, 0xff, 0o17])
+        );
+        assert_eq!(
+            "set a 42".parse::<Command>().unwrap(),
+            Command::Set(Register::A, Value::Number(42))
+        );
+        assert_eq!(
+            "provenance 4 page".parse::<Command>().unwrap(),
+            Command::Provenance(Some((4, Granularity::Page)))
+        );
+        assert_eq!(
+            "provenance off".parse::<Command>().unwrap(),
+            Command::Provenance(None)
+        );
+        assert_eq!(
+            "format nocolor zilog dec".parse::<Command>().unwrap(),
+            Command::Format(Some(false), Some(Syntax::Zilog), Some(Radix::Decimal))
+        );
+        assert!("format bold".parse::<Command>().is_err());
+        assert!("e 0x20 0x100".parse::<Command>().is_err());
+        assert!("frobnicate".parse::<Command>().is_err());
+        assert!("break".parse::<Command>().is_err());
+    }
+}
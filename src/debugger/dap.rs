@@ -0,0 +1,1122 @@
+//! Debug Adapter Protocol server.
+//!
+//! The `launch` request accepts the following arguments:
+//! - `program`: the binary to load (required)
+//! - `loadAddress`: where to load it, `0x100` by default
+//! - `listing`: an assembler listing used to map source lines to addresses,
+//!   breakpoints on source lines are resolved through it
+//! - `symbols`: a symbol file, its names can be used for function
+//!   breakpoints and in the evaluated expressions
+//! - `stopOnEntry`: stop before the first instruction
+//!
+//! Breakpoints accept a `condition` written in the debugger expression
+//! language, a `hitCondition` which is the number of hits before stopping,
+//! and a `logMessage` turning them into tracepoints.
+//!
+//! There is a single thread, its stack frames come from the call stack of
+//! the cpu. Two variable scopes are exposed: the registers and the flags.
+//!
+//! `continue`, `stepOut` and the `next` over a call run the program between
+//! the requests, which are read by another thread. While it runs, `pause`,
+//! `disconnect` and `terminate` are answered at once, the other requests
+//! once it stops. A request coming after one running the program again
+//! waits for that run to stop.
+
+use super::expr::FLAGS;
+use super::{Condition, Debugger, Expr, Listing, LogMessage, Register, Stop};
+use crate::callstack::CallStack;
+use crate::decompiler::Format;
+use crate::number::parse_number;
+use crate::symbols::SymbolTable;
+use crate::Cpu;
+use anyhow::{anyhow, bail, Result};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryFrom;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+
+const THREAD_ID: u64 = 1;
+const REGISTERS_REF: u64 = 1;
+const FLAGS_REF: u64 = 2;
+/// instructions executed between two looks at the requests
+const SLICE: usize = 10_000;
+
+const REGISTERS: [Register; 13] = [
+    Register::A,
+    Register::B,
+    Register::C,
+    Register::D,
+    Register::E,
+    Register::H,
+    Register::L,
+    Register::Flags,
+    Register::BC,
+    Register::DE,
+    Register::HL,
+    Register::SP,
+    Register::PC,
+];
+
+const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i)) as usize & 0x3f] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(s: &str) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let (mut n, mut bits) = (0u32, 0);
+    for c in s.bytes().filter(|&c| c != b'=') {
+        let v = BASE64
+            .iter()
+            .position(|&b| b == c)
+            .ok_or_else(|| anyhow!("Invalid base64 character {}", c as char))?;
+        n = (n << 6) | v as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((n >> bits) as u8);
+        }
+    }
+    Ok(out)
+}
+
+/// A program running between the requests
+#[derive(Clone, Copy, Debug)]
+enum Run {
+    Continue,
+    /// until the call at `sp` returns to `ret`
+    Over {
+        ret: usize,
+        sp: u16,
+    },
+    /// until the current subroutine returns
+    Out {
+        depth: usize,
+    },
+}
+
+impl Run {
+    fn done(self, cpu: &Cpu) -> bool {
+        match self {
+            Run::Continue => false,
+            Run::Over { ret, sp } => cpu.pc == ret && cpu.sp == sp,
+            Run::Out { depth } => call_depth(cpu) < depth,
+        }
+    }
+}
+
+/// true for the requests running the program
+fn resumes(request: &Value) -> bool {
+    matches!(
+        request["command"].as_str(),
+        Some("continue" | "next" | "stepIn" | "stepOut")
+    )
+}
+
+/// true for the requests answered while the program runs
+fn interrupts(request: &Value) -> bool {
+    matches!(
+        request["command"].as_str(),
+        Some("pause" | "disconnect" | "terminate")
+    )
+}
+
+fn call_depth(cpu: &Cpu) -> usize {
+    cpu.call_stack.as_ref().map_or(0, CallStack::depth)
+}
+
+/// A debug adapter session over any reader and writer, usually stdio or a
+/// TCP stream
+pub struct DapServer<W> {
+    requests: Receiver<Result<Option<Value>>>,
+    /// the requests received while the program runs
+    pending: VecDeque<Value>,
+    running: Option<Run>,
+    output: W,
+    seq: u64,
+    dbg: Option<Debugger>,
+    listing: Option<(String, Listing)>,
+    stop_on_entry: bool,
+    line_breakpoints: BTreeMap<usize, Condition>,
+    instruction_breakpoints: BTreeMap<usize, Condition>,
+    function_breakpoints: BTreeMap<usize, Condition>,
+}
+
+/// Serve debug adapter requests until the client disconnects
+pub fn serve(input: impl Read + Send + 'static, output: impl Write) -> Result<()> {
+    DapServer::new(input, output).run()
+}
+
+/// read the next message, or None at the end of the input
+fn read_message(input: &mut impl BufRead) -> Result<Option<Value>> {
+    let mut len = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(n) = header.strip_prefix("Content-Length:") {
+            len = Some(n.trim().parse()?);
+        }
+    }
+
+    let len = len.ok_or_else(|| anyhow!("Missing Content-Length header"))?;
+    let mut body = vec![0; len];
+    input.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+/// read the messages in another thread, up to the end of the input or an error
+fn spawn_reader(input: impl Read + Send + 'static) -> Receiver<Result<Option<Value>>> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut input = BufReader::new(input);
+        loop {
+            let message = read_message(&mut input);
+            let last = !matches!(message, Ok(Some(_)));
+            if sender.send(message).is_err() || last {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+fn arg<'a>(args: &'a Value, name: &str) -> Result<&'a Value> {
+    args.get(name)
+        .ok_or_else(|| anyhow!("Missing argument {}", name))
+}
+
+/// the condition, hit count and log message of a breakpoint
+fn condition(bp: &Value, symbols: &SymbolTable) -> Result<Condition> {
+    let mut condition = Condition::default();
+    if let Some(expr) = bp["condition"].as_str().filter(|e| !e.trim().is_empty()) {
+        condition = Condition::when(expr.trim(), symbols)?;
+    }
+    if let Some(hits) = bp["hitCondition"].as_str().filter(|h| !h.trim().is_empty()) {
+        // VS Code users often write `>= 3`, which is what a hit count means here
+        condition.hit_count = parse_number(hits.trim_start_matches(['>', '=', ' ']))? as usize;
+    }
+    if let Some(log) = bp["logMessage"].as_str() {
+        condition.log = Some((log.to_string(), LogMessage::parse(log, symbols)?));
+    }
+    Ok(condition)
+}
+
+/// parse a memory reference, written as a number in any notation
+fn address(value: &Value) -> Result<usize> {
+    match value {
+        Value::String(s) => Ok(parse_number(s)? as usize),
+        Value::Number(n) => n
+            .as_u64()
+            .map(|n| n as usize)
+            .ok_or_else(|| anyhow!("Invalid address {}", n)),
+        v => bail!("Invalid address {}", v),
+    }
+}
+
+/// an address moved by the offset of a request, in the 64K of the 8080
+fn offset(addr: usize, offset: i64) -> Result<usize> {
+    match i64::try_from(addr).ok().and_then(|a| a.checked_add(offset)) {
+        Some(addr @ 0..=0xffff) => Ok(addr as usize),
+        _ => bail!("{:#06x} with the offset {} is out of memory", addr, offset),
+    }
+}
+
+impl<W: Write> DapServer<W> {
+    pub fn new(input: impl Read + Send + 'static, output: W) -> Self {
+        Self {
+            requests: spawn_reader(input),
+            pending: VecDeque::new(),
+            running: None,
+            output,
+            seq: 0,
+            dbg: None,
+            listing: None,
+            stop_on_entry: false,
+            line_breakpoints: BTreeMap::new(),
+            instruction_breakpoints: BTreeMap::new(),
+            function_breakpoints: BTreeMap::new(),
+        }
+    }
+
+    /// the next request to answer, running the program until one comes
+    fn next_request(&mut self) -> Result<Option<Value>> {
+        loop {
+            if self.running.is_none() {
+                if let Some(request) = self.pending.pop_front() {
+                    return Ok(Some(request));
+                }
+                // the reader is gone after the end of the input
+                return self.requests.recv().unwrap_or(Ok(None));
+            }
+            // a pause is for the current run, not for one requested after it
+            let first = self
+                .pending
+                .iter()
+                .position(|request| resumes(request) || interrupts(request));
+            if let Some(i) = first.filter(|&i| interrupts(&self.pending[i])) {
+                return Ok(self.pending.remove(i));
+            }
+            match self.requests.try_recv() {
+                Ok(message) => {
+                    // after the end of the input, the program still runs to its stop
+                    if let Some(request) = message? {
+                        self.pending.push_back(request);
+                    }
+                }
+                Err(TryRecvError::Empty | TryRecvError::Disconnected) => self.run_slice()?,
+            }
+        }
+    }
+
+    /// run the program for a few instructions, telling the client if it stopped
+    fn run_slice(&mut self) -> Result<()> {
+        let run = match self.running {
+            Some(run) => run,
+            None => return Ok(()),
+        };
+        let dbg = self.dbg()?;
+        let mut count = 0;
+        let stop = dbg.run_until(|cpu| {
+            count += 1;
+            count == SLICE || run.done(cpu)
+        });
+        if stop == Stop::Step && !run.done(&dbg.cpu) {
+            return Ok(());
+        }
+        self.running = None;
+        self.stopped(stop)
+    }
+
+    fn send(&mut self, mut msg: Value) -> Result<()> {
+        self.seq += 1;
+        msg["seq"] = json!(self.seq);
+        let body = msg.to_string();
+        write!(
+            self.output,
+            "Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+        self.output.flush()?;
+        Ok(())
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    pub fn run(mut self) -> Result<()> {
+        while let Some(request) = self.next_request()? {
+            let command = request["command"].as_str().unwrap_or_default().to_string();
+            let args = request.get("arguments").cloned().unwrap_or(Value::Null);
+
+            let result = self.handle(&command, &args);
+            let mut response = json!({
+                "type": "response",
+                "request_seq": request["seq"],
+                "command": command,
+                "success": result.is_ok(),
+            });
+            let after = match result {
+                Ok((body, after)) => {
+                    response["body"] = body;
+                    after
+                }
+                Err(e) => {
+                    response["message"] = json!(e.to_string());
+                    None
+                }
+            };
+            self.send(response)?;
+
+            // events consequences of a request are sent after its response
+            match after {
+                Some(After::Initialized) => self.event("initialized", json!({}))?,
+                Some(After::Stopped(stop)) => self.stopped(stop)?,
+                Some(After::Paused) => {
+                    self.logs()?;
+                    self.event(
+                        "stopped",
+                        json!({ "reason": "pause", "threadId": THREAD_ID, "allThreadsStopped": true }),
+                    )?;
+                }
+                Some(After::Start) => {
+                    if self.stop_on_entry {
+                        self.event(
+                            "stopped",
+                            json!({ "reason": "entry", "threadId": THREAD_ID, "allThreadsStopped": true }),
+                        )?;
+                    } else {
+                        self.running = Some(Run::Continue);
+                    }
+                }
+                Some(After::Terminate) => {
+                    self.event("terminated", json!({}))?;
+                    return Ok(());
+                }
+                None => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// send the logs of the tracepoints hit while running
+    fn logs(&mut self) -> Result<()> {
+        let logs = self
+            .dbg
+            .as_mut()
+            .map(Debugger::take_logs)
+            .unwrap_or_default();
+        for log in logs {
+            self.event(
+                "output",
+                json!({ "category": "console", "output": format!("{}\n", log) }),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn stopped(&mut self, stop: Stop) -> Result<()> {
+        self.logs()?;
+        let (reason, text) = match stop {
+            Stop::Step => ("step", None),
+            Stop::Breakpoint(_) => ("breakpoint", None),
+            Stop::Watchpoint { .. } => ("data breakpoint", Some(stop.to_string())),
+            Stop::Fault(_) => ("exception", Some(stop.to_string())),
+            Stop::Halt => {
+                self.event(
+                    "output",
+                    json!({ "category": "console", "output": "CPU halted\n" }),
+                )?;
+                self.event("exited", json!({ "exitCode": 0 }))?;
+                return self.event("terminated", json!({}));
+            }
+        };
+        let mut body =
+            json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true });
+        if let Some(text) = text {
+            body["description"] = json!(text);
+            body["text"] = json!(text);
+        }
+        self.event("stopped", body)
+    }
+
+    fn dbg(&mut self) -> Result<&mut Debugger> {
+        self.dbg
+            .as_mut()
+            .ok_or_else(|| anyhow!("No program launched"))
+    }
+
+    fn symbols(&self) -> SymbolTable {
+        self.dbg
+            .as_ref()
+            .map(|dbg| dbg.cpu.symbols.clone())
+            .unwrap_or_default()
+    }
+
+    /// give the breakpoints to the debugger, keeping the hits of the
+    /// breakpoints which were already set
+    fn update_breakpoints(&mut self) {
+        let all: BTreeMap<usize, Condition> = self
+            .line_breakpoints
+            .iter()
+            .chain(&self.instruction_breakpoints)
+            .chain(&self.function_breakpoints)
+            .map(|(&addr, condition)| (addr, condition.clone()))
+            .collect();
+        if let Some(dbg) = self.dbg.as_mut() {
+            let old = std::mem::replace(&mut dbg.breakpoints, all);
+            for (addr, condition) in dbg.breakpoints.iter_mut() {
+                if let Some(old) = old.get(addr) {
+                    condition.hits = old.hits;
+                }
+            }
+        }
+    }
+
+    fn source(&self) -> Value {
+        match &self.listing {
+            Some((path, _)) => json!({
+                "name": std::path::Path::new(path).file_name().map(|f| f.to_string_lossy()),
+                "path": path,
+            }),
+            None => Value::Null,
+        }
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<()> {
+        let program = arg(args, "program")?
+            .as_str()
+            .ok_or_else(|| anyhow!("program must be a path"))?;
+        let addr = match args.get("loadAddress") {
+            Some(addr) => address(addr)?,
+            None => 0x100,
+        };
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        if let Some(listing) = args["listing"].as_str() {
+            let content = std::fs::read_to_string(listing)?;
+            self.listing = Some((listing.to_string(), Listing::parse(&content)));
+        }
+
+        let mut cpu = Cpu::from_filename_at(program, addr)?;
+        if let Some(symbols) = args["symbols"].as_str() {
+            cpu.symbols = SymbolTable::from_file(symbols)?;
+        }
+        // the editors do not understand the ANSI colours
+        cpu.format = Format::plain();
+        self.dbg = Some(Debugger::new(cpu));
+        self.update_breakpoints();
+        Ok(())
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value> {
+        let symbols = self.symbols();
+        self.line_breakpoints.clear();
+        let mut breakpoints = Vec::new();
+        for bp in args["breakpoints"].as_array().into_iter().flatten() {
+            let line = bp["line"].as_u64().unwrap_or_default() as usize;
+            let condition = match condition(bp, &symbols) {
+                Ok(condition) => condition,
+                Err(e) => {
+                    breakpoints.push(json!({ "verified": false, "message": e.to_string() }));
+                    continue;
+                }
+            };
+            match self.listing.as_ref().and_then(|(_, l)| l.line_addr(line)) {
+                Some((line, addr)) => {
+                    self.line_breakpoints.insert(addr, condition);
+                    breakpoints.push(json!({
+                        "verified": true,
+                        "line": line,
+                        "instructionReference": format!("{:#06x}", addr),
+                    }));
+                }
+                None => breakpoints.push(json!({
+                    "verified": false,
+                    "message": "No code at this line",
+                })),
+            }
+        }
+        self.update_breakpoints();
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn set_instruction_breakpoints(&mut self, args: &Value) -> Result<Value> {
+        let symbols = self.symbols();
+        self.instruction_breakpoints.clear();
+        let mut breakpoints = Vec::new();
+        for bp in args["breakpoints"].as_array().into_iter().flatten() {
+            let addr = address(arg(bp, "instructionReference")?)?;
+            let addr = offset(addr, bp["offset"].as_i64().unwrap_or(0));
+            let (addr, condition) = match addr.and_then(|a| Ok((a, condition(bp, &symbols)?))) {
+                Ok(breakpoint) => breakpoint,
+                Err(e) => {
+                    breakpoints.push(json!({ "verified": false, "message": e.to_string() }));
+                    continue;
+                }
+            };
+            self.instruction_breakpoints.insert(addr, condition);
+            breakpoints.push(json!({
+                "verified": true,
+                "instructionReference": format!("{:#06x}", addr),
+            }));
+        }
+        self.update_breakpoints();
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn set_function_breakpoints(&mut self, args: &Value) -> Result<Value> {
+        let symbols = self.symbols();
+        self.function_breakpoints.clear();
+        let mut breakpoints = Vec::new();
+        for bp in args["breakpoints"].as_array().into_iter().flatten() {
+            let name = arg(bp, "name")?.as_str().unwrap_or_default();
+            let condition = match condition(bp, &symbols) {
+                Ok(condition) => condition,
+                Err(e) => {
+                    breakpoints.push(json!({ "verified": false, "message": e.to_string() }));
+                    continue;
+                }
+            };
+            match symbols.addr(name) {
+                Some(addr) => {
+                    self.function_breakpoints.insert(addr as usize, condition);
+                    breakpoints.push(json!({
+                        "verified": true,
+                        "instructionReference": format!("{:#06x}", addr),
+                    }));
+                }
+                None => breakpoints.push(json!({
+                    "verified": false,
+                    "message": format!("Unknown symbol {}", name),
+                })),
+            }
+        }
+        self.update_breakpoints();
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn stack_trace(&mut self) -> Result<Value> {
+        let backtrace = self.dbg()?.cpu.backtrace();
+        let frames: Vec<Value> = backtrace
+            .frames
+            .iter()
+            .enumerate()
+            .map(|(id, frame)| {
+                let line = self
+                    .listing
+                    .as_ref()
+                    .and_then(|(_, l)| l.addr_line(frame.addr as usize));
+                let mut json = json!({
+                    "id": id,
+                    "name": frame.function(),
+                    "line": line.unwrap_or(0),
+                    "column": 0,
+                    "instructionPointerReference": format!("{:#06x}", frame.addr),
+                });
+                if line.is_some() {
+                    json["source"] = self.source();
+                }
+                json
+            })
+            .collect();
+        Ok(json!({ "totalFrames": frames.len(), "stackFrames": frames }))
+    }
+
+    fn variables(&mut self, args: &Value) -> Result<Value> {
+        let cpu = &self.dbg()?.cpu;
+        let variables: Vec<Value> = match args["variablesReference"].as_u64() {
+            Some(REGISTERS_REF) => REGISTERS
+                .iter()
+                .map(|r| {
+                    let value = r.get(cpu);
+                    if r.is_pair() {
+                        json!({
+                            "name": r.to_string(),
+                            "value": format!("{:#06x}", value),
+                            "variablesReference": 0,
+                            "memoryReference": format!("{:#06x}", value),
+                        })
+                    } else {
+                        json!({
+                            "name": r.to_string(),
+                            "value": format!("{:#04x}", value),
+                            "variablesReference": 0,
+                        })
+                    }
+                })
+                .collect(),
+            Some(FLAGS_REF) => FLAGS
+                .iter()
+                .map(|(name, bit)| {
+                    json!({
+                        "name": name,
+                        "value": ((cpu.reg.flags >> bit) & 1).to_string(),
+                        "variablesReference": 0,
+                    })
+                })
+                .collect(),
+            _ => bail!("Unknown variables reference"),
+        };
+        Ok(json!({ "variables": variables }))
+    }
+
+    fn set_variable(&mut self, args: &Value) -> Result<Value> {
+        let name = arg(args, "name")?.as_str().unwrap_or_default();
+        let value = parse_number(arg(args, "value")?.as_str().unwrap_or_default())?;
+        let reference = args["variablesReference"].as_u64();
+        let cpu = &mut self.dbg()?.cpu;
+
+        match reference {
+            Some(REGISTERS_REF) => {
+                let r: Register = name.parse()?;
+                if !r.is_pair() && value > 0xff {
+                    bail!("{:#x} does not fit in register {}", value, r);
+                }
+                r.set(cpu, value);
+                let value = r.get(cpu);
+                Ok(
+                    json!({ "value": if r.is_pair() { format!("{:#06x}", value) } else { format!("{:#04x}", value) } }),
+                )
+            }
+            Some(FLAGS_REF) => {
+                let bit = FLAGS
+                    .iter()
+                    .find(|(flag, _)| flag.eq_ignore_ascii_case(name))
+                    .map(|(_, bit)| *bit)
+                    .ok_or_else(|| anyhow!("Unknown flag {}", name))?;
+                if value > 1 {
+                    bail!("A flag is either 0 or 1");
+                }
+                cpu.reg.flags = (cpu.reg.flags & !(1 << bit)) | ((value as u8) << bit);
+                Ok(json!({ "value": value.to_string() }))
+            }
+            _ => bail!("Unknown variables reference"),
+        }
+    }
+
+    fn read_memory(&mut self, args: &Value) -> Result<Value> {
+        let addr =
+            address(arg(args, "memoryReference")?)? as i64 + args["offset"].as_i64().unwrap_or(0);
+        let count = arg(args, "count")?.as_u64().unwrap_or(0) as usize;
+        let ram = &self.dbg()?.cpu.ram;
+        let start = (addr.max(0) as usize).min(ram.len());
+        let end = start.saturating_add(count).min(ram.len());
+        Ok(json!({
+            "address": format!("{:#06x}", start),
+            "data": base64_encode(&ram[start..end]),
+            "unreadableBytes": count - (end - start),
+        }))
+    }
+
+    fn write_memory(&mut self, args: &Value) -> Result<Value> {
+        let addr =
+            address(arg(args, "memoryReference")?)? as i64 + args["offset"].as_i64().unwrap_or(0);
+        let data = base64_decode(arg(args, "data")?.as_str().unwrap_or_default())?;
+        let ram = &mut self.dbg()?.cpu.ram;
+        if addr < 0 {
+            bail!("{:#06x} is out of memory", addr);
+        }
+        let (addr, len) = (addr as usize, ram.len());
+        match addr.checked_add(data.len()) {
+            Some(end) if end <= len => ram[addr..end].copy_from_slice(&data),
+            _ => bail!("{:#06x} is out of memory", addr),
+        }
+        Ok(json!({ "bytesWritten": data.len() }))
+    }
+
+    fn disassemble(&mut self, args: &Value) -> Result<Value> {
+        let addr =
+            address(arg(args, "memoryReference")?)? as i64 + args["offset"].as_i64().unwrap_or(0);
+        let offset = args["instructionOffset"].as_i64().unwrap_or(0);
+        let count = arg(args, "instructionCount")?.as_u64().unwrap_or(0);
+        let dbg = self
+            .dbg
+            .as_ref()
+            .ok_or_else(|| anyhow!("No program launched"))?;
+
+        let mut addr = addr.max(0) as usize;
+        for _ in offset..0 {
+            addr = dbg.previous_instruction(addr);
+        }
+        for _ in 0..offset {
+            addr += dbg.disassemble(addr).1;
+        }
+
+        let mut instructions = Vec::new();
+        for _ in 0..count {
+            let (text, len) = dbg.disassemble(addr);
+            let end = (addr + len).min(dbg.cpu.ram.len());
+            let bytes: Vec<String> = dbg.cpu.ram[addr.min(end)..end]
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect();
+            let mut instruction = json!({
+                "address": format!("{:#06x}", addr),
+                "instruction": text.replace('\t', " "),
+                "instructionBytes": bytes.join(" "),
+            });
+            if let Some((path, listing)) = &self.listing {
+                if let Some(line) = listing.addr_line(addr) {
+                    instruction["location"] = json!({ "path": path });
+                    instruction["line"] = json!(line);
+                }
+            }
+            instructions.push(instruction);
+            addr += len;
+        }
+        Ok(json!({ "instructions": instructions }))
+    }
+
+    fn evaluate(&mut self, args: &Value) -> Result<Value> {
+        let expr = arg(args, "expression")?.as_str().unwrap_or_default();
+        let cpu = &self.dbg()?.cpu;
+        let value = Expr::parse(expr, &cpu.symbols)?.eval(cpu);
+        Ok(json!({
+            "result": format!("{:#06x} ({})", value as u16, value),
+            "variablesReference": 0,
+            "memoryReference": format!("{:#06x}", value as u16),
+        }))
+    }
+
+    /// answer a request, returning the body of the response and what to do
+    /// once the response is sent
+    fn handle(&mut self, command: &str, args: &Value) -> Result<(Value, Option<After>)> {
+        let stopped = |stop| Ok((json!({}), Some(After::Stopped(stop))));
+
+        match command {
+            "initialize" => Ok((
+                json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsInstructionBreakpoints": true,
+                    "supportsFunctionBreakpoints": true,
+                    "supportsConditionalBreakpoints": true,
+                    "supportsHitConditionalBreakpoints": true,
+                    "supportsLogPoints": true,
+                    "supportsDisassembleRequest": true,
+                    "supportsReadMemoryRequest": true,
+                    "supportsWriteMemoryRequest": true,
+                    "supportsSetVariable": true,
+                    "supportsSteppingGranularity": true,
+                    "supportsEvaluateForHovers": true,
+                }),
+                None,
+            )),
+            "launch" => {
+                self.launch(args)?;
+                Ok((json!({}), Some(After::Initialized)))
+            }
+            "setBreakpoints" => Ok((self.set_breakpoints(args)?, None)),
+            "setInstructionBreakpoints" => Ok((self.set_instruction_breakpoints(args)?, None)),
+            "setFunctionBreakpoints" => Ok((self.set_function_breakpoints(args)?, None)),
+            "setExceptionBreakpoints" => Ok((json!({}), None)),
+            "configurationDone" => Ok((json!({}), Some(After::Start))),
+            "threads" => Ok((
+                json!({ "threads": [{ "id": THREAD_ID, "name": "8080" }] }),
+                None,
+            )),
+            "stackTrace" => Ok((self.stack_trace()?, None)),
+            "scopes" => Ok((
+                json!({ "scopes": [
+                    { "name": "Registers", "variablesReference": REGISTERS_REF, "expensive": false },
+                    { "name": "Flags", "variablesReference": FLAGS_REF, "expensive": false },
+                ]}),
+                None,
+            )),
+            "variables" => Ok((self.variables(args)?, None)),
+            "setVariable" => Ok((self.set_variable(args)?, None)),
+            "readMemory" => Ok((self.read_memory(args)?, None)),
+            "writeMemory" => Ok((self.write_memory(args)?, None)),
+            "disassemble" => Ok((self.disassemble(args)?, None)),
+            "evaluate" => Ok((self.evaluate(args)?, None)),
+            "continue" => {
+                self.dbg()?;
+                self.running = Some(Run::Continue);
+                Ok((json!({ "allThreadsContinued": true }), None))
+            }
+            // every step is an instruction step, the listing has one line per instruction
+            "next" => {
+                let dbg = self.dbg()?;
+                match dbg.call_return() {
+                    Some(ret) => {
+                        self.running = Some(Run::Over {
+                            ret,
+                            sp: dbg.cpu.sp,
+                        });
+                        Ok((json!({}), None))
+                    }
+                    None => stopped(dbg.step()),
+                }
+            }
+            "stepIn" => stopped(self.dbg()?.step()),
+            "stepOut" => {
+                let depth = call_depth(&self.dbg()?.cpu);
+                if depth == 0 {
+                    bail!("No subroutine to step out of");
+                }
+                self.running = Some(Run::Out { depth });
+                Ok((json!({}), None))
+            }
+            "pause" => {
+                let paused = self.running.take().is_some();
+                Ok((json!({}), paused.then_some(After::Paused)))
+            }
+            "disconnect" | "terminate" => Ok((json!({}), Some(After::Terminate))),
+            _ => bail!("Unsupported request {}", command),
+        }
+    }
+}
+
+/// what to send once the response to a request is sent
+enum After {
+    Initialized,
+    /// configuration is done, start the program
+    Start,
+    Stopped(Stop),
+    /// the running program was paused
+    Paused,
+    Terminate,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(seq: u64, command: &str, args: Value) -> String {
+        let body = json!({ "seq": seq, "type": "request", "command": command, "arguments": args })
+            .to_string();
+        format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+    }
+
+    fn messages(mut output: &[u8]) -> Vec<Value> {
+        std::iter::from_fn(|| read_message(&mut output).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_base64() {
+        for data in [&b""[..], b"f", b"fo", b"foo", b"foob", b"\xff\x00\x10"] {
+            assert_eq!(base64_decode(&base64_encode(data)).unwrap(), data);
+        }
+        assert_eq!(base64_encode(b"foob"), "Zm9vYg==");
+    }
+
+    #[test]
+    fn test_dap_session() {
+        let dir = std::env::temp_dir();
+        let program = dir.join("rust-8080-dap.com");
+        let listing = dir.join("rust-8080-dap.prn");
+        let symbols = dir.join("rust-8080-dap.sym");
+        std::fs::write(&symbols, "0100 START\t0104 DONE\r\n").unwrap();
+        std::fs::write(&program, [0x3e, 0x01, 0x3c, 0x3c, 0x76]).unwrap();
+        std::fs::write(
+            &listing,
+            " 0100 3E01            MVI     A,1\n 0102 3C              INR     A\n 0103 3C              INR     A\n 0104 76              HLT\n",
+        )
+        .unwrap();
+
+        let input = [
+            request(1, "initialize", json!({ "adapterID": "8080" })),
+            request(
+                2,
+                "launch",
+                json!({
+                    "program": program,
+                    "listing": listing,
+                    "symbols": symbols,
+                    "stopOnEntry": true,
+                }),
+            ),
+            request(3, "setBreakpoints", json!({ "source": { "path": listing }, "breakpoints": [{ "line": 3 }] })),
+            request(4, "configurationDone", json!({})),
+            request(5, "stepIn", json!({ "threadId": 1 })),
+            request(6, "continue", json!({ "threadId": 1 })),
+            request(7, "stackTrace", json!({ "threadId": 1 })),
+            request(8, "variables", json!({ "variablesReference": REGISTERS_REF })),
+            request(9, "setVariable", json!({ "variablesReference": REGISTERS_REF, "name": "B", "value": "0x42" })),
+            request(10, "readMemory", json!({ "memoryReference": "0x100", "count": 3 })),
+            request(11, "disassemble", json!({ "memoryReference": "0x103", "instructionOffset": -1, "instructionCount": 2 })),
+            request(12, "evaluate", json!({ "expression": "b" })),
+            request(13, "setFunctionBreakpoints", json!({ "breakpoints": [{ "name": "done" }, { "name": "nope" }, { "name": "start", "condition": "A ==" }] })),
+            request(14, "continue", json!({ "threadId": 1 })),
+            request(15, "stackTrace", json!({ "threadId": 1 })),
+            request(16, "continue", json!({ "threadId": 1 })),
+        ]
+        .concat();
+
+        let mut output = Vec::new();
+        serve(std::io::Cursor::new(input), &mut output).unwrap();
+        let messages = messages(&output);
+
+        let find = |command: &str| {
+            messages
+                .iter()
+                .find(|m| m["command"] == command)
+                .unwrap_or_else(|| panic!("no response to {}", command))
+        };
+        let events: Vec<&Value> = messages.iter().filter(|m| m["type"] == "event").collect();
+
+        assert!(messages
+            .iter()
+            .all(|m| m["type"] == "event" || m["success"] == true));
+        assert_eq!(events[0]["event"], "initialized");
+        assert_eq!(events[1]["body"]["reason"], "entry");
+        assert_eq!(events[2]["body"]["reason"], "step");
+        assert_eq!(events[3]["body"]["reason"], "breakpoint");
+        assert_eq!(events.last().unwrap()["event"], "terminated");
+
+        assert_eq!(find("setBreakpoints")["body"]["breakpoints"][0]["line"], 3);
+        let bps = &find("setFunctionBreakpoints")["body"]["breakpoints"];
+        assert_eq!(bps[0]["verified"], true);
+        assert_eq!(bps[1]["verified"], false);
+        assert_eq!(bps[2]["verified"], false);
+        let frames: Vec<&Value> = messages
+            .iter()
+            .filter(|m| m["command"] == "stackTrace")
+            .map(|m| &m["body"]["stackFrames"][0])
+            .collect();
+        assert_eq!(frames[1]["name"], "DONE");
+        let frame = frames[0];
+        assert_eq!(frame["name"], "START+3");
+        assert_eq!(frame["line"], 3);
+        assert_eq!(frame["instructionPointerReference"], "0x0103");
+        let variables = &find("variables")["body"]["variables"];
+        assert_eq!(variables[0]["name"], "A");
+        assert_eq!(variables[0]["value"], "0x02");
+        assert_eq!(
+            find("readMemory")["body"]["data"],
+            base64_encode(&[0x3e, 0x01, 0x3c])
+        );
+        let instructions = &find("disassemble")["body"]["instructions"];
+        assert_eq!(instructions[0]["address"], "0x0102");
+        assert_eq!(instructions[1]["line"], 3);
+        assert!(find("evaluate")["body"]["result"]
+            .as_str()
+            .unwrap()
+            .starts_with("0x0042"));
+
+        std::fs::remove_file(program).unwrap();
+        std::fs::remove_file(listing).unwrap();
+        std::fs::remove_file(symbols).unwrap();
+    }
+
+    #[test]
+    fn test_dap_conditions() {
+        let program = std::env::temp_dir().join("rust-8080-dap-conditions.com");
+        // MVI A,0 ; LOOP: INR A ; JMP LOOP
+        std::fs::write(&program, [0x3e, 0x00, 0x3c, 0xc3, 0x02, 0x01]).unwrap();
+
+        let input = [
+            request(1, "initialize", json!({ "adapterID": "8080" })),
+            request(2, "launch", json!({ "program": program })),
+            request(3, "setInstructionBreakpoints", json!({ "breakpoints": [
+                { "instructionReference": "0x102", "logMessage": "A is {A}", "hitCondition": ">= 2" },
+                { "instructionReference": "0x103", "condition": "A == 3" },
+                { "instructionReference": "0x100", "condition": "A ==" },
+            ]})),
+            request(4, "configurationDone", json!({})),
+            request(5, "evaluate", json!({ "expression": "A * 2 + [0x100]" })),
+            request(6, "writeMemory", json!({ "memoryReference": "0x100", "offset": -0x101, "data": "AA==" })),
+            request(7, "writeMemory", json!({ "memoryReference": "0x100", "offset": i64::MAX - 0x100, "data": "AA==" })),
+        ]
+        .concat();
+
+        let mut output = Vec::new();
+        serve(std::io::Cursor::new(input), &mut output).unwrap();
+        let messages = messages(&output);
+
+        let initialize = &messages[0]["body"];
+        assert_eq!(initialize["supportsConditionalBreakpoints"], true);
+        assert_eq!(initialize["supportsLogPoints"], true);
+        let bps = &messages
+            .iter()
+            .find(|m| m["command"] == "setInstructionBreakpoints")
+            .unwrap()["body"]["breakpoints"];
+        assert_eq!(bps[1]["verified"], true);
+        assert_eq!(bps[2]["verified"], false);
+
+        let outputs: Vec<&Value> = messages
+            .iter()
+            .filter(|m| m["event"] == "output")
+            .map(|m| &m["body"]["output"])
+            .collect();
+        assert_eq!(outputs, ["A is 0x1\n", "A is 0x2\n"]);
+        let stopped = messages.iter().find(|m| m["event"] == "stopped").unwrap();
+        assert_eq!(stopped["body"]["reason"], "breakpoint");
+        let evaluate = messages
+            .iter()
+            .find(|m| m["command"] == "evaluate")
+            .unwrap();
+        assert_eq!(evaluate["body"]["result"], "0x0044 (68)");
+        let writes: Vec<&Value> = messages
+            .iter()
+            .filter(|m| m["command"] == "writeMemory")
+            .collect();
+        assert_eq!(writes.len(), 2);
+        assert!(writes.iter().all(|m| m["success"] == false));
+
+        std::fs::remove_file(program).unwrap();
+    }
+
+    #[test]
+    fn test_dap_pause() {
+        let program = std::env::temp_dir().join("rust-8080-dap-pause.com");
+        // LXI SP,0x140 ; CALL SUB ; LOOP: JMP LOOP ; NOP ; SUB: DCR B ; JNZ SUB ; RET
+        let mut code = vec![
+            0x31, 0x40, 0x01, 0xcd, 0x0a, 0x01, 0xc3, 0x06, 0x01, 0x00, 0x05, 0xc2, 0x0a, 0x01,
+            0xc9,
+        ];
+        // room for the stack
+        code.resize(0x40, 0);
+        std::fs::write(&program, code).unwrap();
+
+        let input = [
+            request(1, "initialize", json!({ "adapterID": "8080" })),
+            request(
+                2,
+                "launch",
+                json!({ "program": program, "stopOnEntry": true }),
+            ),
+            request(
+                3,
+                "setInstructionBreakpoints",
+                json!({ "breakpoints": [
+                    { "instructionReference": "0x100", "offset": -0x101 },
+                    { "instructionReference": "0xffff", "offset": 1 },
+                    { "instructionReference": "0xffff" },
+                ]}),
+            ),
+            request(4, "configurationDone", json!({})),
+            request(
+                5,
+                "readMemory",
+                json!({ "memoryReference": "0x13e", "count": u64::MAX }),
+            ),
+            request(6, "stepIn", json!({ "threadId": 1 })),
+            request(7, "stepIn", json!({ "threadId": 1 })),
+            request(8, "stepOut", json!({ "threadId": 1 })),
+            request(9, "continue", json!({ "threadId": 1 })),
+            request(10, "pause", json!({ "threadId": 1 })),
+            request(11, "stackTrace", json!({ "threadId": 1 })),
+            request(12, "stepOut", json!({ "threadId": 1 })),
+            request(13, "disconnect", json!({})),
+        ]
+        .concat();
+
+        let mut output = Vec::new();
+        serve(std::io::Cursor::new(input), &mut output).unwrap();
+        let messages = messages(&output);
+        let find = |command: &str| {
+            messages
+                .iter()
+                .find(|m| m["command"] == command)
+                .unwrap_or_else(|| panic!("no response to {}", command))
+        };
+
+        let bps = &find("setInstructionBreakpoints")["body"]["breakpoints"];
+        assert_eq!(bps[0]["verified"], false);
+        assert_eq!(bps[1]["verified"], false);
+        assert_eq!(bps[2]["verified"], true);
+        assert_eq!(find("readMemory")["body"]["data"], base64_encode(&[0, 0]));
+        let reasons: Vec<&Value> = messages
+            .iter()
+            .filter(|m| m["event"] == "stopped")
+            .map(|m| &m["body"]["reason"])
+            .collect();
+        assert_eq!(reasons, ["entry", "step", "step", "step", "pause"]);
+        assert_eq!(
+            find("stackTrace")["body"]["stackFrames"][0]["instructionPointerReference"],
+            "0x0106"
+        );
+        let step_outs: Vec<&Value> = messages
+            .iter()
+            .filter(|m| m["command"] == "stepOut")
+            .map(|m| &m["success"])
+            .collect();
+        assert_eq!(step_outs, [true, false]);
+        assert_eq!(find("pause")["success"], true);
+
+        std::fs::remove_file(program).unwrap();
+    }
+}
@@ -0,0 +1,381 @@
+//! Expressions over the cpu state, used by the breakpoint conditions.
+//!
+//! ```text
+//! A == 0x1A && [HL] > 3 && !CY
+//! ```
+//! An expression can use:
+//! - numbers in any notation accepted by [parse_number](../../number/fn.parse_number.html)
+//! - the registers `A B C D E H L F`, the pairs `BC DE HL PSW SP PC`
+//! - the flags `S Z AC P CY`, worth 0 or 1
+//! - the byte `[addr]` and the little endian word `W[addr]` in memory
+//! - the symbols known when the expression is parsed
+//! - the C operators `|| && | ^ & == != < <= > >= << >> + - * / % ! ~`
+//!   with their usual precedence and parentheses
+//!
+//! Everything is computed on `i64`, comparisons and logical operators give 0
+//! or 1.
+
+use super::value::{is_symbol, Register};
+use crate::number::parse_number;
+use crate::symbols::SymbolTable;
+use crate::Cpu;
+use anyhow::{anyhow, bail, Result};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BinOp {
+    Or,
+    And,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UnOp {
+    Not,
+    BitNot,
+    Neg,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Expr {
+    Const(i64),
+    Register(Register),
+    /// a flag, identified by its bit in the flags register
+    Flag(usize),
+    Byte(Box<Expr>),
+    Word(Box<Expr>),
+    Unary(UnOp, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+/// name and bit of every flag in the flags register
+pub const FLAGS: [(&str, usize); 5] = [("S", 7), ("Z", 6), ("AC", 4), ("P", 2), ("CY", 0)];
+
+/// binary operators from the lowest to the highest precedence
+const BINARY: [&[(&str, BinOp)]; 10] = [
+    &[("||", BinOp::Or)],
+    &[("&&", BinOp::And)],
+    &[("|", BinOp::BitOr)],
+    &[("^", BinOp::BitXor)],
+    &[("&", BinOp::BitAnd)],
+    &[("==", BinOp::Eq), ("!=", BinOp::Ne)],
+    &[
+        ("<=", BinOp::Le),
+        (">=", BinOp::Ge),
+        ("<", BinOp::Lt),
+        (">", BinOp::Gt),
+    ],
+    &[("<<", BinOp::Shl), (">>", BinOp::Shr)],
+    &[("+", BinOp::Add), ("-", BinOp::Sub)],
+    &[("*", BinOp::Mul), ("/", BinOp::Div), ("%", BinOp::Rem)],
+];
+
+const OPERATORS: [&str; 23] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>", "|", "^", "&", "<", ">", "+", "-", "*", "/",
+    "%", "!", "~", "(", ")", "[",
+];
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Token {
+    Number(i64),
+    Ident(String),
+    Op(&'static str),
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = s.trim_start();
+
+    while let Some(c) = rest.chars().next() {
+        let word_len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || "_?@.$".contains(c)))
+            .unwrap_or(rest.len());
+
+        if c.is_ascii_digit() || (c == '$' && word_len > 1) {
+            tokens.push(Token::Number(parse_number(&rest[..word_len])? as i64));
+            rest = &rest[word_len..];
+        } else if is_symbol(&rest[..word_len]) {
+            tokens.push(Token::Ident(rest[..word_len].to_string()));
+            rest = &rest[word_len..];
+        } else if c == ']' {
+            tokens.push(Token::Op("]"));
+            rest = &rest[1..];
+        } else {
+            let op = OPERATORS
+                .iter()
+                .find(|op| rest.starts_with(*op))
+                .ok_or_else(|| anyhow!("Unexpected character `{}` in `{}`", c, s))?;
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    symbols: &'a SymbolTable,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, op: &str) -> Result<()> {
+        match self.next() {
+            Some(Token::Op(o)) if o == op => Ok(()),
+            Some(t) => bail!("Expected `{}`, found {:?}", op, t),
+            None => bail!("Expected `{}` at the end of the expression", op),
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr> {
+        if level == BINARY.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Op(o)) => BINARY[level].iter().find(|(s, _)| s == o),
+                _ => None,
+            };
+            match op {
+                Some(&(_, op)) => {
+                    self.pos += 1;
+                    let rhs = self.binary(level + 1)?;
+                    lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+                }
+                None => return Ok(lhs),
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        let op = match self.peek() {
+            Some(Token::Op("!")) => UnOp::Not,
+            Some(Token::Op("~")) => UnOp::BitNot,
+            Some(Token::Op("-")) => UnOp::Neg,
+            _ => return self.primary(),
+        };
+        self.pos += 1;
+        Ok(Expr::Unary(op, Box::new(self.unary()?)))
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Expr::Const(n)),
+            Some(Token::Op("(")) => {
+                let expr = self.binary(0)?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Some(Token::Op("[")) => {
+                let expr = self.binary(0)?;
+                self.expect("]")?;
+                Ok(Expr::Byte(Box::new(expr)))
+            }
+            Some(Token::Ident(name)) => {
+                if name.eq_ignore_ascii_case("W") && self.peek() == Some(&Token::Op("[")) {
+                    self.pos += 1;
+                    let expr = self.binary(0)?;
+                    self.expect("]")?;
+                    return Ok(Expr::Word(Box::new(expr)));
+                }
+                if let Ok(r) = name.parse() {
+                    return Ok(Expr::Register(r));
+                }
+                if let Some((_, bit)) = FLAGS.iter().find(|(f, _)| f.eq_ignore_ascii_case(&name)) {
+                    return Ok(Expr::Flag(*bit));
+                }
+                match self.symbols.addr(&name) {
+                    Some(addr) => Ok(Expr::Const(addr as i64)),
+                    None => bail!("Unknown register, flag or symbol {}", name),
+                }
+            }
+            Some(t) => bail!("Unexpected {:?}", t),
+            None => bail!("Unexpected end of the expression"),
+        }
+    }
+}
+
+fn byte(cpu: &Cpu, addr: i64) -> i64 {
+    let addr = addr as u16 as usize;
+    if addr < cpu.ram.len() {
+        cpu.ram[addr] as i64
+    } else {
+        0
+    }
+}
+
+impl Expr {
+    /// parse an expression, resolving the symbols once and for all
+    pub fn parse(s: &str, symbols: &SymbolTable) -> Result<Self> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+            symbols,
+        };
+        if parser.tokens.is_empty() {
+            bail!("Empty expression");
+        }
+        let expr = parser.binary(0)?;
+        match parser.peek() {
+            Some(t) => bail!("Unexpected {:?} after the expression", t),
+            None => Ok(expr),
+        }
+    }
+
+    pub fn eval(&self, cpu: &Cpu) -> i64 {
+        match self {
+            Expr::Const(n) => *n,
+            Expr::Register(r) => r.get(cpu) as i64,
+            Expr::Flag(bit) => ((cpu.reg.flags >> bit) & 1) as i64,
+            Expr::Byte(addr) => byte(cpu, addr.eval(cpu)),
+            Expr::Word(addr) => {
+                let addr = addr.eval(cpu);
+                byte(cpu, addr) | byte(cpu, addr + 1) << 8
+            }
+            Expr::Unary(op, e) => {
+                let v = e.eval(cpu);
+                match op {
+                    UnOp::Not => (v == 0) as i64,
+                    UnOp::BitNot => !v,
+                    UnOp::Neg => -v,
+                }
+            }
+            Expr::Binary(BinOp::Or, l, r) => (l.eval(cpu) != 0 || r.eval(cpu) != 0) as i64,
+            Expr::Binary(BinOp::And, l, r) => (l.eval(cpu) != 0 && r.eval(cpu) != 0) as i64,
+            Expr::Binary(op, l, r) => {
+                let (l, r) = (l.eval(cpu), r.eval(cpu));
+                match op {
+                    BinOp::BitOr => l | r,
+                    BinOp::BitXor => l ^ r,
+                    BinOp::BitAnd => l & r,
+                    BinOp::Eq => (l == r) as i64,
+                    BinOp::Ne => (l != r) as i64,
+                    BinOp::Lt => (l < r) as i64,
+                    BinOp::Le => (l <= r) as i64,
+                    BinOp::Gt => (l > r) as i64,
+                    BinOp::Ge => (l >= r) as i64,
+                    BinOp::Shl => l.wrapping_shl(r as u32),
+                    BinOp::Shr => l.wrapping_shr(r as u32),
+                    BinOp::Add => l.wrapping_add(r),
+                    BinOp::Sub => l.wrapping_sub(r),
+                    BinOp::Mul => l.wrapping_mul(r),
+                    BinOp::Div => l.checked_div(r).unwrap_or(0),
+                    BinOp::Rem => l.checked_rem(r).unwrap_or(0),
+                    BinOp::Or | BinOp::And => unreachable!(),
+                }
+            }
+        }
+    }
+}
+
+/// A log message where every `{expr}` is replaced by the value of `expr`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LogMessage {
+    parts: Vec<LogPart>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum LogPart {
+    Text(String),
+    Expr(Expr),
+}
+
+impl LogMessage {
+    pub fn parse(s: &str, symbols: &SymbolTable) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| anyhow!("Unclosed `{{` in the log message"))?;
+            parts.push(LogPart::Text(rest[..start].to_string()));
+            parts.push(LogPart::Expr(Expr::parse(
+                &rest[start + 1..start + end],
+                symbols,
+            )?));
+            rest = &rest[start + end + 1..];
+        }
+        parts.push(LogPart::Text(rest.to_string()));
+        Ok(Self { parts })
+    }
+
+    pub fn format(&self, cpu: &Cpu) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                LogPart::Text(s) => s.clone(),
+                LogPart::Expr(e) => format!("{:#x}", e.eval(cpu)),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(s: &str, cpu: &Cpu) -> i64 {
+        Expr::parse(s, &cpu.symbols).unwrap().eval(cpu)
+    }
+
+    #[test]
+    fn test_expressions() {
+        let mut cpu = Cpu::from_raw(vec![0x00, 0x05, 0x34, 0x12]);
+        cpu.reg.a = 0x1a;
+        cpu.reg.h = 0;
+        cpu.reg.l = 1;
+        cpu.reg.set_carry(true);
+        cpu.symbols.insert("TABLE", 2);
+
+        assert_eq!(eval("A == 0x1A && [HL] > 3 && !CY", &cpu), 0);
+        assert_eq!(eval("A == 0x1A && [HL] > 3 && CY", &cpu), 1);
+        assert_eq!(eval("1 + 2 * 3", &cpu), 7);
+        assert_eq!(eval("(1 + 2) * 3", &cpu), 9);
+        assert_eq!(eval("1 << 4 | 1", &cpu), 17);
+        assert_eq!(eval("W[TABLE]", &cpu), 0x1234);
+        assert_eq!(eval("w[table] >> 8 == 12h", &cpu), 1);
+        assert_eq!(eval("[100] + 1", &cpu), 1);
+        assert_eq!(eval("-1 + ~0 + !0", &cpu), -1);
+        assert_eq!(eval("Z || P || 7 / 0", &cpu), 0);
+        assert_eq!(eval("a - 1ah", &cpu), 0);
+
+        for bad in ["", "A ==", "(A", "[HL", "nope", "A # 2", "A B"] {
+            assert!(Expr::parse(bad, &cpu.symbols).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_log_message() {
+        let mut cpu = Cpu::from_raw(vec![0x42]);
+        cpu.reg.b = 3;
+        let log = LogMessage::parse("b={B} mem={[0]}!", &cpu.symbols).unwrap();
+        assert_eq!(log.format(&cpu), "b=0x3 mem=0x42!");
+        assert!(LogMessage::parse("oops {B", &cpu.symbols).is_err());
+    }
+}
@@ -0,0 +1,520 @@
+//! GDB remote serial protocol stub.
+//!
+//! The registers exposed to GDB are described by [TARGET_XML]: the eight 8 bits
+//! registers followed by SP and PC. Stop replies use the usual signals:
+//! `SIGTRAP` for steps, breakpoints (`swbreak`) and watchpoints (`watch`),
+//! `SIGINT` when the client interrupts a `continue`, and `SIGILL` when the cpu
+//! faults. A HLT is reported as `SIGTRAP` with a `hlt` stop reason.
+
+use super::{Condition, Debugger, Register, Stop};
+use anyhow::{bail, Result};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::ops::Range;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+/// number of instructions executed between two checks for an interrupt
+const INTERRUPT_POLL: usize = 1024;
+
+/// order of the registers in the `g` and `p` packets
+const REGISTERS: [Register; 10] = [
+    Register::A,
+    Register::Flags,
+    Register::B,
+    Register::C,
+    Register::D,
+    Register::E,
+    Register::H,
+    Register::L,
+    Register::SP,
+    Register::PC,
+];
+
+pub const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.rust-8080.cpu">
+    <reg name="a" bitsize="8" regnum="0"/>
+    <reg name="f" bitsize="8"/>
+    <reg name="b" bitsize="8"/>
+    <reg name="c" bitsize="8"/>
+    <reg name="d" bitsize="8"/>
+    <reg name="e" bitsize="8"/>
+    <reg name="h" bitsize="8"/>
+    <reg name="l" bitsize="8"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+/// A connection to a GDB client
+pub struct GdbServer<'a> {
+    dbg: &'a mut Debugger,
+    stream: TcpStream,
+    /// bytes received but not consumed yet
+    buffer: Vec<u8>,
+    no_ack: bool,
+    last_stop: String,
+}
+
+/// Wait for a GDB client on `listener` and serve it until it detaches
+pub fn serve(dbg: &mut Debugger, listener: &TcpListener) -> Result<()> {
+    let (stream, _) = listener.accept()?;
+    GdbServer::new(dbg, stream).run()
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Result<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        bail!("Odd number of hex digits");
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&s[i..i + 2], 16)?))
+        .collect()
+}
+
+/// parse the `addr,len` part of the `m`, `M`, `Z` and `z` packets
+fn addr_len(s: &str) -> Result<(usize, usize)> {
+    let mut parts = s.splitn(2, ',');
+    let addr = usize::from_str_radix(parts.next().unwrap_or(""), 16)?;
+    let len = usize::from_str_radix(parts.next().unwrap_or(""), 16)?;
+    Ok((addr, len))
+}
+
+impl<'a> GdbServer<'a> {
+    pub fn new(dbg: &'a mut Debugger, stream: TcpStream) -> Self {
+        Self {
+            dbg,
+            stream,
+            buffer: Vec::new(),
+            no_ack: false,
+            last_stop: format!("S{:02x}", SIGTRAP),
+        }
+    }
+
+    /// serve packets until the client detaches, kills the target or disconnects
+    pub fn run(mut self) -> Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            let reply = match packet.as_str() {
+                "D" | "k" => {
+                    self.send("OK")?;
+                    return Ok(());
+                }
+                p => self.handle(p),
+            };
+            match reply {
+                Ok(reply) => self.send(&reply)?,
+                Err(_) => self.send("E01")?,
+            }
+            // the OK itself is still acknowledged by the client
+            if packet == "QStartNoAckMode" {
+                self.no_ack = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn fill_buffer(&mut self) -> Result<bool> {
+        let mut buf = [0; 1024];
+        let n = self.stream.read(&mut buf)?;
+        self.buffer.extend_from_slice(&buf[..n]);
+        Ok(n != 0)
+    }
+
+    /// read the next packet, acknowledging it, or None when the client is gone
+    fn read_packet(&mut self) -> Result<Option<String>> {
+        loop {
+            // drop everything before the start of a packet: acks and stray interrupts
+            match self.buffer.iter().position(|&b| b == b'$') {
+                Some(start) => {
+                    self.buffer.drain(..start);
+                }
+                None => self.buffer.clear(),
+            }
+
+            if let Some(end) = self.buffer.iter().position(|&b| b == b'#') {
+                if self.buffer.len() >= end + 3 {
+                    let packet: Vec<u8> = self.buffer.drain(..end + 3).collect();
+                    let data = &packet[1..end];
+                    let sum = std::str::from_utf8(&packet[end + 1..])
+                        .ok()
+                        .and_then(|s| u8::from_str_radix(s, 16).ok());
+
+                    if !self.no_ack {
+                        if sum != Some(checksum(data)) {
+                            self.stream.write_all(b"-")?;
+                            continue;
+                        }
+                        self.stream.write_all(b"+")?;
+                    }
+                    return Ok(Some(String::from_utf8_lossy(data).into_owned()));
+                }
+            }
+
+            if !self.fill_buffer()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())?;
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    /// true if the client sent a `^C` while the target was running
+    fn interrupted(&mut self) -> Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let res = match self.fill_buffer() {
+            Ok(_) => Ok(()),
+            Err(e) => match e.downcast_ref::<std::io::Error>() {
+                Some(io) if io.kind() == ErrorKind::WouldBlock => Ok(()),
+                _ => Err(e),
+            },
+        };
+        self.stream.set_nonblocking(false)?;
+        res?;
+
+        match self.buffer.iter().position(|&b| b == 0x03) {
+            Some(pos) => {
+                self.buffer.remove(pos);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn stop_reply(&mut self, stop: Stop) -> String {
+        let reply = match stop {
+            Stop::Step => format!("T{:02x}", SIGTRAP),
+            Stop::Breakpoint(_) => format!("T{:02x}swbreak:;", SIGTRAP),
+            Stop::Watchpoint { addr, .. } => format!("T{:02x}watch:{:x};", SIGTRAP, addr),
+            Stop::Halt => format!("T{:02x}hlt:;", SIGTRAP),
+            Stop::Fault(_) => format!("T{:02x}", SIGILL),
+        };
+        self.last_stop = reply.clone();
+        reply
+    }
+
+    fn resume_at(&mut self, args: &str) -> Result<()> {
+        if !args.is_empty() {
+            self.dbg.cpu.pc = usize::from_str_radix(args, 16)?;
+        }
+        Ok(())
+    }
+
+    fn cont(&mut self) -> Result<String> {
+        loop {
+            let mut steps = 0;
+            let stop = self.dbg.run_until(|_| {
+                steps += 1;
+                steps == INTERRUPT_POLL
+            });
+            if stop != Stop::Step {
+                return Ok(self.stop_reply(stop));
+            }
+            if self.interrupted()? {
+                self.last_stop = format!("T{:02x}", SIGINT);
+                return Ok(self.last_stop.clone());
+            }
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        REGISTERS
+            .iter()
+            .map(|r| self.register(*r))
+            .collect::<Vec<_>>()
+            .concat()
+    }
+
+    fn register(&self, r: Register) -> String {
+        let value = r.get(&self.dbg.cpu);
+        if r.is_pair() {
+            hex(&value.to_le_bytes())
+        } else {
+            hex(&[value as u8])
+        }
+    }
+
+    fn write_register(&mut self, r: Register, bytes: &[u8]) -> Result<()> {
+        match *bytes {
+            [low] if !r.is_pair() => r.set(&mut self.dbg.cpu, low as u16),
+            [low, high] if r.is_pair() => r.set(&mut self.dbg.cpu, u16::from_le_bytes([low, high])),
+            _ => bail!("Invalid size for register {}", r),
+        }
+        Ok(())
+    }
+
+    /// the `len` bytes at `addr`, which must all be in the memory
+    fn range(&self, addr: usize, len: usize) -> Result<Range<usize>> {
+        match addr.checked_add(len) {
+            Some(end) if end <= self.dbg.cpu.ram.len() => Ok(addr..end),
+            _ => bail!("{:#06x} is out of memory", addr),
+        }
+    }
+
+    fn read_memory(&self, addr: usize, len: usize) -> Result<String> {
+        let ram = &self.dbg.cpu.ram;
+        if addr >= ram.len() {
+            bail!("{:#06x} is out of memory", addr);
+        }
+        // a read past the end of the memory is cut short
+        Ok(hex(&ram[addr..addr.saturating_add(len).min(ram.len())]))
+    }
+
+    fn write_memory(&mut self, addr: usize, bytes: &[u8]) -> Result<()> {
+        let range = self.range(addr, bytes.len())?;
+        self.dbg.cpu.ram[range].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn xfer_features(&self, args: &str) -> Result<String> {
+        let (annex, range) = match args.split_once(':') {
+            Some(parts) => parts,
+            None => bail!("Malformed qXfer packet"),
+        };
+        if annex != "target.xml" {
+            bail!("Unknown annex {}", annex);
+        }
+        let (offset, len) = addr_len(range)?;
+        let xml = TARGET_XML.as_bytes();
+        let start = offset.min(xml.len());
+        let end = offset.saturating_add(len).min(xml.len());
+        let kind = if end == xml.len() { 'l' } else { 'm' };
+        Ok(format!(
+            "{}{}",
+            kind,
+            String::from_utf8_lossy(&xml[start..end])
+        ))
+    }
+
+    fn set_breakpoint(&mut self, args: &str, insert: bool) -> Result<String> {
+        let (kind, rest) = match args.split_once(',') {
+            Some(parts) => parts,
+            None => bail!("Malformed breakpoint packet"),
+        };
+        let (addr, len) = addr_len(rest)?;
+        match (kind, insert) {
+            // software and hardware breakpoints are the same thing for us
+            ("0" | "1", true) => {
+                self.dbg.breakpoints.insert(addr, Condition::default());
+            }
+            ("0" | "1", false) => {
+                self.dbg.breakpoints.remove(&addr);
+            }
+            ("2", true) => {
+                for addr in self.range(addr, len.max(1))? {
+                    self.dbg.watch(addr, Condition::default())?;
+                }
+            }
+            ("2", false) => {
+                for addr in self.range(addr, len.max(1))? {
+                    self.dbg.watchpoints.remove(&addr);
+                }
+            }
+            // read and access watchpoints are not supported
+            _ => return Ok(String::new()),
+        }
+        Ok("OK".to_string())
+    }
+
+    /// answer a single packet, an empty reply means the packet is not supported
+    fn handle(&mut self, packet: &str) -> Result<String> {
+        let (cmd, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+
+        Ok(match cmd {
+            "?" => self.last_stop.clone(),
+            "g" => self.read_registers(),
+            "G" => {
+                let mut bytes = &unhex(args)?[..];
+                for r in REGISTERS.iter() {
+                    let size = if r.is_pair() { 2 } else { 1 };
+                    if bytes.len() < size {
+                        break;
+                    }
+                    self.write_register(*r, &bytes[..size])?;
+                    bytes = &bytes[size..];
+                }
+                "OK".to_string()
+            }
+            "p" => {
+                let n = usize::from_str_radix(args, 16)?;
+                match REGISTERS.get(n) {
+                    Some(r) => self.register(*r),
+                    None => bail!("Unknown register {}", n),
+                }
+            }
+            "P" => {
+                let (n, value) = match args.split_once('=') {
+                    Some(parts) => parts,
+                    None => bail!("Malformed P packet"),
+                };
+                let n = usize::from_str_radix(n, 16)?;
+                match REGISTERS.get(n) {
+                    Some(r) => self.write_register(*r, &unhex(value)?)?,
+                    None => bail!("Unknown register {}", n),
+                }
+                "OK".to_string()
+            }
+            "m" => {
+                let (addr, len) = addr_len(args)?;
+                self.read_memory(addr, len)?
+            }
+            "M" => {
+                let (range, data) = match args.split_once(':') {
+                    Some(parts) => parts,
+                    None => bail!("Malformed M packet"),
+                };
+                let (addr, _) = addr_len(range)?;
+                self.write_memory(addr, &unhex(data)?)?;
+                "OK".to_string()
+            }
+            "s" => {
+                self.resume_at(args)?;
+                let stop = self.dbg.step();
+                self.stop_reply(stop)
+            }
+            "c" => {
+                self.resume_at(args)?;
+                self.cont()?
+            }
+            "Z" => self.set_breakpoint(args, true)?,
+            "z" => self.set_breakpoint(args, false)?,
+            "H" => "OK".to_string(),
+            "q" | "Q" => match packet.split_once(':').unwrap_or((packet, "")) {
+                ("qSupported", _) => {
+                    "PacketSize=4000;qXfer:features:read+;swbreak+;QStartNoAckMode+".to_string()
+                }
+                ("QStartNoAckMode", _) => "OK".to_string(),
+                ("qXfer", args) => match args.strip_prefix("features:read:") {
+                    Some(args) => self.xfer_features(args)?,
+                    None => String::new(),
+                },
+                ("qAttached", _) => "1".to_string(),
+                ("qC", _) => "QC1".to_string(),
+                ("qfThreadInfo", _) => "m1".to_string(),
+                ("qsThreadInfo", _) => "l".to_string(),
+                _ => String::new(),
+            },
+            _ => String::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cpu;
+    use std::io::{BufRead, BufReader};
+
+    /// minimal GDB client, reading the replies one packet at a time
+    struct Client {
+        stream: TcpStream,
+        reader: BufReader<TcpStream>,
+    }
+
+    impl Client {
+        fn send(&mut self, data: &str) -> String {
+            let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+            self.stream.write_all(packet.as_bytes()).unwrap();
+            self.reply()
+        }
+
+        fn reply(&mut self) -> String {
+            let mut packet = Vec::new();
+            self.reader.read_until(b'#', &mut packet).unwrap();
+            let mut sum = [0; 2];
+            self.reader.read_exact(&mut sum).unwrap();
+            let start = packet.iter().position(|&b| b == b'$').unwrap();
+            let data = &packet[start + 1..packet.len() - 1];
+            let sum = u8::from_str_radix(std::str::from_utf8(&sum).unwrap(), 16).unwrap();
+            assert_eq!(sum, checksum(data));
+            String::from_utf8(data.to_vec()).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_gdb_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = std::thread::spawn(move || {
+            let mut ram = vec![
+                0x3e, 0x01, //       MVI  A, 1
+                0x3c, //             INR  A
+                0x32, 0x20, 0x00, // STA  0x0020
+                0xc3, 0x02, 0x00, // JMP  0x0002
+            ];
+            ram.resize(0x30, 0);
+            let mut dbg = Debugger::new(Cpu::from_raw(ram));
+            serve(&mut dbg, &listener).unwrap();
+            dbg.cpu.ram[0]
+        });
+
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut client = Client {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            stream,
+        };
+
+        assert!(client
+            .send("qSupported:swbreak+")
+            .contains("qXfer:features:read+"));
+        let xml = client.send("qXfer:features:read:target.xml:0,fff");
+        assert!(xml.starts_with('l'));
+        assert!(xml.contains(r#"<reg name="pc" bitsize="16""#));
+        assert_eq!(client.send("?"), "S05");
+
+        // a f b c d e h l, sp and pc are little endian
+        client.send("P2=42");
+        assert_eq!(client.send("g"), "000042000000000000000000");
+        assert_eq!(client.send("m0,3"), "3e013c");
+        assert_eq!(client.send("s"), "T05");
+        assert_eq!(client.send("p9"), "0200");
+        assert_eq!(client.send("p0"), "01");
+
+        assert_eq!(client.send("Z0,3,1"), "OK");
+        assert_eq!(client.send("c"), "T05swbreak:;");
+        assert_eq!(client.send("p9"), "0300");
+        assert_eq!(client.send("z0,3,1"), "OK");
+
+        assert_eq!(client.send("Z2,20,1"), "OK");
+        assert_eq!(client.send("c"), "T05watch:20;");
+        assert_eq!(client.send("m20,1"), "02");
+        assert_eq!(client.send("z2,20,1"), "OK");
+
+        // the program loops forever, interrupt it
+        client.stream.write_all(b"$c#63").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        client.stream.write_all(&[0x03]).unwrap();
+        assert_eq!(client.reply(), "T02");
+
+        assert_eq!(client.send("M0,1:76"), "OK");
+        assert_eq!(client.send("c0"), "T05hlt:;");
+        assert_eq!(client.send("m0,1"), "76");
+        assert!(client.send("m100,1").starts_with('E'));
+        assert_eq!(client.send("m2f,ffffffffffffffff"), "00");
+        assert!(client.send("M2f,2:0000").starts_with('E'));
+        assert!(client.send("Z2,ffffffffffffffff,2").starts_with('E'));
+        assert!(client.send("z2,20,ffffffffffffffff").starts_with('E'));
+        assert_eq!(client.send("vMustReplyEmpty"), "");
+        assert_eq!(client.send("D"), "OK");
+
+        assert_eq!(server.join().unwrap(), 0x76);
+    }
+}
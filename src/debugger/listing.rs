@@ -0,0 +1,118 @@
+use std::collections::BTreeMap;
+
+/// Map between the lines of an assembler listing (`.PRN` file) and the
+/// addresses of the code they generated.
+///
+/// The listing has fixed columns: the address in hexadecimal, a space, the
+/// object bytes and the source from the column 16. The Digital Research ASM
+/// output has an error flag column before the address, MAC and our assembler
+/// have none:
+/// ```text
+///  0100 0E09      START:  MVI     C,9
+///  0005 =         BDOS    EQU     5
+/// D0102 C30000            JMP     NOWHERE
+/// ```
+/// Lines with an `=` after the address define a symbol and generate nothing,
+/// the lines without source continue the bytes of the previous line.
+#[derive(Clone, Debug, Default)]
+pub struct Listing {
+    /// line number (starting at 1) to address
+    lines: BTreeMap<usize, usize>,
+}
+
+/// the column of the source
+const SOURCE: usize = 16;
+
+fn is_hex(s: &str) -> bool {
+    s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// the address of a line generating code
+fn code_addr(line: &str) -> Option<usize> {
+    // the address comes after the error flag, if there is one
+    let start = (0..2)
+        .find(|&i| line.get(i..i + 4).is_some_and(is_hex) && line.get(i + 4..i + 5) == Some(" "))?;
+    let bytes = line
+        .get(start + 5..SOURCE)
+        .or_else(|| line.get(start + 5..))?
+        .trim_end();
+    let source = line.get(SOURCE..).unwrap_or_default();
+    if bytes.is_empty() || bytes.len() % 2 != 0 || !is_hex(bytes) || source.trim().is_empty() {
+        return None;
+    }
+    usize::from_str_radix(&line[start..start + 4], 16).ok()
+}
+
+impl Listing {
+    pub fn parse(listing: &str) -> Self {
+        let mut lines = BTreeMap::new();
+
+        for (n, line) in listing.lines().enumerate() {
+            if let Some(addr) = code_addr(line) {
+                lines.insert(n + 1, addr);
+            }
+        }
+        Self { lines }
+    }
+
+    /// the first line generating code at or after `line`, with its address
+    pub fn line_addr(&self, line: usize) -> Option<(usize, usize)> {
+        self.lines
+            .range(line..)
+            .next()
+            .map(|(&line, &addr)| (line, addr))
+    }
+
+    /// the line that generated the code at `addr`
+    pub fn addr_line(&self, addr: usize) -> Option<usize> {
+        self.lines
+            .iter()
+            .find(|(_, &a)| a == addr)
+            .map(|(&line, _)| line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listing() {
+        let listing = Listing::parse(
+            "\
+ 0005 =         BDOS    EQU     5
+ 0100                   ORG     100H
+ 0100 0E09      START:  MVI     C,9
+ 0102 110901            LXI     D,MSG
+                ; comment
+ 0105 C30500            JMP     BDOS
+ 0108 48492400  MSG:    DB      'HI$'",
+        );
+        assert_eq!(listing.line_addr(1), Some((3, 0x100)));
+        assert_eq!(listing.line_addr(5), Some((6, 0x105)));
+        assert_eq!(listing.line_addr(8), None);
+        assert_eq!(listing.addr_line(0x102), Some(4));
+        assert_eq!(listing.addr_line(0x103), None);
+    }
+
+    #[test]
+    fn test_listing_columns() {
+        let listing = Listing::parse(
+            "\
+0100            ADD\tB
+0100 87         +\tADD\tB
+D0101 C30000            JMP     NOWHERE
+0104            DAA
+0104 27          CC:\tDAA
+0105 48656C6C6F \tDB\t'Hello'
+010A 2C
+E0100 =         CC      EQU     100H",
+        );
+        assert_eq!(listing.line_addr(1), Some((2, 0x100)));
+        assert_eq!(listing.addr_line(0x101), Some(3));
+        assert_eq!(listing.line_addr(4), Some((5, 0x104)));
+        assert_eq!(listing.addr_line(0x105), Some(6));
+        assert_eq!(listing.addr_line(0x10a), None);
+        assert_eq!(listing.line_addr(7), None);
+    }
+}
@@ -0,0 +1,191 @@
+use crate::number::parse_number;
+use crate::Cpu;
+use anyhow::{anyhow, bail, Result};
+
+/// Every register the debugger can name, including the 16 bits pairs
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Register {
+    A,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    Flags,
+    BC,
+    DE,
+    HL,
+    PSW,
+    SP,
+    PC,
+}
+
+impl std::str::FromStr for Register {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        use Register::*;
+
+        Ok(match s.to_ascii_uppercase().as_str() {
+            "A" => A,
+            "B" => B,
+            "C" => C,
+            "D" => D,
+            "E" => E,
+            "H" => H,
+            "L" => L,
+            "F" | "FLAGS" => Flags,
+            "BC" => BC,
+            "DE" => DE,
+            "HL" => HL,
+            "PSW" | "AF" => PSW,
+            "SP" => SP,
+            "PC" => PC,
+            _ => bail!("Unknown register {}", s),
+        })
+    }
+}
+
+impl std::fmt::Display for Register {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            Register::Flags => "F".to_string(),
+            r => format!("{:?}", r),
+        };
+        f.write_str(&name)
+    }
+}
+
+impl Register {
+    /// read the register from the cpu
+    pub fn get(self, cpu: &Cpu) -> u16 {
+        use Register::*;
+
+        match self {
+            A => cpu.reg.a as u16,
+            B => cpu.reg.b as u16,
+            C => cpu.reg.c as u16,
+            D => cpu.reg.d as u16,
+            E => cpu.reg.e as u16,
+            H => cpu.reg.h as u16,
+            L => cpu.reg.l as u16,
+            Flags => cpu.reg.flags as u16,
+            BC => cpu.reg.bc(),
+            DE => cpu.reg.de(),
+            HL => cpu.reg.hl(),
+            PSW => ((cpu.reg.a as u16) << 8) | cpu.reg.flags as u16,
+            SP => cpu.sp,
+            PC => cpu.pc as u16,
+        }
+    }
+
+    /// write the register in the cpu, 8 bits registers only keep the low byte
+    pub fn set(self, cpu: &mut Cpu, value: u16) {
+        use Register::*;
+
+        let (high, low) = ((value >> 8) as u8, value as u8);
+        match self {
+            A => cpu.reg.a = low,
+            B => cpu.reg.b = low,
+            C => cpu.reg.c = low,
+            D => cpu.reg.d = low,
+            E => cpu.reg.e = low,
+            H => cpu.reg.h = low,
+            L => cpu.reg.l = low,
+            Flags => {
+                cpu.reg.flags = low;
+                cpu.reg.fix_flags();
+            }
+            BC => cpu.reg.bc_set(value),
+            DE => cpu.reg.de_set(value),
+            HL => {
+                cpu.reg.h = high;
+                cpu.reg.l = low;
+            }
+            PSW => {
+                cpu.reg.a = high;
+                cpu.reg.flags = low;
+                cpu.reg.fix_flags();
+            }
+            SP => cpu.sp = value,
+            PC => cpu.pc = value as usize,
+        }
+    }
+
+    /// true for the registers holding 16 bits
+    pub fn is_pair(self) -> bool {
+        use Register::*;
+        matches!(self, BC | DE | HL | PSW | SP | PC)
+    }
+}
+
+/// A command argument, either a number, a register or a symbol evaluated when
+/// the command is executed
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Value {
+    Number(u16),
+    Register(Register),
+    Symbol(String),
+}
+
+impl std::str::FromStr for Value {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Ok(r) = s.parse() {
+            return Ok(Value::Register(r));
+        }
+        match parse_number(s) {
+            Ok(n) => Ok(Value::Number(n)),
+            Err(_) if is_symbol(s) => Ok(Value::Symbol(s.to_string())),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// true if `s` can be the name of a symbol
+pub fn is_symbol(s: &str) -> bool {
+    let mut chars = s.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || "_?@.$".contains(c))
+        && chars.all(|c| c.is_ascii_alphanumeric() || "_?@.$".contains(c))
+}
+
+impl Value {
+    pub fn resolve(&self, cpu: &Cpu) -> Result<u16> {
+        match self {
+            Value::Number(n) => Ok(*n),
+            Value::Register(r) => Ok(r.get(cpu)),
+            Value::Symbol(name) => cpu
+                .symbols
+                .addr(name)
+                .ok_or_else(|| anyhow!("Unknown symbol {}", name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_value() {
+        let mut cpu = Cpu::from_raw(vec![0]);
+        "HL".parse::<Register>().unwrap().set(&mut cpu, 0x1234);
+        assert_eq!(cpu.reg.h, 0x12);
+        assert_eq!(cpu.reg.l, 0x34);
+
+        let v: Value = "hl".parse().unwrap();
+        assert_eq!(v.resolve(&cpu).unwrap(), 0x1234);
+        let v: Value = "0x10".parse().unwrap();
+        assert_eq!(v, Value::Number(0x10));
+        // `b` is a register and not a binary number
+        assert_eq!("b".parse::<Value>().unwrap(), Value::Register(Register::B));
+
+        let v: Value = "print".parse().unwrap();
+        assert!(v.resolve(&cpu).is_err());
+        cpu.symbols.insert("PRINT", 0x109);
+        assert_eq!(v.resolve(&cpu).unwrap(), 0x109);
+        assert!("2print".parse::<Value>().is_err());
+    }
+}
@@ -1,87 +1,581 @@
-use bitmatch::bitmatch;
-
-#[bitmatch]
-pub fn instr(opcode: &[u8]) -> String {
-    #[bitmatch]
-    match opcode[0] {
-        "0000_0000" => "NOP".to_string(),
-        "1100_0011" => format!("JMP\t{}", addr(opcode)),
-        "11cc_c010" => format!("{}\t{}", cond_jmp(c), addr(opcode)),
-        "1100_1101" => format!("CALL\t{}", addr(opcode)),
-        "1100_1001" => "RET".to_string(),
+//! Disassembler.
+//!
+//! The output is configured by a [Format]:
+//! - [Syntax::Att] is the historical notation of this crate, with `%` before
+//!   the registers, like `MVI %A 0x01`
+//! - [Syntax::Intel] uses the standard Intel mnemonics, like `MVI A,01H`
+//! - [Syntax::Zilog] uses the Z80 mnemonics for the 8080 instructions, like
+//!   `LD A,01H`
+//!
+//! The numbers are written in hexadecimal, octal or decimal following the
+//! conventions of each syntax, and the ANSI colours can be turned off.
+
+pub mod analysis;
+pub mod cfg;
+pub mod cpm;
+pub mod ir;
+pub mod pseudo;
+pub mod traversal;
+
+use crate::instruction::{self, Instruction, Reg};
+use crate::symbols::SymbolTable;
+use anyhow::{bail, Result};
+use std::ops::Range;
+
+/// An instruction disassembled by [range]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Line {
+    pub addr: usize,
+    /// the bytes of the instruction, their number is its length
+    pub bytes: Vec<u8>,
+    pub text: String,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Syntax {
+    /// `MVI %A 0x01`
+    Att,
+    /// `MVI A,01H`
+    Intel,
+    /// `LD A,01H`
+    Zilog,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Radix {
+    Hex,
+    Octal,
+    Decimal,
+}
+
+/// How the instructions are written
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Format {
+    /// highlight the operands with ANSI escape codes
+    pub color: bool,
+    pub syntax: Syntax,
+    pub radix: Radix,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Self {
+            color: true,
+            syntax: Syntax::Att,
+            radix: Radix::Hex,
+        }
+    }
+}
+
+impl Format {
+    /// Intel mnemonics in hexadecimal, without colours
+    pub fn plain() -> Self {
+        Self {
+            color: false,
+            syntax: Syntax::Intel,
+            radix: Radix::Hex,
+        }
+    }
+}
+
+impl std::str::FromStr for Syntax {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "att" => Syntax::Att,
+            "intel" => Syntax::Intel,
+            "zilog" | "z80" => Syntax::Zilog,
+            _ => bail!("Unknown syntax {}, expected att, intel or zilog", s),
+        })
+    }
+}
+
+impl std::str::FromStr for Radix {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "hex" | "hexadecimal" => Radix::Hex,
+            "oct" | "octal" => Radix::Octal,
+            "dec" | "decimal" => Radix::Decimal,
+            _ => bail!("Unknown radix {}, expected hex, oct or dec", s),
+        })
+    }
+}
+
+/// An operand of an instruction, written differently by every syntax
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Operand {
+    /// B C D E H L M A
+    Reg(u8),
+    /// BC DE HL SP
+    Pair(u8),
+    /// BC DE HL PSW
+    PairPsw(u8),
+    /// the memory pointed by BC DE HL SP, only used by the Zilog syntax
+    Indirect(u8),
+    /// NZ Z NC C PO PE P M, only used by the Zilog syntax
+    Cond(u8),
+    Imm8(u8),
+    Imm16(u16),
+    /// the target of a jump or a call
+    Addr(u16),
+    /// a memory address read or written
+    Mem(u16),
+    Port(u8),
+    /// the number of a RST
+    Rst(u8),
+}
+
+/// decompile an instruction, returning its text and its length in bytes
+pub fn instr(opcode: &[u8]) -> (String, usize) {
+    instr_with_symbols(opcode, &SymbolTable::new())
+}
+
+/// length in bytes of the instruction starting with `opcode`, 1 for the
+/// undocumented opcodes
+pub fn len(opcode: u8) -> usize {
+    instruction::len(opcode)
+}
+
+/// decompile an instruction, replacing the addresses by their symbol names
+pub fn instr_with_symbols(opcode: &[u8], symbols: &SymbolTable) -> (String, usize) {
+    instr_with_format(opcode, symbols, &Format::default())
+}
+
+/// decompile an instruction in the given format, replacing the addresses by
+/// their symbol names.
+/// The undocumented opcodes and the instructions truncated by the end of
+/// `opcode` are shown as a `DB` of their first byte, of length 1.
+/// An empty `opcode` gives an empty text of length 0.
+pub fn instr_with_format(opcode: &[u8], symbols: &SymbolTable, format: &Format) -> (String, usize) {
+    let (mnemonic, operands) = match Instruction::decode(opcode) {
+        Ok(instruction) => operands(instruction),
+        Err(_) => match opcode.first() {
+            Some(&first) => db(first),
+            None => return (String::new(), 0),
+        },
+    };
+    let len = if mnemonic == "DB" { 1 } else { len(opcode[0]) };
+    (Writer { symbols, format }.instr(mnemonic, &operands), len)
+}
+
+/// the Intel mnemonic and the operands of an instruction
+fn operands(instruction: Instruction) -> (&'static str, Vec<Operand>) {
+    use Instruction::*;
+    use Operand::{Addr, Imm16, Imm8, Mem, Port};
+
+    let reg = |r: Reg| Operand::Reg(r as u8);
+    let pair = |p: instruction::Pair| Operand::Pair(p as u8);
+    match instruction {
+        Nop => ("NOP", vec![]),
+        Rlc => ("RLC", vec![]),
+        Rrc => ("RRC", vec![]),
+        Ral => ("RAL", vec![]),
+        Rar => ("RAR", vec![]),
+        Daa => ("DAA", vec![]),
+        Cma => ("CMA", vec![]),
+        Stc => ("STC", vec![]),
+        Cmc => ("CMC", vec![]),
+        Hlt => ("HLT", vec![]),
+        Ret => ("RET", vec![]),
+        Pchl => ("PCHL", vec![]),
+        Sphl => ("SPHL", vec![]),
+        Xthl => ("XTHL", vec![]),
+        Xchg => ("XCHG", vec![]),
+        Di => ("DI", vec![]),
+        Ei => ("EI", vec![]),
+        // jumps and calls
+        Jmp(addr) => ("JMP", vec![Addr(addr)]),
+        Jcc(c, addr) => (JUMPS[c as usize], vec![Addr(addr)]),
+        Call(addr) => ("CALL", vec![Addr(addr)]),
+        Ccc(c, addr) => (CALLS[c as usize], vec![Addr(addr)]),
+        Rcc(c) => (RETURNS[c as usize], vec![]),
+        Rst(n) => ("RST", vec![Operand::Rst(n)]),
         // ports
-        "1101_1011" => format!("IN\t{}", opcode[1]),
-        "1101_0011" => format!("OUT\t{}", opcode[1]),
+        In(port) => ("IN", vec![Port(port)]),
+        Out(port) => ("OUT", vec![Port(port)]),
         // register
-        "00rr_r101" => format!("DCR\t{}", reg(r)),
-        "00rr_r100" => format!("INR\t{}", reg(r)),
-        "1111_1110" => format!("CPI\t{:#04x}", opcode[1]),
-        "00rr_r110" => format!("MVI\t{}\t{:#04x}", reg(r), opcode[1]),
+        Dcr(r) => ("DCR", vec![reg(r)]),
+        Inr(r) => ("INR", vec![reg(r)]),
+        Mvi(r, d8) => ("MVI", vec![reg(r), Imm8(d8)]),
+        Mov(dst, src) => ("MOV", vec![reg(dst), reg(src)]),
+        Alu(op, r) => (ALU[op as usize], vec![reg(r)]),
+        AluImm(op, d8) => (ALU_IMMEDIATE[op as usize], vec![Imm8(d8)]),
         // register pair
-        "1111_1001" => format!("SPHL"),
-        "00rr_0001" => format!("LXI\t{}\t{}", regpair(r), d16(opcode)),
-        "0011_1010" => format!("LDA\t{}", addr(opcode)),
-        "0011_0010" => format!("STA\t{}", addr(opcode)),
-        "0010_1010" => format!("LHLD\t{}", addr(opcode)),
-        "0010_0010" => format!("SHLD\t{}", addr(opcode)),
-        "00rr_1010" => format!("LDAX\t{}", regpair(r)),
-        "00rr_0010" => format!("STAX\t{}", regpair(r)),
-        "00rr_1011" => format!("DCR\t{}", regpair(r)),
-        "00rr_0011" => format!("INX\t{}", regpair(r)),
-        "11rr_0101" => format!("PUSH\t{}", regpair(r)),
-        "11rr_0001" => format!("POP\t{}", regpair(r)),
-        // other
-        "0111_0110" => format!("HALT"),
-        "01aa_abbb" => format!("MOV\t{}\t{}", reg(a), reg(b)),
-        "aaaa_aaaa" => panic!("Instruction {0:#010b} {0:#04x} is not implemented", a),
-    }
-}
-
-fn cond_jmp(cond: u8) -> String {
-    let res = match cond {
-        0b000 => "JNZ",
-        0b001 => "JZ",
-        0b010 => "JNC",
-        0b011 => "JC",
-        0b100 => "JPO",
-        0b101 => "JPE",
-        0b110 => "JP",
-        0b111 => "JM",
-        c => panic!("cond_jmp called with invalid value: {:b}", c),
-    };
-    res.to_string()
+        Lxi(rp, d16) => ("LXI", vec![pair(rp), Imm16(d16)]),
+        Lda(addr) => ("LDA", vec![Mem(addr)]),
+        Sta(addr) => ("STA", vec![Mem(addr)]),
+        Lhld(addr) => ("LHLD", vec![Mem(addr)]),
+        Shld(addr) => ("SHLD", vec![Mem(addr)]),
+        Ldax(rp) => ("LDAX", vec![pair(rp)]),
+        Stax(rp) => ("STAX", vec![pair(rp)]),
+        Dad(rp) => ("DAD", vec![pair(rp)]),
+        Dcx(rp) => ("DCX", vec![pair(rp)]),
+        Inx(rp) => ("INX", vec![pair(rp)]),
+        Push(rp) => ("PUSH", vec![Operand::PairPsw(rp as u8)]),
+        Pop(rp) => ("POP", vec![Operand::PairPsw(rp as u8)]),
+        Undocumented(opcode) => db(opcode),
+    }
 }
 
-fn addr(opcode: &[u8]) -> String {
-    format!("\x1B[1;35m{:#04x}{:02x}\x1B[m", opcode[2], opcode[1])
+/// disassemble every instruction starting in `range`, the last one may end
+/// after it
+pub fn range(
+    memory: &[u8],
+    range: Range<usize>,
+    symbols: &SymbolTable,
+    format: &Format,
+) -> Vec<Line> {
+    let mut lines = Vec::new();
+    let mut addr = range.start;
+    while addr < range.end && addr < memory.len() {
+        let (text, len) = instr_with_format(&memory[addr..], symbols, format);
+        lines.push(Line {
+            addr,
+            bytes: memory[addr..addr + len].to_vec(),
+            text,
+        });
+        addr += len;
+    }
+    lines
 }
 
-fn d16(opcode: &[u8]) -> String {
-    format!("\x1B[1;33m#${:#04x}{:02x}\x1B[m", opcode[2], opcode[1])
+fn db(byte: u8) -> (&'static str, Vec<Operand>) {
+    ("DB", vec![Operand::Imm8(byte)])
 }
 
-fn reg(r: u8) -> String {
-    let r = match r {
-        0x00 => "%B",
-        0x01 => "%C",
-        0x02 => "%D",
-        0x03 => "%E",
-        0x04 => "%H",
-        0x05 => "%L",
-        0x06 => "%M",
-        0x07 => "%A",
-        _ => panic!("Failed to decompile register {}", r),
+const JUMPS: [&str; 8] = ["JNZ", "JZ", "JNC", "JC", "JPO", "JPE", "JP", "JM"];
+const CALLS: [&str; 8] = ["CNZ", "CZ", "CNC", "CC", "CPO", "CPE", "CP", "CM"];
+const RETURNS: [&str; 8] = ["RNZ", "RZ", "RNC", "RC", "RPO", "RPE", "RP", "RM"];
+const ALU: [&str; 8] = ["ADD", "ADC", "SUB", "SBB", "ANA", "XRA", "ORA", "CMP"];
+const ALU_IMMEDIATE: [&str; 8] = ["ADI", "ACI", "SUI", "SBI", "ANI", "XRI", "ORI", "CPI"];
+const CONDITIONS: [&str; 8] = ["NZ", "Z", "NC", "C", "PO", "PE", "P", "M"];
+
+const REGISTERS: [&str; 8] = ["B", "C", "D", "E", "H", "L", "M", "A"];
+const PAIRS: [&str; 4] = ["B", "D", "H", "SP"];
+const ZILOG_PAIRS: [&str; 4] = ["BC", "DE", "HL", "SP"];
+
+const GREEN: &str = "\x1B[1;32m";
+const YELLOW: &str = "\x1B[1;33m";
+const MAGENTA: &str = "\x1B[1;35m";
+const CYAN: &str = "\x1B[1;36m";
+
+/// translate an Intel instruction to the Zilog mnemonics, where A and HL are
+/// explicit operands
+fn zilog(mnemonic: &'static str, ops: Vec<Operand>) -> (&'static str, Vec<Operand>) {
+    use Operand::*;
+
+    const A: Operand = Reg(7);
+    const HL: Operand = Pair(2);
+    let indirect = |op: Operand| match op {
+        Pair(r) => Indirect(r),
+        op => op,
     };
-    format!("\x1B[1;32m{}\x1B[m", r)
+    let with_a = |ops: Vec<Operand>| [vec![A], ops].concat();
+    let cond = |table: &[&str; 8]| table.iter().position(|m| *m == mnemonic);
+
+    match mnemonic {
+        "MOV" | "MVI" | "LXI" => ("LD", ops),
+        "LDA" | "LDAX" => ("LD", vec![A, indirect(ops[0])]),
+        "STA" | "STAX" => ("LD", vec![indirect(ops[0]), A]),
+        "LHLD" => ("LD", vec![HL, ops[0]]),
+        "SHLD" => ("LD", vec![ops[0], HL]),
+        "SPHL" => ("LD", vec![Pair(3), HL]),
+        "INR" | "INX" => ("INC", ops),
+        "DCR" | "DCX" => ("DEC", ops),
+        "DAD" => ("ADD", vec![HL, ops[0]]),
+        "ADD" | "ADI" => ("ADD", with_a(ops)),
+        "ADC" | "ACI" => ("ADC", with_a(ops)),
+        "SBB" | "SBI" => ("SBC", with_a(ops)),
+        "SUB" | "SUI" => ("SUB", ops),
+        "ANA" | "ANI" => ("AND", ops),
+        "XRA" | "XRI" => ("XOR", ops),
+        "ORA" | "ORI" => ("OR", ops),
+        "CMP" | "CPI" => ("CP", ops),
+        "RLC" => ("RLCA", ops),
+        "RRC" => ("RRCA", ops),
+        "RAL" => ("RLA", ops),
+        "RAR" => ("RRA", ops),
+        "CMA" => ("CPL", ops),
+        "STC" => ("SCF", ops),
+        "CMC" => ("CCF", ops),
+        "HLT" => ("HALT", ops),
+        "JMP" => ("JP", ops),
+        "PCHL" => ("JP", vec![Indirect(2)]),
+        "XTHL" => ("EX", vec![Indirect(3), HL]),
+        "XCHG" => ("EX", vec![Pair(1), HL]),
+        "IN" => ("IN", with_a(ops)),
+        "OUT" => ("OUT", vec![ops[0], A]),
+        _ => {
+            if let Some(c) = cond(&JUMPS) {
+                ("JP", [vec![Cond(c as u8)], ops].concat())
+            } else if let Some(c) = cond(&CALLS) {
+                ("CALL", [vec![Cond(c as u8)], ops].concat())
+            } else if let Some(c) = cond(&RETURNS) {
+                ("RET", vec![Cond(c as u8)])
+            } else {
+                (mnemonic, ops)
+            }
+        }
+    }
 }
 
-fn regpair(r: u8) -> String {
-    let r = match r {
-        0x00 => "%%BC",
-        0x01 => "%%DE",
-        0x02 => "%%HL",
-        0x03 => "SP",
-        _ => panic!("Failed to decompile register pair {}", r),
-    };
-    format!("\x1B[1;36m{}\x1B[m", r)
+/// write the instructions in a format
+struct Writer<'a> {
+    symbols: &'a SymbolTable,
+    format: &'a Format,
+}
+
+impl Writer<'_> {
+    fn color(&self, color: &str, text: String) -> String {
+        if self.format.color {
+            format!("{}{}\x1B[m", color, text)
+        } else {
+            text
+        }
+    }
+
+    /// write a number of `digits` hexadecimal digits in the radix of the format
+    fn number(&self, n: u16, digits: usize) -> String {
+        match (self.format.syntax, self.format.radix) {
+            (Syntax::Att, Radix::Hex) => format!("{:#0width$x}", n, width = digits + 2),
+            (Syntax::Att, Radix::Octal) => format!("{:#o}", n),
+            (_, Radix::Hex) => {
+                let hex = format!("{:0width$X}H", n, width = digits);
+                // a number can not start with a letter
+                if hex.starts_with(|c: char| c.is_ascii_alphabetic()) {
+                    format!("0{}", hex)
+                } else {
+                    hex
+                }
+            }
+            (_, Radix::Octal) => format!("{:o}Q", n),
+            (_, Radix::Decimal) => n.to_string(),
+        }
+    }
+
+    /// an address, replaced by its symbol when there is one
+    fn addr(&self, addr: u16) -> String {
+        match self.symbols.name(addr) {
+            Some(name) => name.to_string(),
+            None => self.number(addr, 4),
+        }
+    }
+
+    fn operand(&self, operand: Operand) -> String {
+        use Operand::*;
+        use Syntax::*;
+
+        match (self.format.syntax, operand) {
+            (Att, Reg(r)) => self.color(GREEN, format!("%{}", REGISTERS[r as usize])),
+            (Zilog, Reg(6)) => self.color(GREEN, "(HL)".to_string()),
+            (_, Reg(r)) => self.color(GREEN, REGISTERS[r as usize].to_string()),
+            (Att, PairPsw(3)) => self.color(CYAN, "%%PSW".to_string()),
+            (Intel, PairPsw(3)) => self.color(CYAN, "PSW".to_string()),
+            (Zilog, PairPsw(3)) => self.color(CYAN, "AF".to_string()),
+            (Att, Pair(r)) | (Att, PairPsw(r)) => {
+                self.color(CYAN, format!("%%{}", ZILOG_PAIRS[r as usize]))
+            }
+            (Intel, Pair(r)) | (Intel, PairPsw(r)) => {
+                self.color(CYAN, PAIRS[r as usize].to_string())
+            }
+            (_, Pair(r)) | (_, PairPsw(r)) => self.color(CYAN, ZILOG_PAIRS[r as usize].to_string()),
+            (_, Indirect(r)) => self.color(CYAN, format!("({})", ZILOG_PAIRS[r as usize])),
+            (_, Cond(c)) => CONDITIONS[c as usize].to_string(),
+            (Att, Imm16(n)) => match self.symbols.name(n) {
+                Some(name) => self.color(YELLOW, format!("#{}", name)),
+                None => self.color(YELLOW, format!("#${}", self.number(n, 4))),
+            },
+            (_, Imm16(n)) => self.color(YELLOW, self.addr(n)),
+            (_, Addr(addr)) => self.color(MAGENTA, self.addr(addr)),
+            (Zilog, Mem(addr)) => self.color(MAGENTA, format!("({})", self.addr(addr))),
+            (_, Mem(addr)) => self.color(MAGENTA, self.addr(addr)),
+            (Zilog, Port(p)) => format!("({})", self.number(p as u16, 2)),
+            (_, Imm8(n)) | (_, Port(n)) => self.number(n as u16, 2),
+            (Zilog, Rst(n)) => self.number(n as u16 * 8, 2),
+            (_, Rst(n)) => n.to_string(),
+        }
+    }
+
+    fn instr(&self, mnemonic: &'static str, operands: &[Operand]) -> String {
+        let (mnemonic, operands) = match (self.format.syntax, mnemonic) {
+            // the historical name of HLT in this crate
+            (Syntax::Att, "HLT") => ("HALT", vec![]),
+            (Syntax::Zilog, _) => zilog(mnemonic, operands.to_vec()),
+            _ => (mnemonic, operands.to_vec()),
+        };
+        let operands: Vec<String> = operands.into_iter().map(|o| self.operand(o)).collect();
+        let separator = match self.format.syntax {
+            Syntax::Att => "\t",
+            _ => ",",
+        };
+        if operands.is_empty() {
+            mnemonic.to_string()
+        } else {
+            format!("{}\t{}", mnemonic, operands.join(separator))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the text without the colors
+    fn plain(text: &str) -> String {
+        let mut plain = String::new();
+        let mut escape = false;
+        for c in text.chars() {
+            match c {
+                '\x1B' => escape = true,
+                'm' if escape => escape = false,
+                c if !escape => plain.push(c),
+                _ => (),
+            }
+        }
+        plain
+    }
+
+    #[test]
+    fn test_every_opcode() {
+        let mut undocumented = Vec::new();
+        for opcode in 0..=255u8 {
+            let bytes = [opcode, 0x34, 0x12];
+            let (text, len) = instr(&bytes);
+            assert_eq!(len, len_of(&text, opcode), "{:#04x}: {}", opcode, text);
+            if text.starts_with("DB") {
+                undocumented.push(opcode);
+            }
+        }
+        assert_eq!(
+            undocumented,
+            [0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38, 0xcb, 0xd9, 0xdd, 0xed, 0xfd]
+        );
+    }
+
+    /// the length an instruction should have from its text
+    fn len_of(text: &str, opcode: u8) -> usize {
+        let text = plain(text);
+        if text.contains("0x1234") || text.contains("$0x1234") {
+            3
+        } else if text.contains("0x34") {
+            2
+        } else {
+            assert!(!text.contains("0x12"), "{:#04x}: {}", opcode, text);
+            1
+        }
+    }
+
+    #[test]
+    fn test_fixed_instructions() {
+        let text = |bytes: &[u8]| plain(&instr(bytes).0);
+        assert_eq!(text(&[0x0b]), "DCX\t%%BC");
+        assert_eq!(text(&[0x3b]), "DCX\t%%SP");
+        assert_eq!(text(&[0xf5]), "PUSH\t%%PSW");
+        assert_eq!(text(&[0xf1]), "POP\t%%PSW");
+        assert_eq!(text(&[0x36, 0x42]), "MVI\t%M\t0x42");
+        assert_eq!(text(&[0xfe, 0x0a]), "CPI\t0x0a");
+        assert_eq!(text(&[0xd4, 0x05, 0x00]), "CNC\t0x0005");
+        assert_eq!(text(&[0xff]), "RST\t7");
+    }
+
+    #[test]
+    fn test_truncated() {
+        assert_eq!(plain(&instr(&[0xcd, 0x05]).0), "DB\t0xcd");
+        assert_eq!(instr(&[0xcd, 0x05]).1, 1);
+        assert_eq!(instr(&[0x3e]).1, 1);
+        assert_eq!(instr(&[]), (String::new(), 0));
+    }
+
+    #[test]
+    fn test_range() {
+        let memory = [0x3e, 0x01, 0xcd, 0x05, 0x00, 0x08, 0x76, 0xc3];
+        let lines = range(
+            &memory,
+            0..memory.len(),
+            &SymbolTable::new(),
+            &Format::default(),
+        );
+        let summary: Vec<(usize, usize)> = lines.iter().map(|l| (l.addr, l.bytes.len())).collect();
+        assert_eq!(summary, [(0, 2), (2, 3), (5, 1), (6, 1), (7, 1)]);
+        assert_eq!(plain(&lines[2].text), "DB\t0x08");
+        assert_eq!(lines[1].bytes, [0xcd, 0x05, 0x00]);
+
+        // the last instruction ends after the range
+        let lines = range(&memory, 0..3, &SymbolTable::new(), &Format::default());
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1].addr, 2);
+        assert_eq!(lines[1].bytes.len(), 3);
+    }
+
+    #[test]
+    fn test_syntaxes() {
+        let text = |bytes: &[u8], syntax, radix| {
+            let format = Format {
+                color: false,
+                syntax,
+                radix,
+            };
+            instr_with_format(bytes, &SymbolTable::new(), &format).0
+        };
+        let intel = |bytes: &[u8]| text(bytes, Syntax::Intel, Radix::Hex);
+        let zilog = |bytes: &[u8]| text(bytes, Syntax::Zilog, Radix::Hex);
+
+        assert_eq!(intel(&[0x3e, 0x01]), "MVI\tA,01H");
+        assert_eq!(intel(&[0xfe, 0xff]), "CPI\t0FFH");
+        assert_eq!(intel(&[0x21, 0x34, 0x12]), "LXI\tH,1234H");
+        assert_eq!(intel(&[0xf5]), "PUSH\tPSW");
+        assert_eq!(intel(&[0x76]), "HLT");
+        assert_eq!(intel(&[0xcf]), "RST\t1");
+
+        assert_eq!(zilog(&[0x3e, 0x01]), "LD\tA,01H");
+        assert_eq!(zilog(&[0x77]), "LD\t(HL),A");
+        assert_eq!(zilog(&[0x1a]), "LD\tA,(DE)");
+        assert_eq!(zilog(&[0x32, 0x00, 0x24]), "LD\t(2400H),A");
+        assert_eq!(zilog(&[0x2a, 0x00, 0x24]), "LD\tHL,(2400H)");
+        assert_eq!(zilog(&[0x09]), "ADD\tHL,BC");
+        assert_eq!(zilog(&[0x80]), "ADD\tA,B");
+        assert_eq!(zilog(&[0x90]), "SUB\tB");
+        assert_eq!(zilog(&[0xc2, 0x05, 0x00]), "JP\tNZ,0005H");
+        assert_eq!(zilog(&[0xc8]), "RET\tZ");
+        assert_eq!(zilog(&[0xe3]), "EX\t(SP),HL");
+        assert_eq!(zilog(&[0xe9]), "JP\t(HL)");
+        assert_eq!(zilog(&[0xdb, 0x01]), "IN\tA,(01H)");
+        assert_eq!(zilog(&[0xf1]), "POP\tAF");
+        assert_eq!(zilog(&[0xcf]), "RST\t08H");
+
+        assert_eq!(
+            text(&[0x3e, 0xff], Syntax::Intel, Radix::Octal),
+            "MVI\tA,377Q"
+        );
+        assert_eq!(
+            text(&[0x3e, 0xff], Syntax::Intel, Radix::Decimal),
+            "MVI\tA,255"
+        );
+        assert_eq!(
+            text(&[0x3e, 0x0a], Syntax::Att, Radix::Decimal),
+            "MVI\t%A\t10"
+        );
+    }
+
+    #[test]
+    fn test_color() {
+        let (text, _) = instr_with_format(&[0x78], &SymbolTable::new(), &Format::default());
+        assert_eq!(text, "MOV\t\x1B[1;32m%A\x1B[m\t\x1B[1;32m%B\x1B[m");
+        let format = Format {
+            color: false,
+            ..Format::default()
+        };
+        let (text, _) = instr_with_format(&[0x78], &SymbolTable::new(), &format);
+        assert_eq!(text, "MOV\t%A\t%B");
+    }
+
+    #[test]
+    fn test_parse_format() {
+        assert_eq!("Zilog".parse::<Syntax>().unwrap(), Syntax::Zilog);
+        assert_eq!("oct".parse::<Radix>().unwrap(), Radix::Octal);
+        assert!("arm".parse::<Syntax>().is_err());
+    }
 }
@@ -0,0 +1,759 @@
+//! Static analysis of the functions.
+//!
+//! For every function of a [Program], [analyze] computes without running
+//! the code:
+//! - its extent, from its first to its last instruction
+//! - the maximum number of bytes it uses on the stack, including the return
+//!   addresses and the functions it calls
+//! - the registers it reads before writing them, and the registers it does
+//!   not preserve
+//! - the paths returning with bytes left on the stack, and the other reasons
+//!   the stack depth may be wrong, as [Warning]s
+//!
+//! It also builds the cross references of the addresses read, written,
+//! jumped to, called or loaded by the instructions.
+
+use super::cfg::{Function, Program};
+use crate::instruction::{AluOp, Instruction, Pair, PairPsw, Reg};
+use crate::symbols::SymbolTable;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fmt::Write;
+
+/// A set of 8 bit registers, by their [Reg] number. `M` is never included.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Regs(pub u8);
+
+impl Regs {
+    pub const ALL: Regs = Regs(0b1011_1111);
+
+    pub fn contains(&self, r: Reg) -> bool {
+        self.0 & 1 << r as u8 != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+fn reg(r: Reg) -> u8 {
+    match r {
+        Reg::M => 1 << Reg::H as u8 | 1 << Reg::L as u8,
+        r => 1 << r as u8,
+    }
+}
+
+/// the registers of a pair, high first
+fn halves(p: PairPsw) -> [Option<Reg>; 2] {
+    match p {
+        PairPsw::BC => [Some(Reg::B), Some(Reg::C)],
+        PairPsw::DE => [Some(Reg::D), Some(Reg::E)],
+        PairPsw::HL => [Some(Reg::H), Some(Reg::L)],
+        PairPsw::PSW => [Some(Reg::A), None],
+    }
+}
+
+fn pair(p: Pair) -> u8 {
+    match p {
+        Pair::BC => reg(Reg::B) | reg(Reg::C),
+        Pair::DE => reg(Reg::D) | reg(Reg::E),
+        Pair::HL => reg(Reg::M),
+        Pair::SP => 0,
+    }
+}
+
+fn pair_psw(p: PairPsw) -> u8 {
+    halves(p).iter().flatten().fold(0, |m, r| m | reg(*r))
+}
+
+/// the registers as `A BC H`, naming the complete pairs
+impl fmt::Display for Regs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut names = Vec::new();
+        if self.contains(Reg::A) {
+            names.push("A".to_string());
+        }
+        for (hi, lo, name) in [
+            (Reg::B, Reg::C, "BC"),
+            (Reg::D, Reg::E, "DE"),
+            (Reg::H, Reg::L, "HL"),
+        ] {
+            match (self.contains(hi), self.contains(lo)) {
+                (true, true) => names.push(name.to_string()),
+                (true, false) => names.push(format!("{:?}", hi)),
+                (false, true) => names.push(format!("{:?}", lo)),
+                (false, false) => (),
+            }
+        }
+        if names.is_empty() {
+            write!(f, "-")
+        } else {
+            write!(f, "{}", names.join(" "))
+        }
+    }
+}
+
+/// Why the stack depth or the registers of a function may be wrong
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Warning {
+    /// a return or a tail call with `depth` bytes left on the stack, negative
+    /// when more bytes were popped than pushed
+    Unbalanced { addr: u16, depth: i32 },
+    /// the paths reaching the block at `addr` push different numbers of bytes
+    Mismatch { addr: u16, depths: (i32, i32) },
+    /// SP is loaded or adjusted
+    StackPointer(u16),
+    /// a jump through PCHL
+    Indirect(u16),
+    /// a call back to a function not analyzed yet
+    Recursion(u16),
+    /// a call outside of the disassembled code, using an unknown stack
+    External(u16),
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Warning::Unbalanced { addr, depth } if depth > 0 => {
+                write!(f, "{:04X}: leaves {} bytes on the stack", addr, depth)
+            }
+            Warning::Unbalanced { addr, depth } => {
+                write!(f, "{:04X}: pops {} bytes of the caller", addr, -depth)
+            }
+            Warning::Mismatch { addr, depths } => write!(
+                f,
+                "{:04X}: reached with {} and {} bytes on the stack",
+                addr, depths.0, depths.1
+            ),
+            Warning::StackPointer(addr) => write!(f, "{:04X}: changes SP", addr),
+            Warning::Indirect(addr) => write!(f, "{:04X}: jumps through HL", addr),
+            Warning::Recursion(addr) => write!(f, "{:04X}: recursive call", addr),
+            Warning::External(addr) => write!(f, "{:04X}: calls unknown code", addr),
+        }
+    }
+}
+
+/// What a function does with the stack and the registers
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Routine {
+    pub entry: u16,
+    pub name: String,
+    /// the first instruction and the address after the last one
+    pub start: u16,
+    pub end: u32,
+    /// the maximum number of bytes used on the stack, not counting the
+    /// return address of the function itself
+    pub stack: u16,
+    /// false if the stack depth is only a lower bound, see the warnings
+    pub complete: bool,
+    /// the registers read before being written
+    pub inputs: Regs,
+    /// the registers which may be changed when returning
+    pub outputs: Regs,
+    /// the registers always written
+    must: Regs,
+    pub warnings: Vec<Warning>,
+}
+
+impl Routine {
+    /// the effect of a call to code which is not disassembled
+    fn external(entry: u16) -> Self {
+        Routine {
+            entry,
+            name: String::new(),
+            start: entry,
+            end: entry as u32,
+            stack: 0,
+            complete: false,
+            inputs: Regs::default(),
+            outputs: Regs::ALL,
+            must: Regs::default(),
+            warnings: vec![],
+        }
+    }
+}
+
+/// How an instruction uses an address
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Access {
+    Read,
+    Write,
+    Jump,
+    Call,
+    /// loaded in a register pair by LXI
+    Address,
+}
+
+impl Access {
+    fn name(&self) -> &'static str {
+        match self {
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::Jump => "jump",
+            Access::Call => "call",
+            Access::Address => "address",
+        }
+    }
+}
+
+/// The routines and the cross references of a program
+#[derive(Clone, Debug)]
+pub struct Analysis {
+    pub routines: BTreeMap<u16, Routine>,
+    /// the instructions using each address, with how they use it
+    pub xrefs: BTreeMap<u16, BTreeSet<(u16, Access)>>,
+}
+
+/// What a register or a pushed byte holds
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+struct Value {
+    /// the register whose entry value it holds on every path
+    exact: Option<Reg>,
+    /// the registers whose entry value it may hold
+    sources: u8,
+}
+
+impl Value {
+    fn entry(r: Reg) -> Self {
+        Value {
+            exact: Some(r),
+            sources: reg(r),
+        }
+    }
+
+    fn merge(&mut self, other: &Value) {
+        if self.exact != other.exact {
+            self.exact = None;
+        }
+        self.sources |= other.sources;
+    }
+}
+
+/// The state of the stack and the registers before an instruction
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct State {
+    /// the bytes pushed since the entry
+    depth: i32,
+    /// the values of each pushed word, high first
+    slots: Vec<[Value; 2]>,
+    values: [Value; 8],
+    /// the registers written on every path
+    written: u8,
+}
+
+impl State {
+    fn entry() -> Self {
+        use Reg::*;
+
+        let mut values = [Value::default(); 8];
+        for r in [B, C, D, E, H, L, A] {
+            values[r as usize] = Value::entry(r);
+        }
+        State {
+            depth: 0,
+            slots: vec![],
+            values,
+            written: 0,
+        }
+    }
+
+    /// the state common to both paths
+    fn merge(&mut self, other: &State) {
+        for (v, o) in self.values.iter_mut().zip(&other.values) {
+            v.merge(o);
+        }
+        for (slot, o) in self.slots.iter_mut().zip(&other.slots) {
+            for (s, o) in slot.iter_mut().zip(o) {
+                s.merge(o);
+            }
+        }
+        self.written &= other.written;
+    }
+
+    /// the entry registers which may be in the registers of `mask`
+    fn read(&self, mask: u8) -> u8 {
+        (0..8)
+            .filter(|i| mask & 1 << i != 0)
+            .fold(0, |m, i| m | self.values[i].sources)
+    }
+
+    /// the registers of `may` may be written, the registers of `must` are
+    fn write(&mut self, may: u8, must: u8) {
+        for (i, v) in self.values.iter_mut().enumerate() {
+            if must & 1 << i != 0 {
+                *v = Value::default();
+            } else if may & 1 << i != 0 {
+                v.exact = None;
+            }
+        }
+        self.written |= must;
+    }
+
+    /// the registers not holding their entry value
+    fn changed(&self) -> u8 {
+        (0..8)
+            .filter(|&i| i != Reg::M as usize)
+            .filter(|&i| self.values[i].exact.map(|r| r as usize) != Some(i))
+            .fold(0, |m, i| m | 1 << i)
+    }
+}
+
+/// How an instruction reads and writes the registers
+struct Effects {
+    reads: u8,
+    /// the registers which may be written
+    writes: u8,
+    /// the registers always written
+    must: u8,
+}
+
+struct Analyzer<'a> {
+    program: &'a Program,
+    routines: BTreeMap<u16, Routine>,
+    /// the functions being analyzed, to find the recursions
+    active: BTreeSet<u16>,
+}
+
+impl<'a> Analyzer<'a> {
+    /// the routine called at `addr`, and a warning if it is not known
+    fn callee(&mut self, addr: u16) -> (Routine, Option<Warning>) {
+        if let Some(routine) = self.routines.get(&addr) {
+            return (routine.clone(), None);
+        }
+        match self.program.functions.get(&addr) {
+            Some(_) if self.active.contains(&addr) => {
+                (Routine::external(addr), Some(Warning::Recursion(addr)))
+            }
+            Some(function) => (self.routine(function), None),
+            None => (Routine::external(addr), Some(Warning::External(addr))),
+        }
+    }
+
+    /// the registers used by the instructions which do not move the values
+    /// between the registers and the stack
+    fn effects(instruction: Instruction) -> Effects {
+        use Instruction::*;
+
+        let a = reg(Reg::A);
+        let (reads, writes) = match instruction {
+            Mov(d, s) => (reg(s), if d == Reg::M { 0 } else { reg(d) }),
+            Mvi(Reg::M, _) => (reg(Reg::M), 0),
+            Mvi(r, _) => (0, reg(r)),
+            Lxi(p, _) => (0, pair(p)),
+            Stax(p) => (pair(p) | a, 0),
+            Ldax(p) => (pair(p), a),
+            Shld(_) => (reg(Reg::M), 0),
+            Lhld(_) => (0, reg(Reg::M)),
+            Sta(_) | Out(_) => (a, 0),
+            Lda(_) | In(_) => (0, a),
+            Inx(p) | Dcx(p) => (pair(p), pair(p)),
+            Inr(Reg::M) | Dcr(Reg::M) => (reg(Reg::M), 0),
+            Inr(r) | Dcr(r) => (reg(r), reg(r)),
+            Dad(p) => (pair(p) | reg(Reg::M), reg(Reg::M)),
+            Rlc | Rrc | Ral | Rar | Daa | Cma => (a, a),
+            // XRA A and SUB A do not depend on A
+            Alu(AluOp::Xra, Reg::A) | Alu(AluOp::Sub, Reg::A) => (0, a),
+            Alu(AluOp::Cmp, r) => (a | reg(r), 0),
+            Alu(_, r) => (a | reg(r), a),
+            AluImm(AluOp::Cmp, _) => (a, 0),
+            AluImm(_, _) => (a, a),
+            Pchl | Sphl => (reg(Reg::M), 0),
+            _ => (0, 0),
+        };
+        Effects {
+            reads,
+            writes,
+            must: writes,
+        }
+    }
+
+    fn routine(&mut self, function: &Function) -> Routine {
+        self.active.insert(function.entry);
+        let mut routine = Routine {
+            entry: function.entry,
+            name: function.name.clone(),
+            start: *function.blocks.keys().next().unwrap_or(&function.entry),
+            end: function.blocks.values().map(|b| b.end()).max().unwrap_or(0),
+            stack: 0,
+            complete: true,
+            inputs: Regs::default(),
+            outputs: Regs::default(),
+            must: Regs::ALL,
+            warnings: vec![],
+        };
+
+        // the state at the start of every block
+        let mut states: BTreeMap<u16, State> = BTreeMap::new();
+        states.insert(function.entry, State::entry());
+        let mut todo = vec![function.entry];
+        let mut returns = false;
+        while let Some(start) = todo.pop() {
+            let block = &function.blocks[&start];
+            let mut state = states[&start].clone();
+            for &(addr, instruction) in &block.instructions {
+                self.step(&mut routine, &mut state, addr, instruction, &mut returns);
+            }
+            for (target, _) in &block.successors {
+                let changed = match states.get_mut(target) {
+                    None => {
+                        states.insert(*target, state.clone());
+                        true
+                    }
+                    Some(old) if old.depth != state.depth => {
+                        let warning = Warning::Mismatch {
+                            addr: *target,
+                            depths: (old.depth, state.depth),
+                        };
+                        if !routine.warnings.contains(&warning) {
+                            routine.warnings.push(warning);
+                        }
+                        false
+                    }
+                    Some(old) => {
+                        let before = old.clone();
+                        old.merge(&state);
+                        *old != before
+                    }
+                };
+                if changed {
+                    todo.push(*target);
+                }
+            }
+        }
+        if !returns {
+            routine.must = Regs::default();
+        }
+
+        self.active.remove(&function.entry);
+        self.routines.insert(function.entry, routine.clone());
+        routine
+    }
+
+    /// execute an instruction on the state
+    fn step(
+        &mut self,
+        routine: &mut Routine,
+        state: &mut State,
+        addr: u16,
+        instruction: Instruction,
+        returns: &mut bool,
+    ) {
+        use Instruction::*;
+
+        let warn = |routine: &mut Routine, warning| {
+            if !routine.warnings.contains(&warning) {
+                routine.warnings.push(warning);
+            }
+        };
+        let depth = |d: i32| d.max(0) as u16;
+        routine.stack = routine.stack.max(depth(state.depth));
+
+        // leaving the function
+        let next = addr.wrapping_add(instruction.len() as u16);
+        let function = &self.program.functions[&routine.entry];
+        let tail = match instruction {
+            Jmp(t) | Jcc(_, t) if !function.blocks.contains_key(&t) => Some(t),
+            Ret | Rcc(_) | Jmp(_) | Jcc(_, _) | Pchl | Hlt => None,
+            _ if !function.blocks.contains_key(&next) && function.calls.contains(&next) => {
+                Some(next)
+            }
+            _ => None,
+        };
+        let mut exit = |routine: &mut Routine, state: &State| {
+            if state.depth != 0 {
+                warn(
+                    routine,
+                    Warning::Unbalanced {
+                        addr,
+                        depth: state.depth,
+                    },
+                );
+            }
+            routine.outputs.0 |= state.changed();
+            // the values moved to other registers are returned
+            for (i, value) in state.values.iter().enumerate() {
+                routine.inputs.0 |= value.sources & !(1 << i);
+            }
+            // the registers restored are not written for the caller
+            routine.must.0 &= state.written & state.changed();
+            *returns = true;
+        };
+        if let Ret | Rcc(_) = instruction {
+            exit(routine, state);
+        }
+
+        match instruction {
+            // a copy, the value is read where it is used
+            Mov(d, s) if d != Reg::M && s != Reg::M => {
+                state.write(0, reg(d));
+                state.values[d as usize] = state.values[s as usize];
+            }
+            Push(p) => {
+                state.depth += 2;
+                let [hi, lo] = halves(p);
+                let value =
+                    |r: Option<Reg>| r.map_or(Value::default(), |r| state.values[r as usize]);
+                state.slots.push([value(hi), value(lo)]);
+            }
+            Pop(p) => {
+                state.depth -= 2;
+                let slot = state.slots.pop().unwrap_or_default();
+                state.write(0, pair_psw(p));
+                for (r, value) in halves(p).iter().zip(slot) {
+                    if let Some(r) = r {
+                        state.values[*r as usize] = value;
+                    }
+                }
+            }
+            Xchg => {
+                state.written |= pair(Pair::DE) | pair(Pair::HL);
+                state.values.swap(Reg::D as usize, Reg::H as usize);
+                state.values.swap(Reg::E as usize, Reg::L as usize);
+            }
+            Xthl => {
+                state.written |= reg(Reg::M);
+                let (h, l) = (state.values[Reg::H as usize], state.values[Reg::L as usize]);
+                match state.slots.last_mut() {
+                    Some(top) => {
+                        state.values[Reg::H as usize] = top[0];
+                        state.values[Reg::L as usize] = top[1];
+                        *top = [h, l];
+                    }
+                    None => state.write(0, reg(Reg::M)),
+                }
+            }
+            Call(_) | Ccc(_, _) | Rst(_) => {
+                let (callee, warning) = self.callee(call_target(instruction));
+                if let Some(warning) = warning {
+                    routine.complete = false;
+                    warn(routine, warning);
+                }
+                routine.complete &= callee.complete;
+                routine.stack = routine.stack.max(depth(state.depth) + 2 + callee.stack);
+                routine.inputs.0 |= state.read(callee.inputs.0);
+                let must = match instruction {
+                    Ccc(_, _) => 0,
+                    _ => callee.must.0,
+                };
+                state.write(callee.outputs.0, must);
+            }
+            _ => {
+                if let Lxi(Pair::SP, _) | Inx(Pair::SP) | Dcx(Pair::SP) | Sphl = instruction {
+                    routine.complete = false;
+                    warn(routine, Warning::StackPointer(addr));
+                }
+                if let Pchl = instruction {
+                    routine.complete = false;
+                    warn(routine, Warning::Indirect(addr));
+                }
+                let effects = Self::effects(instruction);
+                routine.inputs.0 |= state.read(effects.reads);
+                state.write(effects.writes, effects.must);
+            }
+        }
+
+        if let Some(t) = tail {
+            // a jump to another function returns for it
+            let (callee, warning) = self.callee(t);
+            if let Some(warning) = warning {
+                routine.complete = false;
+                warn(routine, warning);
+            }
+            routine.complete &= callee.complete;
+            routine.stack = routine.stack.max(depth(state.depth) + callee.stack);
+            routine.inputs.0 |= state.read(callee.inputs.0);
+            state.write(callee.outputs.0, callee.must.0);
+            exit(routine, state);
+        }
+    }
+}
+
+/// the address called by CALL, a conditional call or RST
+fn call_target(instruction: Instruction) -> u16 {
+    match instruction {
+        Instruction::Call(t) | Instruction::Ccc(_, t) => t,
+        Instruction::Rst(n) => n as u16 * 8,
+        _ => unreachable!("{:?} is not a call", instruction),
+    }
+}
+
+/// analyze every function of `program`
+pub fn analyze(program: &Program) -> Analysis {
+    use Instruction::*;
+
+    let mut analyzer = Analyzer {
+        program,
+        routines: BTreeMap::new(),
+        active: BTreeSet::new(),
+    };
+    for function in program.functions.values() {
+        if !analyzer.routines.contains_key(&function.entry) {
+            analyzer.routine(function);
+        }
+    }
+
+    let mut xrefs: BTreeMap<u16, BTreeSet<(u16, Access)>> = BTreeMap::new();
+    for function in program.functions.values() {
+        for block in function.blocks.values() {
+            for &(addr, instruction) in &block.instructions {
+                let (target, access) = match instruction {
+                    Lda(t) | Lhld(t) => (t, Access::Read),
+                    Sta(t) | Shld(t) => (t, Access::Write),
+                    Jmp(t) | Jcc(_, t) => (t, Access::Jump),
+                    Call(t) | Ccc(_, t) => (t, Access::Call),
+                    Rst(n) => (n as u16 * 8, Access::Call),
+                    Lxi(_, t) => (t, Access::Address),
+                    _ => continue,
+                };
+                xrefs.entry(target).or_default().insert((addr, access));
+            }
+        }
+    }
+    // only the constants which are labels are addresses
+    let labels = program.labels();
+    xrefs.retain(|target, refs| {
+        refs.retain(|(_, access)| *access != Access::Address || labels.name(*target).is_some());
+        !refs.is_empty()
+    });
+
+    Analysis {
+        routines: analyzer.routines,
+        xrefs,
+    }
+}
+
+impl Analysis {
+    /// the routines and the cross references as text
+    pub fn report(&self, labels: &SymbolTable) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "{:<12} {:<9} {:>6}  {:<12} OUTPUTS",
+            "ROUTINE", "RANGE", "STACK", "INPUTS"
+        );
+        for routine in self.routines.values() {
+            let stack = if routine.complete {
+                routine.stack.to_string()
+            } else {
+                format!(">={}", routine.stack)
+            };
+            let _ = writeln!(
+                out,
+                "{:<12} {:04X}-{:04X} {:>6}  {:<12} {}",
+                routine.name,
+                routine.start,
+                routine.end.saturating_sub(1),
+                stack,
+                routine.inputs.to_string(),
+                routine.outputs
+            );
+            for warning in &routine.warnings {
+                let _ = writeln!(out, "    {}", warning);
+            }
+        }
+        let _ = writeln!(out);
+        let _ = writeln!(out, "XREFS");
+        for (target, refs) in &self.xrefs {
+            let refs: Vec<String> = refs
+                .iter()
+                .map(|(addr, access)| format!("{} {:04X}", access.name(), addr))
+                .collect();
+            let _ = writeln!(
+                out,
+                "{:04X} {:<12} {}",
+                target,
+                labels.name(*target).unwrap_or(""),
+                refs.join(", ")
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decompiler::cfg::build;
+    use crate::decompiler::traversal::{disassemble, Hints};
+
+    fn analyze_bytes(bytes: &[u8]) -> (Analysis, SymbolTable) {
+        let mut symbols = SymbolTable::new();
+        symbols.insert("BDOS", 0x0005);
+        let program = build(&disassemble(bytes, 0x100, &Hints::default(), &symbols));
+        let labels = program.labels().clone();
+        (analyze(&program), labels)
+    }
+
+    #[test]
+    fn test_stack() {
+        let (analysis, _) = analyze_bytes(&[
+            0xc5, // PUSH B
+            0xcd, 0x08, 0x01, // CALL SAVE
+            0xc1, // POP B
+            0x78, // MOV A,B
+            0xc9, // RET
+            0x00, // NOP
+            0xe5, // SAVE: PUSH H
+            0xd5, // PUSH D
+            0x21, 0x00, 0x00, // LXI H,0
+            0xd1, // POP D
+            0xe1, // POP H
+            0x3e, 0x01, // MVI A,1
+            0xc9, // RET
+        ]);
+        let save = &analysis.routines[&0x108];
+        assert_eq!(save.stack, 4);
+        assert!(save.complete);
+        // H and L are saved and restored
+        assert_eq!(save.outputs, Regs(reg(Reg::A)));
+        assert_eq!(save.inputs, Regs::default());
+        assert!(save.warnings.is_empty());
+
+        let main = &analysis.routines[&0x100];
+        // BC, the return address and the stack of SAVE
+        assert_eq!(main.stack, 2 + 2 + 4);
+        assert_eq!(main.inputs.to_string(), "B");
+        assert_eq!(main.outputs.to_string(), "A");
+        assert_eq!((main.start, main.end), (0x100, 0x107));
+    }
+
+    #[test]
+    fn test_warnings() {
+        let (analysis, labels) = analyze_bytes(&[
+            0xc5, // PUSH B
+            0xca, 0x05, 0x01, // JZ L0105
+            0xc9, // RET with BC on the stack
+            0xc1, // L0105: POP B
+            0x5f, // MOV E,A
+            0x48, // MOV C,B
+            0xc3, 0x05, 0x00, // JMP BDOS
+        ]);
+        let main = &analysis.routines[&0x100];
+        assert_eq!(
+            main.warnings,
+            [
+                Warning::Unbalanced {
+                    addr: 0x104,
+                    depth: 2
+                },
+                Warning::External(0x0005),
+            ]
+        );
+        assert!(!main.complete);
+        assert_eq!(main.outputs, Regs::ALL);
+        // B and A are passed to BDOS in C and E
+        assert_eq!(main.inputs.to_string(), "A B");
+
+        assert_eq!(
+            analysis.xrefs[&0x0005].iter().copied().collect::<Vec<_>>(),
+            [(0x108, Access::Jump)]
+        );
+        let report = analysis.report(&labels);
+        assert!(
+            report.contains("    0104: leaves 2 bytes on the stack"),
+            "{}",
+            report
+        );
+        assert!(report.contains("0005 BDOS         jump 0108"), "{}", report);
+    }
+}
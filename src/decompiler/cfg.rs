@@ -0,0 +1,401 @@
+//! Control flow graphs and call graph.
+//!
+//! The code found by a [Disassembly] is split in basic blocks, which end at
+//! the jumps, the returns and before the targets of the jumps. A [Function]
+//! is made of the blocks reachable from an entry point or a subroutine
+//! without going through a call. A jump to another function is a tail call,
+//! it is part of the call graph and not of the CFG.
+//!
+//! Both graphs are exported to Graphviz DOT with [Program::cfg_dot] and
+//! [Program::call_graph_dot], and to JSON with [Program::to_json].
+
+use super::traversal::Disassembly;
+use super::{instr_with_format, Format};
+use crate::instruction::Instruction;
+use crate::symbols::SymbolTable;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+/// How a block leads to another
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Edge {
+    /// an unconditional jump
+    Jump,
+    /// the condition of a jump is true
+    Taken,
+    /// the next instruction, after a conditional jump or return or before a
+    /// jump target
+    Fallthrough,
+}
+
+impl Edge {
+    fn name(&self) -> &'static str {
+        match self {
+            Edge::Jump => "jump",
+            Edge::Taken => "taken",
+            Edge::Fallthrough => "fallthrough",
+        }
+    }
+}
+
+/// Instructions always executed one after the other
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Block {
+    pub start: u16,
+    /// the instructions with their address
+    pub instructions: Vec<(u16, Instruction)>,
+    /// the following blocks of the same function
+    pub successors: Vec<(u16, Edge)>,
+}
+
+impl Block {
+    /// the address after the last instruction
+    pub fn end(&self) -> u32 {
+        self.instructions
+            .last()
+            .map_or(self.start as u32, |(addr, i)| *addr as u32 + i.len() as u32)
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Function {
+    pub entry: u16,
+    pub name: String,
+    /// the blocks by address
+    pub blocks: BTreeMap<u16, Block>,
+    /// the called functions and external addresses, including the tail calls
+    pub calls: BTreeSet<u16>,
+}
+
+/// The functions of a disassembled image
+#[derive(Clone, Debug)]
+pub struct Program {
+    pub functions: BTreeMap<u16, Function>,
+    /// the names of the functions and the external symbols
+    labels: SymbolTable,
+}
+
+/// split the code of `dis` in functions
+pub fn build(dis: &Disassembly) -> Program {
+    use Instruction::*;
+
+    let instructions: BTreeMap<u16, Instruction> = dis.instructions().collect();
+    let entries: BTreeSet<u16> = dis.functions().collect();
+
+    // the first instruction of every block
+    let mut leaders = entries.clone();
+    for (&addr, instruction) in &instructions {
+        let next = addr.wrapping_add(instruction.len() as u16);
+        match *instruction {
+            Jmp(t) | Jcc(_, t) => {
+                leaders.insert(t);
+                leaders.insert(next);
+            }
+            Rcc(_) | Ret | Pchl | Hlt => {
+                leaders.insert(next);
+            }
+            _ => (),
+        }
+    }
+
+    let mut blocks = BTreeMap::new();
+    for &start in leaders.iter().filter(|a| instructions.contains_key(a)) {
+        let mut block = Block {
+            start,
+            instructions: Vec::new(),
+            successors: Vec::new(),
+        };
+        let mut addr = start;
+        while let Some(&instruction) = instructions.get(&addr) {
+            block.instructions.push((addr, instruction));
+            let next = addr.wrapping_add(instruction.len() as u16);
+            match instruction {
+                Jmp(t) => block.successors.push((t, Edge::Jump)),
+                Jcc(_, t) => {
+                    block.successors.push((t, Edge::Taken));
+                    block.successors.push((next, Edge::Fallthrough));
+                }
+                Rcc(_) => block.successors.push((next, Edge::Fallthrough)),
+                Ret | Pchl | Hlt => (),
+                _ if leaders.contains(&next) => block.successors.push((next, Edge::Fallthrough)),
+                _ => {
+                    addr = next;
+                    continue;
+                }
+            }
+            break;
+        }
+        blocks.insert(start, block);
+    }
+
+    let labels = dis.labels();
+    let mut functions = BTreeMap::new();
+    for &entry in &entries {
+        let mut function = Function {
+            entry,
+            name: name(entry, &labels),
+            blocks: BTreeMap::new(),
+            calls: BTreeSet::new(),
+        };
+        let mut todo = vec![entry];
+        while let Some(start) = todo.pop() {
+            let block: &Block = match blocks.get(&start) {
+                Some(block) if !function.blocks.contains_key(&start) => block,
+                _ => continue,
+            };
+            for (_, instruction) in &block.instructions {
+                match *instruction {
+                    Call(t) | Ccc(_, t) => function.calls.insert(t),
+                    Rst(n) => function.calls.insert(n as u16 * 8),
+                    _ => false,
+                };
+            }
+            let mut block = block.clone();
+            block.successors.retain(|&(target, edge)| {
+                // a jump to another function or outside of the code
+                let tail =
+                    entries.contains(&target) && target != entry || !blocks.contains_key(&target);
+                // falling into the next function is also a tail call
+                if tail && (edge != Edge::Fallthrough || entries.contains(&target)) {
+                    function.calls.insert(target);
+                }
+                !tail
+            });
+            todo.extend(block.successors.iter().map(|(target, _)| *target));
+            function.blocks.insert(start, block);
+        }
+        functions.insert(entry, function);
+    }
+
+    Program { functions, labels }
+}
+
+/// the label of an address, or the address itself
+fn name(addr: u16, labels: &SymbolTable) -> String {
+    match labels.name(addr) {
+        Some(name) => name.to_string(),
+        None => format!("{:04X}H", addr),
+    }
+}
+
+/// escape a string in a DOT label
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+impl Program {
+    /// the names of the functions and the external symbols
+    pub fn labels(&self) -> &SymbolTable {
+        &self.labels
+    }
+
+    /// the text of an instruction
+    fn text(&self, instruction: &Instruction) -> String {
+        instr_with_format(&instruction.encode(), &self.labels, &Format::plain())
+            .0
+            .replace('\t', " ")
+    }
+
+    /// the CFG of every function, each in its own cluster
+    pub fn cfg_dot(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "digraph cfg {{");
+        let _ = writeln!(out, "\tnode [shape=box fontname=monospace];");
+        for function in self.functions.values() {
+            let id = |addr: u16| format!("\"{:04x}_{:04x}\"", function.entry, addr);
+            let _ = writeln!(out, "\tsubgraph cluster_{:04x} {{", function.entry);
+            let _ = writeln!(out, "\t\tlabel=\"{}\";", escape(&function.name));
+            for block in function.blocks.values() {
+                let mut label = String::new();
+                for (addr, instruction) in &block.instructions {
+                    let _ = write!(
+                        label,
+                        "{:04X}  {}\\l",
+                        addr,
+                        escape(&self.text(instruction))
+                    );
+                }
+                let _ = writeln!(out, "\t\t{} [label=\"{}\"];", id(block.start), label);
+                for (target, edge) in &block.successors {
+                    let color = match edge {
+                        Edge::Jump => "black",
+                        Edge::Taken => "darkgreen",
+                        Edge::Fallthrough => "red",
+                    };
+                    let _ = writeln!(
+                        out,
+                        "\t\t{} -> {} [color={}];",
+                        id(block.start),
+                        id(*target),
+                        color
+                    );
+                }
+            }
+            let _ = writeln!(out, "\t}}");
+        }
+        let _ = writeln!(out, "}}");
+        out
+    }
+
+    /// the functions calling each other, the external addresses in grey
+    pub fn call_graph_dot(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "digraph calls {{");
+        let _ = writeln!(out, "\tnode [shape=box fontname=monospace];");
+        let externals: BTreeSet<u16> = self
+            .functions
+            .values()
+            .flat_map(|f| f.calls.iter().copied())
+            .filter(|addr| !self.functions.contains_key(addr))
+            .collect();
+        for function in self.functions.values() {
+            let _ = writeln!(
+                out,
+                "\tf{:04x} [label=\"{}\"];",
+                function.entry,
+                escape(&function.name)
+            );
+        }
+        for addr in externals {
+            let _ = writeln!(
+                out,
+                "\tf{:04x} [label=\"{}\" style=filled fillcolor=lightgrey];",
+                addr,
+                escape(&name(addr, &self.labels))
+            );
+        }
+        for function in self.functions.values() {
+            for callee in &function.calls {
+                let _ = writeln!(out, "\tf{:04x} -> f{:04x};", function.entry, callee);
+            }
+        }
+        let _ = writeln!(out, "}}");
+        out
+    }
+
+    /// the functions with their blocks and calls
+    pub fn to_json(&self) -> Value {
+        let functions: Vec<Value> = self
+            .functions
+            .values()
+            .map(|function| {
+                let blocks: Vec<Value> = function
+                    .blocks
+                    .values()
+                    .map(|block| {
+                        json!({
+                            "start": block.start,
+                            "end": block.end(),
+                            "instructions": block.instructions.iter().map(|(addr, instruction)| json!({
+                                "address": addr,
+                                "bytes": instruction.encode(),
+                                "text": self.text(instruction),
+                            })).collect::<Vec<_>>(),
+                            "successors": block.successors.iter().map(|(target, edge)| json!({
+                                "target": target,
+                                "kind": edge.name(),
+                            })).collect::<Vec<_>>(),
+                        })
+                    })
+                    .collect();
+                json!({
+                    "entry": function.entry,
+                    "name": function.name,
+                    "blocks": blocks,
+                    "calls": function.calls,
+                })
+            })
+            .collect();
+        json!({ "functions": functions })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decompiler::traversal::{disassemble, Hints};
+
+    fn program() -> Program {
+        let bytes = [
+            0xcd, 0x07, 0x01, // CALL COUNT
+            0xc3, 0x00, 0x00, // JMP  BOOT
+            0x00, // NOP, never executed
+            0x06, 0x03, // COUNT: MVI B,3
+            0x05, // LOOP: DCR B
+            0xc2, 0x09, 0x01, // JNZ  LOOP
+            0xc8, // RZ
+            0xc3, 0x05, 0x00, // JMP  BDOS
+        ];
+        let mut symbols = SymbolTable::new();
+        symbols.insert("COUNT", 0x107);
+        symbols.insert("BDOS", 0x0005);
+        build(&disassemble(&bytes, 0x100, &Hints::default(), &symbols))
+    }
+
+    #[test]
+    fn test_blocks() {
+        let program = program();
+        assert_eq!(
+            program.functions.keys().copied().collect::<Vec<_>>(),
+            [0x100, 0x107]
+        );
+
+        let count = &program.functions[&0x107];
+        assert_eq!(count.name, "COUNT");
+        assert_eq!(
+            count.blocks.keys().copied().collect::<Vec<_>>(),
+            [0x107, 0x109, 0x10d, 0x10e]
+        );
+        assert_eq!(
+            count.blocks[&0x107].successors,
+            [(0x109, Edge::Fallthrough)]
+        );
+        assert_eq!(
+            count.blocks[&0x109].successors,
+            [(0x109, Edge::Taken), (0x10d, Edge::Fallthrough)]
+        );
+        assert_eq!(
+            count.blocks[&0x10d].successors,
+            [(0x10e, Edge::Fallthrough)]
+        );
+        assert!(count.blocks[&0x10e].successors.is_empty());
+        assert_eq!(count.blocks[&0x109].end(), 0x10d);
+        // the tail call
+        assert_eq!(count.calls.iter().copied().collect::<Vec<_>>(), [0x0005]);
+
+        let main = &program.functions[&0x100];
+        assert_eq!(main.blocks.len(), 1);
+        assert_eq!(
+            main.calls.iter().copied().collect::<Vec<_>>(),
+            [0x0000, 0x0107]
+        );
+    }
+
+    #[test]
+    fn test_export() {
+        let program = program();
+        let dot = program.cfg_dot();
+        assert!(
+            dot.contains("label=\"0109  DCR B\\l010A  JNZ L0109\\l\""),
+            "{}",
+            dot
+        );
+        assert!(
+            dot.contains("\"0107_0109\" -> \"0107_0109\" [color=darkgreen];"),
+            "{}",
+            dot
+        );
+
+        let calls = program.call_graph_dot();
+        assert!(calls.contains("f0107 -> f0005;"), "{}", calls);
+        assert!(calls.contains("f0005 [label=\"BDOS\" style=filled fillcolor=lightgrey];"));
+
+        let json = program.to_json();
+        let count = &json["functions"][1];
+        assert_eq!(count["name"], "COUNT");
+        assert_eq!(count["blocks"][1]["instructions"][1]["text"], "JNZ L0109");
+        assert_eq!(count["blocks"][1]["successors"][0]["kind"], "taken");
+        assert_eq!(count["calls"], json!([5]));
+    }
+}
@@ -0,0 +1,295 @@
+//! CP/M 2.2 knowledge for the disassembler.
+//!
+//! A CP/M program is loaded at 0x100 and calls the BDOS at 0x0005 with the
+//! function number in C and its parameter in E or DE. [bdos_calls] finds
+//! these values before the calls, so the listing can name the functions and
+//! the strings printed by `C_WRITESTR` can be written as text.
+
+use crate::instruction::{Instruction, Pair, PairPsw, Reg};
+use crate::symbols::SymbolTable;
+
+/// the BDOS entry point
+pub const BDOS: u16 = 0x0005;
+
+/// The register holding the parameter of a BDOS function
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Param {
+    None,
+    /// a byte in E
+    E,
+    /// an address or a word in DE
+    DE,
+}
+
+/// the name and the parameter of the BDOS functions of CP/M 2.2
+pub const FUNCTIONS: [(&str, Param); 41] = [
+    ("P_TERMCPM", Param::None),
+    ("C_READ", Param::None),
+    ("C_WRITE", Param::E),
+    ("A_READ", Param::None),
+    ("A_WRITE", Param::E),
+    ("L_WRITE", Param::E),
+    ("C_RAWIO", Param::E),
+    ("A_GETIOBYTE", Param::None),
+    ("A_SETIOBYTE", Param::E),
+    ("C_WRITESTR", Param::DE),
+    ("C_READSTR", Param::DE),
+    ("C_STAT", Param::None),
+    ("S_BDOSVER", Param::None),
+    ("DRV_ALLRESET", Param::None),
+    ("DRV_SET", Param::E),
+    ("F_OPEN", Param::DE),
+    ("F_CLOSE", Param::DE),
+    ("F_SFIRST", Param::DE),
+    ("F_SNEXT", Param::None),
+    ("F_DELETE", Param::DE),
+    ("F_READ", Param::DE),
+    ("F_WRITE", Param::DE),
+    ("F_MAKE", Param::DE),
+    ("F_RENAME", Param::DE),
+    ("DRV_LOGINVEC", Param::None),
+    ("DRV_GET", Param::None),
+    ("F_DMAOFF", Param::DE),
+    ("DRV_ALLOCVEC", Param::None),
+    ("DRV_SETRO", Param::None),
+    ("DRV_ROVEC", Param::None),
+    ("F_ATTRIB", Param::DE),
+    ("DRV_DPB", Param::None),
+    ("F_USERNUM", Param::E),
+    ("F_READRAND", Param::DE),
+    ("F_WRITERAND", Param::DE),
+    ("F_SIZE", Param::DE),
+    ("F_RANDREC", Param::DE),
+    ("DRV_RESET", Param::DE),
+    ("", Param::None),
+    ("", Param::None),
+    ("F_WRITEZF", Param::DE),
+];
+
+/// the name and the parameter of a BDOS function
+pub fn function(number: u8) -> Option<(&'static str, Param)> {
+    FUNCTIONS
+        .get(number as usize)
+        .filter(|(name, _)| !name.is_empty())
+        .copied()
+}
+
+/// the labels of the page zero: the warm boot jump, the BDOS entry point,
+/// the two default FCBs and the default DMA buffer
+pub fn page_zero() -> SymbolTable {
+    let mut symbols = SymbolTable::new();
+    symbols.insert("BOOT", 0x0000);
+    symbols.insert("BDOS", BDOS);
+    symbols.insert("FCB", 0x005c);
+    symbols.insert("FCB2", 0x006c);
+    symbols.insert("DMA", 0x0080);
+    symbols
+}
+
+/// A call or a jump to the BDOS, with the values known in the registers
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BdosCall {
+    pub addr: u16,
+    /// the value of C
+    pub function: Option<u8>,
+    pub e: Option<u8>,
+    pub de: Option<u16>,
+}
+
+impl BdosCall {
+    /// the comment of the call, like `BDOS 9 C_WRITESTR DE=MSG`
+    pub fn comment(&self, labels: &SymbolTable) -> Option<String> {
+        let number = self.function?;
+        let (name, param) = function(number).unwrap_or(("", Param::None));
+        let mut comment = format!("BDOS {}", number);
+        if !name.is_empty() {
+            comment.push(' ');
+            comment.push_str(name);
+        }
+        match (param, self.e, self.de) {
+            (Param::E, Some(e), _) => comment.push_str(&format!(" E={}", hex(e as u16, 2))),
+            (Param::DE, _, Some(de)) => match labels.name(de) {
+                Some(name) => comment.push_str(&format!(" DE={}", name)),
+                None => comment.push_str(&format!(" DE={}", hex(de, 4))),
+            },
+            _ => (),
+        }
+        Some(comment)
+    }
+
+    /// the address of the string printed by this call
+    pub fn string(&self) -> Option<u16> {
+        match self.function {
+            Some(9) => self.de,
+            _ => None,
+        }
+    }
+}
+
+/// a number in the Intel syntax
+fn hex(n: u16, digits: usize) -> String {
+    let hex = format!("{:0width$X}H", n, width = digits);
+    if hex.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        format!("0{}", hex)
+    } else {
+        hex
+    }
+}
+
+/// find the calls and the jumps to the BDOS in `instructions`, given in
+/// order. The registers are only followed from one instruction to the next
+/// one, `is_label` telling where another path may join.
+pub fn bdos_calls(
+    instructions: impl Iterator<Item = (u16, Instruction)>,
+    is_label: impl Fn(u16) -> bool,
+) -> Vec<BdosCall> {
+    use Instruction::*;
+
+    let mut calls = Vec::new();
+    let (mut c, mut e, mut de) = (None, None, None);
+    let mut next = None;
+    for (addr, instruction) in instructions {
+        if next != Some(addr) || is_label(addr) {
+            (c, e, de) = (None, None, None);
+        }
+        next = Some(addr.wrapping_add(instruction.len() as u16));
+        match instruction {
+            Call(BDOS) | Jmp(BDOS) => calls.push(BdosCall {
+                addr,
+                function: c,
+                e,
+                de,
+            }),
+            Mvi(Reg::C, n) => c = Some(n),
+            Mvi(Reg::E, n) => {
+                e = Some(n);
+                de = None;
+            }
+            Lxi(Pair::DE, n) => {
+                de = Some(n);
+                e = Some(n as u8);
+            }
+            Mvi(Reg::D, _) => de = None,
+            Mov(Reg::C, _)
+            | Inr(Reg::C)
+            | Dcr(Reg::C)
+            | Lxi(Pair::BC, _)
+            | Inx(Pair::BC)
+            | Dcx(Pair::BC)
+            | Pop(PairPsw::BC) => c = None,
+            Mov(Reg::D | Reg::E, _)
+            | Inr(Reg::D | Reg::E)
+            | Dcr(Reg::D | Reg::E)
+            | Inx(Pair::DE)
+            | Dcx(Pair::DE)
+            | Pop(PairPsw::DE)
+            | Xchg => (e, de) = (None, None),
+            _ => (),
+        }
+        // the registers are unknown after a call, and after a jump
+        match instruction {
+            Call(_) | Ccc(_, _) | Rst(_) | Jmp(_) | Ret | Pchl => {
+                (c, e, de) = (None, None, None);
+            }
+            _ => (),
+        }
+    }
+    calls
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_functions() {
+        assert_eq!(function(9), Some(("C_WRITESTR", Param::DE)));
+        assert_eq!(function(2), Some(("C_WRITE", Param::E)));
+        assert_eq!(function(38), None);
+        assert_eq!(function(41), None);
+        assert_eq!(page_zero().name(0x5c), Some("FCB"));
+    }
+
+    #[test]
+    fn test_bdos_calls() {
+        use Instruction::*;
+
+        let instructions = [
+            (0x100, Mvi(Reg::C, 9)),
+            (0x102, Lxi(Pair::DE, 0x120)),
+            (0x105, Call(BDOS)),
+            // C is lost after the call
+            (0x108, Mvi(Reg::E, b'!')),
+            (0x10a, Call(BDOS)),
+            (0x10d, Mvi(Reg::C, 2)),
+            // a jump target, C may have another value
+            (0x10f, Jmp(BDOS)),
+            (0x112, Mvi(Reg::C, 2)),
+            (0x114, Jmp(BDOS)),
+            // INX B and DCX B change C
+            (0x117, Mvi(Reg::C, 2)),
+            (0x119, Inx(Pair::BC)),
+            (0x11a, Call(BDOS)),
+            (0x11d, Mvi(Reg::C, 2)),
+            (0x11f, Dcx(Pair::BC)),
+            (0x120, Call(BDOS)),
+        ];
+        let calls = bdos_calls(instructions.iter().copied(), |addr| addr == 0x10f);
+        assert_eq!(
+            calls,
+            [
+                BdosCall {
+                    addr: 0x105,
+                    function: Some(9),
+                    e: Some(0x20),
+                    de: Some(0x120)
+                },
+                BdosCall {
+                    addr: 0x10a,
+                    function: None,
+                    e: Some(b'!'),
+                    de: None
+                },
+                BdosCall {
+                    addr: 0x10f,
+                    function: None,
+                    e: None,
+                    de: None
+                },
+                BdosCall {
+                    addr: 0x114,
+                    function: Some(2),
+                    e: None,
+                    de: None
+                },
+                BdosCall {
+                    addr: 0x11a,
+                    function: None,
+                    e: None,
+                    de: None
+                },
+                BdosCall {
+                    addr: 0x120,
+                    function: None,
+                    e: None,
+                    de: None
+                },
+            ]
+        );
+
+        let mut labels = SymbolTable::new();
+        labels.insert("MSG", 0x120);
+        assert_eq!(
+            calls[0].comment(&labels).unwrap(),
+            "BDOS 9 C_WRITESTR DE=MSG"
+        );
+        assert_eq!(calls[0].string(), Some(0x120));
+        assert_eq!(calls[1].comment(&labels), None);
+        assert_eq!(calls[3].comment(&labels).unwrap(), "BDOS 2 C_WRITE");
+        let call = BdosCall {
+            e: Some(b'!'),
+            ..calls[3]
+        };
+        assert_eq!(call.comment(&labels).unwrap(), "BDOS 2 C_WRITE E=21H");
+    }
+}
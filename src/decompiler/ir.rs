@@ -0,0 +1,830 @@
+//! Intermediate representation of the instructions.
+//!
+//! Every instruction is lifted to C like statements over the registers, the
+//! register pairs and the memory. The flags are not assigned by the
+//! statements: [lift] describes how to compute them instead, before the
+//! instruction with [FlagDef::Exprs::before], or after it when the operands
+//! are still available with [FlagDef::Exprs::after]. The pseudo-C writer
+//! chooses between both, or skips the flags nobody reads.
+
+use crate::instruction::{AluOp, Cond, Instruction, Pair, PairPsw, Reg};
+use crate::symbols::SymbolTable;
+use std::ops::Not;
+
+/// A register or a register pair
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Var {
+    A,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    BC,
+    DE,
+    HL,
+    SP,
+    /// A and the flags
+    PSW,
+}
+
+impl Var {
+    fn name(&self) -> &'static str {
+        use Var::*;
+
+        match self {
+            A => "a",
+            B => "b",
+            C => "c",
+            D => "d",
+            E => "e",
+            H => "h",
+            L => "l",
+            BC => "bc",
+            DE => "de",
+            HL => "hl",
+            SP => "sp",
+            PSW => "psw",
+        }
+    }
+
+    /// the registers sharing some bits with `self`
+    fn overlaps(&self, other: Var) -> bool {
+        use Var::*;
+
+        let pair = |v: Var| match v {
+            B | C | BC => Some(BC),
+            D | E | DE => Some(DE),
+            H | L | HL => Some(HL),
+            A | PSW => Some(PSW),
+            SP => Some(SP),
+        };
+        *self == other || (pair(*self) == pair(other) && (self.is_pair() || other.is_pair()))
+    }
+
+    fn is_pair(&self) -> bool {
+        matches!(self, Var::BC | Var::DE | Var::HL | Var::SP | Var::PSW)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Flag {
+    Carry,
+    Zero,
+    Sign,
+    Parity,
+    AuxCarry,
+}
+
+pub const FLAGS: [Flag; 5] = [
+    Flag::Carry,
+    Flag::Zero,
+    Flag::Sign,
+    Flag::Parity,
+    Flag::AuxCarry,
+];
+
+impl Flag {
+    fn name(&self) -> &'static str {
+        match self {
+            Flag::Carry => "cy",
+            Flag::Zero => "z",
+            Flag::Sign => "s",
+            Flag::Parity => "p",
+            Flag::AuxCarry => "ac",
+        }
+    }
+}
+
+/// the flag tested by a condition, and true if the condition is the flag
+/// being set
+pub fn cond_flag(cond: Cond) -> (Flag, bool) {
+    match cond {
+        Cond::NZ => (Flag::Zero, false),
+        Cond::Z => (Flag::Zero, true),
+        Cond::NC => (Flag::Carry, false),
+        Cond::C => (Flag::Carry, true),
+        Cond::PO => (Flag::Parity, false),
+        Cond::PE => (Flag::Parity, true),
+        Cond::P => (Flag::Sign, false),
+        Cond::M => (Flag::Sign, true),
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+    Eq,
+    Ne,
+    Lt,
+    Ge,
+    Gt,
+    Le,
+}
+
+impl BinOp {
+    fn symbol(&self) -> &'static str {
+        use BinOp::*;
+
+        match self {
+            Add => "+",
+            Sub => "-",
+            And => "&",
+            Or => "|",
+            Xor => "^",
+            Shl => "<<",
+            Shr => ">>",
+            Eq => "==",
+            Ne => "!=",
+            Lt => "<",
+            Ge => ">=",
+            Gt => ">",
+            Le => "<=",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UnOp {
+    /// `!`
+    Not,
+    /// `~`
+    Compl,
+    /// `(uint8_t)`
+    Byte,
+    /// `(int8_t)`
+    Signed,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Expr {
+    Const(u16),
+    /// a constant written with its label when there is one
+    Addr(u16),
+    Var(Var),
+    Flag(Flag),
+    /// the byte at an address
+    Mem(Box<Expr>),
+    /// the little endian word at an address
+    Mem16(Box<Expr>),
+    Unary(UnOp, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    /// a helper function like `parity` or `in`
+    Intrinsic(&'static str, Vec<Expr>),
+    /// the condition of a conditional instruction, replaced by the writer
+    Cond(Cond),
+}
+
+fn bin(op: BinOp, l: Expr, r: Expr) -> Expr {
+    Expr::Binary(op, Box::new(l), Box::new(r))
+}
+
+fn un(op: UnOp, e: Expr) -> Expr {
+    Expr::Unary(op, Box::new(e))
+}
+
+fn konst(n: u16) -> Expr {
+    Expr::Const(n)
+}
+
+/// the logical negation, simplified
+impl Not for Expr {
+    type Output = Expr;
+
+    fn not(self) -> Expr {
+        use BinOp::*;
+
+        match self {
+            Expr::Binary(op, l, r) => {
+                let op = match op {
+                    Eq => Ne,
+                    Ne => Eq,
+                    Lt => Ge,
+                    Ge => Lt,
+                    Gt => Le,
+                    Le => Gt,
+                    op => return un(UnOp::Not, Expr::Binary(op, l, r)),
+                };
+                Expr::Binary(op, l, r)
+            }
+            Expr::Unary(UnOp::Not, e) => *e,
+            Expr::Const(n) => konst((n == 0) as u16),
+            e => un(UnOp::Not, e),
+        }
+    }
+}
+
+impl Expr {
+    /// the registers read, and `None` for the memory
+    pub fn reads(&self, out: &mut Vec<Option<Var>>) {
+        match self {
+            Expr::Var(v) => out.push(Some(*v)),
+            Expr::Mem(e) | Expr::Mem16(e) => {
+                out.push(None);
+                e.reads(out);
+            }
+            Expr::Unary(_, e) => e.reads(out),
+            Expr::Binary(_, l, r) => {
+                l.reads(out);
+                r.reads(out);
+            }
+            Expr::Intrinsic(_, args) => args.iter().for_each(|a| a.reads(out)),
+            Expr::Const(_) | Expr::Addr(_) | Expr::Flag(_) | Expr::Cond(_) => (),
+        }
+    }
+
+    /// the flags read
+    pub fn flags(&self, out: &mut Vec<Flag>) {
+        match self {
+            Expr::Flag(f) => out.push(*f),
+            Expr::Cond(c) => out.push(cond_flag(*c).0),
+            Expr::Mem(e) | Expr::Mem16(e) | Expr::Unary(_, e) => e.flags(out),
+            Expr::Binary(_, l, r) => {
+                l.flags(out);
+                r.flags(out);
+            }
+            Expr::Intrinsic(_, args) => args.iter().for_each(|a| a.flags(out)),
+            Expr::Const(_) | Expr::Addr(_) | Expr::Var(_) => (),
+        }
+    }
+
+    /// replace the conditions
+    pub fn map_cond(self, f: &mut impl FnMut(Cond) -> Expr) -> Expr {
+        let mut map = |e: Box<Expr>| Box::new(e.map_cond(f));
+        match self {
+            Expr::Cond(c) => f(c),
+            Expr::Mem(e) => Expr::Mem(map(e)),
+            Expr::Mem16(e) => Expr::Mem16(map(e)),
+            Expr::Unary(op, e) => Expr::Unary(op, map(e)),
+            Expr::Binary(op, l, r) => {
+                let l = map(l);
+                Expr::Binary(op, l, map(r))
+            }
+            Expr::Intrinsic(name, args) => {
+                Expr::Intrinsic(name, args.into_iter().map(|a| a.map_cond(f)).collect())
+            }
+            e => e,
+        }
+    }
+
+    pub fn render(&self, labels: &SymbolTable) -> String {
+        // parenthesize the operations inside other operations
+        let operand = |e: &Expr| match e {
+            Expr::Binary(..) => format!("({})", e.render(labels)),
+            e => e.render(labels),
+        };
+        match self {
+            Expr::Const(n) if *n < 10 => n.to_string(),
+            Expr::Const(n) if *n <= 0xff => format!("{:#04x}", n),
+            Expr::Const(n) => format!("{:#06x}", n),
+            Expr::Addr(n) => match labels.name(*n) {
+                Some(name) => name.to_string(),
+                None => format!("{:#06x}", n),
+            },
+            Expr::Var(v) => v.name().to_string(),
+            Expr::Flag(f) => f.name().to_string(),
+            Expr::Mem(e) => format!("mem[{}]", e.render(labels)),
+            Expr::Mem16(e) => format!("mem16[{}]", e.render(labels)),
+            Expr::Unary(op, e) => {
+                let op = match op {
+                    UnOp::Not => "!",
+                    UnOp::Compl => "~",
+                    UnOp::Byte => "(uint8_t)",
+                    UnOp::Signed => "(int8_t)",
+                };
+                format!("{}{}", op, operand(e))
+            }
+            Expr::Binary(op, l, r) => {
+                // `a + b + cy` does not need parentheses
+                let left = match (op, &**l) {
+                    (BinOp::Add, Expr::Binary(BinOp::Add, ..))
+                    | (BinOp::Or, Expr::Binary(BinOp::Or, ..)) => l.render(labels),
+                    _ => operand(l),
+                };
+                format!("{} {} {}", left, op.symbol(), operand(r))
+            }
+            Expr::Intrinsic(name, args) => {
+                let args: Vec<String> = args.iter().map(|a| a.render(labels)).collect();
+                format!("{}({})", name, args.join(", "))
+            }
+            Expr::Cond(c) => {
+                let (flag, set) = cond_flag(*c);
+                let flag = Expr::Flag(flag);
+                if set { flag } else { flag.not() }.render(labels)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Stmt {
+    /// the left side is a variable, a flag or a memory access
+    Assign(Expr, Expr),
+    Call(u16),
+    /// an intrinsic procedure like `push` or `out`
+    Intrinsic(&'static str, Vec<Expr>),
+    If(Expr, Vec<Stmt>),
+}
+
+impl Stmt {
+    /// the registers written, and `None` for the memory
+    pub fn writes(&self, out: &mut Vec<Option<Var>>) {
+        match self {
+            Stmt::Assign(Expr::Var(v), _) => out.push(Some(*v)),
+            Stmt::Assign(Expr::Flag(_), _) => (),
+            Stmt::Assign(_, _) => out.push(None),
+            Stmt::Intrinsic(_, args) => {
+                // push and swap write their arguments or the stack
+                out.push(None);
+                out.push(Some(Var::SP));
+                for arg in args {
+                    if let Expr::Var(v) = arg {
+                        out.push(Some(*v));
+                    }
+                }
+            }
+            Stmt::Call(_) | Stmt::If(_, _) => {
+                out.push(None);
+                for v in [Var::PSW, Var::BC, Var::DE, Var::HL, Var::SP] {
+                    out.push(Some(v));
+                }
+            }
+        }
+    }
+
+    pub fn map_cond(self, f: &mut impl FnMut(Cond) -> Expr) -> Stmt {
+        match self {
+            Stmt::Assign(l, r) => Stmt::Assign(l.map_cond(f), r.map_cond(f)),
+            Stmt::Intrinsic(name, args) => {
+                Stmt::Intrinsic(name, args.into_iter().map(|a| a.map_cond(f)).collect())
+            }
+            Stmt::If(c, body) => Stmt::If(
+                c.map_cond(f),
+                body.into_iter().map(|s| s.map_cond(f)).collect(),
+            ),
+            s => s,
+        }
+    }
+
+    /// the statement, `name` giving the name of the called functions
+    pub fn render(&self, labels: &SymbolTable, name: &impl Fn(u16) -> String) -> String {
+        match self {
+            Stmt::Assign(l, Expr::Binary(op @ (BinOp::Add | BinOp::Sub), x, one))
+                if **x == *l && **one == konst(1) =>
+            {
+                let op = if *op == BinOp::Add { "++" } else { "--" };
+                format!("{}{};", l.render(labels), op)
+            }
+            Stmt::Assign(l, r) => format!("{} = {};", l.render(labels), r.render(labels)),
+            Stmt::Call(addr) => format!("{}();", name(*addr)),
+            Stmt::Intrinsic(intrinsic, args) => {
+                format!(
+                    "{};",
+                    Expr::Intrinsic(intrinsic, args.clone()).render(labels)
+                )
+            }
+            Stmt::If(c, body) => {
+                let body: Vec<String> = body.iter().map(|s| s.render(labels, name)).collect();
+                format!("if ({}) {}", c.render(labels), body.join(" "))
+            }
+        }
+    }
+}
+
+/// How an instruction sets the flags
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FlagDef {
+    /// the flags are not modified
+    None,
+    /// the flags variables are assigned by the statements themselves, by a
+    /// call or a `pop(psw)`
+    Global,
+    Exprs {
+        /// the value of the flags, computed before the statements
+        before: Vec<(Flag, Expr)>,
+        /// the value of some flags, computed after the statements
+        after: Vec<(Flag, Expr)>,
+    },
+}
+
+impl FlagDef {
+    /// the flags modified
+    pub fn flags(&self) -> Vec<Flag> {
+        match self {
+            FlagDef::None => vec![],
+            FlagDef::Global => FLAGS.to_vec(),
+            FlagDef::Exprs { before, .. } => before.iter().map(|(f, _)| *f).collect(),
+        }
+    }
+}
+
+/// An instruction in the intermediate representation
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Lifted {
+    pub stmts: Vec<Stmt>,
+    pub flags: FlagDef,
+}
+
+impl Lifted {
+    /// the flags read by the statements
+    pub fn uses(&self) -> Vec<Flag> {
+        let mut flags = Vec::new();
+        for stmt in &self.stmts {
+            match stmt {
+                Stmt::Assign(l, r) => {
+                    l.flags(&mut flags);
+                    r.flags(&mut flags);
+                }
+                Stmt::Intrinsic(_, args) => args.iter().for_each(|a| a.flags(&mut flags)),
+                Stmt::If(c, _) => c.flags(&mut flags),
+                Stmt::Call(_) => (),
+            }
+        }
+        flags
+    }
+}
+
+fn reg(r: Reg) -> Expr {
+    match r {
+        Reg::B => Expr::Var(Var::B),
+        Reg::C => Expr::Var(Var::C),
+        Reg::D => Expr::Var(Var::D),
+        Reg::E => Expr::Var(Var::E),
+        Reg::H => Expr::Var(Var::H),
+        Reg::L => Expr::Var(Var::L),
+        Reg::M => Expr::Mem(Box::new(Expr::Var(Var::HL))),
+        Reg::A => Expr::Var(Var::A),
+    }
+}
+
+fn pair(p: Pair) -> Expr {
+    Expr::Var(match p {
+        Pair::BC => Var::BC,
+        Pair::DE => Var::DE,
+        Pair::HL => Var::HL,
+        Pair::SP => Var::SP,
+    })
+}
+
+fn pair_psw(p: PairPsw) -> Expr {
+    Expr::Var(match p {
+        PairPsw::BC => Var::BC,
+        PairPsw::DE => Var::DE,
+        PairPsw::HL => Var::HL,
+        PairPsw::PSW => Var::PSW,
+    })
+}
+
+fn parity(e: Expr) -> Expr {
+    Expr::Intrinsic("parity", vec![e])
+}
+
+/// the zero, sign and parity flags of an 8 bit result
+fn result_flags(result: Expr) -> Vec<(Flag, Expr)> {
+    vec![
+        (
+            Flag::Zero,
+            bin(BinOp::Eq, un(UnOp::Byte, result.clone()), konst(0)),
+        ),
+        (
+            Flag::Sign,
+            bin(BinOp::Lt, un(UnOp::Signed, result.clone()), konst(0)),
+        ),
+        (Flag::Parity, parity(un(UnOp::Byte, result))),
+    ]
+}
+
+/// the zero, sign and parity flags of a value already stored
+fn value_flags(value: Expr) -> Vec<(Flag, Expr)> {
+    vec![
+        (Flag::Zero, bin(BinOp::Eq, value.clone(), konst(0))),
+        (
+            Flag::Sign,
+            bin(BinOp::Lt, un(UnOp::Signed, value.clone()), konst(0)),
+        ),
+        (Flag::Parity, parity(value)),
+    ]
+}
+
+fn nibble(e: Expr) -> Expr {
+    bin(BinOp::And, e, konst(0x0f))
+}
+
+/// lift an arithmetic or logic operation of A with `x`
+fn alu(op: AluOp, x: Expr) -> Lifted {
+    use BinOp::*;
+
+    let a = || Expr::Var(Var::A);
+    let cy = || Expr::Flag(Flag::Carry);
+    let assign = |e: Expr| vec![Stmt::Assign(a(), e)];
+    let (stmts, mut before, mut after) = match op {
+        AluOp::Add | AluOp::Adc => {
+            let mut sum = bin(Add, a(), x.clone());
+            let mut nibbles = bin(Add, nibble(a()), nibble(x.clone()));
+            if op == AluOp::Adc {
+                sum = bin(Add, sum, cy());
+                nibbles = bin(Add, nibbles, cy());
+            }
+            let mut before = vec![
+                (Flag::Carry, bin(Gt, sum.clone(), konst(0xff))),
+                (Flag::AuxCarry, bin(Gt, nibbles, konst(0x0f))),
+            ];
+            before.extend(result_flags(sum.clone()));
+            (assign(sum), before, value_flags(a()))
+        }
+        AluOp::Sub | AluOp::Sbb | AluOp::Cmp => {
+            let (diff, carry, aux) = match op {
+                AluOp::Sbb => (
+                    bin(Sub, bin(Sub, a(), x.clone()), cy()),
+                    bin(Lt, a(), bin(Add, x.clone(), cy())),
+                    bin(Ge, nibble(a()), bin(Add, nibble(x.clone()), cy())),
+                ),
+                _ => (
+                    bin(Sub, a(), x.clone()),
+                    bin(Lt, a(), x.clone()),
+                    bin(Ge, nibble(a()), nibble(x.clone())),
+                ),
+            };
+            let mut before = vec![(Flag::Carry, carry.clone()), (Flag::AuxCarry, aux)];
+            if op == AluOp::Sbb {
+                before.extend(result_flags(diff.clone()));
+            } else {
+                before.push((Flag::Zero, bin(Eq, a(), x.clone())));
+                before.extend(result_flags(diff.clone()).into_iter().skip(1));
+            }
+            match op {
+                // the operands are unchanged, all the flags can be computed after
+                AluOp::Cmp => (vec![], before.clone(), before),
+                _ => (assign(diff), before, value_flags(a())),
+            }
+        }
+        AluOp::Ana | AluOp::Xra | AluOp::Ora => {
+            let (bop, aux) = match op {
+                AluOp::Ana => (
+                    And,
+                    bin(Ne, bin(And, bin(Or, a(), x.clone()), konst(0x08)), konst(0)),
+                ),
+                AluOp::Xra => (Xor, konst(0)),
+                _ => (Or, konst(0)),
+            };
+            let result = match (op, &x) {
+                (AluOp::Xra, Expr::Var(Var::A)) => konst(0),
+                (_, Expr::Var(Var::A)) => a(),
+                _ => bin(bop, a(), x.clone()),
+            };
+            let mut before = vec![(Flag::Carry, konst(0)), (Flag::AuxCarry, aux)];
+            before.extend(match result {
+                Expr::Const(_) => vec![
+                    (Flag::Zero, konst(1)),
+                    (Flag::Sign, konst(0)),
+                    (Flag::Parity, konst(1)),
+                ],
+                _ => result_flags(result.clone()),
+            });
+            let mut after = value_flags(a());
+            after.push((Flag::Carry, konst(0)));
+            let stmts = match result {
+                // ORA A and ANA A only set the flags
+                Expr::Var(Var::A) => vec![],
+                result => assign(result),
+            };
+            (stmts, before, after)
+        }
+    };
+    // the constant flags of the logic operations are known anyway
+    before.sort_by_key(|(f, _)| *f);
+    after.sort_by_key(|(f, _)| *f);
+    Lifted {
+        stmts,
+        flags: FlagDef::Exprs { before, after },
+    }
+}
+
+/// lift INR and DCR
+fn inc_dec(r: Reg, inc: bool) -> Lifted {
+    let x = reg(r);
+    let (op, aux) = if inc {
+        (BinOp::Add, bin(BinOp::Eq, nibble(x.clone()), konst(0x0f)))
+    } else {
+        (BinOp::Sub, bin(BinOp::Ne, nibble(x.clone()), konst(0)))
+    };
+    let result = bin(op, x.clone(), konst(1));
+    let mut before = vec![(Flag::AuxCarry, aux)];
+    before.extend(result_flags(result.clone()));
+    Lifted {
+        stmts: vec![Stmt::Assign(x.clone(), result)],
+        flags: FlagDef::Exprs {
+            before,
+            after: value_flags(x),
+        },
+    }
+}
+
+/// lift the instructions which do not end a block. The jumps and returns are
+/// handled by the structuring of the CFG.
+pub fn lift(instruction: Instruction) -> Lifted {
+    use BinOp::*;
+    use Instruction::*;
+
+    let a = || Expr::Var(Var::A);
+    let hl = || Expr::Var(Var::HL);
+    let cy = || Expr::Flag(Flag::Carry);
+    let stmts = |stmts: Vec<Stmt>| Lifted {
+        stmts,
+        flags: FlagDef::None,
+    };
+    let carry = |stmt: Stmt, before: Expr, after: Option<Expr>| Lifted {
+        stmts: vec![stmt],
+        flags: FlagDef::Exprs {
+            before: vec![(Flag::Carry, before)],
+            after: after.map(|e| vec![(Flag::Carry, e)]).unwrap_or_default(),
+        },
+    };
+    let assign = |l: Expr, r: Expr| vec![Stmt::Assign(l, r)];
+    let mem = |e: Expr| Expr::Mem(Box::new(e));
+    let intrinsic = |name: &'static str, args: Vec<Expr>| vec![Stmt::Intrinsic(name, args)];
+
+    match instruction {
+        Nop | Jmp(_) | Jcc(_, _) | Ret | Rcc(_) | Pchl | Hlt => stmts(vec![]),
+        Lxi(p, n) => stmts(assign(pair(p), Expr::Addr(n))),
+        Stax(p) => stmts(assign(mem(pair(p)), a())),
+        Ldax(p) => stmts(assign(a(), mem(pair(p)))),
+        Shld(n) => stmts(assign(Expr::Mem16(Box::new(Expr::Addr(n))), hl())),
+        Lhld(n) => stmts(assign(hl(), Expr::Mem16(Box::new(Expr::Addr(n))))),
+        Sta(n) => stmts(assign(mem(Expr::Addr(n)), a())),
+        Lda(n) => stmts(assign(a(), mem(Expr::Addr(n)))),
+        Inx(p) => stmts(assign(pair(p), bin(Add, pair(p), konst(1)))),
+        Dcx(p) => stmts(assign(pair(p), bin(Sub, pair(p), konst(1)))),
+        Inr(r) => inc_dec(r, true),
+        Dcr(r) => inc_dec(r, false),
+        Mvi(r, n) => stmts(assign(reg(r), konst(n as u16))),
+        Mov(dst, src) => stmts(assign(reg(dst), reg(src))),
+        Rlc => carry(
+            Stmt::Assign(
+                a(),
+                bin(Or, bin(Shl, a(), konst(1)), bin(Shr, a(), konst(7))),
+            ),
+            bin(Shr, a(), konst(7)),
+            Some(bin(And, a(), konst(1))),
+        ),
+        Rrc => carry(
+            Stmt::Assign(
+                a(),
+                bin(Or, bin(Shr, a(), konst(1)), bin(Shl, a(), konst(7))),
+            ),
+            bin(And, a(), konst(1)),
+            Some(bin(Shr, a(), konst(7))),
+        ),
+        Ral => carry(
+            Stmt::Assign(a(), bin(Or, bin(Shl, a(), konst(1)), cy())),
+            bin(Shr, a(), konst(7)),
+            None,
+        ),
+        Rar => carry(
+            Stmt::Assign(
+                a(),
+                bin(Or, bin(Shr, a(), konst(1)), bin(Shl, cy(), konst(7))),
+            ),
+            bin(And, a(), konst(1)),
+            None,
+        ),
+        Dad(p) => carry(
+            Stmt::Assign(hl(), bin(Add, hl(), pair(p))),
+            bin(Gt, bin(Add, hl(), pair(p)), konst(0xffff)),
+            None,
+        ),
+        Daa => Lifted {
+            // reads A, CY and AC, sets A and every flag
+            stmts: intrinsic("daa", vec![a(), cy(), Expr::Flag(Flag::AuxCarry)]),
+            flags: FlagDef::Global,
+        },
+        Cma => stmts(assign(a(), un(UnOp::Compl, a()))),
+        Stc => Lifted {
+            stmts: vec![],
+            flags: FlagDef::Exprs {
+                before: vec![(Flag::Carry, konst(1))],
+                after: vec![(Flag::Carry, konst(1))],
+            },
+        },
+        Cmc => Lifted {
+            // the only statement reading a flag it sets
+            stmts: vec![Stmt::Assign(cy(), cy().not())],
+            flags: FlagDef::None,
+        },
+        Alu(op, r) => alu(op, reg(r)),
+        AluImm(op, n) => alu(op, konst(n as u16)),
+        Pop(PairPsw::PSW) => Lifted {
+            stmts: assign(Expr::Var(Var::PSW), Expr::Intrinsic("pop", vec![])),
+            flags: FlagDef::Global,
+        },
+        Pop(p) => stmts(assign(pair_psw(p), Expr::Intrinsic("pop", vec![]))),
+        Push(PairPsw::PSW) => stmts(intrinsic(
+            "push",
+            // push(psw) reads the flags
+            vec![Expr::Intrinsic(
+                "psw",
+                FLAGS.iter().map(|f| Expr::Flag(*f)).collect(),
+            )],
+        )),
+        Push(p) => stmts(intrinsic("push", vec![pair_psw(p)])),
+        Call(t) => Lifted {
+            stmts: vec![Stmt::Call(t)],
+            flags: FlagDef::Global,
+        },
+        Rst(n) => Lifted {
+            stmts: vec![Stmt::Call(n as u16 * 8)],
+            flags: FlagDef::Global,
+        },
+        Ccc(c, t) => Lifted {
+            stmts: vec![Stmt::If(Expr::Cond(c), vec![Stmt::Call(t)])],
+            // the flags may be set by the call, or kept when it is not made
+            flags: FlagDef::Global,
+        },
+        Out(port) => stmts(intrinsic("out", vec![konst(port as u16), a()])),
+        In(port) => stmts(assign(a(), Expr::Intrinsic("in", vec![konst(port as u16)]))),
+        Xthl => stmts(intrinsic(
+            "swap",
+            vec![hl(), Expr::Mem16(Box::new(Expr::Var(Var::SP)))],
+        )),
+        Xchg => stmts(intrinsic("swap", vec![Expr::Var(Var::DE), hl()])),
+        Di => stmts(intrinsic("disable_interrupts", vec![])),
+        Ei => stmts(intrinsic("enable_interrupts", vec![])),
+        Sphl => stmts(assign(Expr::Var(Var::SP), hl())),
+        Undocumented(opcode) => stmts(intrinsic("undocumented", vec![konst(opcode as u16)])),
+    }
+}
+
+/// true if writing `writes` may change the value of `reads`
+pub fn conflicts(reads: &[Option<Var>], writes: &[Option<Var>]) -> bool {
+    reads.iter().any(|r| {
+        writes.iter().any(|w| match (r, w) {
+            (Some(r), Some(w)) => r.overlaps(*w),
+            (None, None) => true,
+            _ => false,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(instruction: Instruction) -> Vec<String> {
+        lift(instruction)
+            .stmts
+            .iter()
+            .map(|s| s.render(&SymbolTable::new(), &|addr| format!("sub_{:04x}", addr)))
+            .collect()
+    }
+
+    #[test]
+    fn test_lift() {
+        use Instruction::*;
+
+        assert_eq!(render(Mov(Reg::A, Reg::M)), ["a = mem[hl];"]);
+        assert_eq!(render(Inx(Pair::HL)), ["hl++;"]);
+        assert_eq!(render(Alu(AluOp::Adc, Reg::B)), ["a = a + b + cy;"]);
+        assert_eq!(render(Alu(AluOp::Xra, Reg::A)), ["a = 0;"]);
+        assert!(render(Alu(AluOp::Ora, Reg::A)).is_empty());
+        assert_eq!(render(Rlc), ["a = (a << 1) | (a >> 7);"]);
+        assert_eq!(render(Ccc(Cond::NZ, 0x10)), ["if (!z) sub_0010();"]);
+        assert_eq!(render(Lhld(0x2400)), ["hl = mem16[0x2400];"]);
+        assert_eq!(render(Xchg), ["swap(de, hl);"]);
+    }
+
+    #[test]
+    fn test_flags() {
+        let labels = SymbolTable::new();
+        let flags = |instruction| match lift(instruction).flags {
+            FlagDef::Exprs { before, after } => (before, after),
+            other => panic!("{:?}", other),
+        };
+        let find = |flags: &[(Flag, Expr)], flag| {
+            flags
+                .iter()
+                .find(|(f, _)| *f == flag)
+                .map(|(_, e)| e.render(&labels))
+        };
+
+        let (before, after) = flags(Instruction::AluImm(AluOp::Cmp, 0x0a));
+        assert_eq!(find(&before, Flag::Carry).unwrap(), "a < 0x0a");
+        assert_eq!(find(&after, Flag::Zero).unwrap(), "a == 0x0a");
+
+        let (before, after) = flags(Instruction::Alu(AluOp::Add, Reg::B));
+        assert_eq!(find(&before, Flag::Carry).unwrap(), "(a + b) > 0xff");
+        // the carry of an addition can not be computed from the result
+        assert_eq!(find(&after, Flag::Carry), None);
+        assert_eq!(find(&after, Flag::Zero).unwrap(), "a == 0");
+
+        assert!(Var::H.overlaps(Var::HL));
+        assert!(!Var::H.overlaps(Var::L));
+        assert!(conflicts(&[Some(Var::HL)], &[Some(Var::L)]));
+    }
+}
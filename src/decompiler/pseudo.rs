@@ -0,0 +1,784 @@
+//! Pseudo-C decompiler.
+//!
+//! Each [Function] of a [Program] is lifted to the [ir](super::ir), and its
+//! CFG is structured in `if`, `while`, `do while` and `while (1)` loops
+//! using the dominators and the post-dominators of the blocks. The edges
+//! which do not fit are kept as `goto`, so the control flow is always the
+//! one of the machine code.
+//!
+//! The registers and the flags are global variables. A conditional jump
+//! after a comparison is written as the comparison, like `a < 0x0a`, when
+//! the compared values did not change in between. Otherwise the flags are
+//! assigned where they are set and tested where they are read. Every flag is
+//! assumed to be read after the returns and the tail calls, as a routine can
+//! return a status in any of them.
+
+use super::cfg::{Function, Program};
+use super::ir::{cond_flag, conflicts, lift, Expr, Flag, FlagDef, Lifted, Stmt, Var, FLAGS};
+use crate::instruction::{Cond, Instruction};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Not;
+
+const HEADER: &str = "\
+/*
+ * uint8_t a, b, c, d, e, h, l are the registers, uint16_t bc, de and hl
+ * are the pairs b:c, d:e and h:l, and psw is a with the flags.
+ * cy, z, s, p and ac are the flags, assigned only when they are read.
+ * mem[] is the memory and mem16[] its little endian words.
+ * parity(x) is true when x has an even number of bits set.
+ * halt(), jump(addr) and goto *hl do not return.
+ */
+";
+
+/// Where a block goes
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Target {
+    Block(u16),
+    /// a jump to another function
+    Tail(u16),
+    Return,
+    /// PCHL
+    Indirect,
+    Halt,
+    /// the code stops at an address not disassembled
+    Unknown(u16),
+}
+
+/// How a block ends
+#[derive(Clone, Copy, Debug)]
+enum Exit {
+    Jump(Target),
+    /// the address of the conditional instruction, its condition, the
+    /// targets when true and when false
+    Branch(u16, Cond, Target, Target),
+}
+
+impl Exit {
+    fn targets(&self) -> Vec<Target> {
+        match *self {
+            Exit::Jump(t) => vec![t],
+            Exit::Branch(_, _, taken, not) => vec![taken, not],
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Stmt(String),
+    Label(u16),
+    Goto(u16),
+    If(Expr, Vec<Node>, Vec<Node>),
+    Loop(Vec<Node>),
+    While(Expr, Vec<Node>),
+    DoWhile(Vec<Node>, Expr),
+    Break,
+    Continue,
+    Return,
+}
+
+fn mask(flags: &[Flag]) -> u8 {
+    flags.iter().fold(0, |m, f| m | 1 << *f as u8)
+}
+
+/// the dominators of every node, `preds` giving the predecessors
+fn dominators(
+    nodes: &BTreeSet<u32>,
+    root: u32,
+    preds: &BTreeMap<u32, Vec<u32>>,
+) -> BTreeMap<u32, BTreeSet<u32>> {
+    let mut doms: BTreeMap<u32, BTreeSet<u32>> =
+        nodes.iter().map(|&n| (n, nodes.clone())).collect();
+    doms.insert(root, std::iter::once(root).collect());
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &n in nodes.iter().filter(|&&n| n != root) {
+            let mut new: Option<BTreeSet<u32>> = None;
+            for p in preds.get(&n).into_iter().flatten() {
+                let d = &doms[p];
+                new = Some(match new {
+                    None => d.clone(),
+                    Some(new) => new.intersection(d).copied().collect(),
+                });
+            }
+            let mut new = new.unwrap_or_default();
+            new.insert(n);
+            if new != doms[&n] {
+                doms.insert(n, new);
+                changed = true;
+            }
+        }
+    }
+    doms
+}
+
+/// the closest strict dominator of `n`
+fn immediate(doms: &BTreeMap<u32, BTreeSet<u32>>, n: u32) -> Option<u32> {
+    doms[&n]
+        .iter()
+        .filter(|&&d| d != n)
+        .max_by_key(|d| doms[d].len())
+        .copied()
+}
+
+/// the virtual node after the exits of a function
+const EXIT: u32 = 0x10000;
+
+struct Loop {
+    nodes: BTreeSet<u16>,
+    /// the block after the loop
+    follow: Option<u16>,
+}
+
+struct Decompiler<'a> {
+    program: &'a Program,
+    function: &'a Function,
+    lifted: BTreeMap<u16, Lifted>,
+    exits: BTreeMap<u16, Exit>,
+    /// the conditions of the instructions computed from the values compared
+    fused: BTreeMap<u16, Expr>,
+    /// the flags live after every instruction
+    live: BTreeMap<u16, u8>,
+    /// the immediate post-dominators
+    joins: BTreeMap<u16, u16>,
+    /// the loops by header
+    loops: BTreeMap<u16, Loop>,
+    emitted: BTreeSet<u16>,
+    gotos: BTreeSet<u16>,
+}
+
+impl<'a> Decompiler<'a> {
+    fn new(program: &'a Program, function: &'a Function) -> Self {
+        let mut d = Decompiler {
+            program,
+            function,
+            lifted: BTreeMap::new(),
+            exits: BTreeMap::new(),
+            fused: BTreeMap::new(),
+            live: BTreeMap::new(),
+            joins: BTreeMap::new(),
+            loops: BTreeMap::new(),
+            emitted: BTreeSet::new(),
+            gotos: BTreeSet::new(),
+        };
+        for block in function.blocks.values() {
+            for &(addr, instruction) in &block.instructions {
+                d.lifted.insert(addr, lift(instruction));
+            }
+            let exit = d.exit(block.instructions.last().copied());
+            d.exits.insert(block.start, exit);
+        }
+        d.fuse();
+        d.liveness();
+        d.structure();
+        d
+    }
+
+    fn target(&self, addr: u16) -> Target {
+        if self.function.blocks.contains_key(&addr) {
+            Target::Block(addr)
+        } else if self.function.calls.contains(&addr) {
+            Target::Tail(addr)
+        } else {
+            Target::Unknown(addr)
+        }
+    }
+
+    fn exit(&self, last: Option<(u16, Instruction)>) -> Exit {
+        use Instruction::*;
+
+        let (addr, instruction) = match last {
+            Some(last) => last,
+            None => return Exit::Jump(Target::Return),
+        };
+        let next = addr.wrapping_add(instruction.len() as u16);
+        match instruction {
+            Jmp(t) => Exit::Jump(self.target(t)),
+            Jcc(c, t) => Exit::Branch(addr, c, self.target(t), self.target(next)),
+            Rcc(c) => Exit::Branch(addr, c, Target::Return, self.target(next)),
+            Ret => Exit::Jump(Target::Return),
+            Pchl => Exit::Jump(Target::Indirect),
+            Hlt => Exit::Jump(Target::Halt),
+            _ => Exit::Jump(self.target(next)),
+        }
+    }
+
+    /// find the conditions which can be written with the values compared
+    fn fuse(&mut self) {
+        use Instruction::*;
+
+        for block in self.function.blocks.values() {
+            // the expression of a flag and the registers it reads
+            let mut current: BTreeMap<Flag, (Expr, Vec<Option<Var>>)> = BTreeMap::new();
+            for &(addr, instruction) in &block.instructions {
+                if let Jcc(c, _) | Rcc(c) | Ccc(c, _) = instruction {
+                    let (flag, set) = cond_flag(c);
+                    if let Some((e, _)) = current.get(&flag) {
+                        let e = if set { e.clone() } else { e.clone().not() };
+                        self.fused.insert(addr, e);
+                    }
+                }
+                let lifted = &self.lifted[&addr];
+                let mut writes = Vec::new();
+                lifted.stmts.iter().for_each(|s| s.writes(&mut writes));
+                current.retain(|_, (_, reads)| !conflicts(reads, &writes));
+                // CMC assigns the carry in its statement
+                for stmt in &lifted.stmts {
+                    if let Stmt::Assign(Expr::Flag(flag), _) = stmt {
+                        current.remove(flag);
+                    }
+                }
+                match &lifted.flags {
+                    FlagDef::None => (),
+                    FlagDef::Global => current.clear(),
+                    FlagDef::Exprs { before, after } => {
+                        for (flag, _) in before {
+                            current.remove(flag);
+                        }
+                        for (flag, e) in after {
+                            let mut reads = Vec::new();
+                            e.reads(&mut reads);
+                            current.insert(*flag, (e.clone(), reads));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// the flags read by an instruction
+    fn uses(&self, addr: u16, instruction: Instruction) -> u8 {
+        let mut uses = mask(&self.lifted[&addr].uses());
+        if let Instruction::Jcc(c, _) | Instruction::Rcc(c) = instruction {
+            uses |= mask(&[cond_flag(c).0]);
+        }
+        if self.fused.contains_key(&addr) {
+            if let Instruction::Jcc(c, _) | Instruction::Rcc(c) | Instruction::Ccc(c, _) =
+                instruction
+            {
+                uses &= !mask(&[cond_flag(c).0]);
+            }
+        }
+        uses
+    }
+
+    /// the flags live after every instruction
+    fn liveness(&mut self) {
+        let mut live_in: BTreeMap<u16, u8> = BTreeMap::new();
+        loop {
+            let mut changed = false;
+            for block in self.function.blocks.values().rev() {
+                let mut live = 0;
+                for target in self.exits[&block.start].targets() {
+                    live |= match target {
+                        Target::Block(b) => live_in.get(&b).copied().unwrap_or(0),
+                        Target::Return
+                        | Target::Tail(_)
+                        | Target::Indirect
+                        | Target::Unknown(_) => mask(&FLAGS),
+                        Target::Halt => 0,
+                    };
+                }
+                for &(addr, instruction) in block.instructions.iter().rev() {
+                    self.live.insert(addr, live);
+                    // the flags are kept when a conditional call is not taken
+                    if !matches!(instruction, Instruction::Ccc(_, _)) {
+                        live &= !mask(&self.lifted[&addr].flags.flags());
+                    }
+                    live |= self.uses(addr, instruction);
+                }
+                if live_in.insert(block.start, live) != Some(live) {
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+    }
+
+    /// find the joins of the branches and the loops
+    fn structure(&mut self) {
+        let entry = self.function.entry as u32;
+        let mut nodes: BTreeSet<u32> = self.function.blocks.keys().map(|&b| b as u32).collect();
+        let mut preds: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+        let mut succs: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+        for (&b, exit) in &self.exits {
+            for target in exit.targets() {
+                let t = match target {
+                    Target::Block(t) => t as u32,
+                    _ => EXIT,
+                };
+                preds.entry(t).or_default().push(b as u32);
+                succs.entry(b as u32).or_default().push(t);
+            }
+        }
+        let doms = dominators(&nodes, entry, &preds);
+
+        // the natural loops of the back edges
+        for (&b, exit) in &self.exits {
+            for target in exit.targets() {
+                let h = match target {
+                    Target::Block(h) if doms[&(b as u32)].contains(&(h as u32)) => h,
+                    _ => continue,
+                };
+                let lp = self.loops.entry(h).or_insert_with(|| Loop {
+                    nodes: std::iter::once(h).collect(),
+                    follow: None,
+                });
+                let mut todo = vec![b];
+                while let Some(n) = todo.pop() {
+                    if lp.nodes.insert(n) {
+                        todo.extend(preds[&(n as u32)].iter().map(|&p| p as u16));
+                    }
+                }
+            }
+        }
+
+        // the post-dominators, ignoring the blocks never reaching an exit
+        nodes.insert(EXIT);
+        let mut exiting = BTreeSet::new();
+        let mut todo = vec![EXIT];
+        while let Some(n) = todo.pop() {
+            if exiting.insert(n) {
+                todo.extend(preds.get(&n).into_iter().flatten().copied());
+            }
+        }
+        let pdoms = dominators(&exiting, EXIT, &succs);
+        for &n in exiting.iter().filter(|&&n| n != EXIT) {
+            match immediate(&pdoms, n) {
+                Some(j) if j != EXIT => {
+                    self.joins.insert(n as u16, j as u16);
+                }
+                _ => (),
+            }
+        }
+
+        // the follow of a loop is its post-dominator, or its most common exit
+        for (h, lp) in self.loops.iter_mut() {
+            let mut exits: BTreeMap<u16, usize> = BTreeMap::new();
+            for n in &lp.nodes {
+                for target in self.exits[n].targets() {
+                    if let Target::Block(t) = target {
+                        if !lp.nodes.contains(&t) {
+                            *exits.entry(t).or_default() += 1;
+                        }
+                    }
+                }
+            }
+            lp.follow = match self.joins.get(h) {
+                Some(j) if exits.contains_key(j) => Some(*j),
+                _ => exits.iter().max_by_key(|(_, n)| **n).map(|(t, _)| *t),
+            };
+        }
+    }
+
+    fn name(&self, addr: u16) -> String {
+        match self.program.labels().name(addr) {
+            Some(name) => name.to_string(),
+            None => format!("sub_{:04x}", addr),
+        }
+    }
+
+    fn label(&self, addr: u16) -> String {
+        match self.program.labels().name(addr) {
+            Some(name) => name.to_string(),
+            None => format!("L{:04X}", addr),
+        }
+    }
+
+    /// the condition of the instruction at `addr`
+    fn cond(&self, addr: u16, cond: Cond) -> Expr {
+        self.fused.get(&addr).cloned().unwrap_or(Expr::Cond(cond))
+    }
+
+    /// the statements of a block, with the flags read later
+    fn statements(&self, start: u16) -> Vec<Node> {
+        let labels = self.program.labels();
+        let name = |addr| self.name(addr);
+        let mut nodes = Vec::new();
+        for &(addr, _) in &self.function.blocks[&start].instructions {
+            let lifted = &self.lifted[&addr];
+            if let FlagDef::Exprs { before, .. } = &lifted.flags {
+                for (flag, e) in before {
+                    if self.live[&addr] & mask(&[*flag]) != 0 {
+                        let stmt = Stmt::Assign(Expr::Flag(*flag), e.clone());
+                        nodes.push(Node::Stmt(stmt.render(labels, &name)));
+                    }
+                }
+            }
+            for stmt in &lifted.stmts {
+                let stmt = stmt.clone().map_cond(&mut |c| self.cond(addr, c));
+                nodes.push(Node::Stmt(stmt.render(labels, &name)));
+            }
+        }
+        nodes
+    }
+
+    /// the nodes of a target which does not continue in the function
+    fn leaf(&self, target: Target) -> Vec<Node> {
+        match target {
+            Target::Tail(addr) => vec![
+                Node::Stmt(format!("{}(); /* tail call */", self.name(addr))),
+                Node::Return,
+            ],
+            Target::Return => vec![Node::Return],
+            Target::Indirect => vec![Node::Stmt("goto *hl;".to_string())],
+            Target::Halt => vec![Node::Stmt("halt();".to_string())],
+            Target::Unknown(addr) => vec![Node::Stmt(format!("jump({:#06x});", addr))],
+            Target::Block(_) => unreachable!(),
+        }
+    }
+
+    /// the code from `target` until `stop`, in the loop of header `ctx`
+    fn region(&mut self, mut target: Target, stop: Option<u16>, ctx: Option<u16>) -> Vec<Node> {
+        let mut nodes = Vec::new();
+        loop {
+            let b = match target {
+                Target::Block(b) => b,
+                leaf => {
+                    nodes.extend(self.leaf(leaf));
+                    return nodes;
+                }
+            };
+            if Some(b) == stop {
+                return nodes;
+            }
+            if let Some(h) = ctx {
+                if b == h {
+                    nodes.push(Node::Continue);
+                    return nodes;
+                }
+                if Some(b) == self.loops[&h].follow {
+                    nodes.push(Node::Break);
+                    return nodes;
+                }
+            }
+            if self.emitted.contains(&b) {
+                self.gotos.insert(b);
+                nodes.push(Node::Goto(b));
+                return nodes;
+            }
+            nodes.push(Node::Label(b));
+            if self.loops.contains_key(&b) {
+                let mut body = Vec::new();
+                let next = self.block(b, stop, Some(b), &mut body);
+                if let Some(next) = next {
+                    body.extend(self.region(next, None, Some(b)));
+                }
+                nodes.push(Node::Loop(body));
+                match self.loops[&b].follow {
+                    Some(follow) => target = Target::Block(follow),
+                    None => return nodes,
+                }
+            } else {
+                match self.block(b, stop, ctx, &mut nodes) {
+                    Some(next) => target = next,
+                    None => return nodes,
+                }
+            }
+        }
+    }
+
+    /// the code of the block `b`, and where it continues
+    fn block(
+        &mut self,
+        b: u16,
+        stop: Option<u16>,
+        ctx: Option<u16>,
+        nodes: &mut Vec<Node>,
+    ) -> Option<Target> {
+        self.emitted.insert(b);
+        nodes.extend(self.statements(b));
+        match self.exits[&b] {
+            Exit::Jump(target) => Some(target),
+            Exit::Branch(addr, cond, taken, not) => {
+                // the join must be in the current loop
+                let join = self.joins.get(&b).copied().filter(|j| match ctx {
+                    Some(h) => self.loops[&h].nodes.contains(j),
+                    None => true,
+                });
+                let inner = join.or(stop);
+                let then = self.region(taken, inner, ctx);
+                let other = self.region(not, inner, ctx);
+                nodes.push(Node::If(self.cond(addr, cond), then, other));
+                join.map(Target::Block)
+            }
+        }
+    }
+
+    fn decompile(mut self) -> String {
+        let body = self.region(Target::Block(self.function.entry), None, None);
+        let body = simplify(body);
+        let mut out = format!("void {}(void)\n{{\n", self.name(self.function.entry));
+        self.render(&body, 1, &mut out);
+        out.push_str("}\n");
+        out
+    }
+
+    fn render(&self, nodes: &[Node], depth: usize, out: &mut String) {
+        let labels = self.program.labels();
+        let indent = "    ".repeat(depth);
+        for (i, node) in nodes.iter().enumerate() {
+            match node {
+                Node::Stmt(s) => out.push_str(&format!("{}{}\n", indent, s)),
+                Node::Label(b) if self.gotos.contains(b) => {
+                    // a label must be followed by a statement
+                    let empty = if i + 1 == nodes.len() { " ;" } else { "" };
+                    out.push_str(&format!("{}:{}\n", self.label(*b), empty));
+                }
+                Node::Label(_) => (),
+                Node::Goto(b) => out.push_str(&format!("{}goto {};\n", indent, self.label(*b))),
+                Node::If(c, then, other) => {
+                    out.push_str(&format!("{}if ({}) {{\n", indent, c.render(labels)));
+                    self.render(then, depth + 1, out);
+                    if !other.is_empty() {
+                        out.push_str(&format!("{}}} else {{\n", indent));
+                        self.render(other, depth + 1, out);
+                    }
+                    out.push_str(&format!("{}}}\n", indent));
+                }
+                Node::Loop(body) => {
+                    out.push_str(&format!("{}while (1) {{\n", indent));
+                    self.render(body, depth + 1, out);
+                    out.push_str(&format!("{}}}\n", indent));
+                }
+                Node::While(c, body) => {
+                    out.push_str(&format!("{}while ({}) {{\n", indent, c.render(labels)));
+                    self.render(body, depth + 1, out);
+                    out.push_str(&format!("{}}}\n", indent));
+                }
+                Node::DoWhile(body, c) => {
+                    out.push_str(&format!("{}do {{\n", indent));
+                    self.render(body, depth + 1, out);
+                    out.push_str(&format!("{}}} while ({});\n", indent, c.render(labels)));
+                }
+                Node::Break => out.push_str(&format!("{}break;\n", indent)),
+                Node::Continue => out.push_str(&format!("{}continue;\n", indent)),
+                Node::Return => out.push_str(&format!("{}return;\n", indent)),
+            }
+        }
+    }
+}
+
+/// true if `nodes` continue the current loop
+fn continues(nodes: &[Node]) -> bool {
+    nodes.iter().any(|node| match node {
+        Node::Continue => true,
+        Node::If(_, then, other) => continues(then) || continues(other),
+        _ => false,
+    })
+}
+
+/// true if `nodes` never continue after their end
+fn jumps(nodes: &[Node]) -> bool {
+    matches!(
+        nodes.last(),
+        Some(Node::Return | Node::Break | Node::Continue | Node::Goto(_))
+    )
+}
+
+/// rewrite the conditions and the loops in their usual form
+fn simplify(nodes: Vec<Node>) -> Vec<Node> {
+    let mut out = Vec::new();
+    for node in nodes {
+        match node {
+            Node::If(c, then, other) => {
+                let (then, other) = (simplify(then), simplify(other));
+                if then.is_empty() && other.is_empty() {
+                    continue;
+                }
+                // the else is not needed after a jump
+                if jumps(&then) {
+                    out.push(Node::If(c, then, vec![]));
+                    out.extend(other);
+                } else if then.is_empty() || jumps(&other) {
+                    out.push(Node::If(c.not(), other, vec![]));
+                    out.extend(then);
+                } else {
+                    out.push(Node::If(c, then, other));
+                }
+            }
+            Node::Loop(body) => out.push(simplify_loop(simplify(body))),
+            node => out.push(node),
+        }
+    }
+    out
+}
+
+fn simplify_loop(mut body: Vec<Node>) -> Node {
+    if let Some(Node::Continue) = body.last() {
+        body.pop();
+    }
+    // if (c) continue; break; or if (c) break; at the end
+    let n = body.len();
+    let end = match &body[..] {
+        [.., Node::If(_, then, other), Node::Break]
+            if matches!(then.as_slice(), [Node::Continue]) && other.is_empty() =>
+        {
+            Some((n - 2, true))
+        }
+        [.., Node::If(_, then, other)]
+            if matches!(then.as_slice(), [Node::Break]) && other.is_empty() =>
+        {
+            Some((n - 1, false))
+        }
+        _ => None,
+    };
+    if let Some((i, set)) = end {
+        // a continue would skip the condition of a do while
+        if !continues(&body[..i]) {
+            if let Node::If(c, _, _) = body.remove(i) {
+                body.truncate(i);
+                let c = if set { c } else { c.not() };
+                return Node::DoWhile(body, c);
+            }
+        }
+    }
+    // if (c) break; at the start
+    if let Some(Node::If(_, then, other)) = body.first() {
+        if matches!(then.as_slice(), [Node::Break]) && other.is_empty() {
+            if let Node::If(c, _, _) = body.remove(0) {
+                return Node::While(c.not(), body);
+            }
+        }
+    }
+    Node::Loop(body)
+}
+
+/// the pseudo-C of a function
+pub fn function(program: &Program, function: &Function) -> String {
+    Decompiler::new(program, function).decompile()
+}
+
+/// the pseudo-C of every function, with the description of the variables
+pub fn decompile(program: &Program) -> String {
+    let mut out = HEADER.to_string();
+    for f in program.functions.values() {
+        out.push('\n');
+        out.push_str(&function(program, f));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decompiler::cfg::build;
+    use crate::decompiler::traversal::{disassemble, Hints};
+    use crate::symbols::SymbolTable;
+
+    fn decompile(bytes: &[u8]) -> String {
+        let mut symbols = SymbolTable::new();
+        symbols.insert("BDOS", 0x0005);
+        let program = build(&disassemble(bytes, 0x100, &Hints::default(), &symbols));
+        function(&program, &program.functions[&0x100])
+    }
+
+    #[test]
+    fn test_loops() {
+        let c = decompile(&[
+            0x06, 0x03, // MVI B,3
+            0x05, // LOOP: DCR B
+            0xc2, 0x02, 0x01, // JNZ LOOP
+            0xc9, // RET
+        ]);
+        assert_eq!(
+            c,
+            "void L0100(void)\n{\n    b = 3;\n    do {\n        ac = (b & 0x0f) != 0;\n        z = (uint8_t)(b - 1) == 0;\n        s = (int8_t)(b - 1) < 0;\n        p = parity((uint8_t)(b - 1));\n        b--;\n    } while (b != 0);\n    return;\n}\n"
+        );
+
+        let c = decompile(&[
+            0x7e, // LOOP: MOV A,M
+            0xfe, 0x24, // CPI '$'
+            0xc8, // RZ
+            0x5f, // MOV E,A
+            0x0e, 0x02, // MVI C,2
+            0xcd, 0x05, 0x00, // CALL BDOS
+            0x23, // INX H
+            0xc3, 0x00, 0x01, // JMP LOOP
+        ]);
+        assert!(c.contains("if (a == 0x24) {\n"), "{}", c);
+        assert!(c.contains("        BDOS();\n        hl++;\n"), "{}", c);
+        // the flags of the comparison may be returned
+        assert!(c.contains("cy = a < 0x24;"), "{}", c);
+        assert!(c.contains("s = (int8_t)(a - 0x24) < 0;"), "{}", c);
+    }
+
+    #[test]
+    fn test_flags() {
+        // the carry of an addition is assigned before being tested
+        let c = decompile(&[
+            0x80, // ADD B
+            0xda, 0x05, 0x01, // JC L0105
+            0x3c, // INR A
+            0x32, 0x00, 0x20, // STA 2000H
+            0xaf, // XRA A
+            0xc9, // RET
+        ]);
+        assert!(
+            c.contains("    cy = (a + b) > 0xff;\n    a = a + b;\n    if (!cy) {"),
+            "{}",
+            c
+        );
+        assert!(c.contains("mem[0x2000] = a;"), "{}", c);
+        // XRA A is known to clear the carry and set the zero flag
+        assert!(c.contains("cy = 0;\n    z = 1;"), "{}", c);
+
+        // the comparison is not fused when A changes before the jump
+        let c = decompile(&[
+            0xb8, // CMP B
+            0x3e, 0x00, // MVI A,0
+            0xca, 0x07, 0x01, // JZ L0107
+            0x3c, // INR A
+            0xc9, // RET
+        ]);
+        assert!(c.contains("    z = a == b;\n"), "{}", c);
+        assert!(c.contains("    a = 0;\n    if (!z) {"), "{}", c);
+
+        // CMC inverts the carry of the comparison
+        let c = decompile(&[
+            0xb8, // CMP B
+            0x3f, // CMC
+            0xda, 0x06, 0x01, // JC L0106
+            0xc9, // RET
+            0x76, // L0106: HLT
+        ]);
+        assert!(c.contains("    cy = a < b;\n"), "{}", c);
+        assert!(
+            c.contains("cy = !cy;\n    if (!cy) {\n        return;"),
+            "{}",
+            c
+        );
+        assert!(!c.contains("a >= b"), "{}", c);
+
+        // a conditional call may set the flags
+        let c = decompile(&[
+            0xb7, // ORA A
+            0xcc, 0x00, 0x02, // CZ 0200H
+            0xda, 0x08, 0x01, // JC L0108
+            0xc9, // RET
+            0x76, // L0108: HLT
+        ]);
+        assert!(c.contains("    cy = 0;\n"), "{}", c);
+        assert!(c.contains("sub_0200();\n    if (!cy) {"), "{}", c);
+    }
+
+    #[test]
+    fn test_goto() {
+        // a jump into the middle of a loop
+        let c = decompile(&[
+            0xc3, 0x05, 0x01, // JMP L0105
+            0x05, // L0103: DCR B
+            0x00, // NOP
+            0x0d, // L0105: DCR C
+            0xc2, 0x03, 0x01, // JNZ L0103
+            0xc3, 0x05, 0x00, // JMP BDOS
+        ]);
+        assert!(c.contains("BDOS(); /* tail call */\n    return;"), "{}", c);
+        let c = decompile(&[
+            0xe9, // PCHL
+        ]);
+        assert!(c.contains("goto *hl;"), "{}", c);
+    }
+}
@@ -0,0 +1,627 @@
+//! Recursive traversal disassembler.
+//!
+//! A linear sweep decodes the data as if it was code. Starting from the entry
+//! points, [disassemble] instead follows the jumps, calls and RSTs to find the
+//! code, and everything never reached is written as data. The listing uses
+//! the Intel syntax with generated labels, and assembles back to the same
+//! bytes.
+//!
+//! The targets of PCHL can not be known, these jumps are reported by
+//! [Disassembly::unresolved]. A [Hints] file can then give more entry points,
+//! the jump tables, or force regions to be data.
+//!
+//! For a CP/M program, the page zero is labelled, the calls to the BDOS are
+//! commented with the [cpm] function, and the strings printed by
+//! `C_WRITESTR` are text.
+
+use super::cpm;
+use super::{instr_with_format, Format};
+use crate::instruction::Instruction;
+use crate::number::parse_number;
+use crate::symbols::SymbolTable;
+use anyhow::{anyhow, bail, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::ops::Range;
+
+/// What a byte of the image is
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Kind {
+    Code,
+    /// bytes, written as `DB`
+    Data,
+    /// addresses, written as `DW`
+    Words,
+    /// addresses of code, like a jump table for PCHL
+    Table,
+    /// a string ending with `$`, written as `DB` with quotes
+    Text,
+}
+
+/// What the user knows about the image
+///
+/// ```text
+/// ; the origin and the RST vectors are always entry points
+/// entry 0x0150
+/// cpm                     ; a CP/M program, calling the BDOS
+/// code  0x0200 0x0210     ; START END, END excluded
+/// data  0x0300 0x0320
+/// words 0x0400 0x0410
+/// table 0x0410 0x0420     ; addresses of code
+/// text  0x0500 0x0510     ; a string ending with $
+/// ```
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Hints {
+    pub entries: Vec<u16>,
+    /// the last region containing a byte wins
+    pub regions: Vec<(Range<usize>, Kind)>,
+    pub cpm: bool,
+}
+
+impl Hints {
+    pub fn from_file(file: &str) -> Result<Self> {
+        Self::parse(&std::fs::read_to_string(file)?)
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut hints = Self::default();
+        for (nb, line) in content.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or_default();
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let number = |i: usize| -> Result<u16> {
+                let word = words
+                    .get(i)
+                    .ok_or_else(|| anyhow!("line {}: missing address", nb + 1))?;
+                parse_number(word).map_err(|e| anyhow!("line {}: {}", nb + 1, e))
+            };
+            let kind = match words.first() {
+                None => continue,
+                Some(&"entry") => {
+                    hints.entries.push(number(1)?);
+                    continue;
+                }
+                Some(&"cpm") => {
+                    hints.cpm = true;
+                    continue;
+                }
+                Some(&"code") => Kind::Code,
+                Some(&"data") => Kind::Data,
+                Some(&"words") => Kind::Words,
+                Some(&"table") => Kind::Table,
+                Some(&"text") => Kind::Text,
+                Some(other) => bail!(
+                    "line {}: unknown hint {}, expected entry, cpm, code, data, words, table or text",
+                    nb + 1,
+                    other
+                ),
+            };
+            let (start, end) = (number(1)? as usize, number(2)? as usize);
+            if end <= start {
+                bail!("line {}: empty region {:#06x}..{:#06x}", nb + 1, start, end);
+            }
+            hints.regions.push((start..end, kind));
+        }
+        Ok(hints)
+    }
+
+    fn kind(&self, addr: usize) -> Option<Kind> {
+        self.regions
+            .iter()
+            .rev()
+            .find(|(range, _)| range.contains(&addr))
+            .map(|(_, kind)| *kind)
+    }
+}
+
+/// The result of a traversal
+#[derive(Clone, Debug)]
+pub struct Disassembly {
+    origin: u16,
+    bytes: Vec<u8>,
+    /// what every byte is, None until it is reached
+    kinds: Vec<Option<Kind>>,
+    /// the indexes where an instruction starts
+    starts: BTreeSet<usize>,
+    /// the referenced addresses of the image, with their label
+    labels: BTreeMap<u16, String>,
+    /// the PCHL instructions
+    unresolved: Vec<u16>,
+    /// the entry points and the targets of the calls and RSTs
+    entries: BTreeSet<u16>,
+    /// the user symbols outside of the image
+    externals: SymbolTable,
+    /// the comments of the instructions
+    comments: BTreeMap<u16, String>,
+}
+
+/// disassemble `bytes` loaded at `origin`, starting from the origin, the RST
+/// vectors in the image and the hints. The symbols are used as labels.
+pub fn disassemble(bytes: &[u8], origin: u16, hints: &Hints, symbols: &SymbolTable) -> Disassembly {
+    if !hints.cpm {
+        return traverse(bytes, origin, hints, symbols);
+    }
+    let mut symbols = symbols.clone();
+    for (name, addr) in cpm::page_zero().iter() {
+        if symbols.name(addr).is_none() && symbols.addr(name).is_none() {
+            symbols.insert(name, addr);
+        }
+    }
+    // traverse again until every string printed is text, the hints of the
+    // user still winning
+    let mut hints = hints.clone();
+    loop {
+        let mut dis = traverse(bytes, origin, &hints, &symbols);
+        let labels = dis.labels();
+        let calls = cpm::bdos_calls(dis.instructions(), |addr| dis.labels.contains_key(&addr));
+        let mut strings = Vec::new();
+        for call in &calls {
+            let start = match call.string().and_then(|addr| dis.index(addr)) {
+                Some(start) => start,
+                None => continue,
+            };
+            if let Some(len) = bytes[start..].iter().position(|&b| b == b'$') {
+                let addr = dis.addr(start) as usize;
+                let region = (addr..addr + len + 1, Kind::Text);
+                if !hints.regions.contains(&region) {
+                    strings.push(region);
+                }
+            }
+        }
+        if strings.is_empty() {
+            for call in calls {
+                if let Some(comment) = call.comment(&labels) {
+                    dis.comments.insert(call.addr, comment);
+                }
+            }
+            return dis;
+        }
+        hints.regions.splice(0..0, strings);
+    }
+}
+
+/// disassemble without the knowledge of CP/M
+fn traverse(bytes: &[u8], origin: u16, hints: &Hints, symbols: &SymbolTable) -> Disassembly {
+    let mut dis = Disassembly {
+        origin,
+        bytes: bytes.to_vec(),
+        kinds: vec![None; bytes.len()],
+        starts: BTreeSet::new(),
+        labels: BTreeMap::new(),
+        unresolved: Vec::new(),
+        entries: BTreeSet::new(),
+        externals: SymbolTable::new(),
+        comments: BTreeMap::new(),
+    };
+    for (name, addr) in symbols.iter() {
+        if dis.index(addr).is_none() {
+            dis.externals.insert(name, addr);
+        }
+    }
+
+    let mut todo: Vec<u16> = (0..8).map(|n| n * 8).rev().collect();
+    todo.push(origin);
+    todo.extend(&hints.entries);
+    for i in 0..bytes.len() {
+        let addr = dis.addr(i) as usize;
+        match hints.kind(addr) {
+            // the start of a code region
+            Some(Kind::Code) if addr == 0 || hints.kind(addr - 1) != Some(Kind::Code) => {
+                todo.push(addr as u16)
+            }
+            Some(Kind::Code) | None => (),
+            Some(kind) => dis.kinds[i] = Some(kind),
+        }
+    }
+    // the addresses in the tables
+    let mut i = 0;
+    while i + 1 < bytes.len() {
+        match dis.kinds[i] {
+            Some(kind @ Kind::Words) | Some(kind @ Kind::Table)
+                if dis.kinds[i + 1] == Some(kind) =>
+            {
+                let addr = u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+                dis.reference(addr, symbols);
+                if kind == Kind::Table {
+                    todo.push(addr);
+                }
+                i += 2;
+            }
+            _ => i += 1,
+        }
+    }
+    for &addr in &todo {
+        if dis.index(addr).is_some() {
+            dis.reference(addr, symbols);
+            dis.entries.insert(addr);
+        }
+    }
+
+    while let Some(addr) = todo.pop() {
+        dis.follow(addr, &mut todo, symbols);
+    }
+    for kind in dis.kinds.iter_mut() {
+        kind.get_or_insert(Kind::Data);
+    }
+    dis
+}
+
+impl Disassembly {
+    fn index(&self, addr: u16) -> Option<usize> {
+        let i = addr.wrapping_sub(self.origin) as usize;
+        if addr >= self.origin && i < self.bytes.len() {
+            Some(i)
+        } else {
+            None
+        }
+    }
+
+    fn addr(&self, i: usize) -> u16 {
+        self.origin.wrapping_add(i as u16)
+    }
+
+    /// name the address, only a label at the start of a line is defined
+    fn reference(&mut self, addr: u16, symbols: &SymbolTable) {
+        if self.index(addr).is_some() {
+            let name = match symbols.name(addr) {
+                Some(name) => name.to_string(),
+                None => format!("L{:04X}", addr),
+            };
+            self.labels.entry(addr).or_insert(name);
+        }
+    }
+
+    /// decode the code from `addr` until a jump, a return or known bytes
+    fn follow(&mut self, mut addr: u16, todo: &mut Vec<u16>, symbols: &SymbolTable) {
+        use Instruction::*;
+
+        while let Some(i) = self.index(addr) {
+            if self.kinds[i].is_some() {
+                return;
+            }
+            let instruction = match Instruction::decode(&self.bytes[i..]) {
+                Ok(instruction) if !instruction.is_undocumented() => instruction,
+                _ => return,
+            };
+            let len = instruction.len();
+            if self.kinds[i..i + len].iter().any(Option::is_some) {
+                return;
+            }
+            for kind in &mut self.kinds[i..i + len] {
+                *kind = Some(Kind::Code);
+            }
+            self.starts.insert(i);
+
+            let (target, stop) = match instruction {
+                Jmp(t) => (Some(t), true),
+                Jcc(_, t) => (Some(t), false),
+                Call(t) | Ccc(_, t) => {
+                    self.entries.insert(t);
+                    (Some(t), false)
+                }
+                Rst(n) => {
+                    self.entries.insert(n as u16 * 8);
+                    (Some(n as u16 * 8), false)
+                }
+                Ret | Hlt => (None, true),
+                Pchl => {
+                    self.unresolved.push(addr);
+                    (None, true)
+                }
+                Lxi(_, a) | Lda(a) | Sta(a) | Lhld(a) | Shld(a) => {
+                    self.reference(a, symbols);
+                    (None, false)
+                }
+                _ => (None, false),
+            };
+            if let Some(target) = target {
+                self.reference(target, symbols);
+                todo.push(target);
+            }
+            if stop {
+                return;
+            }
+            addr = match addr.checked_add(len as u16) {
+                Some(next) => next,
+                None => return,
+            };
+        }
+    }
+
+    /// the kind of the byte at `addr`, None outside of the image
+    pub fn kind(&self, addr: u16) -> Option<Kind> {
+        self.index(addr).and_then(|i| self.kinds[i])
+    }
+
+    /// every instruction with its address, in order
+    pub fn instructions(&self) -> impl Iterator<Item = (u16, Instruction)> + '_ {
+        self.starts.iter().filter_map(move |&i| {
+            Instruction::decode(&self.bytes[i..])
+                .ok()
+                .map(|instruction| (self.addr(i), instruction))
+        })
+    }
+
+    /// the entry points and the subroutines found in the image
+    pub fn functions(&self) -> impl Iterator<Item = u16> + '_ {
+        self.entries
+            .iter()
+            .copied()
+            .filter(move |&addr| self.index(addr).is_some_and(|i| self.starts.contains(&i)))
+    }
+
+    /// the comment of the instruction at `addr`
+    pub fn comment(&self, addr: u16) -> Option<&str> {
+        self.comments.get(&addr).map(String::as_str)
+    }
+
+    /// the addresses of the PCHL instructions, whose targets are unknown
+    pub fn unresolved(&self) -> &[u16] {
+        &self.unresolved
+    }
+
+    /// the labels placed in the listing, with the user symbols outside of the
+    /// image
+    pub fn labels(&self) -> SymbolTable {
+        let mut labels = self.externals.clone();
+        for (&addr, name) in &self.labels {
+            if self.is_boundary(addr) {
+                labels.insert(name, addr);
+            }
+        }
+        labels
+    }
+
+    /// true if a line can start at `addr`
+    fn is_boundary(&self, addr: u16) -> bool {
+        match self.index(addr) {
+            Some(i) => self.kinds[i] != Some(Kind::Code) || self.starts.contains(&i),
+            None => false,
+        }
+    }
+
+    /// the source of the image, in the Intel syntax
+    pub fn listing(&self) -> String {
+        let labels = self.labels();
+        let format = Format::plain();
+        let number = |n: u16, digits: usize| {
+            let hex = format!("{:0width$X}H", n, width = digits);
+            if hex.starts_with(|c: char| c.is_ascii_alphabetic()) {
+                format!("0{}", hex)
+            } else {
+                hex
+            }
+        };
+        let is_label =
+            |i: usize| self.labels.contains_key(&self.addr(i)) && self.is_boundary(self.addr(i));
+
+        let mut out = String::new();
+        for (name, addr) in self.externals.iter() {
+            let _ = writeln!(out, "{}\tEQU\t{}", name, number(addr, 4));
+        }
+        let _ = writeln!(out, "\tORG\t{}", number(self.origin, 4));
+
+        let mut i = 0;
+        while i < self.bytes.len() {
+            let addr = self.addr(i);
+            if is_label(i) {
+                let _ = writeln!(out, "{}:", self.labels[&addr]);
+            }
+            let (text, len) = match self.kinds[i] {
+                Some(Kind::Code) => {
+                    let (text, len) = instr_with_format(&self.bytes[i..], &labels, &format);
+                    match self.unresolved.contains(&addr) {
+                        true => (format!("{}\t\t; unresolved jump", text), len),
+                        false => (text, len),
+                    }
+                }
+                Some(kind @ Kind::Words) | Some(kind @ Kind::Table)
+                    if i + 1 < self.bytes.len()
+                        && self.kinds[i + 1] == Some(kind)
+                        && !is_label(i + 1) =>
+                {
+                    let word = u16::from_le_bytes([self.bytes[i], self.bytes[i + 1]]);
+                    let word = match labels.name(word) {
+                        Some(name) => name.to_string(),
+                        None => number(word, 4),
+                    };
+                    (format!("DW\t{}", word), 2)
+                }
+                Some(Kind::Text) => {
+                    let end = (i + 1..self.bytes.len())
+                        .find(|&j| is_label(j) || self.kinds[j] != self.kinds[i])
+                        .unwrap_or(self.bytes.len());
+                    self.text(i, end, &number)
+                }
+                _ => {
+                    // the data until the next line which is not a DB
+                    let end = (i + 1..self.bytes.len())
+                        .find(|&j| is_label(j) || self.kinds[j] != self.kinds[i])
+                        .unwrap_or(self.bytes.len());
+                    self.data(i, end, &number)
+                }
+            };
+            let comment = match self.comments.get(&addr) {
+                Some(comment) => format!(" {}", comment),
+                None => String::new(),
+            };
+            let _ = writeln!(out, "\t{:<23} ; {:04X}{}", text, addr, comment);
+            i += len;
+        }
+        let _ = writeln!(out, "\tEND");
+        out
+    }
+
+    /// a DB line of text starting at `i`, with the quoted characters and the
+    /// other bytes, up to 40 bytes
+    fn text(&self, i: usize, end: usize, number: &dyn Fn(u16, usize) -> String) -> (String, usize) {
+        let end = end.min(i + 40);
+        let mut parts: Vec<String> = Vec::new();
+        let mut quoted = String::new();
+        for &b in &self.bytes[i..end] {
+            if (0x20..0x7f).contains(&b) {
+                quoted.push(b as char);
+                if b == b'\'' {
+                    quoted.push('\'');
+                }
+                continue;
+            }
+            if !quoted.is_empty() {
+                parts.push(format!("'{}'", quoted));
+                quoted.clear();
+            }
+            parts.push(number(b as u16, 2));
+        }
+        if !quoted.is_empty() {
+            parts.push(format!("'{}'", quoted));
+        }
+        (format!("DB\t{}", parts.join(",")), end - i)
+    }
+
+    /// a DB line starting at `i`, either a string or up to 8 bytes
+    fn data(&self, i: usize, end: usize, number: &dyn Fn(u16, usize) -> String) -> (String, usize) {
+        let printable = |j: &usize| (0x20..0x7f).contains(&self.bytes[*j]);
+        let string = (i..end.min(i + 40)).take_while(printable).count();
+        if string >= 4 {
+            let text: String = self.bytes[i..i + string]
+                .iter()
+                .map(|&b| b as char)
+                .collect();
+            return (format!("DB\t'{}'", text.replace('\'', "''")), string);
+        }
+        // stop before the next string
+        let end = (i + 1..end.min(i + 8))
+            .find(|&j| (j..end.min(j + 4)).take_while(printable).count() >= 4)
+            .unwrap_or_else(|| end.min(i + 8));
+        let bytes: Vec<String> = self.bytes[i..end]
+            .iter()
+            .map(|&b| number(b as u16, 2))
+            .collect();
+        (format!("DB\t{}", bytes.join(",")), end - i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traversal() {
+        let bytes = [
+            0x21, 0x0a, 0x01, // LXI  H,MSG
+            0xcd, 0x12, 0x01, // CALL PRINT
+            0xc3, 0x00, 0x00, // JMP  0
+            0xff, // never executed
+            b'H', b'E', b'L', b'L', b'O', b'$', 0x00, 0x3e, // MSG
+            0x7e, // PRINT: MOV A,M
+            0xe9, // PCHL
+        ];
+        let mut symbols = SymbolTable::new();
+        symbols.insert("PRINT", 0x112);
+        symbols.insert("BOOT", 0x0000);
+        let dis = disassemble(&bytes, 0x100, &Hints::default(), &symbols);
+
+        assert_eq!(dis.kind(0x100), Some(Kind::Code));
+        assert_eq!(dis.kind(0x109), Some(Kind::Data));
+        assert_eq!(dis.kind(0x111), Some(Kind::Data));
+        assert_eq!(dis.kind(0x113), Some(Kind::Code));
+        assert_eq!(dis.unresolved(), [0x113]);
+
+        let listing = dis.listing();
+        let lines: Vec<&str> = listing
+            .lines()
+            .map(|l| l.split(';').next().unwrap().trim_end())
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "BOOT\tEQU\t0000H",
+                "\tORG\t0100H",
+                "L0100:",
+                "\tLXI\tH,L010A",
+                "\tCALL\tPRINT",
+                "\tJMP\tBOOT",
+                "\tDB\t0FFH",
+                "L010A:",
+                "\tDB\t'HELLO$'",
+                "\tDB\t00H,3EH",
+                "PRINT:",
+                "\tMOV\tA,M",
+                "\tPCHL",
+                "\tEND",
+            ]
+        );
+        assert!(listing.contains("PCHL\t\t; unresolved jump"));
+    }
+
+    #[test]
+    fn test_cpm() {
+        let bytes = [
+            0x0e, 0x09, // MVI  C,9
+            0x11, 0x0e, 0x01, // LXI  D,MSG
+            0xcd, 0x05, 0x00, // CALL BDOS
+            0x3a, 0x5c, 0x00, // LDA  FCB
+            0xc3, 0x00, 0x00, // JMP  BOOT
+            b'H', b'i', b'\'', 0x0d, 0x0a, b'$', // MSG
+            0x3a, // not printed
+        ];
+        let hints = Hints {
+            cpm: true,
+            ..Hints::default()
+        };
+        let dis = disassemble(&bytes, 0x100, &hints, &SymbolTable::new());
+        assert_eq!(dis.kind(0x10e), Some(Kind::Text));
+        assert_eq!(dis.kind(0x114), Some(Kind::Data));
+        assert_eq!(dis.comment(0x105), Some("BDOS 9 C_WRITESTR DE=L010E"));
+
+        let listing = dis.listing();
+        assert!(
+            listing.starts_with("BOOT\tEQU\t0000H\nBDOS\tEQU\t0005H\n"),
+            "{}",
+            listing
+        );
+        assert!(
+            listing.contains("; 0105 BDOS 9 C_WRITESTR DE=L010E"),
+            "{}",
+            listing
+        );
+        assert!(listing.contains("\tLDA\tFCB"), "{}", listing);
+        assert!(
+            listing.contains("L010E:\n\tDB\t'Hi''',0DH,0AH,'$'"),
+            "{}",
+            listing
+        );
+        assert!(listing.contains("\tDB\t3AH"), "{}", listing);
+
+        // without CP/M, the string is too short for a quoted DB
+        let dis = disassemble(&bytes, 0x100, &Hints::default(), &SymbolTable::new());
+        assert_eq!(dis.kind(0x10e), Some(Kind::Data));
+        assert_eq!(dis.comment(0x105), None);
+    }
+
+    #[test]
+    fn test_hints() {
+        let bytes = [
+            0xc3, 0x05, 0x01, // JMP  DISPATCH
+            0x08, 0x01, // DW   HANDLER
+            0xe9, // DISPATCH: PCHL
+            0x3e, 0x01, // never executed
+            0x76, // HANDLER: HLT
+        ];
+        let hints = Hints::parse("; the jump table\ntable 0x103 0x105\ndata 106H 108H\n").unwrap();
+        assert_eq!(hints.regions[0], (0x103..0x105, Kind::Table));
+
+        let dis = disassemble(&bytes, 0x100, &Hints::default(), &SymbolTable::new());
+        assert_eq!(dis.kind(0x108), Some(Kind::Data));
+
+        let dis = disassemble(&bytes, 0x100, &hints, &SymbolTable::new());
+        assert_eq!(dis.kind(0x106), Some(Kind::Data));
+        assert_eq!(dis.kind(0x108), Some(Kind::Code));
+        let listing = dis.listing();
+        assert!(listing.contains("\tDW\tL0108"), "{}", listing);
+        assert!(listing.contains("L0108:\n\tHLT"), "{}", listing);
+
+        assert!(Hints::parse("cpm\n").unwrap().cpm);
+        assert!(Hints::parse("code 0x200").is_err());
+        assert!(Hints::parse("stack 0x200 0x300").is_err());
+    }
+}
@@ -0,0 +1,468 @@
+//! The 8080 instruction set.
+//!
+//! [Instruction::decode] is the only place where the opcodes are matched, the
+//! cpu executes the decoded instructions and the disassembler writes them.
+//! [Instruction::encode] gives the bytes back, and the metadata of every
+//! instruction (length, cycles, flags affected) is available from the
+//! instruction itself.
+
+use crate::Flags::{self, *};
+use anyhow::{bail, Result};
+use bitmatch::bitmatch;
+
+/// The 8 bit registers, in the order of their encoding. `M` is the memory
+/// pointed by HL.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Reg {
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    M,
+    A,
+}
+
+/// The register pairs used by LXI, DAD, INX, DCX, LDAX and STAX
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Pair {
+    BC,
+    DE,
+    HL,
+    SP,
+}
+
+/// The register pairs used by PUSH and POP, where SP is replaced by A and the
+/// flags
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PairPsw {
+    BC,
+    DE,
+    HL,
+    PSW,
+}
+
+/// The conditions of the conditional jumps, calls and returns
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Cond {
+    NZ,
+    Z,
+    NC,
+    C,
+    PO,
+    PE,
+    P,
+    M,
+}
+
+/// The arithmetic and logic operations with A
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AluOp {
+    Add,
+    Adc,
+    Sub,
+    Sbb,
+    Ana,
+    Xra,
+    Ora,
+    Cmp,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Instruction {
+    Nop,
+    Lxi(Pair, u16),
+    /// only BC and DE
+    Stax(Pair),
+    Shld(u16),
+    Sta(u16),
+    Inx(Pair),
+    Inr(Reg),
+    Dcr(Reg),
+    Mvi(Reg, u8),
+    Rlc,
+    Rrc,
+    Ral,
+    Rar,
+    Dad(Pair),
+    /// only BC and DE
+    Ldax(Pair),
+    Lhld(u16),
+    Lda(u16),
+    Dcx(Pair),
+    Daa,
+    Cma,
+    Stc,
+    Cmc,
+    /// destination, source
+    Mov(Reg, Reg),
+    Hlt,
+    /// ADD ADC SUB SBB ANA XRA ORA CMP
+    Alu(AluOp, Reg),
+    /// ADI ACI SUI SBI ANI XRI ORI CPI
+    AluImm(AluOp, u8),
+    Rcc(Cond),
+    Pop(PairPsw),
+    Jcc(Cond, u16),
+    Jmp(u16),
+    Ccc(Cond, u16),
+    Push(PairPsw),
+    Rst(u8),
+    Ret,
+    Call(u16),
+    Out(u8),
+    In(u8),
+    Xthl,
+    Pchl,
+    Xchg,
+    Di,
+    Ei,
+    Sphl,
+    /// one of the 12 opcodes missing from the documentation, kept to encode
+    /// it back. It is taken as a single byte like NOP, although the 8080 runs
+    /// some of them as JMP, RET or CALL.
+    Undocumented(u8),
+}
+
+const REGS: [Reg; 8] = [
+    Reg::B,
+    Reg::C,
+    Reg::D,
+    Reg::E,
+    Reg::H,
+    Reg::L,
+    Reg::M,
+    Reg::A,
+];
+const PAIRS: [Pair; 4] = [Pair::BC, Pair::DE, Pair::HL, Pair::SP];
+const PAIRS_PSW: [PairPsw; 4] = [PairPsw::BC, PairPsw::DE, PairPsw::HL, PairPsw::PSW];
+const CONDS: [Cond; 8] = [
+    Cond::NZ,
+    Cond::Z,
+    Cond::NC,
+    Cond::C,
+    Cond::PO,
+    Cond::PE,
+    Cond::P,
+    Cond::M,
+];
+const ALU_OPS: [AluOp; 8] = [
+    AluOp::Add,
+    AluOp::Adc,
+    AluOp::Sub,
+    AluOp::Sbb,
+    AluOp::Ana,
+    AluOp::Xra,
+    AluOp::Ora,
+    AluOp::Cmp,
+];
+
+/// length in bytes of the instruction starting with `opcode`, 1 for the
+/// undocumented opcodes
+#[bitmatch]
+pub fn len(opcode: u8) -> usize {
+    #[bitmatch]
+    match opcode {
+        "00??_0001" => 3, // LXI
+        "001?_?010" => 3, // SHLD LHLD STA LDA
+        "11??_?010" => 3, // Jcc
+        "11??_?100" => 3, // Ccc
+        "1100_0011" => 3, // JMP
+        "1100_1101" => 3, // CALL
+        "00??_?110" => 2, // MVI
+        "11??_?110" => 2, // immediate arithmetic
+        "1101_?011" => 2, // OUT IN
+        "????_????" => 1,
+    }
+}
+
+impl Instruction {
+    /// decode the instruction at the start of `bytes`, which must contain all
+    /// its operands
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let opcode = match bytes.first() {
+            Some(&opcode) => opcode,
+            None => bail!("No instruction to decode"),
+        };
+        if bytes.len() < len(opcode) {
+            bail!(
+                "Instruction {:#04x} needs {} bytes, found {}",
+                opcode,
+                len(opcode),
+                bytes.len()
+            );
+        }
+        Ok(Self::decode_complete(bytes))
+    }
+
+    #[bitmatch]
+    fn decode_complete(bytes: &[u8]) -> Self {
+        use Instruction::*;
+
+        let d8 = || bytes[1];
+        let d16 = || u16::from_le_bytes([bytes[1], bytes[2]]);
+        let reg = |r: u8| REGS[r as usize];
+        let pair = |p: u8| PAIRS[p as usize];
+
+        #[bitmatch]
+        match bytes[0] {
+            "0000_0000" => Nop,
+            "0000_0111" => Rlc,
+            "0000_1111" => Rrc,
+            "0001_0111" => Ral,
+            "0001_1111" => Rar,
+            "0010_0111" => Daa,
+            "0010_1111" => Cma,
+            "0011_0111" => Stc,
+            "0011_1111" => Cmc,
+            "0111_0110" => Hlt, // overlap with the mov instruction
+            "1100_1001" => Ret,
+            "1110_1001" => Pchl,
+            "1111_1001" => Sphl,
+            "1110_0011" => Xthl,
+            "1110_1011" => Xchg,
+            "1111_0011" => Di,
+            "1111_1011" => Ei,
+            // jumps and calls
+            "1100_0011" => Jmp(d16()),
+            "11cc_c010" => Jcc(CONDS[c as usize], d16()),
+            "1100_1101" => Call(d16()),
+            "11cc_c100" => Ccc(CONDS[c as usize], d16()),
+            "11cc_c000" => Rcc(CONDS[c as usize]),
+            "11nn_n111" => Rst(n),
+            // ports
+            "1101_1011" => In(d8()),
+            "1101_0011" => Out(d8()),
+            // register
+            "00rr_r101" => Dcr(reg(r)),
+            "00rr_r100" => Inr(reg(r)),
+            "00rr_r110" => Mvi(reg(r), d8()),
+            "01aa_abbb" => Mov(reg(a), reg(b)),
+            "10oo_orrr" => Alu(ALU_OPS[o as usize], reg(r)),
+            "11oo_o110" => AluImm(ALU_OPS[o as usize], d8()),
+            // register pair
+            "00rr_0001" => Lxi(pair(r), d16()),
+            "0011_1010" => Lda(d16()),
+            "0011_0010" => Sta(d16()),
+            "0010_1010" => Lhld(d16()),
+            "0010_0010" => Shld(d16()),
+            "000r_1010" => Ldax(pair(r)),
+            "000r_0010" => Stax(pair(r)),
+            "00rr_1001" => Dad(pair(r)),
+            "00rr_1011" => Dcx(pair(r)),
+            "00rr_0011" => Inx(pair(r)),
+            "11rr_0101" => Push(PAIRS_PSW[r as usize]),
+            "11rr_0001" => Pop(PAIRS_PSW[r as usize]),
+            // duplicates of NOP, JMP, RET and CALL
+            "????_????" => Undocumented(bytes[0]),
+        }
+    }
+
+    /// the first byte of the instruction
+    pub fn opcode(&self) -> u8 {
+        use Instruction::*;
+
+        let r = |r: Reg| r as u8;
+        let p = |p: Pair| p as u8;
+        match *self {
+            Nop => 0x00,
+            Lxi(rp, _) => 0x01 | p(rp) << 4,
+            Stax(rp) => 0x02 | p(rp) << 4,
+            Shld(_) => 0x22,
+            Sta(_) => 0x32,
+            Inx(rp) => 0x03 | p(rp) << 4,
+            Inr(reg) => 0x04 | r(reg) << 3,
+            Dcr(reg) => 0x05 | r(reg) << 3,
+            Mvi(reg, _) => 0x06 | r(reg) << 3,
+            Rlc => 0x07,
+            Rrc => 0x0f,
+            Ral => 0x17,
+            Rar => 0x1f,
+            Dad(rp) => 0x09 | p(rp) << 4,
+            Ldax(rp) => 0x0a | p(rp) << 4,
+            Lhld(_) => 0x2a,
+            Lda(_) => 0x3a,
+            Dcx(rp) => 0x0b | p(rp) << 4,
+            Daa => 0x27,
+            Cma => 0x2f,
+            Stc => 0x37,
+            Cmc => 0x3f,
+            Mov(dst, src) => 0x40 | r(dst) << 3 | r(src),
+            Hlt => 0x76,
+            Alu(op, reg) => 0x80 | (op as u8) << 3 | r(reg),
+            AluImm(op, _) => 0xc6 | (op as u8) << 3,
+            Rcc(cond) => 0xc0 | (cond as u8) << 3,
+            Pop(rp) => 0xc1 | (rp as u8) << 4,
+            Jcc(cond, _) => 0xc2 | (cond as u8) << 3,
+            Jmp(_) => 0xc3,
+            Ccc(cond, _) => 0xc4 | (cond as u8) << 3,
+            Push(rp) => 0xc5 | (rp as u8) << 4,
+            Rst(n) => 0xc7 | (n & 0b111) << 3,
+            Ret => 0xc9,
+            Call(_) => 0xcd,
+            Out(_) => 0xd3,
+            In(_) => 0xdb,
+            Xthl => 0xe3,
+            Pchl => 0xe9,
+            Xchg => 0xeb,
+            Di => 0xf3,
+            Ei => 0xfb,
+            Sphl => 0xf9,
+            Undocumented(opcode) => opcode,
+        }
+    }
+
+    /// the bytes of the instruction
+    pub fn encode(&self) -> Vec<u8> {
+        use Instruction::*;
+
+        let mut bytes = vec![self.opcode()];
+        match *self {
+            Mvi(_, d8) | AluImm(_, d8) | Out(d8) | In(d8) => bytes.push(d8),
+            Lxi(_, d16)
+            | Shld(d16)
+            | Sta(d16)
+            | Lhld(d16)
+            | Lda(d16)
+            | Jcc(_, d16)
+            | Jmp(d16)
+            | Ccc(_, d16)
+            | Call(d16) => bytes.extend_from_slice(&d16.to_le_bytes()),
+            _ => (),
+        }
+        bytes
+    }
+
+    /// length in bytes, an instruction is never empty
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        len(self.opcode())
+    }
+
+    /// number of clock cycles (states) to execute the instruction, when the
+    /// condition of a conditional call or return is false
+    pub fn cycles(&self) -> u8 {
+        use Instruction::*;
+
+        match *self {
+            Nop | Rlc | Rrc | Ral | Rar | Daa | Cma | Stc | Cmc | Xchg | Di | Ei => 4,
+            Undocumented(_) => 4,
+            Inx(_) | Dcx(_) | Rcc(_) | Pchl | Sphl => 5,
+            Inr(Reg::M) | Dcr(Reg::M) | Mvi(Reg::M, _) => 10,
+            Inr(_) | Dcr(_) => 5,
+            Mov(Reg::M, _) | Mov(_, Reg::M) | Alu(_, Reg::M) => 7,
+            Mov(_, _) => 5,
+            Alu(_, _) => 4,
+            Mvi(_, _) | AluImm(_, _) | Stax(_) | Ldax(_) | Hlt => 7,
+            Lxi(_, _) | Dad(_) | Pop(_) | Jcc(_, _) | Jmp(_) | Ret | Out(_) | In(_) => 10,
+            Ccc(_, _) | Push(_) | Rst(_) => 11,
+            Sta(_) | Lda(_) => 13,
+            Shld(_) | Lhld(_) => 16,
+            Call(_) => 17,
+            Xthl => 18,
+        }
+    }
+
+    /// number of clock cycles when the condition of a conditional call or
+    /// return is true, the same as [Instruction::cycles] for the other
+    /// instructions
+    pub fn cycles_taken(&self) -> u8 {
+        match self {
+            Instruction::Ccc(_, _) => 17,
+            Instruction::Rcc(_) => 11,
+            _ => self.cycles(),
+        }
+    }
+
+    /// the flags modified by the instruction
+    pub fn flags(&self) -> &'static [Flags] {
+        use Instruction::*;
+
+        match self {
+            Alu(_, _) | AluImm(_, _) | Daa | Pop(PairPsw::PSW) => {
+                &[Zero, Sign, Parity, Carry, AuxCarry]
+            }
+            Inr(_) | Dcr(_) => &[Zero, Sign, Parity, AuxCarry],
+            Dad(_) | Rlc | Rrc | Ral | Rar | Stc | Cmc => &[Carry],
+            _ => &[],
+        }
+    }
+
+    /// true for the instructions pushing a return address
+    pub fn is_call(&self) -> bool {
+        use Instruction::*;
+
+        matches!(self, Call(_) | Ccc(_, _) | Rst(_))
+    }
+
+    /// true for the undocumented opcodes
+    pub fn is_undocumented(&self) -> bool {
+        matches!(self, Instruction::Undocumented(_))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut undocumented = Vec::new();
+        for opcode in 0..=255u8 {
+            let bytes = [opcode, 0x34, 0x12];
+            let instr = Instruction::decode(&bytes).unwrap();
+            let len = instr.len();
+            assert_eq!(instr.encode(), bytes[..len], "{:?}", instr);
+            assert_eq!(Instruction::decode(&bytes[..len]).unwrap(), instr);
+            assert!(instr.cycles_taken() >= instr.cycles());
+            // every byte fetched takes 3 states, and decoding one more
+            assert!(instr.cycles() as usize > 3 * len, "{:?}", instr);
+            // the return address pushed is the next instruction
+            match instr {
+                Instruction::Rst(_) => assert_eq!(len, 1),
+                _ if instr.is_call() => assert_eq!(len, 3, "{:?}", instr),
+                _ => (),
+            }
+            if instr.is_undocumented() {
+                assert_eq!((len, instr.cycles()), (1, 4));
+                assert!(!instr.is_call());
+                undocumented.push(opcode);
+            }
+        }
+        assert_eq!(
+            undocumented,
+            [0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38, 0xcb, 0xd9, 0xdd, 0xed, 0xfd]
+        );
+    }
+
+    #[test]
+    fn test_decode() {
+        use Instruction::*;
+
+        let decode = |bytes: &[u8]| Instruction::decode(bytes).unwrap();
+        assert_eq!(decode(&[0x76]), Hlt);
+        assert_eq!(decode(&[0x77]), Mov(Reg::M, Reg::A));
+        assert_eq!(decode(&[0xdb, 0x01]), In(0x01));
+        assert_eq!(decode(&[0xf5]), Push(PairPsw::PSW));
+        assert_eq!(decode(&[0x31, 0x00, 0x24]), Lxi(Pair::SP, 0x2400));
+        assert_eq!(decode(&[0xfe, 0x0a]), AluImm(AluOp::Cmp, 0x0a));
+        assert_eq!(decode(&[0xd4, 0x05, 0x00]), Ccc(Cond::NC, 0x0005));
+        assert_eq!(decode(&[0xff]), Rst(7));
+        assert!(Instruction::decode(&[0xcd, 0x05]).is_err());
+        assert!(Instruction::decode(&[]).is_err());
+    }
+
+    #[test]
+    fn test_metadata() {
+        use Instruction::*;
+
+        assert_eq!(Mov(Reg::A, Reg::B).cycles(), 5);
+        assert_eq!(Mov(Reg::M, Reg::B).cycles(), 7);
+        assert_eq!(Ccc(Cond::Z, 0).cycles(), 11);
+        assert_eq!(Ccc(Cond::Z, 0).cycles_taken(), 17);
+        assert_eq!(Inr(Reg::A).flags(), [Zero, Sign, Parity, AuxCarry]);
+        assert!(Pop(PairPsw::BC).flags().is_empty());
+        assert_eq!(Pop(PairPsw::PSW).flags().len(), 5);
+    }
+}
@@ -1,12 +1,19 @@
-#![allow(mutable_borrow_reservation_conflict)]
 #![allow(dead_code)]
 
+pub mod assembler;
+pub mod callstack;
+pub mod cpm;
 mod cpu;
+pub mod debugger;
 pub mod decompiler;
+pub mod instruction;
 mod memory;
+pub mod number;
+pub mod object;
+pub mod provenance;
 mod registers;
+pub mod symbols;
 
-use bitmatch::bitmatch;
 pub use cpu::Cpu;
 pub use memory::Memory;
 pub use registers::*;
@@ -1,11 +1,315 @@
-fn main() {
-    let file = std::env::args()
-        .skip(1)
-        .next()
-        .expect("Provide a file to load");
-    let mut cpu = rust_8080::Cpu::from_filename_at(&file, 0x100).unwrap();
-
-    loop {
-        cpu.cycle();
+use rust_8080::assembler;
+use rust_8080::cpm::disk::{Disk, Format};
+use rust_8080::cpm::fs::FileSystem;
+use rust_8080::cpm::{bdos::Bdos, bios::Bios, runner::Runner, Terminal};
+use rust_8080::debugger::{dap, gdb, Debugger};
+use rust_8080::decompiler::traversal::{self, Disassembly, Hints};
+use rust_8080::decompiler::{analysis, cfg, pseudo};
+use rust_8080::number::parse_number;
+use rust_8080::object::{link::Linker, omf, rel};
+use rust_8080::symbols::SymbolTable;
+use std::io::IsTerminal;
+
+/// the symbols of the `.SYM` file next to `file`
+fn symbols(file: &str) -> anyhow::Result<SymbolTable> {
+    for ext in ["SYM", "sym"] {
+        let sym = std::path::Path::new(file).with_extension(ext);
+        if sym.exists() {
+            return SymbolTable::from_file(&sym.to_string_lossy());
+        }
+    }
+    Ok(SymbolTable::new())
+}
+
+/// load `file` at 0x100 with the symbols of the `.SYM` file next to it
+fn load(file: &str) -> anyhow::Result<rust_8080::Cpu> {
+    let bytes = std::fs::read(file)?;
+    let mut cpu = match omf::is_omf(&bytes) {
+        // an ISIS-II module at its own addresses, with its public symbols
+        true => {
+            let module = omf::parse(&bytes).map_err(|e| anyhow::anyhow!("{}: {}", file, e))?;
+            let mut cpu = rust_8080::Cpu::from_raw(Vec::new());
+            cpu.ram = module.memory()?;
+            cpu.pc = module.entry().unwrap_or_default() as usize;
+            cpu.symbols = module.symbols();
+            cpu
+        }
+        false => rust_8080::Cpu::from_filename_at(file, 0x100)?,
+    };
+    cpu.symbols.extend(&symbols(file)?);
+    // no colours in the files and the pipes
+    cpu.format.color = std::io::stdout().is_terminal();
+    Ok(cpu)
+}
+
+/// disassemble the `FILE [ORIGIN] [HINTS]` given in `args`
+fn disassemble(mut args: impl Iterator<Item = String>) -> anyhow::Result<Disassembly> {
+    let file = args.next().expect("Provide a file to disassemble");
+    let mut origin = args.next().map_or(Ok(0x100), |o| parse_number(&o))?;
+    let mut hints = match args.next() {
+        Some(hints) => Hints::from_file(&hints)?,
+        None => Hints::default(),
+    };
+    // a CP/M program
+    let ext = std::path::Path::new(&file).extension();
+    hints.cpm |= ext.is_some_and(|ext| ext.eq_ignore_ascii_case("com")) && origin == 0x100;
+    let mut bytes = std::fs::read(&file)?;
+    let mut symbols = symbols(&file)?;
+    // an ISIS-II module gives its origin, its start and its symbols
+    if omf::is_omf(&bytes) {
+        let module = omf::parse(&bytes)?;
+        let (low, image) = module.image()?;
+        hints.entries.extend(module.entry());
+        let mut all = module.symbols();
+        all.extend(&symbols);
+        (origin, bytes, symbols) = (low, image, all);
+    }
+    let dis = traversal::disassemble(&bytes, origin, &hints, &symbols);
+    for addr in dis.unresolved() {
+        eprintln!("warning: unresolved jump at {:#06x}", addr);
+    }
+    Ok(dis)
+}
+
+/// the user and the name of a CP/M file written `[USER:]NAME`
+fn user_name(arg: &str) -> anyhow::Result<(u8, &str)> {
+    match arg.split_once(':') {
+        Some((user, name)) => match user.parse() {
+            Ok(user) if user <= 15 => Ok((user, name)),
+            _ => anyhow::bail!("Invalid user {}", user),
+        },
+        None => Ok((0, arg)),
+    }
+}
+
+/// the `ls`, `get`, `put`, `rm`, `mv` and `mkfs` commands of `cpm` on the
+/// disk images
+fn cpm_files(command: &str, mut args: impl Iterator<Item = String>) -> anyhow::Result<()> {
+    let (mut format, mut text) = (Format::SSSD, false);
+    let image = loop {
+        let arg = args.next().expect("Provide a disk image");
+        match arg.as_str() {
+            "--format" => format = args.next().expect("Provide a format").parse()?,
+            // the text files end at the first ^Z
+            "--text" => text = true,
+            _ => break arg,
+        }
+    };
+    if command == "mkfs" {
+        return Disk::new(format).save(&image);
+    }
+    let mut fs = FileSystem::new(Disk::open(&image, format)?);
+    let args: Vec<String> = args.collect();
+    match (command, &args[..]) {
+        ("ls", []) => {
+            for file in fs.list()? {
+                let attributes = [(file.read_only, " R/O"), (file.system, " SYS")];
+                let attributes: String = attributes
+                    .iter()
+                    .filter(|(set, _)| *set)
+                    .map(|(_, name)| *name)
+                    .collect();
+                let size = file.records * 128;
+                println!("{:2}: {:12} {:8}{}", file.user, file.name, size, attributes);
+            }
+        }
+        ("get", [name, out @ ..]) if out.len() <= 1 => {
+            let (user, name) = user_name(name)?;
+            let mut data = fs.read(user, name)?;
+            if text {
+                let end = data.iter().position(|c| *c == 0x1a);
+                data.truncate(end.unwrap_or(data.len()));
+            }
+            std::fs::write(out.first().map_or(name, String::as_str), data)?;
+        }
+        ("put", [file, name @ ..]) if name.len() <= 1 => {
+            let host = std::path::Path::new(file).file_name().unwrap_or_default();
+            let (user, name) = match name.first() {
+                Some(name) => user_name(name)?,
+                None => (0, host.to_str().unwrap_or_default()),
+            };
+            fs.write(user, name, &std::fs::read(file)?)?;
+        }
+        ("rm", names) if !names.is_empty() => {
+            for name in names {
+                let (user, name) = user_name(name)?;
+                fs.delete(user, name)?;
+            }
+        }
+        ("mv", [from, to]) => {
+            let (user, from) = user_name(from)?;
+            fs.rename(user, from, to)?;
+        }
+        _ => anyhow::bail!(
+            "Usage: cpm ls|get|put|rm|mv|mkfs [--format FORMAT] [--text] IMAGE ARGS..."
+        ),
+    }
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let arg = args.next().expect("Provide a file to load");
+
+    match arg.as_str() {
+        "debug" => {
+            let file = args.next().expect("Provide a file to debug");
+            let cpu = load(&file)?;
+            let stdin = std::io::stdin();
+            Debugger::new(cpu).repl(stdin.lock(), std::io::stdout())
+        }
+        "gdb" => {
+            let file = args.next().expect("Provide a file to debug");
+            let port = args.next().map_or(Ok(1234), |p| p.parse())?;
+            let cpu = load(&file)?;
+            let listener = std::net::TcpListener::bind(("127.0.0.1", port))?;
+            eprintln!("Waiting for gdb on 127.0.0.1:{}", port);
+            gdb::serve(&mut Debugger::new(cpu), &listener)
+        }
+        "asm" => {
+            let file = args.next().expect("Provide a file to assemble");
+            let assembly =
+                assembler::assemble_file(&file).map_err(|e| anyhow::anyhow!("{}: {}", file, e))?;
+            let out = match args.next() {
+                Some(out) => out,
+                None => std::path::Path::new(&file)
+                    .with_extension("COM")
+                    .to_string_lossy()
+                    .into_owned(),
+            };
+            std::fs::write(&out, &assembly.image)?;
+            // the listing and the symbols next to the binary, like MAC
+            let out_path = std::path::Path::new(&out);
+            std::fs::write(out_path.with_extension("PRN"), assembly.prn())?;
+            std::fs::write(out_path.with_extension("SYM"), assembly.sym())?;
+            eprintln!(
+                "{}: {} bytes at {:04X}H",
+                out,
+                assembly.image.len(),
+                assembly.origin
+            );
+            Ok(())
+        }
+        "link" => {
+            let out = args.next().expect("Provide the file to write");
+            // L80 leaves room for a jump to the start at 0100H
+            let mut linker = Linker::new(0x103);
+            let mut search = false;
+            for file in args {
+                if file == "--search" {
+                    search = true;
+                    continue;
+                }
+                let modules =
+                    rel::from_file(&file).map_err(|e| anyhow::anyhow!("{}: {}", file, e))?;
+                match search {
+                    true => linker.search(modules),
+                    false => linker.add(modules),
+                }
+            }
+            let linked = linker.link()?;
+            let com = linked.com()?;
+            std::fs::write(&out, &com)?;
+            std::fs::write(
+                std::path::Path::new(&out).with_extension("SYM"),
+                linked.symbols.to_dri(),
+            )?;
+            eprintln!("{}: {} bytes at 0100H", out, com.len());
+            Ok(())
+        }
+        "disasm" => {
+            print!("{}", disassemble(args)?.listing());
+            Ok(())
+        }
+        "graph" => {
+            let kind = args.next().expect("Provide a graph: cfg, calls or json");
+            let program = cfg::build(&disassemble(args)?);
+            match kind.as_str() {
+                "cfg" => print!("{}", program.cfg_dot()),
+                "calls" => print!("{}", program.call_graph_dot()),
+                "json" => println!("{:#}", program.to_json()),
+                other => anyhow::bail!("Unknown graph {}, expected cfg, calls or json", other),
+            }
+            Ok(())
+        }
+        "decompile" => {
+            print!("{}", pseudo::decompile(&cfg::build(&disassemble(args)?)));
+            Ok(())
+        }
+        "analyze" => {
+            let program = cfg::build(&disassemble(args)?);
+            print!("{}", analysis::analyze(&program).report(program.labels()));
+            Ok(())
+        }
+        "cpm" => match args.next().as_deref() {
+            Some("run") => {
+                let mut bdos = Bdos::new(Terminal::default());
+                bdos.mount(0, ".");
+                let (mut limit, mut timeout) = (None, None);
+                let program = loop {
+                    let arg = args.next().expect("Provide a program to run");
+                    match arg.as_str() {
+                        "--limit" => {
+                            let instructions = args.next().expect("Provide a number of instructions");
+                            limit = Some(instructions.parse()?)
+                        }
+                        "--timeout" => {
+                            let seconds: f64 = args.next().expect("Provide a timeout").parse()?;
+                            timeout = Some(std::time::Duration::from_secs_f64(seconds));
+                        }
+                        // the drives as `B:DIR`
+                        "--drive" => {
+                            let drive = args.next().expect("Provide a drive like B:DIR");
+                            match drive.as_bytes() {
+                                [d @ b'A'..=b'P', b':', ..] | [d @ b'a'..=b'p', b':', ..] => {
+                                    bdos.mount(d.to_ascii_uppercase() - b'A', &drive[2..])
+                                }
+                                _ => anyhow::bail!("Invalid drive {}", drive),
+                            }
+                        }
+                        _ => break arg,
+                    }
+                };
+                let args: Vec<String> = args.collect();
+                let mut runner = Runner::new(&std::fs::read(&program)?, &args, bdos)?;
+                runner.limit = limit;
+                runner.timeout = timeout;
+                let exit = runner.run()?;
+                if exit.status() >= 2 {
+                    eprintln!("{}: {:?} after {} instructions", program, exit, runner.cpu.instructions);
+                }
+                std::process::exit(exit.status())
+            }
+            // the images of the 8" disks in A:, B:...
+            Some("boot") => {
+                let mut bios = Bios::new(Terminal::default());
+                for (drive, image) in args.enumerate() {
+                    bios.mount(drive as u8, Disk::open(image, Format::SSSD)?);
+                }
+                let mut cpu = rust_8080::Cpu::from_raw(vec![0; 0x10000]);
+                cpu.trace = false;
+                bios.boot(&mut cpu)?;
+                bios.run(&mut cpu)
+            }
+            Some(command @ ("ls" | "get" | "put" | "rm" | "mv" | "mkfs")) => cpm_files(command, args),
+            _ => anyhow::bail!("Usage: cpm ls|get|put|rm|mv|mkfs IMAGE ... | cpm boot IMAGE... | cpm run [--limit INSTRUCTIONS] [--timeout SECONDS] [--drive X:DIR]... PROG.COM ARGS..."),
+        },
+        "dap" => {
+            match args.next() {
+                Some(port) => {
+                    let listener = std::net::TcpListener::bind(("127.0.0.1", port.parse()?))?;
+                    let (stream, _) = listener.accept()?;
+                    dap::serve(stream.try_clone()?, stream)
+                }
+                None => dap::serve(std::io::stdin(), std::io::stdout()),
+            }
+        }
+        file => {
+            let mut cpu = load(file)?;
+
+            loop {
+                cpu.cycle();
+            }
+        }
     }
 }
@@ -1,9 +1,62 @@
+use crate::provenance::{MemoryWrite, Provenance};
 use anyhow::Result;
 use std::io::Read;
+use std::ops::{Range, RangeFrom, RangeFull, RangeInclusive, RangeTo, RangeToInclusive};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct Memory {
     vec: Vec<u8>,
+    /// Record the writes of the cpu when set
+    pub provenance: Option<Provenance>,
+    /// PC and number of the instruction being executed
+    writer: Option<(u16, u64)>,
+}
+
+/// The addresses covered by an index, to know what a mutable access writes
+pub trait Addresses {
+    fn addresses(&self, len: usize) -> Range<usize>;
+}
+
+impl Addresses for usize {
+    fn addresses(&self, _len: usize) -> Range<usize> {
+        *self..*self + 1
+    }
+}
+
+impl Addresses for Range<usize> {
+    fn addresses(&self, _len: usize) -> Range<usize> {
+        self.clone()
+    }
+}
+
+impl Addresses for RangeInclusive<usize> {
+    fn addresses(&self, _len: usize) -> Range<usize> {
+        *self.start()..*self.end() + 1
+    }
+}
+
+impl Addresses for RangeFrom<usize> {
+    fn addresses(&self, len: usize) -> Range<usize> {
+        self.start..len
+    }
+}
+
+impl Addresses for RangeTo<usize> {
+    fn addresses(&self, _len: usize) -> Range<usize> {
+        0..self.end
+    }
+}
+
+impl Addresses for RangeToInclusive<usize> {
+    fn addresses(&self, _len: usize) -> Range<usize> {
+        0..self.end + 1
+    }
+}
+
+impl Addresses for RangeFull {
+    fn addresses(&self, len: usize) -> Range<usize> {
+        0..len
+    }
 }
 
 impl<T: std::slice::SliceIndex<[u8]>> std::ops::Index<T> for Memory {
@@ -14,15 +67,19 @@ impl<T: std::slice::SliceIndex<[u8]>> std::ops::Index<T> for Memory {
     }
 }
 
-impl<T: std::slice::SliceIndex<[u8]>> std::ops::IndexMut<T> for Memory {
+impl<T: std::slice::SliceIndex<[u8]> + Addresses> std::ops::IndexMut<T> for Memory {
     fn index_mut(&mut self, idx: T) -> &mut Self::Output {
+        self.record(idx.addresses(self.vec.len()));
         &mut self.vec[idx]
     }
 }
 
 impl Memory {
     pub fn from_raw(vec: Vec<u8>) -> Self {
-        Self { vec }
+        Self {
+            vec,
+            ..Default::default()
+        }
     }
 
     pub fn from_file_at(file: &str, starting_addr: usize) -> Result<Self> {
@@ -33,16 +90,54 @@ impl Memory {
         let mut vec = vec![0; starting_addr];
         vec.append(&mut buffer);
 
-        Ok(Self { vec })
+        Ok(Self::from_raw(vec))
+    }
+
+    /// attribute the next writes to the instruction at `pc`, the number
+    /// `instruction` executed, or to nobody
+    pub(crate) fn set_writer(&mut self, writer: Option<(u16, u64)>) {
+        self.writer = writer;
+    }
+
+    fn record(&mut self, addresses: Range<usize>) {
+        if let (Some(provenance), Some((pc, instruction))) = (&mut self.provenance, self.writer) {
+            for addr in addresses {
+                provenance.record(MemoryWrite {
+                    addr: addr as u16,
+                    pc,
+                    instruction,
+                });
+            }
+        }
+    }
+
+    /// number of bytes currently backed by the memory
+    pub fn len(&self) -> usize {
+        self.vec.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vec.is_empty()
+    }
+
+    /// copy `bytes` at `addr`, growing the memory if needed
+    pub fn load_at(&mut self, addr: usize, bytes: &[u8]) {
+        if self.vec.len() < addr + bytes.len() {
+            self.vec.resize(addr + bytes.len(), 0);
+        }
+        self.vec[addr..addr + bytes.len()].copy_from_slice(bytes);
     }
 
+    /// the word at `idx`, its low byte first
     pub fn dword(&self, idx: impl Into<usize>) -> u16 {
-        let dword = *unsafe { std::mem::transmute::<&u8, &u16>(&self.vec[idx.into()]) };
-        u16::from_be(dword)
+        let idx = idx.into();
+        u16::from_le_bytes([self.vec[idx], self.vec[idx + 1]])
     }
 
+    /// write a word at `idx`, its low byte first
     pub fn dword_set(&mut self, idx: impl Into<usize>, value: u16) {
-        let dword = unsafe { std::mem::transmute::<&mut u8, &mut u16>(&mut self.vec[idx.into()]) };
-        *dword = u16::to_be(value);
+        let idx = idx.into();
+        self.record(idx..idx + 2);
+        self.vec[idx..idx + 2].copy_from_slice(&value.to_le_bytes());
     }
 }
@@ -0,0 +1,53 @@
+//! Numbers written in the notations of the 8080 assemblers and of C.
+
+use anyhow::{anyhow, Result};
+
+/// Parse a number written in one of the usual notations:
+/// - `0x1A`, `$1A` and `1AH` are hexadecimal
+/// - `0o17`, `17O` and `17Q` are octal
+/// - `0b101` and `101B` are binary
+/// - `42` and `42D` are decimal
+pub fn parse_number(s: &str) -> Result<u16> {
+    let lower = s.trim().to_ascii_lowercase();
+    let (digits, radix) = if let Some(digits) = lower.strip_suffix('h') {
+        (digits, 16)
+    } else if let Some(digits) = lower.strip_prefix("0x").or_else(|| lower.strip_prefix('$')) {
+        (digits, 16)
+    } else if let Some(digits) = lower.strip_prefix("0o").filter(|d| !d.is_empty()) {
+        (digits, 8)
+    } else if let Some(digits) = lower.strip_prefix("0b").filter(|d| !d.is_empty()) {
+        (digits, 2)
+    } else if let Some(digits) = lower.strip_suffix('o').or_else(|| lower.strip_suffix('q')) {
+        (digits, 8)
+    } else if let Some(digits) = lower.strip_suffix('b') {
+        (digits, 2)
+    } else if let Some(digits) = lower.strip_suffix('d') {
+        (digits, 10)
+    } else {
+        (lower.as_str(), 10)
+    };
+
+    u16::from_str_radix(digits, radix).map_err(|e| anyhow!("Invalid number {}: {}", s, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number("42").unwrap(), 42);
+        assert_eq!(parse_number("42d").unwrap(), 42);
+        assert_eq!(parse_number("0x1A").unwrap(), 0x1a);
+        assert_eq!(parse_number("$1a").unwrap(), 0x1a);
+        assert_eq!(parse_number("0FFH").unwrap(), 0xff);
+        assert_eq!(parse_number("0BH").unwrap(), 0x0b);
+        assert_eq!(parse_number("0o17").unwrap(), 0o17);
+        assert_eq!(parse_number("17O").unwrap(), 0o17);
+        assert_eq!(parse_number("377Q").unwrap(), 0o377);
+        assert_eq!(parse_number("0b101").unwrap(), 5);
+        assert_eq!(parse_number("101B").unwrap(), 5);
+        assert!(parse_number("0x10000").is_err());
+        assert!(parse_number("hello").is_err());
+    }
+}
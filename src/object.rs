@@ -0,0 +1,9 @@
+//! Relocatable object files.
+//!
+//! - [rel] reads the Microsoft `.REL` modules and libraries of M80 and F80
+//! - [link] links them at an address, like L80
+//! - [omf] reads the Intel OMF-80 modules of ISIS-II
+
+pub mod link;
+pub mod omf;
+pub mod rel;
@@ -0,0 +1,447 @@
+//! A linker of `.REL` modules, like L80.
+//!
+//! The modules are loaded one after the other from the base address, the
+//! data segment of a module following its program segment unless a data
+//! address is given, and the common blocks follow all the modules. The
+//! modules of a library are only loaded when they define a symbol still
+//! undefined, until no module is needed anymore.
+//!
+//! ```
+//! use rust_8080::object::link::Linker;
+//! # let modules = Vec::new();
+//! let mut linker = Linker::new(0x103);
+//! linker.add(modules);
+//! let linked = linker.link().unwrap();
+//! // a `JMP` to the start at 0100H, like L80
+//! let com = linked.com().unwrap();
+//! ```
+
+use super::rel::{Address, Content, Module, Segment};
+use crate::symbols::SymbolTable;
+use crate::Memory;
+use anyhow::{anyhow, bail, Result};
+use std::collections::HashMap;
+
+/// The modules to link
+#[derive(Clone, Debug, Default)]
+pub struct Linker {
+    /// the address of the first module
+    pub base: u16,
+    /// the address of the data segments, following the program segments
+    /// when `None`
+    pub data: Option<u16>,
+    modules: Vec<Module>,
+    library: Vec<Module>,
+}
+
+/// The result of a link
+#[derive(Clone, Debug, Default)]
+pub struct Linked {
+    /// address of the first byte of `image`
+    pub origin: u16,
+    /// the bytes from the lowest to the highest address written
+    pub image: Vec<u8>,
+    /// the public symbols
+    pub symbols: SymbolTable,
+    /// the start address of the main module
+    pub entry: Option<u16>,
+}
+
+impl Linked {
+    /// the image loaded at its origin
+    pub fn memory(&self) -> Memory {
+        let mut memory = Memory::default();
+        self.load(&mut memory);
+        memory
+    }
+
+    /// copy the image into `memory`
+    pub fn load(&self, memory: &mut Memory) {
+        memory.load_at(self.origin as usize, &self.image);
+    }
+
+    /// the `.COM` file, starting at 0100H with a `JMP` to the entry point
+    /// when the image leaves room for it
+    pub fn com(&self) -> Result<Vec<u8>> {
+        if self.origin < 0x100 {
+            bail!("The program starts at {:04X}H, below 0100H", self.origin);
+        }
+        let mut com = vec![0; (self.origin - 0x100) as usize];
+        match self.entry {
+            Some(entry) if entry != 0x100 => {
+                if com.len() < 3 {
+                    bail!("No room for a jump to {:04X}H at 0100H", entry);
+                }
+                com[..3].copy_from_slice(&[0xc3, entry as u8, (entry >> 8) as u8]);
+            }
+            _ => (),
+        }
+        com.extend_from_slice(&self.image);
+        Ok(com)
+    }
+}
+
+/// The addresses of the segments of a module
+struct Layout {
+    program: u16,
+    data: u16,
+}
+
+impl Linker {
+    pub fn new(base: u16) -> Self {
+        Linker {
+            base,
+            ..Default::default()
+        }
+    }
+
+    /// link all of `modules`
+    pub fn add(&mut self, modules: Vec<Module>) {
+        self.modules.extend(modules);
+    }
+
+    /// link the modules of `library` defining an undefined symbol
+    pub fn search(&mut self, library: Vec<Module>) {
+        self.library.extend(library);
+    }
+
+    /// the modules to link, with the modules needed from the library
+    fn modules(&self) -> Vec<&Module> {
+        let mut modules: Vec<&Module> = self.modules.iter().collect();
+        let mut used = vec![false; self.library.len()];
+        loop {
+            let defined = |name: &str, modules: &[&Module]| {
+                modules
+                    .iter()
+                    .any(|m| m.entries.iter().any(|(n, _)| n.eq_ignore_ascii_case(name)))
+            };
+            let undefined: Vec<&str> = modules
+                .iter()
+                .flat_map(|m| m.externals.iter().map(|(name, _)| name.as_str()))
+                .filter(|name| !defined(name, &modules))
+                .collect();
+            let needed =
+                self.library.iter().enumerate().position(|(i, m)| {
+                    !used[i] && undefined.iter().any(|name| defined(name, &[m]))
+                });
+            match needed {
+                Some(i) => {
+                    used[i] = true;
+                    modules.push(&self.library[i]);
+                }
+                None => return modules,
+            }
+        }
+    }
+
+    /// link the modules
+    pub fn link(&self) -> Result<Linked> {
+        let modules = self.modules();
+
+        // the segments
+        let mut pc = self.base;
+        let mut data = self.data;
+        let mut layouts = Vec::new();
+        for module in &modules {
+            let program = pc;
+            pc = pc.wrapping_add(module.program_size);
+            let layout = match data.as_mut() {
+                Some(data) => {
+                    let layout = Layout {
+                        program,
+                        data: *data,
+                    };
+                    *data = data.wrapping_add(module.data_size);
+                    layout
+                }
+                None => {
+                    let layout = Layout { program, data: pc };
+                    pc = pc.wrapping_add(module.data_size);
+                    layout
+                }
+            };
+            layouts.push(layout);
+        }
+        let mut sizes: Vec<(&str, u16)> = Vec::new();
+        for (name, size) in modules.iter().flat_map(|m| &m.commons) {
+            match sizes.iter_mut().find(|(n, _)| n == name) {
+                Some((_, max)) => *max = (*max).max(*size),
+                None => sizes.push((name, *size)),
+            }
+        }
+        let mut commons = HashMap::new();
+        for (name, size) in sizes {
+            commons.insert(name.to_string(), pc);
+            pc = pc.wrapping_add(size);
+        }
+        let resolve = |address: &Address, layout: &Layout| -> Result<u16> {
+            let base = match &address.segment {
+                Segment::Absolute => 0,
+                Segment::Program => layout.program,
+                Segment::Data => layout.data,
+                Segment::Common(name) => *commons
+                    .get(name)
+                    .ok_or_else(|| anyhow!("Undefined common block /{}/", name))?,
+            };
+            Ok(base.wrapping_add(address.offset))
+        };
+
+        // the public symbols
+        let mut symbols = SymbolTable::new();
+        let mut owners: HashMap<String, &str> = HashMap::new();
+        for (module, layout) in modules.iter().zip(&layouts) {
+            for (name, address) in &module.entries {
+                let upper = name.to_ascii_uppercase();
+                if let Some(owner) = owners.insert(upper, &module.name) {
+                    bail!("{} is defined in {} and {}", name, owner, module.name);
+                }
+                symbols.insert(name, resolve(address, layout)?);
+            }
+        }
+
+        // the content
+        let mut memory = vec![0u8; 0x10000];
+        let mut range: Option<(usize, usize)> = None;
+        let mut write = |addr: u16, byte: u8| {
+            memory[addr as usize] = byte;
+            let addr = addr as usize;
+            range = Some(range.map_or((addr, addr), |(low, high)| (low.min(addr), high.max(addr))));
+        };
+        for (module, layout) in modules.iter().zip(&layouts) {
+            for (address, content) in &module.content {
+                let addr = resolve(address, layout)?;
+                match content {
+                    Content::Byte(byte) => write(addr, *byte),
+                    Content::Word(value) => {
+                        let value = resolve(value, layout)?;
+                        write(addr, value as u8);
+                        write(addr.wrapping_add(1), (value >> 8) as u8);
+                    }
+                }
+            }
+        }
+
+        // the chains and the offsets of the externals
+        for (module, layout) in modules.iter().zip(&layouts) {
+            let externals = module.externals.iter().map(|(name, head)| -> Result<_> {
+                let value = symbols
+                    .addr(name)
+                    .ok_or_else(|| anyhow!("Undefined symbol {} in {}", name, module.name))?;
+                Ok((head, value))
+            });
+            let chains = module
+                .chains
+                .iter()
+                .map(|(head, value)| Ok((head, resolve(value, layout)?)));
+            for chain in externals.chain(chains) {
+                let (head, value) = chain?;
+                let mut addr = resolve(head, layout)?;
+                let mut count = 0;
+                while addr != 0 {
+                    if addr == 0xffff {
+                        bail!("A chain goes past the end of the memory in {}", module.name);
+                    }
+                    let next = u16::from_le_bytes([
+                        memory[addr as usize],
+                        memory[addr.wrapping_add(1) as usize],
+                    ]);
+                    memory[addr as usize..][..2].copy_from_slice(&value.to_le_bytes());
+                    addr = next;
+                    count += 1;
+                    if count > 0x8000 {
+                        bail!("Endless chain at {:04X}H in {}", addr, module.name);
+                    }
+                }
+            }
+            for (address, offset) in &module.offsets {
+                let addr = resolve(address, layout)? as usize;
+                if addr == 0xffff {
+                    bail!(
+                        "An offset goes past the end of the memory in {}",
+                        module.name
+                    );
+                }
+                let word = u16::from_le_bytes([memory[addr], memory[addr + 1]]);
+                memory[addr..][..2].copy_from_slice(&word.wrapping_add(*offset).to_le_bytes());
+            }
+        }
+
+        let mut entry = None;
+        for (module, layout) in modules.iter().zip(&layouts) {
+            if let Some(start) = &module.start {
+                if entry.is_some() {
+                    bail!("{} has a second start address", module.name);
+                }
+                entry = Some(resolve(start, layout)?);
+            }
+        }
+        let (origin, image) = match range {
+            Some((low, high)) => {
+                let low = low.min(self.base as usize);
+                (low as u16, memory[low..=high].to_vec())
+            }
+            None => (self.base, Vec::new()),
+        };
+        Ok(Linked {
+            origin,
+            image,
+            symbols,
+            entry,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::rel::{parse, tests::Writer};
+
+    /// MAIN: LXI H,DATA ; CALL PRINT ; CALL PRINT ; JMP 0
+    fn main_module(writer: &mut Writer) -> &mut Writer {
+        writer
+            .link(2, None, Some("MAIN"))
+            .link(13, Some((1, 12)), None)
+            .link(10, Some((0, 2)), None)
+            .link(7, Some((1, 0)), Some("START"))
+            .link(11, Some((1, 0)), None)
+            .byte(0x21)
+            .word(2, 0)
+            .byte(0xcd)
+            .byte(0)
+            .byte(0)
+            .byte(0xcd)
+            .word(1, 4)
+            .byte(0xc3)
+            .byte(0)
+            .byte(0)
+            .link(11, Some((2, 0)), None)
+            .byte(b'h')
+            .byte(b'i')
+            .link(6, Some((1, 7)), Some("PRINT"))
+            .end(Some(0))
+    }
+
+    /// PRINT: MVI C,9 ; JMP 5, and an unused module
+    fn library() -> Vec<u8> {
+        Writer::default()
+            .link(2, None, Some("UNUSED"))
+            .link(13, Some((1, 1)), None)
+            .link(7, Some((1, 0)), Some("OTHER"))
+            .link(11, Some((1, 0)), None)
+            .byte(0)
+            .end(None)
+            .link(2, None, Some("PRINT"))
+            .link(13, Some((1, 5)), None)
+            .link(7, Some((1, 0)), Some("PRINT"))
+            .link(11, Some((1, 0)), None)
+            .byte(0x0e)
+            .byte(9)
+            .byte(0xc3)
+            .byte(5)
+            .byte(0)
+            .end(None)
+            .finish()
+    }
+
+    #[test]
+    fn test_link() {
+        let mut linker = Linker::new(0x103);
+        linker.add(parse(&main_module(&mut Writer::default()).finish()).unwrap());
+        assert!(linker
+            .link()
+            .unwrap_err()
+            .to_string()
+            .contains("Undefined symbol PRINT in MAIN"));
+        linker.search(parse(&library()).unwrap());
+        let linked = linker.link().unwrap();
+        assert_eq!(linked.origin, 0x103);
+        assert_eq!(linked.entry, Some(0x103));
+        assert_eq!(linked.symbols.addr("PRINT"), Some(0x111));
+        assert_eq!(linked.symbols.addr("OTHER"), None);
+        assert_eq!(
+            linked.image,
+            [
+                0x21, 0x0f, 0x01, 0xcd, 0x11, 0x01, 0xcd, 0x11, 0x01, 0xc3, 0, 0, b'h', b'i', 0x0e,
+                9, 0xc3, 5, 0
+            ]
+        );
+        assert_eq!(&linked.com().unwrap()[..4], [0xc3, 0x03, 0x01, 0x21]);
+        assert_eq!(linked.memory()[0x111], 0x0e);
+
+        // the data elsewhere
+        linker.data = Some(0x8000);
+        let linked = linker.link().unwrap();
+        assert_eq!(linked.origin, 0x103);
+        assert_eq!(&linked.image[..3], [0x21, 0x00, 0x80]);
+        assert_eq!(linked.symbols.addr("PRINT"), Some(0x10f));
+
+        // twice the same module
+        let mut linker = Linker::new(0x100);
+        linker.add(parse(&library()).unwrap());
+        linker.add(parse(&library()).unwrap());
+        assert_eq!(
+            linker.link().unwrap_err().to_string(),
+            "OTHER is defined in UNUSED and UNUSED"
+        );
+    }
+
+    #[test]
+    fn test_commons_and_offsets() {
+        // LXI H,BUF+1 in the blank common, LXI D,EXT+2
+        let bytes = Writer::default()
+            .link(2, None, Some("A"))
+            .link(5, Some((0, 4)), Some(""))
+            .link(13, Some((1, 6)), None)
+            .link(1, None, Some(""))
+            .link(11, Some((1, 0)), None)
+            .byte(0x21)
+            .word(3, 1)
+            .byte(0x11)
+            .link(9, Some((0, 2)), None)
+            .byte(0)
+            .byte(0)
+            .link(6, Some((1, 4)), Some("EXT"))
+            .end(None)
+            .link(2, None, Some("B"))
+            .link(5, Some((0, 8)), Some(""))
+            .link(7, Some((0, 0x1234)), Some("EXT"))
+            .end(None)
+            .finish();
+        let mut linker = Linker::new(0x100);
+        linker.add(parse(&bytes).unwrap());
+        let linked = linker.link().unwrap();
+        assert_eq!(linked.image, [0x21, 0x07, 0x01, 0x11, 0x36, 0x12]);
+        assert_eq!(linked.entry, None);
+        assert!(linked.com().is_ok());
+    }
+
+    #[test]
+    fn test_end_of_memory() {
+        let chain = Writer::default()
+            .link(2, None, Some("A"))
+            .link(7, Some((0, 0x1234)), Some("EXT"))
+            .link(6, Some((0, 0xffff)), Some("EXT"))
+            .end(None)
+            .finish();
+        let mut linker = Linker::new(0x100);
+        linker.add(parse(&chain).unwrap());
+        assert_eq!(
+            linker.link().unwrap_err().to_string(),
+            "A chain goes past the end of the memory in A"
+        );
+
+        let offset = Writer::default()
+            .link(2, None, Some("B"))
+            .link(7, Some((0, 0x1234)), Some("EXT"))
+            .link(11, Some((0, 0xffff)), None)
+            .link(9, Some((0, 2)), None)
+            .end(None)
+            .finish();
+        let mut linker = Linker::new(0x100);
+        linker.add(parse(&offset).unwrap());
+        assert_eq!(
+            linker.link().unwrap_err().to_string(),
+            "An offset goes past the end of the memory in B"
+        );
+    }
+}
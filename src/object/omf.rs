@@ -0,0 +1,413 @@
+//! The Intel OMF-80 object modules of ISIS-II, written by ASM80, PL/M-80
+//! and LOCATE.
+//!
+//! A module is a list of records: a type byte, the length of the rest of the
+//! record (low byte first), the fields and a checksum making the sum of all
+//! the bytes of the record 0. The records understood are:
+//! - 02H the module header: the name and the segments
+//! - 04H the module end: main module or not, and the start address
+//! - 06H the content: bytes at an offset of a segment
+//! - 12H and 16H the local and public symbols
+//! - 18H and 20H the external names and the references to them
+//! - 22H and 24H the relocations inside a segment and between segments
+//! - 0EH the end of the file
+//!
+//! The other records, like the line numbers, are skipped. Only the absolute
+//! segment can be loaded: a relocatable module must be located first.
+
+use crate::symbols::SymbolTable;
+use crate::Memory;
+use anyhow::{anyhow, bail, Result};
+
+/// the segment of the absolute addresses
+pub const ABSOLUTE: u8 = 0;
+
+/// A symbol at an offset of a segment
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub segment: u8,
+    pub offset: u16,
+}
+
+/// Bytes at an offset of a segment
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Content {
+    pub segment: u8,
+    pub offset: u16,
+    pub bytes: Vec<u8>,
+}
+
+/// The places to relocate in the content
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Relocation {
+    /// 1 for the low byte, 2 for the high byte, 3 for the word
+    pub kind: u8,
+    /// the segment the places refer to, their own segment when `None`
+    pub segment: Option<u8>,
+    pub offsets: Vec<u16>,
+}
+
+/// An OMF-80 module
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Module {
+    pub name: String,
+    /// the segments and their length
+    pub segments: Vec<(u8, u16)>,
+    pub content: Vec<Content>,
+    pub publics: Vec<Symbol>,
+    pub locals: Vec<Symbol>,
+    pub externals: Vec<String>,
+    /// the external number and the place of the references
+    pub references: Vec<(u16, u16)>,
+    pub relocations: Vec<Relocation>,
+    /// a main module has a start address
+    pub main: bool,
+    pub start: Option<(u8, u16)>,
+}
+
+/// A reader of the fields of a record
+struct Fields<'a> {
+    bytes: &'a [u8],
+    kind: u8,
+}
+
+impl Fields<'_> {
+    fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        let (&byte, rest) = self
+            .bytes
+            .split_first()
+            .ok_or_else(|| anyhow!("Truncated record {:02X}H", self.kind))?;
+        self.bytes = rest;
+        Ok(byte)
+    }
+
+    fn word(&mut self) -> Result<u16> {
+        let low = self.byte()?;
+        Ok(u16::from_le_bytes([low, self.byte()?]))
+    }
+
+    /// a name preceded by its length
+    fn name(&mut self) -> Result<String> {
+        let len = self.byte()? as usize;
+        if self.bytes.len() < len {
+            bail!("Truncated record {:02X}H", self.kind);
+        }
+        let (name, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(String::from_utf8_lossy(name).into_owned())
+    }
+
+    /// the symbols of a segment, followed by a reserved byte
+    fn symbols(&mut self) -> Result<Vec<Symbol>> {
+        let segment = self.byte()?;
+        let mut symbols = Vec::new();
+        while !self.is_empty() {
+            let offset = self.word()?;
+            let name = self.name()?;
+            self.byte()?;
+            symbols.push(Symbol {
+                name,
+                segment,
+                offset,
+            });
+        }
+        Ok(symbols)
+    }
+
+    fn words(&mut self) -> Result<Vec<u16>> {
+        let mut words = Vec::new();
+        while !self.is_empty() {
+            words.push(self.word()?);
+        }
+        Ok(words)
+    }
+}
+
+/// the bytes look like an OMF-80 module: a module header with a valid
+/// checksum
+pub fn is_omf(bytes: &[u8]) -> bool {
+    match bytes {
+        [0x02, low, high, ..] => {
+            let len = u16::from_le_bytes([*low, *high]) as usize;
+            bytes.len() >= len + 3 && checksum(&bytes[..len + 3]) == 0
+        }
+        _ => false,
+    }
+}
+
+fn checksum(record: &[u8]) -> u8 {
+    record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+/// parse an OMF-80 module
+pub fn parse(bytes: &[u8]) -> Result<Module> {
+    let mut module = Module::default();
+    let mut pos = 0;
+    let mut header = false;
+    while pos < bytes.len() {
+        let kind = bytes[pos];
+        // a record holds at least its checksum
+        let len = match bytes.get(pos + 1..pos + 3) {
+            Some(&[low, high]) => u16::from_le_bytes([low, high]) as usize,
+            _ => 0,
+        };
+        let record = bytes
+            .get(pos..pos + 3 + len)
+            .filter(|_| len > 0)
+            .ok_or_else(|| anyhow!("Truncated record {:02X}H at {:04X}H", kind, pos))?;
+        if checksum(record) != 0 {
+            bail!("Bad checksum in record {:02X}H at {:04X}H", kind, pos);
+        }
+        if kind != 0x02 && !header {
+            bail!("Missing module header");
+        }
+        let mut fields = Fields {
+            bytes: &record[3..record.len() - 1],
+            kind,
+        };
+        pos += 3 + len;
+        match kind {
+            0x02 => {
+                header = true;
+                module.name = fields.name()?;
+                // the translator and its version
+                fields.word()?;
+                while !fields.is_empty() {
+                    let segment = fields.byte()?;
+                    let len = fields.word()?;
+                    // the alignment
+                    fields.byte()?;
+                    module.segments.push((segment, len));
+                }
+            }
+            0x04 => {
+                module.main = fields.byte()? == 1;
+                let segment = fields.byte()?;
+                let offset = fields.word()?;
+                if module.main {
+                    module.start = Some((segment, offset));
+                }
+            }
+            0x06 => {
+                let segment = fields.byte()?;
+                let offset = fields.word()?;
+                module.content.push(Content {
+                    segment,
+                    offset,
+                    bytes: fields.bytes.to_vec(),
+                });
+            }
+            0x0e => break,
+            0x12 => module.locals.extend(fields.symbols()?),
+            0x16 => module.publics.extend(fields.symbols()?),
+            0x18 => {
+                while !fields.is_empty() {
+                    module.externals.push(fields.name()?);
+                    fields.byte()?;
+                }
+            }
+            0x20 => {
+                // the references are fixed the same way, as words
+                fields.byte()?;
+                while !fields.is_empty() {
+                    let external = fields.word()?;
+                    module.references.push((external, fields.word()?));
+                }
+            }
+            0x22 => {
+                let kind = fields.byte()?;
+                module.relocations.push(Relocation {
+                    kind,
+                    segment: None,
+                    offsets: fields.words()?,
+                });
+            }
+            0x24 => {
+                let segment = fields.byte()?;
+                let kind = fields.byte()?;
+                module.relocations.push(Relocation {
+                    kind,
+                    segment: Some(segment),
+                    offsets: fields.words()?,
+                });
+            }
+            _ => (),
+        }
+    }
+    if !header {
+        bail!("Missing module header");
+    }
+    Ok(module)
+}
+
+/// parse the OMF-80 file `file`
+pub fn from_file(file: &str) -> Result<Module> {
+    parse(&std::fs::read(file)?)
+}
+
+impl Module {
+    /// fail for the modules that are not located
+    fn check_absolute(&self) -> Result<()> {
+        let relocatable = self.content.iter().any(|c| c.segment != ABSOLUTE)
+            || !self.relocations.is_empty()
+            || !self.references.is_empty();
+        if relocatable {
+            bail!("{} is relocatable, locate it first", self.name);
+        }
+        Ok(())
+    }
+
+    /// copy the content into `memory`
+    pub fn load(&self, memory: &mut Memory) -> Result<()> {
+        self.check_absolute()?;
+        for content in &self.content {
+            memory.load_at(content.offset as usize, &content.bytes);
+        }
+        Ok(())
+    }
+
+    /// the content loaded in an empty memory
+    pub fn memory(&self) -> Result<Memory> {
+        let mut memory = Memory::default();
+        self.load(&mut memory)?;
+        Ok(memory)
+    }
+
+    /// the address of the first byte and the bytes up to the last one, the
+    /// gaps being zeros
+    pub fn image(&self) -> Result<(u16, Vec<u8>)> {
+        self.check_absolute()?;
+        let content = self.content.iter().filter(|c| !c.bytes.is_empty());
+        let low = content.clone().map(|c| c.offset as usize).min();
+        let high = content.map(|c| c.offset as usize + c.bytes.len()).max();
+        let (low, high) = match (low, high) {
+            (Some(low), Some(high)) => (low, high),
+            _ => return Ok((0, Vec::new())),
+        };
+        let mut image = vec![0; high - low];
+        for content in &self.content {
+            let start = content.offset as usize - low;
+            image[start..start + content.bytes.len()].copy_from_slice(&content.bytes);
+        }
+        Ok((low as u16, image))
+    }
+
+    /// the absolute start address
+    pub fn entry(&self) -> Option<u16> {
+        match self.start {
+            Some((ABSOLUTE, offset)) => Some(offset),
+            _ => None,
+        }
+    }
+
+    /// the absolute public and local symbols, the public names first
+    pub fn symbols(&self) -> SymbolTable {
+        let mut symbols = SymbolTable::new();
+        for symbol in self.publics.iter().chain(&self.locals) {
+            if symbol.segment == ABSOLUTE && symbols.addr(&symbol.name).is_none() {
+                symbols.insert(&symbol.name, symbol.offset);
+            }
+        }
+        symbols
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a record with its length and its checksum
+    fn record(kind: u8, fields: &[u8]) -> Vec<u8> {
+        let len = (fields.len() + 1) as u16;
+        let mut record = vec![kind, len as u8, (len >> 8) as u8];
+        record.extend_from_slice(fields);
+        record.push(checksum(&record).wrapping_neg());
+        record
+    }
+
+    fn hello() -> Vec<u8> {
+        [
+            record(0x02, b"\x05HELLO\x00\x00\x00\x10\x00\x03"),
+            record(0x06, b"\x00\x00\x01\x0e\x09\x11\x09\x01"),
+            record(0x06, b"\x00\x09\x01hi$"),
+            record(0x16, b"\x00\x00\x01\x05START\x00\x09\x01\x03MSG\x00"),
+            record(0x12, b"\x00\x00\x01\x04MAIN\x00\x05\x01\x03LXI\x00"),
+            record(0x08, b"\x00\x00\x01\x01\x00"),
+            record(0x04, b"\x01\x00\x00\x01"),
+            record(0x0e, b""),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn test_parse() {
+        let bytes = hello();
+        assert!(is_omf(&bytes));
+        let module = parse(&bytes).unwrap();
+        assert_eq!(module.name, "HELLO");
+        assert_eq!(module.segments, [(0, 0x10)]);
+        assert_eq!(module.entry(), Some(0x100));
+        assert_eq!(module.content.len(), 2);
+        assert_eq!(
+            module.image().unwrap(),
+            (0x100, b"\x0e\x09\x11\x09\x01\x00\x00\x00\x00hi$".to_vec())
+        );
+        assert_eq!(module.memory().unwrap()[0x10a], b'i');
+
+        let symbols = module.symbols();
+        assert_eq!(symbols.addr("MSG"), Some(0x109));
+        assert_eq!(symbols.addr("LXI"), Some(0x105));
+        // the public name wins
+        assert_eq!(symbols.name(0x100), Some("START"));
+
+        let mut bad = hello();
+        bad[20] ^= 1;
+        assert!(!is_omf(&bytes[16..]));
+        assert_eq!(
+            parse(&bad).unwrap_err().to_string(),
+            "Bad checksum in record 06H at 0010H"
+        );
+        assert_eq!(
+            parse(&bytes[..30]).unwrap_err().to_string(),
+            "Truncated record 06H at 001CH"
+        );
+        assert_eq!(
+            parse(&bytes[16..]).unwrap_err().to_string(),
+            "Missing module header"
+        );
+    }
+
+    #[test]
+    fn test_relocatable() {
+        let bytes = [
+            record(0x02, b"\x03LIB\x00\x00\x01\x03\x00\x03"),
+            record(0x18, b"\x04EXIT\x00"),
+            record(0x06, b"\x01\x00\x00\xc3\x00\x00"),
+            record(0x20, b"\x03\x00\x00\x01\x00"),
+            record(0x22, b"\x03\x01\x00"),
+            record(0x04, b"\x00\x00\x00\x00"),
+        ]
+        .concat();
+        let module = parse(&bytes).unwrap();
+        assert_eq!(module.externals, ["EXIT"]);
+        assert_eq!(module.references, [(0, 1)]);
+        assert_eq!(
+            module.relocations,
+            [Relocation {
+                kind: 3,
+                segment: None,
+                offsets: vec![1]
+            }]
+        );
+        assert!(!module.main);
+        assert_eq!(
+            module.memory().unwrap_err().to_string(),
+            "LIB is relocatable, locate it first"
+        );
+    }
+}
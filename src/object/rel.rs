@@ -0,0 +1,403 @@
+//! The Microsoft `.REL` relocatable object format of M80, F80 and L80.
+//!
+//! A `.REL` file is a stream of bits, read from the most significant bit of
+//! every byte:
+//! - `0` and 8 bits is an absolute byte
+//! - `1`, a 2 bits segment and 16 bits (low byte first) is a word relative
+//!   to the program, the data or the selected common segment
+//! - `1 00`, a 4 bits control code, an optional address (2 bits segment and
+//!   16 bits) and an optional name (3 bits length and 8 bits characters) is
+//!   a link item: program name, entry point, external chain, sizes, location
+//!   counter, end of module...
+//!
+//! The references to an external symbol are chained: the last one is given
+//! by the link item, and every reference holds the address of the previous
+//! one, down to an absolute 0. A library is a list of modules, the file ends
+//! with the control code 15.
+
+use anyhow::{bail, Result};
+
+/// The segments an address is relative to
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Segment {
+    Absolute,
+    Program,
+    Data,
+    /// a named common block, the blank common being `""`
+    Common(String),
+}
+
+/// An address relative to a segment
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Address {
+    pub segment: Segment,
+    pub offset: u16,
+}
+
+impl Address {
+    pub fn absolute(offset: u16) -> Self {
+        Address {
+            segment: Segment::Absolute,
+            offset,
+        }
+    }
+}
+
+/// The bytes written by a module
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Content {
+    Byte(u8),
+    /// a word to relocate
+    Word(Address),
+}
+
+/// A module of a `.REL` file
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Module {
+    pub name: String,
+    pub program_size: u16,
+    pub data_size: u16,
+    /// the common blocks and their size
+    pub commons: Vec<(String, u16)>,
+    /// the public symbols defined by the module
+    pub entries: Vec<(String, Address)>,
+    /// the external symbols and the head of the chain of their references
+    pub externals: Vec<(String, Address)>,
+    /// chains of references to an address of the module itself
+    pub chains: Vec<(Address, Address)>,
+    /// a value to add to the word at an address, once the externals are
+    /// resolved
+    pub offsets: Vec<(Address, u16)>,
+    /// the libraries the module asks to search
+    pub requests: Vec<String>,
+    /// the bytes, at their address
+    pub content: Vec<(Address, Content)>,
+    pub start: Option<Address>,
+}
+
+/// A reader of the bits, the most significant first
+struct Bits<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Bits<'_> {
+    fn remaining(&self) -> usize {
+        (self.bytes.len() * 8).saturating_sub(self.pos)
+    }
+
+    fn bits(&mut self, n: usize) -> Result<u16> {
+        if self.remaining() < n {
+            bail!("Unexpected end of the file at byte {}", self.pos / 8);
+        }
+        let mut value = 0;
+        for _ in 0..n {
+            let bit = self.bytes[self.pos / 8] >> (7 - self.pos % 8) & 1;
+            value = value << 1 | bit as u16;
+            self.pos += 1;
+        }
+        Ok(value)
+    }
+
+    fn word(&mut self) -> Result<u16> {
+        let low = self.bits(8)?;
+        let high = self.bits(8)?;
+        Ok(high << 8 | low)
+    }
+
+    fn segment(&mut self, common: &str) -> Result<Segment> {
+        Ok(match self.bits(2)? {
+            0 => Segment::Absolute,
+            1 => Segment::Program,
+            2 => Segment::Data,
+            _ => Segment::Common(common.to_string()),
+        })
+    }
+
+    /// the A field of a link item
+    fn address(&mut self, common: &str) -> Result<Address> {
+        let segment = self.segment(common)?;
+        Ok(Address {
+            segment,
+            offset: self.word()?,
+        })
+    }
+
+    /// the B field of a link item
+    fn name(&mut self) -> Result<String> {
+        let len = self.bits(3)?;
+        let mut name = String::new();
+        for _ in 0..len {
+            name.push((self.bits(8)? & 0x7f) as u8 as char);
+        }
+        Ok(name.trim_end().to_string())
+    }
+
+    fn align(&mut self) {
+        self.pos = self.pos.div_ceil(8) * 8;
+    }
+}
+
+/// parse the modules of a `.REL` file or library
+pub fn parse(bytes: &[u8]) -> Result<Vec<Module>> {
+    let mut bits = Bits { bytes, pos: 0 };
+    let mut modules = Vec::new();
+    let mut module = Module::default();
+    // the selected common block and the location counter
+    let mut common = String::new();
+    let mut location = Address::absolute(0);
+    let mut started = false;
+
+    // a module ends with an aligned code 14, a truncated file is accepted
+    // after it
+    while bits.remaining() >= 8 {
+        let content = match bits.bits(1)? {
+            0 => Content::Byte(bits.bits(8)? as u8),
+            _ => match bits.segment(&common)? {
+                Segment::Absolute => {
+                    let code = bits.bits(4)?;
+                    started |= code != 15;
+                    match code {
+                        0 | 3 | 4 => {
+                            // entry symbol of a library, library search,
+                            // extension
+                            let name = bits.name()?;
+                            if code == 3 {
+                                module.requests.push(name);
+                            }
+                        }
+                        1 => common = bits.name()?,
+                        2 => module.name = bits.name()?,
+                        5 => {
+                            let size = bits.address(&common)?.offset;
+                            let name = bits.name()?;
+                            module.commons.push((name, size));
+                        }
+                        6 => {
+                            let head = bits.address(&common)?;
+                            module.externals.push((bits.name()?, head));
+                        }
+                        7 => {
+                            let addr = bits.address(&common)?;
+                            module.entries.push((bits.name()?, addr));
+                        }
+                        8 | 9 => {
+                            let offset = bits.address(&common)?.offset;
+                            let offset = match code {
+                                8 => offset.wrapping_neg(),
+                                _ => offset,
+                            };
+                            module.offsets.push((location.clone(), offset));
+                        }
+                        10 => module.data_size = bits.address(&common)?.offset,
+                        11 => location = bits.address(&common)?,
+                        12 => {
+                            let head = bits.address(&common)?;
+                            module.chains.push((head, location.clone()));
+                        }
+                        13 => module.program_size = bits.address(&common)?.offset,
+                        14 => {
+                            let start = bits.address(&common)?;
+                            if start != Address::absolute(0) {
+                                module.start = Some(start);
+                            }
+                            bits.align();
+                            modules.push(std::mem::take(&mut module));
+                            common.clear();
+                            location = Address::absolute(0);
+                            started = false;
+                        }
+                        _ => break,
+                    }
+                    continue;
+                }
+                segment => Content::Word(Address {
+                    segment,
+                    offset: bits.word()?,
+                }),
+            },
+        };
+        started = true;
+        let len = match content {
+            Content::Byte(_) => 1,
+            Content::Word(_) => 2,
+        };
+        module.content.push((location.clone(), content));
+        location.offset = location.offset.wrapping_add(len);
+    }
+    if started {
+        bail!("Missing end of module {}", module.name);
+    }
+    Ok(modules)
+}
+
+/// parse the `.REL` file `file`
+pub fn from_file(file: &str) -> Result<Vec<Module>> {
+    parse(&std::fs::read(file)?)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A writer of `.REL` files
+    #[derive(Default)]
+    pub struct Writer {
+        bytes: Vec<u8>,
+        bits: usize,
+    }
+
+    impl Writer {
+        pub fn bits(&mut self, n: usize, value: u16) -> &mut Self {
+            for i in (0..n).rev() {
+                if self.bits.is_multiple_of(8) {
+                    self.bytes.push(0);
+                }
+                let bit = (value >> i & 1) as u8;
+                *self.bytes.last_mut().unwrap() |= bit << (7 - self.bits % 8);
+                self.bits += 1;
+            }
+            self
+        }
+
+        pub fn byte(&mut self, byte: u8) -> &mut Self {
+            self.bits(1, 0).bits(8, byte as u16)
+        }
+
+        pub fn word(&mut self, segment: u16, word: u16) -> &mut Self {
+            self.bits(1, 1)
+                .bits(2, segment)
+                .bits(8, word & 0xff)
+                .bits(8, word >> 8)
+        }
+
+        /// a link item with its A and B fields
+        pub fn link(&mut self, code: u16, a: Option<(u16, u16)>, b: Option<&str>) -> &mut Self {
+            self.bits(3, 0b100).bits(4, code);
+            if let Some((segment, value)) = a {
+                self.bits(2, segment)
+                    .bits(8, value & 0xff)
+                    .bits(8, value >> 8);
+            }
+            if let Some(name) = b {
+                self.bits(3, name.len() as u16);
+                for c in name.bytes() {
+                    self.bits(8, c as u16);
+                }
+            }
+            self
+        }
+
+        /// end the module, with its start address
+        pub fn end(&mut self, start: Option<u16>) -> &mut Self {
+            match start {
+                Some(start) => self.link(14, Some((1, start)), None),
+                None => self.link(14, Some((0, 0)), None),
+            };
+            self.bits = self.bytes.len() * 8;
+            self
+        }
+
+        pub fn finish(&mut self) -> Vec<u8> {
+            self.link(15, None, None);
+            let mut bytes = std::mem::take(&mut self.bytes);
+            // the padding of the last CP/M record
+            bytes.extend_from_slice(&[0x1a; 4]);
+            bytes
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        // MAIN: LXI H,DATA ; CALL PRINT ; CALL PRINT ; JMP 0, with PRINT
+        // external and DATA at the start of the data segment
+        let bytes = Writer::default()
+            .link(2, None, Some("MAIN"))
+            .link(13, Some((1, 12)), None)
+            .link(10, Some((0, 2)), None)
+            .link(7, Some((1, 0)), Some("START"))
+            .link(11, Some((1, 0)), None)
+            .byte(0x21)
+            .word(2, 0)
+            .byte(0xcd)
+            .bits(1, 0)
+            .bits(8, 0)
+            .bits(1, 0)
+            .bits(8, 0)
+            .byte(0xcd)
+            .word(1, 4)
+            .byte(0xc3)
+            .byte(0)
+            .byte(0)
+            .link(11, Some((2, 0)), None)
+            .byte(b'h')
+            .byte(b'i')
+            .link(6, Some((1, 7)), Some("PRINT"))
+            .link(3, None, Some("LIB"))
+            .end(Some(0))
+            .finish();
+        let modules = parse(&bytes).unwrap();
+        assert_eq!(modules.len(), 1);
+        let module = &modules[0];
+        assert_eq!(module.name, "MAIN");
+        assert_eq!((module.program_size, module.data_size), (12, 2));
+        assert_eq!(
+            module.entries,
+            [(
+                "START".to_string(),
+                Address {
+                    segment: Segment::Program,
+                    offset: 0
+                }
+            )]
+        );
+        assert_eq!(module.externals[0].0, "PRINT");
+        assert_eq!(module.externals[0].1.offset, 7);
+        assert_eq!(module.requests, ["LIB"]);
+        assert_eq!(module.content.len(), 12);
+        assert_eq!(
+            module.content[1],
+            (
+                Address {
+                    segment: Segment::Program,
+                    offset: 1
+                },
+                Content::Word(Address {
+                    segment: Segment::Data,
+                    offset: 0
+                })
+            )
+        );
+        assert_eq!(
+            module.content[11].0,
+            Address {
+                segment: Segment::Data,
+                offset: 1
+            }
+        );
+        assert_eq!(
+            module.start,
+            Some(Address {
+                segment: Segment::Program,
+                offset: 0
+            })
+        );
+
+        // a library of two modules
+        let bytes = Writer::default()
+            .link(2, None, Some("A"))
+            .end(None)
+            .link(2, None, Some("B"))
+            .end(None)
+            .finish();
+        let names: Vec<_> = parse(&bytes).unwrap().into_iter().map(|m| m.name).collect();
+        assert_eq!(names, ["A", "B"]);
+
+        let truncated = Writer::default()
+            .link(2, None, Some("A"))
+            .byte(0)
+            .bytes
+            .clone();
+        assert!(parse(&truncated).is_err());
+    }
+}
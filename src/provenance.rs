@@ -0,0 +1,168 @@
+//! Memory write provenance.
+//!
+//! When [Memory::provenance](../struct.Memory.html#structfield.provenance) is
+//! set, every byte written by the cpu is recorded with the PC of the writing
+//! instruction and the number of instructions executed before it. Only the
+//! last writes are kept, either for each address or for each 256 bytes page,
+//! to bound the memory used.
+//!
+//! The writes made outside of an instruction, by a debugger for example, are
+//! not recorded.
+
+use std::collections::{HashMap, VecDeque};
+use std::ops::RangeInclusive;
+
+/// A byte stored by the cpu
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MemoryWrite {
+    /// the address written
+    pub addr: u16,
+    /// the address of the writing instruction
+    pub pc: u16,
+    /// the value of `Cpu::instructions` when the instruction was executed
+    pub instruction: u64,
+}
+
+/// How the writes are grouped before keeping only the last ones
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Granularity {
+    Address,
+    Page,
+}
+
+#[derive(Clone, Debug)]
+pub struct Provenance {
+    granularity: Granularity,
+    /// number of writes kept per address or per page
+    depth: usize,
+    /// the writes of every address or page, the most recent last
+    writes: HashMap<u16, VecDeque<MemoryWrite>>,
+}
+
+impl Provenance {
+    /// keep the last `depth` writes of every address
+    pub fn new(depth: usize) -> Self {
+        Self {
+            granularity: Granularity::Address,
+            depth: depth.max(1),
+            writes: HashMap::new(),
+        }
+    }
+
+    /// keep the last `depth` writes of every 256 bytes page
+    pub fn per_page(depth: usize) -> Self {
+        Self {
+            granularity: Granularity::Page,
+            ..Self::new(depth)
+        }
+    }
+
+    pub fn granularity(&self) -> Granularity {
+        self.granularity
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    fn key(&self, addr: u16) -> u16 {
+        match self.granularity {
+            Granularity::Address => addr,
+            Granularity::Page => addr >> 8,
+        }
+    }
+
+    pub fn record(&mut self, write: MemoryWrite) {
+        let depth = self.depth;
+        let writes = self.writes.entry(self.key(write.addr)).or_default();
+        if writes.len() == depth {
+            writes.pop_front();
+        }
+        writes.push_back(write);
+    }
+
+    /// the recorded writes to `range`, the most recent first
+    pub fn writes(&self, range: RangeInclusive<u16>) -> Vec<MemoryWrite> {
+        let mut writes: Vec<MemoryWrite> = match self.granularity {
+            Granularity::Address => range
+                .clone()
+                .filter_map(|addr| self.writes.get(&addr))
+                .flatten()
+                .copied()
+                .collect(),
+            Granularity::Page => (self.key(*range.start())..=self.key(*range.end()))
+                .filter_map(|page| self.writes.get(&page))
+                .flatten()
+                .filter(|write| range.contains(&write.addr))
+                .copied()
+                .collect(),
+        };
+        writes.sort_by(|a, b| b.instruction.cmp(&a.instruction).then(a.addr.cmp(&b.addr)));
+        writes
+    }
+
+    /// the most recent write to `addr`
+    pub fn last_write(&self, addr: u16) -> Option<MemoryWrite> {
+        self.writes(addr..=addr).first().copied()
+    }
+
+    pub fn clear(&mut self) {
+        self.writes.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(addr: u16, pc: u16, instruction: u64) -> MemoryWrite {
+        MemoryWrite {
+            addr,
+            pc,
+            instruction,
+        }
+    }
+
+    #[test]
+    fn test_provenance() {
+        let mut provenance = Provenance::new(2);
+        provenance.record(write(0x2400, 0x100, 1));
+        provenance.record(write(0x2400, 0x103, 2));
+        provenance.record(write(0x2401, 0x106, 3));
+        provenance.record(write(0x2400, 0x109, 4));
+        provenance.record(write(0x2410, 0x10c, 5));
+
+        assert_eq!(
+            provenance.writes(0x2400..=0x240f),
+            [
+                write(0x2400, 0x109, 4),
+                write(0x2401, 0x106, 3),
+                write(0x2400, 0x103, 2)
+            ]
+        );
+        assert_eq!(provenance.last_write(0x2410), Some(write(0x2410, 0x10c, 5)));
+        assert_eq!(provenance.last_write(0x2402), None);
+
+        provenance.record(write(0xffff, 0x10f, 6));
+        assert_eq!(provenance.last_write(0xffff), Some(write(0xffff, 0x10f, 6)));
+        assert_eq!(provenance.writes(0xfff0..=0xffff).len(), 1);
+    }
+
+    #[test]
+    fn test_provenance_per_page() {
+        let mut provenance = Provenance::per_page(2);
+        provenance.record(write(0x2400, 0x100, 1));
+        provenance.record(write(0x2401, 0x103, 2));
+        provenance.record(write(0x24ff, 0x106, 3));
+        provenance.record(write(0x2500, 0x109, 4));
+
+        assert_eq!(
+            provenance.writes(0x2400..=0x24ff),
+            [write(0x24ff, 0x106, 3), write(0x2401, 0x103, 2)]
+        );
+        assert_eq!(
+            provenance.writes(0x24f0..=0x250f),
+            [write(0x2500, 0x109, 4), write(0x24ff, 0x106, 3)]
+        );
+    }
+}
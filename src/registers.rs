@@ -23,9 +23,9 @@ impl Registers {
         Default::default()
     }
 
-    /// give access to a merge of the registers flags and a
+    /// give access to a merge of the registers a and flags
     pub fn psw(&self) -> u16 {
-        self.a as u16 | ((_fix_flags(self.flags) as u16) << 8)
+        (self.a as u16) << 8 | _fix_flags(self.flags) as u16
     }
 
     /// give access to a merge of the register b and c
@@ -48,7 +48,8 @@ impl Registers {
 
     /// set the merge of the registers flags and a
     pub fn set_psw(&mut self, value: u16) {
-        *unsafe { std::mem::transmute::<&mut u8, &mut u16>(&mut self.flags) } = value;
+        self.a = (value >> 8) as u8;
+        self.flags = value as u8;
         self.fix_flags();
     }
 
@@ -67,8 +68,7 @@ impl Registers {
     /// set the merge of the registers H and L to value
     pub fn hl_set(&mut self, value: u16) {
         let hl = unsafe { std::mem::transmute::<&mut u8, &mut u16>(&mut self.h) };
-        // *hl = u16::to_be(value);
-        *hl = value;
+        *hl = u16::to_be(value);
     }
 }
 
@@ -11,7 +11,7 @@ pub enum Flags {
 
 impl Registers {
     /// set the part we should not use of the flags register to their correct value
-    pub(super) fn fix_flags(&mut self) {
+    pub(crate) fn fix_flags(&mut self) {
         self.flags = _fix_flags(self.flags);
     }
 
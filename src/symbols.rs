@@ -0,0 +1,312 @@
+//! Symbol tables mapping names to addresses.
+//!
+//! Three formats are understood:
+//! - the `.SYM` files written by the Digital Research ASM, MAC and RMAC
+//!   assemblers: `ADDR NAME` pairs separated by tabs, several per line
+//! - the Microsoft L80 `.SYM` files: `NAME ADDR` pairs, where the address may
+//!   be followed by a relocation mark (`'`, `"` or `!`)
+//! - a simple text format with one `NAME = ADDR` per line, `;` and `#`
+//!   starting a comment
+//!
+//! [SymbolTable::to_dri] writes the Digital Research format back.
+//!
+//! Names are case insensitive, and written as they were last defined. The
+//! addresses of the text format can use any notation accepted by
+//! [parse_number](../number/fn.parse_number.html).
+
+use crate::number::parse_number;
+use anyhow::{anyhow, bail, Result};
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Clone, Debug, Default)]
+pub struct SymbolTable {
+    /// upper cased name to the name as defined and its address
+    by_name: HashMap<String, (String, u16)>,
+    /// address to the upper cased first name defined there
+    by_addr: BTreeMap<u16, String>,
+}
+
+/// parse a 4 digits hexadecimal address, ignoring an eventual relocation mark
+fn hex_addr(word: &str) -> Option<u16> {
+    let word = word.trim_end_matches(['\'', '"', '!']);
+    if word.is_empty() || word.len() > 4 {
+        return None;
+    }
+    u16::from_str_radix(word, 16).ok()
+}
+
+/// true if `word` is an address that can not be a name, which starts with a
+/// letter and has no relocation mark
+fn only_addr(word: &str) -> bool {
+    hex_addr(word).is_some()
+        && (word.starts_with(|c: char| c.is_ascii_digit()) || word.ends_with(['\'', '"', '!']))
+}
+
+/// strip the CP/M end of file marker
+fn text(content: &str) -> &str {
+    content.split('\x1a').next().unwrap_or_default()
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    /// define `name` at `addr`, replacing a previous definition of `name`
+    pub fn insert(&mut self, name: &str, addr: u16) {
+        let key = name.to_ascii_uppercase();
+        if let Some((_, old)) = self.by_name.insert(key.clone(), (name.to_string(), addr)) {
+            if self.by_addr.get(&old) == Some(&key) {
+                self.by_addr.remove(&old);
+                // another name defined at the old address takes its place
+                let other = self
+                    .by_name
+                    .iter()
+                    .filter(|&(_, &(_, a))| a == old)
+                    .map(|(k, _)| k)
+                    .min();
+                if let Some(other) = other {
+                    self.by_addr.insert(old, other.clone());
+                }
+            }
+        }
+        self.by_addr.entry(addr).or_insert(key);
+    }
+
+    /// the name as defined, from its upper cased key
+    fn spelling(&self, key: &str) -> &str {
+        self.by_name[key].0.as_str()
+    }
+
+    /// add all the symbols of `other`
+    pub fn extend(&mut self, other: &SymbolTable) {
+        for (name, addr) in other.iter() {
+            self.insert(name, addr);
+        }
+    }
+
+    /// address of the symbol `name`
+    pub fn addr(&self, name: &str) -> Option<u16> {
+        self.by_name
+            .get(&name.to_ascii_uppercase())
+            .map(|&(_, addr)| addr)
+    }
+
+    /// name of a symbol defined exactly at `addr`
+    pub fn name(&self, addr: u16) -> Option<&str> {
+        self.by_addr.get(&addr).map(|key| self.spelling(key))
+    }
+
+    /// the closest symbol defined at or before `addr`, with the offset from it
+    pub fn lookup(&self, addr: u16) -> Option<(&str, u16)> {
+        self.by_addr
+            .range(..=addr)
+            .next_back()
+            .map(|(&a, key)| (self.spelling(key), addr - a))
+    }
+
+    /// every symbol, sorted by address
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u16)> {
+        let mut symbols: Vec<(&str, u16)> = self
+            .by_name
+            .values()
+            .map(|(name, addr)| (name.as_str(), *addr))
+            .collect();
+        symbols.sort_by(|a, b| (a.1, a.0).cmp(&(b.1, b.0)));
+        symbols.into_iter()
+    }
+
+    /// resolve either a symbol name or a number
+    pub fn resolve(&self, s: &str) -> Result<u16> {
+        match self.addr(s) {
+            Some(addr) => Ok(addr),
+            None => parse_number(s).map_err(|_| anyhow!("Unknown symbol or number {}", s)),
+        }
+    }
+
+    /// parse a Digital Research `.SYM` file
+    pub fn parse_dri(content: &str) -> Result<Self> {
+        let mut table = Self::new();
+        let mut words = text(content).split_whitespace();
+        while let Some(addr) = words.next() {
+            let addr = hex_addr(addr).ok_or_else(|| anyhow!("Invalid address {}", addr))?;
+            let name = words
+                .next()
+                .ok_or_else(|| anyhow!("Missing name for {:04x}", addr))?;
+            table.insert(name, addr);
+        }
+        Ok(table)
+    }
+
+    /// write a Digital Research `.SYM` file, four symbols per line sorted by
+    /// address
+    pub fn to_dri(&self) -> String {
+        let mut out = String::new();
+        for (i, (name, addr)) in self.iter().enumerate() {
+            let separator = match i % 4 {
+                3 => "\r\n",
+                _ => "\t",
+            };
+            out.push_str(&format!(
+                "{:04X} {}{}",
+                addr,
+                name.to_ascii_uppercase(),
+                separator
+            ));
+        }
+        if out.ends_with('\t') {
+            out.pop();
+            out.push_str("\r\n");
+        }
+        out.push('\x1a');
+        out
+    }
+
+    /// parse a Microsoft L80 `.SYM` file
+    pub fn parse_l80(content: &str) -> Result<Self> {
+        let mut table = Self::new();
+        let mut words = text(content).split_whitespace();
+        while let Some(name) = words.next() {
+            let addr = words
+                .next()
+                .ok_or_else(|| anyhow!("Missing address for {}", name))?;
+            let addr = hex_addr(addr).ok_or_else(|| anyhow!("Invalid address {}", addr))?;
+            table.insert(name, addr);
+        }
+        Ok(table)
+    }
+
+    /// parse the `NAME = ADDR` text format
+    pub fn parse_text(content: &str) -> Result<Self> {
+        let mut table = Self::new();
+        for (n, line) in text(content).lines().enumerate() {
+            let line = line.split([';', '#']).next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (name, addr) = match line.split_once('=') {
+                Some(parts) => parts,
+                None => bail!("line {}: expected `NAME = ADDR`", n + 1),
+            };
+            let addr = parse_number(addr.trim()).map_err(|e| anyhow!("line {}: {}", n + 1, e))?;
+            table.insert(name.trim(), addr);
+        }
+        Ok(table)
+    }
+
+    /// parse a symbol file in any of the supported formats
+    pub fn parse(content: &str) -> Result<Self> {
+        let content = text(content);
+        if content.contains('=') {
+            return Self::parse_text(content);
+        }
+        // the addresses come first in the DRI files and second in the L80
+        // ones, a name made of hexadecimal digits can be taken for either
+        let words: Vec<_> = content.split_whitespace().collect();
+        let dri_addr = |word: &&str| word.len() == 4 && hex_addr(word).is_some();
+        let dri = words.iter().step_by(2).all(dri_addr)
+            && !words.iter().skip(1).step_by(2).any(|w| only_addr(w));
+        let l80 = words
+            .iter()
+            .skip(1)
+            .step_by(2)
+            .all(|w| hex_addr(w).is_some())
+            && !words.iter().step_by(2).any(|w| only_addr(w));
+        match words.first() {
+            None => Ok(Self::new()),
+            Some(word) if dri || (!l80 && dri_addr(word)) => Self::parse_dri(content),
+            Some(_) => Self::parse_l80(content),
+        }
+    }
+
+    pub fn from_file(file: &str) -> Result<Self> {
+        let content = std::fs::read(file)?;
+        Self::parse(&String::from_utf8_lossy(&content))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dri() {
+        let table =
+            SymbolTable::parse("0005 BDOS\t0100 START\t0109 PRINT\r\n0120 MSG\r\n\x1a\x1a\x1a")
+                .unwrap();
+        assert_eq!(table.len(), 4);
+        assert_eq!(table.addr("print"), Some(0x109));
+        assert_eq!(table.name(0x100), Some("START"));
+        assert_eq!(table.lookup(0x10b), Some(("PRINT", 2)));
+        assert_eq!(table.lookup(0x0004), None);
+
+        let written = table.to_dri();
+        assert_eq!(
+            written,
+            "0005 BDOS\t0100 START\t0109 PRINT\t0120 MSG\r\n\x1a"
+        );
+        assert_eq!(SymbolTable::parse(&written).unwrap().iter().count(), 4);
+    }
+
+    #[test]
+    fn test_l80() {
+        let table = SymbolTable::parse("START   0100'   PRINT   0109'\nBDOS    0005").unwrap();
+        assert_eq!(table.addr("START"), Some(0x100));
+        assert_eq!(table.addr("BDOS"), Some(0x5));
+        assert_eq!(table.name(0x109), Some("PRINT"));
+
+        // names which are also hexadecimal numbers
+        let table = SymbolTable::parse("BEEF    0100'   CAFE    0200\nADD1    0005").unwrap();
+        assert_eq!(table.addr("BEEF"), Some(0x100));
+        assert_eq!(table.addr("ADD1"), Some(0x5));
+        let table = SymbolTable::parse("0100 BEEF\t0200 CAFE\r\n").unwrap();
+        assert_eq!(table.addr("CAFE"), Some(0x200));
+    }
+
+    #[test]
+    fn test_text() {
+        let table = SymbolTable::parse(
+            "; cp/m entry points\nbdos = 5\nwboot = 0x0000 # warm boot\nPRINT = 109H\n",
+        )
+        .unwrap();
+        assert_eq!(table.addr("BDOS"), Some(5));
+        assert_eq!(table.addr("wboot"), Some(0));
+        assert_eq!(table.resolve("print").unwrap(), 0x109);
+        assert_eq!(table.resolve("0x42").unwrap(), 0x42);
+        assert!(table.resolve("nope").is_err());
+        let names: Vec<_> = table.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["wboot", "bdos", "PRINT"]);
+        assert!(SymbolTable::parse_text("oops").is_err());
+    }
+
+    #[test]
+    fn test_redefine() {
+        let mut table = SymbolTable::new();
+        table.insert("start", 0x100);
+        table.insert("main", 0x100);
+        table.insert("start", 0x200);
+        assert_eq!(table.name(0x100), Some("main"));
+        assert_eq!(table.name(0x200), Some("start"));
+        assert_eq!(table.lookup(0x105), Some(("main", 5)));
+        table.insert("main", 0x300);
+        assert_eq!(table.name(0x100), None);
+
+        // the case of a name does not depend on the other definitions
+        let mut table = SymbolTable::new();
+        table.insert("Loop", 0x100);
+        table.insert("Next", 0x100);
+        table.insert("LOOP", 0x100);
+        assert_eq!(table.name(0x100), Some("LOOP"));
+        assert_eq!(table.lookup(0x101), Some(("LOOP", 1)));
+        let names: Vec<_> = table.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["LOOP", "Next"]);
+    }
+}
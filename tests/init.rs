@@ -1,7 +1,7 @@
 pub static mut FINISHED: bool = false;
 
 pub fn cpu(file: &str) -> rust_8080::Cpu {
-    let mut cpu = rust_8080::Cpu::from_filename_at(&file, 0x100).unwrap();
+    let mut cpu = rust_8080::Cpu::from_filename_at(file, 0x100).unwrap();
     // inject "out 1, a at 0x0000 (signal to stop the test)
     cpu.ram[0] = 0xD3;
     cpu.ram[1] = 0x00;
@@ -20,7 +20,7 @@ pub fn cpu(file: &str) -> rust_8080::Cpu {
         } else if op == 9 {
             // print from de until '$'
             let mut addr = cpu.reg.de() as usize;
-            while cpu.ram[addr] != '$' as u8 {
+            while cpu.ram[addr] != b'$' {
                 eprint!("{}", cpu.ram[addr] as char);
                 addr += 1;
             }